@@ -4,6 +4,10 @@ import (
 	"context"
 	"time"
 
+	"order-service/internal/tracing"
+
+	"github.com/opentracing/opentracing-go"
+	"github.com/opentracing/opentracing-go/ext"
 	"github.com/segmentio/kafka-go"
 	"go.uber.org/zap"
 )
@@ -22,6 +26,9 @@ func NewConsumer(brokers []string, topic, groupID string, logger *zap.Logger) *C
 		MaxBytes:       10e6,
 		CommitInterval: time.Second,
 		StartOffset:    kafka.LastOffset,
+		// Skip messages from aborted transactions, e.g. saga responses from a
+		// payment-service instance that crashed mid-transaction
+		IsolationLevel: kafka.ReadCommitted,
 	})
 
 	return &Consumer{
@@ -30,7 +37,9 @@ func NewConsumer(brokers []string, topic, groupID string, logger *zap.Logger) *C
 	}
 }
 
-func (c *Consumer) Consume(ctx context.Context, handler func([]byte) error) error {
+// Consume hands each message to handler with a consumer span that continues
+// the sender's trace, if the message carries one.
+func (c *Consumer) Consume(ctx context.Context, handler func(context.Context, []byte) error) error {
 	for {
 		select {
 		case <-ctx.Done():
@@ -46,7 +55,14 @@ func (c *Consumer) Consume(ctx context.Context, handler func([]byte) error) erro
 				zap.String("topic", msg.Topic),
 				zap.String("key", string(msg.Key)))
 
-			if err := handler(msg.Value); err != nil {
+			span := c.startSpan(msg)
+			err = handler(opentracing.ContextWithSpan(ctx, span), msg.Value)
+			if err != nil {
+				ext.LogError(span, err)
+			}
+			span.Finish()
+
+			if err != nil {
 				c.logger.Error("Failed to handle message", zap.Error(err))
 			} else {
 				if err := c.reader.CommitMessages(ctx, msg); err != nil {
@@ -57,6 +73,22 @@ func (c *Consumer) Consume(ctx context.Context, handler func([]byte) error) erro
 	}
 }
 
+func (c *Consumer) startSpan(msg kafka.Message) opentracing.Span {
+	opts := []opentracing.StartSpanOption{ext.SpanKindConsumer}
+	for _, header := range msg.Headers {
+		if header.Key != tracing.TraceparentHeader {
+			continue
+		}
+		if parent, ok := tracing.SpanContextFromTraceparent(string(header.Value)); ok {
+			opts = append(opts, opentracing.ChildOf(parent))
+		}
+	}
+
+	span := opentracing.StartSpan("kafka.consume "+msg.Topic, opts...)
+	ext.MessageBusDestination.Set(span, msg.Topic)
+	return span
+}
+
 func (c *Consumer) Close() error {
 	return c.reader.Close()
 }
@@ -5,6 +5,10 @@ import (
 	"encoding/json"
 	"time"
 
+	"order-service/internal/tracing"
+
+	"github.com/opentracing/opentracing-go"
+	"github.com/opentracing/opentracing-go/ext"
 	"github.com/segmentio/kafka-go"
 	"go.uber.org/zap"
 )
@@ -29,7 +33,14 @@ func NewProducer(brokers []string, logger *zap.Logger) *Producer {
 	}
 }
 
+// PublishMessage sends value as JSON. The message carries the trace context
+// of a producer span, so consumers continue the caller's trace.
 func (p *Producer) PublishMessage(ctx context.Context, topic string, key string, value interface{}) error {
+	span, ctx := opentracing.StartSpanFromContext(ctx, "kafka.produce "+topic)
+	defer span.Finish()
+	ext.SpanKindProducer.Set(span)
+	ext.MessageBusDestination.Set(span, topic)
+
 	data, err := json.Marshal(value)
 	if err != nil {
 		p.logger.Error("Failed to marshal message", zap.Error(err))
@@ -42,9 +53,16 @@ func (p *Producer) PublishMessage(ctx context.Context, topic string, key string,
 		Value: data,
 		Time:  time.Now(),
 	}
+	if traceparent := tracing.Traceparent(ctx); traceparent != "" {
+		msg.Headers = append(msg.Headers, kafka.Header{
+			Key:   tracing.TraceparentHeader,
+			Value: []byte(traceparent),
+		})
+	}
 
 	err = p.writer.WriteMessages(ctx, msg)
 	if err != nil {
+		ext.LogError(span, err)
 		p.logger.Error("Failed to publish message",
 			zap.String("topic", topic),
 			zap.Error(err))
@@ -14,6 +14,13 @@ import (
 	"go.uber.org/zap"
 )
 
+const (
+	// Orders are priced in a single currency
+	orderCurrency        = "USD"
+	// Checkout only takes cards so far
+	defaultPaymentMethod = "credit_card"
+)
+
 type Orchestrator struct {
 	db       *sql.DB
 	producer *kafka.Producer
@@ -92,8 +99,11 @@ func (o *Orchestrator) processPayment(ctx context.Context, sagaID uuid.UUID, ord
 		Success:   true,
 		Message:   "Process payment request",
 		Data: map[string]interface{}{
-			"user_id":      order.UserID,
-			"total_amount": order.TotalAmount,
+			"user_id":        order.UserID,
+			"total_amount":   order.TotalAmount,
+			"currency":       orderCurrency,
+			"payment_method": defaultPaymentMethod,
+			"items":          order.Items,
 		},
 		Timestamp: time.Now(),
 	}
@@ -144,6 +154,12 @@ func (o *Orchestrator) rollbackSaga(ctx context.Context, sagaID uuid.UUID, order
 	case models.StepPaymentProcessed:
 		// Rollback inventory
 		o.rollbackInventory(ctx, sagaID, orderID)
+		// Release any hold still placed for the order and stop payment retries
+		if err := o.rollbackPayment(ctx, sagaID, orderID); err != nil {
+			o.logger.Error("Failed to request payment rollback",
+				zap.String("saga_id", sagaID.String()),
+				zap.Error(err))
+		}
 	case models.StepInventoryReserved:
 		// Nothing to rollback
 	}
@@ -178,6 +194,19 @@ func (o *Orchestrator) rollbackInventory(ctx context.Context, sagaID uuid.UUID,
 	return o.producer.PublishMessage(ctx, "inventory-rollback", sagaID.String(), event)
 }
 
+func (o *Orchestrator) rollbackPayment(ctx context.Context, sagaID uuid.UUID, orderID uuid.UUID) error {
+	event := models.SagaEvent{
+		SagaID:    sagaID.String(),
+		OrderID:   orderID.String(),
+		Step:      models.StepPaymentRollback,
+		Success:   true,
+		Message:   "Rollback payment request",
+		Timestamp: time.Now(),
+	}
+
+	return o.producer.PublishMessage(ctx, "payment-rollback", sagaID.String(), event)
+}
+
 // StartSagaResponseConsumer listens for saga step responses
 func (o *Orchestrator) StartSagaResponseConsumer(ctx context.Context) {
 	consumer := kafka.NewConsumer(
@@ -188,7 +217,7 @@ func (o *Orchestrator) StartSagaResponseConsumer(ctx context.Context) {
 	)
 	defer consumer.Close()
 
-	handler := func(data []byte) error {
+	handler := func(ctx context.Context, data []byte) error {
 		var event models.SagaEvent
 		if err := json.Unmarshal(data, &event); err != nil {
 			return err
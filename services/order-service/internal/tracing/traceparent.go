@@ -0,0 +1,69 @@
+package tracing
+
+import (
+	"context"
+	"fmt"
+	"strconv"
+	"strings"
+
+	"github.com/opentracing/opentracing-go"
+	"github.com/uber/jaeger-client-go"
+)
+
+// TraceparentHeader is the W3C Trace Context header the payment service
+// reads and writes on Kafka messages.
+const TraceparentHeader = "traceparent"
+
+// Traceparent returns the W3C traceparent of the span in ctx, or "" outside a trace.
+func Traceparent(ctx context.Context) string {
+	span := opentracing.SpanFromContext(ctx)
+	if span == nil {
+		return ""
+	}
+
+	sc, ok := span.Context().(jaeger.SpanContext)
+	if !ok || !sc.IsValid() {
+		return ""
+	}
+
+	flags := 0
+	if sc.IsSampled() {
+		flags = 1
+	}
+
+	return fmt.Sprintf("00-%016x%016x-%016x-%02x",
+		sc.TraceID().High, sc.TraceID().Low, uint64(sc.SpanID()), flags)
+}
+
+// SpanContextFromTraceparent parses a W3C traceparent so a span can continue
+// the sender's trace. Returns false for missing or malformed headers.
+func SpanContextFromTraceparent(header string) (opentracing.SpanContext, bool) {
+	parts := strings.Split(header, "-")
+	if len(parts) != 4 || len(parts[1]) != 32 || len(parts[2]) != 16 || len(parts[3]) != 2 {
+		return nil, false
+	}
+
+	high, err := strconv.ParseUint(parts[1][:16], 16, 64)
+	if err != nil {
+		return nil, false
+	}
+	low, err := strconv.ParseUint(parts[1][16:], 16, 64)
+	if err != nil {
+		return nil, false
+	}
+	spanID, err := strconv.ParseUint(parts[2], 16, 64)
+	if err != nil {
+		return nil, false
+	}
+	flags, err := strconv.ParseUint(parts[3], 16, 8)
+	if err != nil {
+		return nil, false
+	}
+
+	traceID := jaeger.TraceID{High: high, Low: low}
+	if !traceID.IsValid() || spanID == 0 {
+		return nil, false
+	}
+
+	return jaeger.NewSpanContext(traceID, jaeger.SpanID(spanID), 0, flags&1 == 1, nil), true
+}
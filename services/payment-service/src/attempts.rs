@@ -0,0 +1,299 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::models::Money;
+
+pub const ATTEMPT_SUCCEEDED: &str = "succeeded";
+pub const ATTEMPT_AUTHORIZED: &str = "authorized";
+pub const ATTEMPT_PENDING: &str = "pending";
+pub const ATTEMPT_FAILED: &str = "failed";
+
+/// One payment per order (or per standalone request), grouping every attempt to pay it.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PaymentIntent {
+    pub id: Uuid,
+    pub order_id: Option<String>,
+    /// Saga that requested payment for the order, when it came through Kafka
+    pub saga_id: Option<String>,
+    pub user_id: Option<String>,
+    pub amount: f64,
+    pub currency: String,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PaymentAttempt {
+    pub id: Uuid,
+    pub intent_id: Uuid,
+    /// Payment id handed back to the caller for this try
+    pub payment_id: Uuid,
+    pub gateway: String,
+    pub operation: String,
+    pub status: String,
+    pub request_summary: serde_json::Value,
+    pub response_summary: serde_json::Value,
+    pub decline_code: Option<String>,
+    pub latency_ms: i64,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PaymentIntentDetails {
+    #[serde(flatten)]
+    pub intent: PaymentIntent,
+    pub attempts: Vec<PaymentAttempt>,
+}
+
+/// A finished gateway call. Summaries must not contain card data or tokens.
+#[derive(Debug, Clone)]
+pub struct NewAttempt {
+    pub intent_id: Uuid,
+    pub payment_id: Uuid,
+    pub gateway: String,
+    pub operation: String,
+    pub status: String,
+    pub request_summary: serde_json::Value,
+    pub response_summary: serde_json::Value,
+    pub decline_code: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AttemptStore {
+    pool: PgPool,
+}
+
+impl AttemptStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Returns the intent for the order, creating it on the first attempt.
+    /// Requests without an order get an intent of their own.
+    pub async fn open_intent(
+        &self,
+        order_id: Option<&str>,
+        user_id: Option<&str>,
+        amount: &Money,
+    ) -> Result<Uuid> {
+        let now = Utc::now();
+
+        let intent_id = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            INSERT INTO payment_intents
+                (id, order_id, user_id, amount, currency, status, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, 'pending', $6, $6)
+            ON CONFLICT (order_id) WHERE order_id IS NOT NULL DO UPDATE
+            SET amount = EXCLUDED.amount,
+                currency = EXCLUDED.currency,
+                user_id = COALESCE(EXCLUDED.user_id, payment_intents.user_id),
+                updated_at = EXCLUDED.updated_at
+            RETURNING id
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(order_id)
+        .bind(user_id)
+        .bind(amount.amount)
+        .bind(&amount.currency)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await
+        .context("Failed to open payment intent")?;
+
+        Ok(intent_id)
+    }
+
+    /// Stores the attempt and moves the intent to the attempt's outcome. A later
+    /// failure never downgrades an intent that was already paid or authorized.
+    pub async fn record(&self, attempt: NewAttempt) -> Result<()> {
+        let latency_ms = (attempt.completed_at - attempt.started_at).num_milliseconds();
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO payment_attempts
+                (id, intent_id, payment_id, gateway, operation, status, request_summary,
+                 response_summary, decline_code, latency_ms, started_at, completed_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(attempt.intent_id)
+        .bind(attempt.payment_id)
+        .bind(&attempt.gateway)
+        .bind(&attempt.operation)
+        .bind(&attempt.status)
+        .bind(&attempt.request_summary)
+        .bind(&attempt.response_summary)
+        .bind(&attempt.decline_code)
+        .bind(latency_ms)
+        .bind(attempt.started_at)
+        .bind(attempt.completed_at)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to insert payment attempt")?;
+
+        sqlx::query(
+            r#"
+            UPDATE payment_intents
+            SET status = $1, updated_at = $2
+            WHERE id = $3
+              AND NOT ($1 = 'failed' AND status IN ('succeeded', 'authorized'))
+            "#,
+        )
+        .bind(&attempt.status)
+        .bind(attempt.completed_at)
+        .bind(attempt.intent_id)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Remembers which saga the order's payment belongs to, so late outcomes
+    /// (such as an abandoned checkout) can be reported back to it.
+    pub async fn link_saga(&self, order_id: &str, saga_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE payment_intents SET saga_id = $1, updated_at = $2 WHERE order_id = $3
+            "#,
+        )
+        .bind(saga_id)
+        .bind(Utc::now())
+        .bind(order_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn saga_for_order(&self, order_id: &str) -> Result<Option<String>> {
+        let saga_id = sqlx::query_scalar::<_, Option<String>>(
+            r#"
+            SELECT saga_id FROM payment_intents WHERE order_id = $1
+            "#,
+        )
+        .bind(order_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(saga_id.flatten())
+    }
+
+    /// Looks an intent up by its own id or by the payment id of any of its attempts.
+    pub async fn get_with_attempts(&self, id: Uuid) -> Result<Option<PaymentIntentDetails>> {
+        let intent = sqlx::query_as::<_, PaymentIntent>(
+            r#"
+            SELECT * FROM payment_intents
+            WHERE id = $1
+               OR id = (SELECT intent_id FROM payment_attempts WHERE payment_id = $1 LIMIT 1)
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(intent) = intent else {
+            return Ok(None);
+        };
+
+        let attempts = sqlx::query_as::<_, PaymentAttempt>(
+            r#"
+            SELECT * FROM payment_attempts WHERE intent_id = $1 ORDER BY started_at
+            "#,
+        )
+        .bind(intent.id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Some(PaymentIntentDetails { intent, attempts }))
+    }
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payment_intents (
+            id UUID PRIMARY KEY,
+            order_id VARCHAR(255),
+            user_id VARCHAR(255),
+            amount DOUBLE PRECISION NOT NULL,
+            currency VARCHAR(3) NOT NULL,
+            status VARCHAR(50) NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payment_intents table")?;
+
+    sqlx::query(
+        r#"
+        ALTER TABLE payment_intents ADD COLUMN IF NOT EXISTS saga_id VARCHAR(255)
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to add saga_id column to payment_intents table")?;
+
+    sqlx::query(
+        r#"
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_payment_intents_order_id
+        ON payment_intents (order_id) WHERE order_id IS NOT NULL
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payment_attempts (
+            id UUID PRIMARY KEY,
+            intent_id UUID NOT NULL REFERENCES payment_intents(id),
+            payment_id UUID NOT NULL,
+            gateway VARCHAR(50) NOT NULL,
+            operation VARCHAR(50) NOT NULL,
+            status VARCHAR(50) NOT NULL,
+            request_summary JSONB NOT NULL,
+            response_summary JSONB NOT NULL,
+            decline_code VARCHAR(100),
+            latency_ms BIGINT NOT NULL,
+            started_at TIMESTAMPTZ NOT NULL,
+            completed_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payment_attempts table")?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_payment_attempts_intent_id ON payment_attempts (intent_id)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_payment_attempts_payment_id ON payment_attempts (payment_id)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
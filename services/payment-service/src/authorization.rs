@@ -0,0 +1,264 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::models::{FxConversion, Money};
+use crate::payment::PaymentService;
+
+pub const STATUS_AUTHORIZED: &str = "authorized";
+pub const STATUS_CAPTURED: &str = "captured";
+pub const STATUS_VOIDED: &str = "voided";
+pub const STATUS_EXPIRED: &str = "expired";
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AuthorizationRecord {
+    pub payment_id: Uuid,
+    pub order_id: Option<String>,
+    pub user_id: String,
+    pub amount: f64,
+    pub currency: String,
+    pub settlement_amount: f64,
+    pub settlement_currency: String,
+    pub fx_rate: f64,
+    pub fx_rate_as_of: Option<DateTime<Utc>>,
+    pub transaction_id: String,
+    pub status: String,
+    pub expires_at: DateTime<Utc>,
+    pub captured_at: Option<DateTime<Utc>>,
+    pub voided_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl AuthorizationRecord {
+    pub fn conversion(&self) -> FxConversion {
+        FxConversion {
+            presentment: Money::new(self.amount, &self.currency),
+            settlement: Money::new(self.settlement_amount, &self.settlement_currency),
+            rate: self.fx_rate,
+            rate_as_of: self.fx_rate_as_of.unwrap_or(self.created_at),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthorizationError {
+    #[error("Authorization not found")]
+    NotFound,
+    #[error("Authorization is {0}")]
+    InvalidState(String),
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+/// Stores a hold in the caller's transaction, so it commits together with
+/// the events and saga answer that announce it.
+pub async fn insert(tx: &mut Transaction<'_, Postgres>, record: &AuthorizationRecord) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO payment_authorizations
+            (payment_id, order_id, user_id, amount, currency, settlement_amount, settlement_currency,
+             fx_rate, fx_rate_as_of, transaction_id, status, expires_at, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+        "#,
+    )
+    .bind(record.payment_id)
+    .bind(&record.order_id)
+    .bind(&record.user_id)
+    .bind(record.amount)
+    .bind(&record.currency)
+    .bind(record.settlement_amount)
+    .bind(&record.settlement_currency)
+    .bind(record.fx_rate)
+    .bind(record.fx_rate_as_of)
+    .bind(&record.transaction_id)
+    .bind(&record.status)
+    .bind(record.expires_at)
+    .bind(record.created_at)
+    .bind(record.updated_at)
+    .execute(&mut **tx)
+    .await
+    .context("Failed to insert authorization record")?;
+
+    Ok(())
+}
+
+/// Reads the hold and locks it until the caller's transaction ends, so a
+/// capture and a void of the same hold cannot both reach the gateway.
+pub async fn lock(
+    tx: &mut Transaction<'_, Postgres>,
+    payment_id: Uuid,
+) -> Result<Option<AuthorizationRecord>> {
+    let record = sqlx::query_as::<_, AuthorizationRecord>(
+        r#"
+        SELECT * FROM payment_authorizations WHERE payment_id = $1 FOR UPDATE
+        "#,
+    )
+    .bind(payment_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    Ok(record)
+}
+
+/// Moves an authorization out of the `authorized` state in the caller's
+/// transaction, which also records and books the move. Returns false when
+/// the hold was already captured or released.
+pub async fn transition(
+    tx: &mut Transaction<'_, Postgres>,
+    payment_id: Uuid,
+    status: &str,
+) -> Result<bool> {
+    let now = Utc::now();
+
+    let result = sqlx::query(
+        r#"
+        UPDATE payment_authorizations
+        SET status = $1,
+            captured_at = CASE WHEN $1 = 'captured' THEN $2 ELSE captured_at END,
+            voided_at = CASE WHEN $1 <> 'captured' THEN $2 ELSE voided_at END,
+            updated_at = $2
+        WHERE payment_id = $3 AND status = 'authorized'
+        "#,
+    )
+    .bind(status)
+    .bind(now)
+    .bind(payment_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthorizationStore {
+    pool: PgPool,
+}
+
+impl AuthorizationStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Latest authorization placed for an order, whatever its state.
+    pub async fn get_by_order(&self, order_id: &str) -> Result<Option<AuthorizationRecord>> {
+        let record = sqlx::query_as::<_, AuthorizationRecord>(
+            r#"
+            SELECT * FROM payment_authorizations
+            WHERE order_id = $1
+            ORDER BY created_at DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(order_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    pub async fn find_expired(&self, limit: i64) -> Result<Vec<AuthorizationRecord>> {
+        let records = sqlx::query_as::<_, AuthorizationRecord>(
+            r#"
+            SELECT * FROM payment_authorizations
+            WHERE status = 'authorized' AND expires_at < $1
+            ORDER BY expires_at
+            LIMIT $2
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records)
+    }
+}
+
+/// Periodically releases holds that were never captured before they expired.
+pub async fn run_expiry_sweeper(payment_service: Arc<PaymentService>, interval: Duration) {
+    info!("Authorization expiry sweeper started, interval {:?}", interval);
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        match payment_service.expire_stale_authorizations().await {
+            Ok(0) => {}
+            Ok(count) => info!("Voided {} expired authorizations", count),
+            Err(e) => error!("Authorization expiry sweep failed: {}", e),
+        }
+    }
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payment_authorizations (
+            payment_id UUID PRIMARY KEY,
+            order_id VARCHAR(255),
+            user_id VARCHAR(255) NOT NULL,
+            amount DOUBLE PRECISION NOT NULL,
+            transaction_id VARCHAR(255) NOT NULL,
+            status VARCHAR(50) NOT NULL,
+            expires_at TIMESTAMPTZ NOT NULL,
+            captured_at TIMESTAMPTZ,
+            voided_at TIMESTAMPTZ,
+            created_at TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payment_authorizations table")?;
+
+    // Presentment and settlement currency of each hold; older rows were USD only
+    sqlx::query(
+        r#"
+        ALTER TABLE payment_authorizations
+            ADD COLUMN IF NOT EXISTS currency VARCHAR(3) NOT NULL DEFAULT 'USD',
+            ADD COLUMN IF NOT EXISTS settlement_amount DOUBLE PRECISION,
+            ADD COLUMN IF NOT EXISTS settlement_currency VARCHAR(3) NOT NULL DEFAULT 'USD',
+            ADD COLUMN IF NOT EXISTS fx_rate DOUBLE PRECISION NOT NULL DEFAULT 1,
+            ADD COLUMN IF NOT EXISTS fx_rate_as_of TIMESTAMPTZ
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to add currency columns to payment_authorizations table")?;
+
+    sqlx::query(
+        r#"
+        UPDATE payment_authorizations SET settlement_amount = amount WHERE settlement_amount IS NULL
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_payment_authorizations_order_id
+        ON payment_authorizations (order_id)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_payment_authorizations_expiry
+        ON payment_authorizations (status, expires_at)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -3,9 +3,44 @@ use std::env;
 pub struct Config {
     pub port: u16,
     pub kafka_brokers: String,
+    /// Where a consumer group without committed offsets starts: `earliest` or `latest`
+    pub kafka_auto_offset_reset: String,
+    /// Messages each consumer works on at once; one order's messages still run in turn
+    pub kafka_max_in_flight: usize,
+    /// Topics published as protobuf instead of JSON. Only list a topic once
+    /// every service consuming it decodes protobuf.
+    pub kafka_protobuf_topics: Vec<String>,
+    /// Consume saga requests and publish their responses in Kafka transactions
+    pub kafka_exactly_once: bool,
+    /// Must be unique per instance and stable across its restarts
+    pub kafka_transactional_id: String,
+    /// How long each exactly-once transaction takes in messages before committing
+    pub kafka_transaction_interval_ms: u64,
     pub redis_host: String,
     pub jaeger_agent_host: String,
     pub jaeger_agent_port: u16,
+    pub authorization_hold_hours: i64,
+    pub authorization_sweep_interval_secs: u64,
+    pub settlement_currency: String,
+    pub fx_rates_file: Option<String>,
+    pub fx_rate_max_age_hours: i64,
+    pub sandbox_fee_percent: f64,
+    pub sandbox_fee_fixed: f64,
+    pub pending_payment_max_age_minutes: i64,
+    pub pending_payment_sweep_interval_secs: u64,
+    pub subscription_billing_interval_secs: u64,
+    /// Delays after a failed charge, e.g. `1d,3d,7d`
+    pub dunning_retry_schedule: String,
+    pub dunning_interval_secs: u64,
+    pub seller_balance_hold_days: i64,
+    pub payout_interval_secs: u64,
+    pub ledger_check_interval_secs: u64,
+    /// Directory polled for provider settlement reports; unset disables the poller
+    pub reconciliation_inbox_dir: Option<String>,
+    pub reconciliation_interval_secs: u64,
+    pub outbox_relay_interval_ms: u64,
+    /// How long delivered outbox messages are kept before purging
+    pub outbox_retention_hours: i64,
 }
 
 impl Config {
@@ -17,6 +52,30 @@ impl Config {
                 .expect("PORT must be a number"),
             kafka_brokers: env::var("KAFKA_BROKERS")
                 .unwrap_or_else(|_| "localhost:9092".to_string()),
+            kafka_auto_offset_reset: env::var("KAFKA_AUTO_OFFSET_RESET")
+                .unwrap_or_else(|_| "earliest".to_string()),
+            kafka_max_in_flight: env::var("KAFKA_MAX_IN_FLIGHT")
+                .unwrap_or_else(|_| "16".to_string())
+                .parse()
+                .expect("KAFKA_MAX_IN_FLIGHT must be a number"),
+            kafka_protobuf_topics: env::var("KAFKA_PROTOBUF_TOPICS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|topic| !topic.is_empty())
+                .map(String::from)
+                .collect(),
+            kafka_exactly_once: env::var("KAFKA_EXACTLY_ONCE")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .expect("KAFKA_EXACTLY_ONCE must be true or false"),
+            kafka_transactional_id: env::var("KAFKA_TRANSACTIONAL_ID")
+                .or_else(|_| env::var("HOSTNAME").map(|host| format!("payment-service-{}", host)))
+                .unwrap_or_else(|_| "payment-service".to_string()),
+            kafka_transaction_interval_ms: env::var("KAFKA_TRANSACTION_INTERVAL_MS")
+                .unwrap_or_else(|_| "100".to_string())
+                .parse()
+                .expect("KAFKA_TRANSACTION_INTERVAL_MS must be a number"),
             redis_host: env::var("REDIS_HOST")
                 .unwrap_or_else(|_| "localhost".to_string())
                 + ":"
@@ -27,6 +86,72 @@ impl Config {
                 .unwrap_or_else(|_| "6831".to_string())
                 .parse()
                 .expect("JAEGER_AGENT_PORT must be a number"),
+            authorization_hold_hours: env::var("AUTHORIZATION_HOLD_HOURS")
+                .unwrap_or_else(|_| "168".to_string())
+                .parse()
+                .expect("AUTHORIZATION_HOLD_HOURS must be a number"),
+            authorization_sweep_interval_secs: env::var("AUTHORIZATION_SWEEP_INTERVAL_SECS")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse()
+                .expect("AUTHORIZATION_SWEEP_INTERVAL_SECS must be a number"),
+            settlement_currency: env::var("SETTLEMENT_CURRENCY")
+                .unwrap_or_else(|_| "USD".to_string()),
+            fx_rates_file: env::var("FX_RATES_FILE").ok(),
+            fx_rate_max_age_hours: env::var("FX_RATE_MAX_AGE_HOURS")
+                .unwrap_or_else(|_| "48".to_string())
+                .parse()
+                .expect("FX_RATE_MAX_AGE_HOURS must be a number"),
+            sandbox_fee_percent: env::var("SANDBOX_FEE_PERCENT")
+                .unwrap_or_else(|_| "2.9".to_string())
+                .parse()
+                .expect("SANDBOX_FEE_PERCENT must be a number"),
+            sandbox_fee_fixed: env::var("SANDBOX_FEE_FIXED")
+                .unwrap_or_else(|_| "0.30".to_string())
+                .parse()
+                .expect("SANDBOX_FEE_FIXED must be a number"),
+            pending_payment_max_age_minutes: env::var("PENDING_PAYMENT_MAX_AGE_MINUTES")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .expect("PENDING_PAYMENT_MAX_AGE_MINUTES must be a number"),
+            pending_payment_sweep_interval_secs: env::var("PENDING_PAYMENT_SWEEP_INTERVAL_SECS")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse()
+                .expect("PENDING_PAYMENT_SWEEP_INTERVAL_SECS must be a number"),
+            subscription_billing_interval_secs: env::var("SUBSCRIPTION_BILLING_INTERVAL_SECS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .expect("SUBSCRIPTION_BILLING_INTERVAL_SECS must be a number"),
+            dunning_retry_schedule: env::var("DUNNING_RETRY_SCHEDULE")
+                .unwrap_or_else(|_| "1d,3d,7d".to_string()),
+            dunning_interval_secs: env::var("DUNNING_INTERVAL_SECS")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse()
+                .expect("DUNNING_INTERVAL_SECS must be a number"),
+            seller_balance_hold_days: env::var("SELLER_BALANCE_HOLD_DAYS")
+                .unwrap_or_else(|_| "7".to_string())
+                .parse()
+                .expect("SELLER_BALANCE_HOLD_DAYS must be a number"),
+            payout_interval_secs: env::var("PAYOUT_INTERVAL_SECS")
+                .unwrap_or_else(|_| "3600".to_string())
+                .parse()
+                .expect("PAYOUT_INTERVAL_SECS must be a number"),
+            ledger_check_interval_secs: env::var("LEDGER_CHECK_INTERVAL_SECS")
+                .unwrap_or_else(|_| "900".to_string())
+                .parse()
+                .expect("LEDGER_CHECK_INTERVAL_SECS must be a number"),
+            reconciliation_inbox_dir: env::var("RECONCILIATION_INBOX_DIR").ok(),
+            reconciliation_interval_secs: env::var("RECONCILIATION_INTERVAL_SECS")
+                .unwrap_or_else(|_| "600".to_string())
+                .parse()
+                .expect("RECONCILIATION_INTERVAL_SECS must be a number"),
+            outbox_relay_interval_ms: env::var("OUTBOX_RELAY_INTERVAL_MS")
+                .unwrap_or_else(|_| "500".to_string())
+                .parse()
+                .expect("OUTBOX_RELAY_INTERVAL_MS must be a number"),
+            outbox_retention_hours: env::var("OUTBOX_RETENTION_HOURS")
+                .unwrap_or_else(|_| "168".to_string())
+                .parse()
+                .expect("OUTBOX_RETENTION_HOURS must be a number"),
         }
     }
 }
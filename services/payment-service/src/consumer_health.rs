@@ -0,0 +1,192 @@
+use lazy_static::lazy_static;
+use rdkafka::statistics::Statistics;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics::PAYMENT_METRICS;
+
+lazy_static! {
+    pub static ref CONSUMER_HEALTH: ConsumerHealth = ConsumerHealth::default();
+}
+
+/// librdkafka's group state once the consumer has joined its group
+const GROUP_STATE_UP: &str = "up";
+
+/// What a consumer's latest statistics said about it.
+struct ConsumerState {
+    stall_after: Duration,
+    last_stats: Option<Instant>,
+    group_state: String,
+    assigned: i32,
+    lag: i64,
+    /// Sum of every partition's consumed and committed offsets, which only
+    /// moves as messages are taken and finished
+    position: i64,
+    /// When the position last moved or there was nothing to do
+    last_progress: Instant,
+    /// Partitions whose lag is exported
+    partitions: BTreeSet<(String, i32)>,
+    stopped: Option<String>,
+}
+
+impl ConsumerState {
+    /// Why the consumer can't be relied on to keep up, if it can't.
+    fn problem(&self, now: Instant) -> Option<String> {
+        if let Some(reason) = &self.stopped {
+            return Some(format!("stopped: {}", reason));
+        }
+        // Statistics are only served while the consumer polls
+        let Some(last_stats) = self.last_stats else {
+            return Some("no statistics yet".to_string());
+        };
+        if now - last_stats > self.stall_after {
+            return Some(format!(
+                "no statistics for {}s",
+                (now - last_stats).as_secs()
+            ));
+        }
+        if self.group_state != GROUP_STATE_UP {
+            return Some(format!("consumer group is {}", self.group_state));
+        }
+        if self.lag > 0 && now - self.last_progress > self.stall_after {
+            return Some(format!(
+                "{} messages behind with no progress for {}s",
+                self.lag,
+                (now - self.last_progress).as_secs()
+            ));
+        }
+        None
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConsumerReport {
+    pub group: String,
+    pub ready: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub group_state: String,
+    pub assigned_partitions: i32,
+    pub lag: i64,
+    pub seconds_since_progress: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub consumers: Vec<ConsumerReport>,
+}
+
+/// Health of every Kafka consumer in the process, fed by librdkafka's
+/// statistics callbacks. A consumer is healthy while it is in its group and
+/// either caught up or moving; one that stops polling goes quiet, which
+/// counts against it too.
+#[derive(Default)]
+pub struct ConsumerHealth {
+    consumers: Mutex<BTreeMap<String, ConsumerState>>,
+}
+
+impl ConsumerHealth {
+    /// Starts tracking a consumer group. It counts as stuck once it is behind
+    /// and has not moved for `stall_after`.
+    pub fn watch(&self, group: &str, stall_after: Duration) {
+        let mut consumers = self.consumers.lock().expect("consumer health poisoned");
+        consumers.insert(
+            group.to_string(),
+            ConsumerState {
+                stall_after,
+                last_stats: None,
+                group_state: String::new(),
+                assigned: 0,
+                lag: 0,
+                position: 0,
+                last_progress: Instant::now(),
+                partitions: BTreeSet::new(),
+                stopped: None,
+            },
+        );
+    }
+
+    /// Takes in a consumer's statistics and exports the lag of the
+    /// partitions it is assigned.
+    pub fn record(&self, group: &str, statistics: &Statistics) {
+        let mut consumers = self.consumers.lock().expect("consumer health poisoned");
+        let Some(state) = consumers.get_mut(group) else {
+            return;
+        };
+
+        let mut partitions = BTreeSet::new();
+        let mut lag = 0;
+        let mut position = 0;
+        for (topic, topic_stats) in &statistics.topics {
+            // The internal -1 partition holds unassigned messages
+            for p in topic_stats.partitions.values().filter(|p| p.desired && p.partition >= 0) {
+                // Offsets and lag are -1 until known
+                if p.consumer_lag >= 0 {
+                    PAYMENT_METRICS
+                        .kafka_consumer_lag
+                        .with_label_values(&[topic, &p.partition.to_string()])
+                        .set(p.consumer_lag);
+                    lag += p.consumer_lag;
+                }
+                position += p.app_offset.max(0) + p.committed_offset.max(0);
+                partitions.insert((topic.clone(), p.partition));
+            }
+        }
+
+        for (topic, partition) in state.partitions.difference(&partitions) {
+            let _ = PAYMENT_METRICS
+                .kafka_consumer_lag
+                .remove_label_values(&[topic, &partition.to_string()]);
+        }
+
+        let now = Instant::now();
+        if position != state.position || lag == 0 {
+            state.last_progress = now;
+        }
+        state.last_stats = Some(now);
+        state.position = position;
+        state.lag = lag;
+        state.partitions = partitions;
+        if let Some(group) = &statistics.cgrp {
+            state.group_state = group.state.clone();
+            state.assigned = group.assignment_size;
+        }
+    }
+
+    /// Marks a consumer that has given up for good.
+    pub fn stopped(&self, group: &str, reason: &str) {
+        let mut consumers = self.consumers.lock().expect("consumer health poisoned");
+        if let Some(state) = consumers.get_mut(group) {
+            state.stopped = Some(reason.to_string());
+        }
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        let consumers = self.consumers.lock().expect("consumer health poisoned");
+        let now = Instant::now();
+
+        let consumers: Vec<ConsumerReport> = consumers
+            .iter()
+            .map(|(group, state)| {
+                let reason = state.problem(now);
+                ConsumerReport {
+                    group: group.clone(),
+                    ready: reason.is_none(),
+                    reason,
+                    group_state: state.group_state.clone(),
+                    assigned_partitions: state.assigned,
+                    lag: state.lag,
+                    seconds_since_progress: (now - state.last_progress).as_secs(),
+                }
+            })
+            .collect();
+
+        Readiness {
+            ready: consumers.iter().all(|consumer| consumer.ready),
+            consumers,
+        }
+    }
+}
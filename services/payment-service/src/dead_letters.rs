@@ -0,0 +1,265 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rdkafka::message::{Header, Message, OwnedMessage};
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::kafka::{
+    header_map, owned_headers, KafkaProducer, HEADER_RETRY_TOPIC, RETRY_HEADER_PREFIX,
+};
+use crate::metrics::PAYMENT_METRICS;
+
+/// Where saga events go once they can't be parsed or keep failing.
+pub const DEAD_LETTER_TOPIC: &str = "payment-process.dlq";
+
+pub const HEADER_ERROR: &str = "dlq.error";
+pub const HEADER_SOURCE_TOPIC: &str = "dlq.source.topic";
+pub const HEADER_SOURCE_PARTITION: &str = "dlq.source.partition";
+pub const HEADER_SOURCE_OFFSET: &str = "dlq.source.offset";
+pub const HEADER_ATTEMPTS: &str = "dlq.attempts";
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_REDRIVEN: &str = "redriven";
+
+/// A dead-lettered message, kept so it can be inspected and re-driven
+/// without reading the DLQ topic back.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct DeadLetter {
+    pub id: Uuid,
+    pub source_topic: String,
+    pub source_partition: i32,
+    pub source_offset: i64,
+    pub message_key: Option<String>,
+    #[serde(serialize_with = "serialize_payload")]
+    pub payload: Vec<u8>,
+    /// Headers of the original message
+    pub headers: serde_json::Value,
+    pub error: String,
+    pub attempts: i32,
+    pub created_at: DateTime<Utc>,
+    pub redriven_at: Option<DateTime<Utc>>,
+}
+
+fn serialize_payload<S: Serializer>(payload: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(payload))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListDeadLettersQuery {
+    pub source_topic: Option<String>,
+    /// `pending` or `redriven`
+    pub status: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeadLetterError {
+    #[error("Dead letter not found")]
+    NotFound,
+    #[error("Invalid dead letter request: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for DeadLetterError {
+    fn from(e: sqlx::Error) -> Self {
+        DeadLetterError::Failed(e.into())
+    }
+}
+
+#[derive(Debug)]
+pub struct DeadLetterService {
+    pool: PgPool,
+    producer: Arc<KafkaProducer>,
+}
+
+impl DeadLetterService {
+    pub fn new(pool: PgPool, producer: Arc<KafkaProducer>) -> Self {
+        Self { pool, producer }
+    }
+
+    /// Publishes the message to the DLQ with its failure in the headers, then
+    /// records it. Forwarding the same source offset twice records it once.
+    pub async fn forward(
+        &self,
+        message: &OwnedMessage,
+        error: &str,
+        attempts: u32,
+    ) -> Result<()> {
+        let original = header_map(message);
+        let partition = message.partition().to_string();
+        let offset = message.offset().to_string();
+        let attempt_count = attempts.to_string();
+
+        let headers = owned_headers(&original)
+            .insert(Header { key: HEADER_ERROR, value: Some(error) })
+            .insert(Header { key: HEADER_SOURCE_TOPIC, value: Some(message.topic()) })
+            .insert(Header { key: HEADER_SOURCE_PARTITION, value: Some(&partition) })
+            .insert(Header { key: HEADER_SOURCE_OFFSET, value: Some(&offset) })
+            .insert(Header { key: HEADER_ATTEMPTS, value: Some(&attempt_count) });
+
+        let key = message.key().map(|k| String::from_utf8_lossy(k).into_owned());
+        let payload = message.payload().unwrap_or_default();
+
+        self.producer
+            .send_with_headers(DEAD_LETTER_TOPIC, key.as_deref(), payload, headers)
+            .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO dead_letters
+                (id, source_topic, source_partition, source_offset, message_key, payload,
+                 headers, error, attempts, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            ON CONFLICT (source_topic, source_partition, source_offset) DO NOTHING
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(message.topic())
+        .bind(message.partition())
+        .bind(message.offset())
+        .bind(&key)
+        .bind(payload)
+        .bind(serde_json::to_value(&original)?)
+        .bind(error)
+        .bind(attempts as i32)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await
+        .context("Failed to record dead letter")?;
+
+        PAYMENT_METRICS
+            .messages_dead_lettered
+            .with_label_values(&[message.topic()])
+            .inc();
+        warn!(
+            "Dead-lettered {}[{}] offset {} after {} attempts: {}",
+            message.topic(),
+            message.partition(),
+            message.offset(),
+            attempts,
+            error
+        );
+
+        Ok(())
+    }
+
+    pub async fn list(&self, query: &ListDeadLettersQuery) -> Result<Vec<DeadLetter>, DeadLetterError> {
+        let redriven = match query.status.as_deref() {
+            None => None,
+            Some(STATUS_PENDING) => Some(false),
+            Some(STATUS_REDRIVEN) => Some(true),
+            Some(other) => {
+                return Err(DeadLetterError::Invalid(format!("unknown status {}", other)));
+            }
+        };
+
+        let letters = sqlx::query_as::<_, DeadLetter>(
+            r#"
+            SELECT * FROM dead_letters
+            WHERE ($1::VARCHAR IS NULL OR source_topic = $1)
+              AND ($2::BOOLEAN IS NULL OR (redriven_at IS NOT NULL) = $2)
+            ORDER BY created_at DESC
+            LIMIT 200
+            "#,
+        )
+        .bind(&query.source_topic)
+        .bind(redriven)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(letters)
+    }
+
+    /// Publishes the message back to the topic it first arrived on, with its
+    /// original key and headers and a fresh retry budget. Each dead letter is
+    /// re-driven at most once; a message that fails again comes back as a new one.
+    pub async fn redrive(&self, id: Uuid) -> Result<DeadLetter, DeadLetterError> {
+        let mut tx = self.pool.begin().await?;
+
+        let letter = sqlx::query_as::<_, DeadLetter>(
+            r#"
+            SELECT * FROM dead_letters WHERE id = $1 FOR UPDATE
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(DeadLetterError::NotFound)?;
+
+        if letter.redriven_at.is_some() {
+            return Err(DeadLetterError::Invalid(format!(
+                "dead letter {} was already re-driven",
+                id
+            )));
+        }
+
+        let mut original: BTreeMap<String, String> =
+            serde_json::from_value(letter.headers.clone()).unwrap_or_default();
+        let target = original
+            .remove(HEADER_RETRY_TOPIC)
+            .unwrap_or_else(|| letter.source_topic.clone());
+        original.retain(|key, _| !key.starts_with(RETRY_HEADER_PREFIX));
+
+        self.producer
+            .send_with_headers(
+                &target,
+                letter.message_key.as_deref(),
+                &letter.payload,
+                owned_headers(&original),
+            )
+            .await?;
+
+        let letter = sqlx::query_as::<_, DeadLetter>(
+            r#"
+            UPDATE dead_letters
+            SET redriven_at = $1
+            WHERE id = $2
+            RETURNING *
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        info!(
+            "Re-drove dead letter {} from {} offset {} to {}",
+            id, letter.source_topic, letter.source_offset, target
+        );
+        Ok(letter)
+    }
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS dead_letters (
+            id UUID PRIMARY KEY,
+            source_topic VARCHAR(255) NOT NULL,
+            source_partition INTEGER NOT NULL,
+            source_offset BIGINT NOT NULL,
+            message_key VARCHAR(255),
+            payload BYTEA NOT NULL,
+            headers JSONB NOT NULL,
+            error TEXT NOT NULL,
+            attempts INTEGER NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL,
+            redriven_at TIMESTAMPTZ,
+            UNIQUE (source_topic, source_partition, source_offset)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create dead_letters table")?;
+
+    Ok(())
+}
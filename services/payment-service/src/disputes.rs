@@ -0,0 +1,410 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::events::{self, PaymentEvent};
+use crate::metrics::PAYMENT_METRICS;
+use crate::outbox::{self, OutboxMessage};
+
+pub const STATUS_NEEDS_RESPONSE: &str = "needs_response";
+pub const STATUS_UNDER_REVIEW: &str = "under_review";
+pub const STATUS_WON: &str = "won";
+pub const STATUS_LOST: &str = "lost";
+pub const STATUS_CLOSED: &str = "closed";
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct DisputeRecord {
+    pub id: Uuid,
+    pub payment_id: Option<Uuid>,
+    pub order_id: Option<String>,
+    pub provider: String,
+    pub provider_dispute_id: String,
+    pub amount: f64,
+    pub currency: String,
+    pub reason: Option<String>,
+    pub status: String,
+    pub evidence_due_by: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub closed_at: Option<DateTime<Utc>>,
+}
+
+impl DisputeRecord {
+    pub fn is_closed(&self) -> bool {
+        matches!(self.status.as_str(), STATUS_WON | STATUS_LOST | STATUS_CLOSED)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct DisputeEvidence {
+    pub id: Uuid,
+    pub dispute_id: Uuid,
+    pub evidence_type: String,
+    pub description: String,
+    pub document_url: Option<String>,
+    pub submitted_by: Option<String>,
+    pub submitted_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DisputeDetails {
+    #[serde(flatten)]
+    pub dispute: DisputeRecord,
+    pub evidence: Vec<DisputeEvidence>,
+}
+
+/// Provider-neutral view of a dispute webhook, built by the Stripe and PayPal handlers.
+#[derive(Debug, Clone)]
+pub struct DisputeUpdate {
+    pub provider: String,
+    pub provider_dispute_id: String,
+    pub payment_id: Option<Uuid>,
+    pub order_id: Option<String>,
+    pub amount: f64,
+    pub currency: String,
+    pub reason: Option<String>,
+    pub status: String,
+    pub evidence_due_by: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListDisputesQuery {
+    pub status: Option<String>,
+    pub payment_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubmitEvidenceRequest {
+    pub evidence_type: String,
+    pub description: String,
+    pub document_url: Option<String>,
+    pub submitted_by: Option<String>,
+}
+
+/// Published on the `payment-disputes` topic for order-service and finance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisputeEvent {
+    pub event_type: String,
+    pub dispute_id: String,
+    pub payment_id: Option<String>,
+    pub order_id: Option<String>,
+    pub provider: String,
+    pub amount: f64,
+    pub currency: String,
+    pub reason: Option<String>,
+    pub status: String,
+    pub evidence_due_by: Option<DateTime<Utc>>,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DisputeError {
+    #[error("Dispute not found")]
+    NotFound,
+    #[error("Evidence rejected: {0}")]
+    Rejected(String),
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for DisputeError {
+    fn from(e: sqlx::Error) -> Self {
+        DisputeError::Failed(e.into())
+    }
+}
+
+#[derive(Debug)]
+pub struct DisputeService {
+    pool: PgPool,
+}
+
+impl DisputeService {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Stores a dispute notification, moves the linked payment into the matching
+    /// state and tells downstream services about it.
+    pub async fn record(&self, update: DisputeUpdate) -> Result<DisputeRecord> {
+        let mut tx = self.pool.begin().await?;
+
+        let existing = sqlx::query_as::<_, DisputeRecord>(
+            r#"
+            SELECT * FROM payment_disputes
+            WHERE provider = $1 AND provider_dispute_id = $2
+            FOR UPDATE
+            "#,
+        )
+        .bind(&update.provider)
+        .bind(&update.provider_dispute_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let now = Utc::now();
+        let closed_at = match update.status.as_str() {
+            STATUS_WON | STATUS_LOST | STATUS_CLOSED => Some(now),
+            _ => None,
+        };
+
+        let dispute = match &existing {
+            Some(current) => {
+                sqlx::query_as::<_, DisputeRecord>(
+                    r#"
+                    UPDATE payment_disputes
+                    SET status = $1,
+                        reason = COALESCE($2, reason),
+                        evidence_due_by = COALESCE($3, evidence_due_by),
+                        amount = $4,
+                        currency = $5,
+                        closed_at = COALESCE(closed_at, $6),
+                        updated_at = $7
+                    WHERE id = $8
+                    RETURNING *
+                    "#,
+                )
+                .bind(&update.status)
+                .bind(&update.reason)
+                .bind(update.evidence_due_by)
+                .bind(update.amount)
+                .bind(&update.currency)
+                .bind(closed_at)
+                .bind(now)
+                .bind(current.id)
+                .fetch_one(&mut *tx)
+                .await?
+            }
+            None => {
+                sqlx::query_as::<_, DisputeRecord>(
+                    r#"
+                    INSERT INTO payment_disputes
+                        (id, payment_id, order_id, provider, provider_dispute_id, amount, currency,
+                         reason, status, evidence_due_by, created_at, updated_at, closed_at)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+                    RETURNING *
+                    "#,
+                )
+                .bind(Uuid::new_v4())
+                .bind(update.payment_id)
+                .bind(&update.order_id)
+                .bind(&update.provider)
+                .bind(&update.provider_dispute_id)
+                .bind(update.amount)
+                .bind(&update.currency)
+                .bind(&update.reason)
+                .bind(&update.status)
+                .bind(update.evidence_due_by)
+                .bind(now)
+                .bind(now)
+                .bind(closed_at)
+                .fetch_one(&mut *tx)
+                .await?
+            }
+        };
+
+        if let Some(payment_id) = dispute.payment_id {
+            let disputed = PaymentEvent::Disputed {
+                dispute_status: dispute.status.clone(),
+            };
+            events::append(&mut tx, payment_id, &disputed).await?;
+        }
+
+        let status_changed = existing.as_ref().map(|d| d.status != dispute.status);
+        let event_type = match (status_changed, dispute.status.as_str()) {
+            (None, _) => "DISPUTE_OPENED",
+            (Some(true), STATUS_WON) => "DISPUTE_WON",
+            (Some(true), STATUS_LOST) => "DISPUTE_LOST",
+            (Some(true), STATUS_CLOSED) => "DISPUTE_CLOSED",
+            _ => "DISPUTE_UPDATED",
+        };
+        outbox::enqueue(&mut tx, &Self::dispute_message(event_type, &dispute)?).await?;
+
+        tx.commit().await?;
+
+        if existing.is_none() {
+            PAYMENT_METRICS.disputes_opened.inc();
+        }
+        let previous_status = existing.as_ref().map(|d| d.status.as_str());
+        if dispute.status == STATUS_LOST && previous_status != Some(STATUS_LOST) {
+            PAYMENT_METRICS.chargebacks.inc();
+        }
+
+        tracing::info!(
+            "{} dispute {} is {} ({})",
+            dispute.provider,
+            dispute.provider_dispute_id,
+            dispute.status,
+            event_type
+        );
+
+        Ok(dispute)
+    }
+
+    pub async fn list(&self, query: &ListDisputesQuery) -> Result<Vec<DisputeRecord>> {
+        let disputes = sqlx::query_as::<_, DisputeRecord>(
+            r#"
+            SELECT * FROM payment_disputes
+            WHERE ($1::VARCHAR IS NULL OR status = $1)
+              AND ($2::UUID IS NULL OR payment_id = $2)
+            ORDER BY evidence_due_by ASC NULLS LAST, created_at DESC
+            "#,
+        )
+        .bind(&query.status)
+        .bind(query.payment_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(disputes)
+    }
+
+    pub async fn get(&self, dispute_id: Uuid) -> Result<Option<DisputeDetails>> {
+        let dispute = sqlx::query_as::<_, DisputeRecord>(
+            r#"
+            SELECT * FROM payment_disputes WHERE id = $1
+            "#,
+        )
+        .bind(dispute_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let dispute = match dispute {
+            Some(dispute) => dispute,
+            None => return Ok(None),
+        };
+
+        let evidence = sqlx::query_as::<_, DisputeEvidence>(
+            r#"
+            SELECT * FROM payment_dispute_evidence
+            WHERE dispute_id = $1
+            ORDER BY submitted_at
+            "#,
+        )
+        .bind(dispute_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Some(DisputeDetails { dispute, evidence }))
+    }
+
+    /// Records evidence metadata for a dispute that is still awaiting our response.
+    pub async fn submit_evidence(
+        &self,
+        dispute_id: Uuid,
+        req: SubmitEvidenceRequest,
+    ) -> Result<DisputeEvidence, DisputeError> {
+        if req.evidence_type.trim().is_empty() || req.description.trim().is_empty() {
+            return Err(DisputeError::Rejected(
+                "evidence_type and description are required".to_string(),
+            ));
+        }
+
+        let dispute = sqlx::query_as::<_, DisputeRecord>(
+            r#"
+            SELECT * FROM payment_disputes WHERE id = $1
+            "#,
+        )
+        .bind(dispute_id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(DisputeError::NotFound)?;
+
+        if dispute.is_closed() {
+            return Err(DisputeError::Rejected(format!("dispute is {}", dispute.status)));
+        }
+        if let Some(due_by) = dispute.evidence_due_by {
+            if due_by < Utc::now() {
+                return Err(DisputeError::Rejected(format!(
+                    "evidence was due by {}",
+                    due_by.to_rfc3339()
+                )));
+            }
+        }
+
+        let evidence = sqlx::query_as::<_, DisputeEvidence>(
+            r#"
+            INSERT INTO payment_dispute_evidence
+                (id, dispute_id, evidence_type, description, document_url, submitted_by, submitted_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(dispute_id)
+        .bind(&req.evidence_type)
+        .bind(&req.description)
+        .bind(&req.document_url)
+        .bind(&req.submitted_by)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        tracing::info!("Evidence {} submitted for dispute {}", evidence.id, dispute_id);
+        Ok(evidence)
+    }
+
+    fn dispute_message(event_type: &str, dispute: &DisputeRecord) -> Result<OutboxMessage> {
+        let event = DisputeEvent {
+            event_type: event_type.to_string(),
+            dispute_id: dispute.id.to_string(),
+            payment_id: dispute.payment_id.map(|id| id.to_string()),
+            order_id: dispute.order_id.clone(),
+            provider: dispute.provider.clone(),
+            amount: dispute.amount,
+            currency: dispute.currency.clone(),
+            reason: dispute.reason.clone(),
+            status: dispute.status.clone(),
+            evidence_due_by: dispute.evidence_due_by,
+            timestamp: Utc::now(),
+        };
+
+        let key = dispute.order_id.clone().unwrap_or_else(|| event.dispute_id.clone());
+        OutboxMessage::json("payment-disputes", &key, &event)
+    }
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payment_disputes (
+            id UUID PRIMARY KEY,
+            payment_id UUID REFERENCES payments (id),
+            order_id VARCHAR(255),
+            provider VARCHAR(50) NOT NULL,
+            provider_dispute_id VARCHAR(255) NOT NULL,
+            amount DOUBLE PRECISION NOT NULL,
+            currency VARCHAR(3) NOT NULL,
+            reason VARCHAR(255),
+            status VARCHAR(50) NOT NULL,
+            evidence_due_by TIMESTAMPTZ,
+            created_at TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL,
+            closed_at TIMESTAMPTZ,
+            UNIQUE (provider, provider_dispute_id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payment_disputes table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payment_dispute_evidence (
+            id UUID PRIMARY KEY,
+            dispute_id UUID NOT NULL REFERENCES payment_disputes (id),
+            evidence_type VARCHAR(100) NOT NULL,
+            description TEXT NOT NULL,
+            document_url TEXT,
+            submitted_by VARCHAR(255),
+            submitted_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payment_dispute_evidence table")?;
+
+    Ok(())
+}
@@ -0,0 +1,467 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::outbox::{self, OutboxMessage};
+use crate::payment::{self, PaymentService};
+use crate::subscriptions::SubscriptionService;
+
+pub const KIND_SAGA_PAYMENT: &str = "saga_payment";
+pub const KIND_SUBSCRIPTION_INVOICE: &str = "subscription_invoice";
+
+pub const RETRY_SCHEDULED: &str = "scheduled";
+pub const RETRY_SUCCEEDED: &str = "succeeded";
+pub const RETRY_GAVE_UP: &str = "gave_up";
+pub const RETRY_CANCELED: &str = "canceled";
+
+/// Declines that will not succeed on a later try; retrying them only annoys the issuer.
+const HARD_DECLINES: [&str; 14] = [
+    "stolen_card",
+    "lost_card",
+    "pickup_card",
+    "fraudulent",
+    "restricted_card",
+    "invalid_account",
+    "incorrect_number",
+    "expired_card",
+    "revocation_of_authorization",
+    "payment_method_unavailable",
+    "invalid_split",
+    payment::DECLINE_NOT_RECORDED,
+    payment::DECLINE_UNSUPPORTED_CURRENCY,
+    payment::DECLINE_INTERNAL_ERROR,
+];
+
+/// How long a claimed retry stays invisible to other workers if this one dies mid-charge.
+const CLAIM_LEASE_MINUTES: i64 = 10;
+
+pub fn is_hard_decline(code: &str) -> bool {
+    HARD_DECLINES.contains(&code)
+}
+
+/// Parses a schedule such as `1d,3d,7d` into delays after the first failure.
+/// Units are `d`, `h`, `m` and `s`.
+pub fn parse_schedule(spec: &str) -> Result<Vec<chrono::Duration>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let (value, unit) = part.split_at(part.len() - 1);
+            let value: i64 = value
+                .parse()
+                .with_context(|| format!("Invalid retry delay '{}'", part))?;
+            match unit {
+                "d" => Ok(chrono::Duration::days(value)),
+                "h" => Ok(chrono::Duration::hours(value)),
+                "m" => Ok(chrono::Duration::minutes(value)),
+                "s" => Ok(chrono::Duration::seconds(value)),
+                _ => anyhow::bail!("Invalid retry delay unit in '{}'", part),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PaymentRetry {
+    pub id: Uuid,
+    pub kind: String,
+    /// Order id for saga payments, invoice id for subscription invoices
+    pub reference: String,
+    pub saga_id: Option<String>,
+    pub user_id: String,
+    /// What to charge again; a serialized `PaymentRequest` for saga payments
+    pub request: serde_json::Value,
+    pub attempt_count: i32,
+    pub last_decline_code: Option<String>,
+    pub last_error: Option<String>,
+    pub status: String,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A failed charge handed to dunning by the code that attempted it.
+#[derive(Debug, Clone)]
+pub struct PaymentFailure {
+    pub kind: &'static str,
+    pub reference: String,
+    pub saga_id: Option<String>,
+    pub user_id: String,
+    pub request: serde_json::Value,
+    pub decline_code: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DunningDecision {
+    Retrying(DateTime<Utc>),
+    GaveUp,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListRetriesQuery {
+    pub status: Option<String>,
+    pub user_id: Option<String>,
+}
+
+/// Published on the `payment-dunning` topic so the customer can be told to
+/// update their payment method.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DunningEvent {
+    pub event_type: String,
+    pub retry_id: String,
+    pub kind: String,
+    pub reference: String,
+    pub user_id: String,
+    pub attempt_count: i32,
+    pub decline_code: Option<String>,
+    pub message: Option<String>,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Schedules retries of failed charges. Retry state changes are written in
+/// the caller's transaction, together with the charge's outcome and whatever
+/// answers it.
+#[derive(Debug)]
+pub struct DunningService {
+    pool: PgPool,
+    schedule: Vec<chrono::Duration>,
+}
+
+impl DunningService {
+    pub fn new(pool: PgPool, schedule: Vec<chrono::Duration>) -> Self {
+        Self { pool, schedule }
+    }
+
+    /// Records the first failure of a charge and decides whether it will be
+    /// retried. A new failure for the same reference starts the schedule over.
+    pub async fn record_failure(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        failure: PaymentFailure,
+    ) -> Result<DunningDecision> {
+        let now = Utc::now();
+        let (status, next_attempt_at) = self.next_step(0, now, &failure.decline_code);
+
+        let retry = sqlx::query_as::<_, PaymentRetry>(
+            r#"
+            INSERT INTO payment_retries
+                (id, kind, reference, saga_id, user_id, request, attempt_count, last_decline_code,
+                 last_error, status, next_attempt_at, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, 0, $7, $8, $9, $10, $11, $11)
+            ON CONFLICT (kind, reference) DO UPDATE
+            SET saga_id = EXCLUDED.saga_id,
+                request = EXCLUDED.request,
+                attempt_count = 0,
+                last_decline_code = EXCLUDED.last_decline_code,
+                last_error = EXCLUDED.last_error,
+                status = EXCLUDED.status,
+                next_attempt_at = EXCLUDED.next_attempt_at,
+                created_at = EXCLUDED.created_at,
+                updated_at = EXCLUDED.updated_at
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(failure.kind)
+        .bind(&failure.reference)
+        .bind(&failure.saga_id)
+        .bind(&failure.user_id)
+        .bind(&failure.request)
+        .bind(&failure.decline_code)
+        .bind(&failure.error)
+        .bind(status)
+        .bind(next_attempt_at)
+        .bind(now)
+        .fetch_one(&mut **tx)
+        .await
+        .context("Failed to record payment failure")?;
+
+        Self::announce(tx, &retry).await?;
+        Ok(Self::decision(&retry))
+    }
+
+    /// Claims retries that are due, bumping their attempt count. Claimed rows
+    /// are leased so a crashed worker's retries come back later.
+    pub async fn claim_due(&self, limit: i64) -> Result<Vec<PaymentRetry>> {
+        let now = Utc::now();
+
+        let retries = sqlx::query_as::<_, PaymentRetry>(
+            r#"
+            UPDATE payment_retries
+            SET attempt_count = attempt_count + 1, next_attempt_at = $1, updated_at = $2
+            WHERE id IN (
+                SELECT id FROM payment_retries
+                WHERE status = 'scheduled' AND next_attempt_at <= $2
+                ORDER BY next_attempt_at
+                LIMIT $3
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING *
+            "#,
+        )
+        .bind(now + chrono::Duration::minutes(CLAIM_LEASE_MINUTES))
+        .bind(now)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(retries)
+    }
+
+    pub async fn retry_succeeded(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        retry: &PaymentRetry,
+    ) -> Result<()> {
+        let retry = Self::finish(tx, retry.id, RETRY_SUCCEEDED).await?;
+        info!("Retry {} of {} {} succeeded", retry.attempt_count, retry.kind, retry.reference);
+        Self::announce(tx, &retry).await
+    }
+
+    pub async fn retry_failed(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        retry: &PaymentRetry,
+        decline_code: &str,
+        error: &str,
+    ) -> Result<DunningDecision> {
+        let (status, next_attempt_at) =
+            self.next_step(retry.attempt_count as usize, retry.created_at, decline_code);
+
+        let retry = sqlx::query_as::<_, PaymentRetry>(
+            r#"
+            UPDATE payment_retries
+            SET status = $1, next_attempt_at = $2, last_decline_code = $3, last_error = $4,
+                updated_at = $5
+            WHERE id = $6
+            RETURNING *
+            "#,
+        )
+        .bind(status)
+        .bind(next_attempt_at)
+        .bind(decline_code)
+        .bind(error)
+        .bind(Utc::now())
+        .bind(retry.id)
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Self::announce(tx, &retry).await?;
+        Ok(Self::decision(&retry))
+    }
+
+    /// Whether a retry of the charge is still scheduled, i.e. dunning owns it.
+    pub async fn is_scheduled(&self, kind: &str, reference: &str) -> Result<bool> {
+        let scheduled = sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM payment_retries
+                WHERE kind = $1 AND reference = $2 AND status = 'scheduled'
+            )
+            "#,
+        )
+        .bind(kind)
+        .bind(reference)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(scheduled)
+    }
+
+    /// Stops retrying, e.g. because the order was rolled back in the meantime.
+    pub async fn cancel(&self, kind: &str, reference: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE payment_retries SET status = $1, next_attempt_at = NULL, updated_at = $2
+            WHERE kind = $3 AND reference = $4 AND status = 'scheduled'
+            "#,
+        )
+        .bind(RETRY_CANCELED)
+        .bind(Utc::now())
+        .bind(kind)
+        .bind(reference)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn cancel_retry(&self, retry: &PaymentRetry) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        Self::finish(&mut tx, retry.id, RETRY_CANCELED).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn list(&self, query: &ListRetriesQuery) -> Result<Vec<PaymentRetry>> {
+        let retries = sqlx::query_as::<_, PaymentRetry>(
+            r#"
+            SELECT * FROM payment_retries
+            WHERE ($1::VARCHAR IS NULL OR status = $1)
+              AND ($2::VARCHAR IS NULL OR user_id = $2)
+            ORDER BY updated_at DESC
+            LIMIT 200
+            "#,
+        )
+        .bind(&query.status)
+        .bind(&query.user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(retries)
+    }
+
+    /// Hard declines and exhausted schedules give up; anything else waits for
+    /// the next delay, counted from the first failure.
+    fn next_step(
+        &self,
+        retries_done: usize,
+        first_failed_at: DateTime<Utc>,
+        decline_code: &str,
+    ) -> (&'static str, Option<DateTime<Utc>>) {
+        if is_hard_decline(decline_code) {
+            return (RETRY_GAVE_UP, None);
+        }
+
+        match self.schedule.get(retries_done) {
+            Some(delay) => (RETRY_SCHEDULED, Some((first_failed_at + *delay).max(Utc::now()))),
+            None => (RETRY_GAVE_UP, None),
+        }
+    }
+
+    fn decision(retry: &PaymentRetry) -> DunningDecision {
+        match (retry.status.as_str(), retry.next_attempt_at) {
+            (RETRY_SCHEDULED, Some(at)) => DunningDecision::Retrying(at),
+            _ => DunningDecision::GaveUp,
+        }
+    }
+
+    async fn finish(tx: &mut Transaction<'_, Postgres>, id: Uuid, status: &str) -> Result<PaymentRetry> {
+        let retry = sqlx::query_as::<_, PaymentRetry>(
+            r#"
+            UPDATE payment_retries SET status = $1, next_attempt_at = NULL, updated_at = $2
+            WHERE id = $3
+            RETURNING *
+            "#,
+        )
+        .bind(status)
+        .bind(Utc::now())
+        .bind(id)
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(retry)
+    }
+
+    /// Tells the customer side about the retry, in the transaction that changed it.
+    async fn announce(tx: &mut Transaction<'_, Postgres>, retry: &PaymentRetry) -> Result<()> {
+        let event_type = match retry.status.as_str() {
+            RETRY_SCHEDULED => "PAYMENT_RETRY_SCHEDULED",
+            RETRY_SUCCEEDED => "PAYMENT_RETRY_SUCCEEDED",
+            _ => "PAYMENT_RETRY_EXHAUSTED",
+        };
+
+        if retry.status == RETRY_GAVE_UP {
+            warn!(
+                "Giving up on {} {} after {} retries ({:?})",
+                retry.kind, retry.reference, retry.attempt_count, retry.last_decline_code
+            );
+        }
+
+        let event = DunningEvent {
+            event_type: event_type.to_string(),
+            retry_id: retry.id.to_string(),
+            kind: retry.kind.clone(),
+            reference: retry.reference.clone(),
+            user_id: retry.user_id.clone(),
+            attempt_count: retry.attempt_count,
+            decline_code: retry.last_decline_code.clone(),
+            message: retry.last_error.clone(),
+            next_attempt_at: retry.next_attempt_at,
+            timestamp: Utc::now(),
+        };
+
+        let message = OutboxMessage::json("payment-dunning", &retry.reference, &event)?;
+        outbox::enqueue(tx, &message).await
+    }
+}
+
+/// Periodically re-attempts failed saga payments and subscription invoices.
+pub async fn run_dunning_scheduler(
+    dunning: Arc<DunningService>,
+    payment_service: Arc<PaymentService>,
+    subscription_service: Arc<SubscriptionService>,
+    interval: Duration,
+) {
+    info!("Dunning scheduler started, interval {:?}", interval);
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let due = match dunning.claim_due(50).await {
+            Ok(due) => due,
+            Err(e) => {
+                error!("Failed to claim due payment retries: {}", e);
+                continue;
+            }
+        };
+
+        for retry in due {
+            let result = match retry.kind.as_str() {
+                KIND_SAGA_PAYMENT => payment_service.retry_saga_payment(&retry).await,
+                KIND_SUBSCRIPTION_INVOICE => subscription_service.retry_invoice(&retry).await,
+                other => Err(anyhow::anyhow!("Unknown retry kind {}", other)),
+            };
+
+            if let Err(e) = result {
+                // The lease brings it back on a later run
+                warn!("Retry of {} {} failed to run: {}", retry.kind, retry.reference, e);
+            }
+        }
+    }
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payment_retries (
+            id UUID PRIMARY KEY,
+            kind VARCHAR(50) NOT NULL,
+            reference VARCHAR(255) NOT NULL,
+            saga_id VARCHAR(255),
+            user_id VARCHAR(255) NOT NULL,
+            request JSONB NOT NULL,
+            attempt_count INTEGER NOT NULL,
+            last_decline_code VARCHAR(100),
+            last_error TEXT,
+            status VARCHAR(20) NOT NULL,
+            next_attempt_at TIMESTAMPTZ,
+            created_at TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL,
+            UNIQUE (kind, reference)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payment_retries table")?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_payment_retries_due ON payment_retries (status, next_attempt_at)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
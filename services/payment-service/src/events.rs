@@ -0,0 +1,729 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::disputes::{STATUS_CLOSED, STATUS_LOST, STATUS_WON};
+use crate::lifecycle;
+use crate::models::{FxConversion, Money, PaymentStatus};
+use crate::outbox::{self, OutboxMessage};
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_AUTHORIZED: &str = "authorized";
+pub const STATUS_COMPLETED: &str = "completed";
+pub const STATUS_VOIDED: &str = "voided";
+pub const STATUS_FAILED: &str = "failed";
+pub const STATUS_EXPIRED: &str = "expired";
+pub const STATUS_REFUNDED: &str = "refunded";
+pub const STATUS_DISPUTED: &str = "disputed";
+pub const STATUS_CHARGED_BACK: &str = "charged_back";
+
+/// Payments charged through the simulated gateway. They are viewed through
+/// Redis; PayPal and Stripe payments through the `payments` table.
+pub const PROVIDER_SANDBOX: &str = "sandbox";
+
+/// What a payment is for and how much, as recorded when it is opened.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentDetails {
+    pub provider: String,
+    pub order_id: Option<String>,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    pub amount: f64,
+    pub currency: String,
+    pub settlement_amount: Option<f64>,
+    pub settlement_currency: Option<String>,
+    pub fx_rate: Option<f64>,
+    pub fx_rate_as_of: Option<DateTime<Utc>>,
+}
+
+impl PaymentDetails {
+    pub fn new(
+        provider: &str,
+        order_id: Option<&str>,
+        user_id: Option<&str>,
+        conversion: &FxConversion,
+    ) -> Self {
+        Self {
+            provider: provider.to_string(),
+            order_id: order_id.map(String::from),
+            user_id: user_id.map(String::from),
+            amount: conversion.presentment.amount,
+            currency: conversion.presentment.currency.clone(),
+            settlement_amount: Some(conversion.settlement.amount),
+            settlement_currency: Some(conversion.settlement.currency.clone()),
+            fx_rate: Some(conversion.rate),
+            fx_rate_as_of: Some(conversion.rate_as_of),
+        }
+    }
+}
+
+/// Everything that can happen to a payment. The `payments` row and the Redis
+/// `payment:{id}` view are derived from these and can be rebuilt from them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum PaymentEvent {
+    Created(PaymentDetails),
+    /// The provider created its order or checkout session for the payment
+    CheckoutStarted { reference: String },
+    Authorized {
+        transaction_id: String,
+        expires_at: DateTime<Utc>,
+    },
+    /// Money was taken; `reference` is the provider's capture or payment intent
+    Captured { reference: Option<String> },
+    /// An authorization hold was released, by request or because it expired
+    Voided { reason: String },
+    Failed { reason: String },
+    /// The buyer abandoned checkout
+    Expired,
+    Refunded {
+        reference: String,
+        amount: f64,
+        currency: String,
+    },
+    Disputed { dispute_status: String },
+    /// State of a payment written before its history was recorded as events
+    Imported {
+        #[serde(flatten)]
+        details: PaymentDetails,
+        status: String,
+        provider_reference: Option<String>,
+        capture_reference: Option<String>,
+        created_at: DateTime<Utc>,
+    },
+}
+
+impl PaymentEvent {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            PaymentEvent::Created(_) => "created",
+            PaymentEvent::CheckoutStarted { .. } => "checkout_started",
+            PaymentEvent::Authorized { .. } => "authorized",
+            PaymentEvent::Captured { .. } => "captured",
+            PaymentEvent::Voided { .. } => "voided",
+            PaymentEvent::Failed { .. } => "failed",
+            PaymentEvent::Expired => "expired",
+            PaymentEvent::Refunded { .. } => "refunded",
+            PaymentEvent::Disputed { .. } => "disputed",
+            PaymentEvent::Imported { .. } => "imported",
+        }
+    }
+
+    fn data(&self) -> Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self)?;
+        Ok(value
+            .get_mut("data")
+            .map(serde_json::Value::take)
+            .unwrap_or(serde_json::Value::Null))
+    }
+
+    fn from_stored(event_type: &str, data: &serde_json::Value) -> Result<Self> {
+        let tagged = match data {
+            serde_json::Value::Null => serde_json::json!({ "type": event_type }),
+            data => serde_json::json!({ "type": event_type, "data": data }),
+        };
+        serde_json::from_value(tagged)
+            .with_context(|| format!("Failed to read {} payment event", event_type))
+    }
+}
+
+/// The payment status a dispute leaves behind.
+fn dispute_payment_status(dispute_status: &str) -> &'static str {
+    match dispute_status {
+        STATUS_LOST => STATUS_CHARGED_BACK,
+        STATUS_WON | STATUS_CLOSED => STATUS_COMPLETED,
+        _ => STATUS_DISPUTED,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct StoredEvent {
+    pub id: i64,
+    pub payment_id: Uuid,
+    pub version: i32,
+    pub event_type: String,
+    pub data: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Current state of a payment, folded from its events.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PaymentAggregate {
+    pub id: Uuid,
+    pub version: i32,
+    pub provider: String,
+    pub order_id: Option<String>,
+    pub user_id: Option<String>,
+    pub amount: f64,
+    pub currency: String,
+    pub settlement_amount: Option<f64>,
+    pub settlement_currency: Option<String>,
+    pub fx_rate: Option<f64>,
+    pub fx_rate_as_of: Option<DateTime<Utc>>,
+    pub status: String,
+    /// Authorization or charge id from the simulated gateway
+    pub transaction_id: Option<String>,
+    /// PayPal order or Stripe checkout session
+    pub provider_reference: Option<String>,
+    /// PayPal capture or Stripe payment intent
+    pub capture_reference: Option<String>,
+    pub authorization_expires_at: Option<DateTime<Utc>>,
+    pub failure_reason: Option<String>,
+    pub refunded_amount: f64,
+    pub refunds: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl PaymentAggregate {
+    pub fn replay(payment_id: Uuid, events: &[StoredEvent]) -> Result<Option<Self>> {
+        let mut aggregate = Self {
+            id: payment_id,
+            ..Default::default()
+        };
+
+        for stored in events {
+            let event = PaymentEvent::from_stored(&stored.event_type, &stored.data)?;
+            aggregate.apply(&event, stored.created_at);
+        }
+
+        Ok((aggregate.version > 0).then_some(aggregate))
+    }
+
+    /// Whether the event changes anything. Repeated webhooks and transitions
+    /// the payment has moved past are dropped rather than recorded.
+    pub fn accepts(&self, event: &PaymentEvent) -> bool {
+        let status = self.status.as_str();
+
+        match event {
+            PaymentEvent::Created(_) | PaymentEvent::Imported { .. } => self.version == 0,
+            _ if self.version == 0 => false,
+            PaymentEvent::CheckoutStarted { reference } => {
+                self.provider_reference.as_ref() != Some(reference)
+            }
+            PaymentEvent::Authorized { .. } => status == STATUS_PENDING,
+            // Money the provider took is recorded even after we gave up on the payment
+            PaymentEvent::Captured { reference } => match status {
+                STATUS_COMPLETED => reference.is_some() && self.capture_reference.is_none(),
+                STATUS_PENDING | STATUS_AUTHORIZED | STATUS_FAILED | STATUS_EXPIRED => true,
+                _ => false,
+            },
+            PaymentEvent::Voided { .. } => status == STATUS_AUTHORIZED,
+            PaymentEvent::Failed { .. } => matches!(status, STATUS_PENDING | STATUS_AUTHORIZED),
+            PaymentEvent::Expired => status == STATUS_PENDING,
+            PaymentEvent::Refunded { reference, .. } => !self.refunds.contains(reference),
+            PaymentEvent::Disputed { dispute_status } => {
+                dispute_payment_status(dispute_status) != status
+            }
+        }
+    }
+
+    fn apply(&mut self, event: &PaymentEvent, at: DateTime<Utc>) {
+        match event {
+            PaymentEvent::Created(details) => {
+                self.open(details);
+                self.status = STATUS_PENDING.to_string();
+                self.created_at = at;
+            }
+            PaymentEvent::Imported {
+                details,
+                status,
+                provider_reference,
+                capture_reference,
+                created_at,
+            } => {
+                self.open(details);
+                self.status = status.clone();
+                self.provider_reference = provider_reference.clone();
+                self.capture_reference = capture_reference.clone();
+                self.created_at = *created_at;
+            }
+            PaymentEvent::CheckoutStarted { reference } => {
+                self.provider_reference = Some(reference.clone());
+            }
+            PaymentEvent::Authorized {
+                transaction_id,
+                expires_at,
+            } => {
+                self.status = STATUS_AUTHORIZED.to_string();
+                self.transaction_id = Some(transaction_id.clone());
+                self.authorization_expires_at = Some(*expires_at);
+            }
+            PaymentEvent::Captured { reference } => {
+                self.status = STATUS_COMPLETED.to_string();
+                if reference.is_some() {
+                    self.capture_reference = reference.clone();
+                }
+                self.authorization_expires_at = None;
+            }
+            PaymentEvent::Voided { reason } => {
+                self.status = STATUS_VOIDED.to_string();
+                self.failure_reason = Some(reason.clone());
+                self.authorization_expires_at = None;
+            }
+            PaymentEvent::Failed { reason } => {
+                self.status = STATUS_FAILED.to_string();
+                self.failure_reason = Some(reason.clone());
+            }
+            PaymentEvent::Expired => {
+                self.status = STATUS_EXPIRED.to_string();
+            }
+            PaymentEvent::Refunded {
+                reference, amount, ..
+            } => {
+                self.refunds.push(reference.clone());
+                self.refunded_amount += amount;
+                if self.refunded_amount >= self.amount - 0.005 {
+                    self.status = STATUS_REFUNDED.to_string();
+                }
+            }
+            PaymentEvent::Disputed { dispute_status } => {
+                self.status = dispute_payment_status(dispute_status).to_string();
+            }
+        }
+
+        self.version += 1;
+        self.updated_at = at;
+    }
+
+    fn open(&mut self, details: &PaymentDetails) {
+        self.provider = details.provider.clone();
+        self.order_id = details.order_id.clone();
+        self.user_id = details.user_id.clone();
+        self.amount = details.amount;
+        self.currency = details.currency.clone();
+        self.settlement_amount = details.settlement_amount;
+        self.settlement_currency = details.settlement_currency.clone();
+        self.fx_rate = details.fx_rate;
+        self.fx_rate_as_of = details.fx_rate_as_of;
+    }
+
+    pub fn conversion(&self) -> Option<FxConversion> {
+        Some(FxConversion {
+            presentment: Money::new(self.amount, &self.currency),
+            settlement: Money::new(self.settlement_amount?, self.settlement_currency.as_deref()?),
+            rate: self.fx_rate?,
+            rate_as_of: self.fx_rate_as_of?,
+        })
+    }
+
+    /// The Redis `payment:{id}` view of a sandbox payment.
+    pub fn to_status(&self) -> PaymentStatus {
+        let status = match self.status.as_str() {
+            // Expired holds have always been reported as voided
+            STATUS_VOIDED => "VOIDED".to_string(),
+            STATUS_FAILED => format!(
+                "FAILED: {}",
+                self.failure_reason.as_deref().unwrap_or("unknown error")
+            ),
+            other => other.to_uppercase(),
+        };
+
+        PaymentStatus {
+            payment_id: self.id.to_string(),
+            status,
+            amount: self.amount,
+            transaction_id: self
+                .transaction_id
+                .clone()
+                .or_else(|| self.capture_reference.clone()),
+            authorization_expires_at: self.authorization_expires_at,
+            conversion: self.conversion(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+async fn load(tx: &mut Transaction<'_, Postgres>, payment_id: Uuid) -> Result<Vec<StoredEvent>> {
+    let events = sqlx::query_as::<_, StoredEvent>(
+        r#"
+        SELECT * FROM payment_events WHERE payment_id = $1 ORDER BY version
+        "#,
+    )
+    .bind(payment_id)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(events)
+}
+
+/// Appends an event to the payment's stream, updates its `payments` row and
+/// queues its lifecycle event in the caller's transaction. Returns the new
+/// state, or `None` when the event changes nothing.
+pub async fn append(
+    tx: &mut Transaction<'_, Postgres>,
+    payment_id: Uuid,
+    event: &PaymentEvent,
+) -> Result<Option<PaymentAggregate>> {
+    // Serialize writers of the same payment so versions stay contiguous
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1::TEXT, 0))")
+        .bind(payment_id)
+        .execute(&mut **tx)
+        .await?;
+
+    let events = load(tx, payment_id).await?;
+    let mut aggregate = PaymentAggregate::replay(payment_id, &events)?.unwrap_or(PaymentAggregate {
+        id: payment_id,
+        ..Default::default()
+    });
+
+    if !aggregate.accepts(event) {
+        return Ok(None);
+    }
+
+    let now = Utc::now();
+    sqlx::query(
+        r#"
+        INSERT INTO payment_events (payment_id, version, event_type, data, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(payment_id)
+    .bind(aggregate.version + 1)
+    .bind(event.event_type())
+    .bind(event.data()?)
+    .bind(now)
+    .execute(&mut **tx)
+    .await
+    .context("Failed to append payment event")?;
+
+    aggregate.apply(event, now);
+    project_row(tx, &aggregate).await?;
+
+    if let Some(message) = lifecycle::message(&aggregate, event)? {
+        outbox::enqueue(tx, &message).await?;
+    }
+
+    Ok(Some(aggregate))
+}
+
+/// Writes the aggregate over its `payments` row. Sandbox payments have no row.
+async fn project_row(tx: &mut Transaction<'_, Postgres>, aggregate: &PaymentAggregate) -> Result<()> {
+    if aggregate.provider == PROVIDER_SANDBOX {
+        return Ok(());
+    }
+
+    let (paypal_order_id, paypal_capture_id, stripe_session_id, stripe_payment_intent) =
+        match aggregate.provider.as_str() {
+            "paypal" => (&aggregate.provider_reference, &aggregate.capture_reference, &None, &None),
+            "stripe" => (&None, &None, &aggregate.provider_reference, &aggregate.capture_reference),
+            _ => (&None, &None, &None, &None),
+        };
+
+    sqlx::query(
+        r#"
+        INSERT INTO payments
+            (id, order_id, amount, currency, settlement_amount, settlement_currency, fx_rate,
+             fx_rate_as_of, paypal_order_id, paypal_capture_id, stripe_session_id,
+             stripe_payment_intent, refunded_amount, status, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
+        ON CONFLICT (id) DO UPDATE SET
+            order_id = EXCLUDED.order_id,
+            amount = EXCLUDED.amount,
+            currency = EXCLUDED.currency,
+            settlement_amount = EXCLUDED.settlement_amount,
+            settlement_currency = EXCLUDED.settlement_currency,
+            fx_rate = EXCLUDED.fx_rate,
+            fx_rate_as_of = EXCLUDED.fx_rate_as_of,
+            paypal_order_id = COALESCE(EXCLUDED.paypal_order_id, payments.paypal_order_id),
+            paypal_capture_id = COALESCE(EXCLUDED.paypal_capture_id, payments.paypal_capture_id),
+            stripe_session_id = COALESCE(EXCLUDED.stripe_session_id, payments.stripe_session_id),
+            stripe_payment_intent = COALESCE(EXCLUDED.stripe_payment_intent, payments.stripe_payment_intent),
+            refunded_amount = EXCLUDED.refunded_amount,
+            status = EXCLUDED.status,
+            created_at = EXCLUDED.created_at,
+            updated_at = EXCLUDED.updated_at
+        "#,
+    )
+    .bind(aggregate.id)
+    .bind(aggregate.order_id.clone().unwrap_or_default())
+    .bind(aggregate.amount)
+    .bind(&aggregate.currency)
+    .bind(aggregate.settlement_amount)
+    .bind(&aggregate.settlement_currency)
+    .bind(aggregate.fx_rate)
+    .bind(aggregate.fx_rate_as_of)
+    .bind(paypal_order_id)
+    .bind(paypal_capture_id)
+    .bind(stripe_session_id)
+    .bind(stripe_payment_intent)
+    .bind(aggregate.refunded_amount)
+    .bind(&aggregate.status)
+    .bind(aggregate.created_at)
+    .bind(aggregate.updated_at)
+    .execute(&mut **tx)
+    .await
+    .context("Failed to project payment row")?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct EventStore {
+    pool: PgPool,
+}
+
+impl EventStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Appends events in one transaction. Returns the final state if any was recorded.
+    pub async fn append(
+        &self,
+        payment_id: Uuid,
+        events: &[PaymentEvent],
+    ) -> Result<Option<PaymentAggregate>> {
+        self.append_with_messages(payment_id, events, &[]).await
+    }
+
+    /// Appends events and queues the messages announcing them in the same
+    /// transaction. The messages are dropped if no event was recorded.
+    pub async fn append_with_messages(
+        &self,
+        payment_id: Uuid,
+        events: &[PaymentEvent],
+        messages: &[OutboxMessage],
+    ) -> Result<Option<PaymentAggregate>> {
+        let mut tx = self.pool.begin().await?;
+
+        let mut latest = None;
+        for event in events {
+            if let Some(aggregate) = append(&mut tx, payment_id, event).await? {
+                latest = Some(aggregate);
+            }
+        }
+
+        if latest.is_none() {
+            return Ok(None);
+        }
+        for message in messages {
+            outbox::enqueue(&mut tx, message).await?;
+        }
+
+        tx.commit().await?;
+        Ok(latest)
+    }
+
+    pub async fn history(&self, payment_id: Uuid) -> Result<Vec<StoredEvent>> {
+        let mut tx = self.pool.begin().await?;
+        let events = load(&mut tx, payment_id).await?;
+        tx.commit().await?;
+        Ok(events)
+    }
+
+    /// Replays the payment's events and rewrites its `payments` row.
+    pub async fn rebuild(&self, payment_id: Uuid) -> Result<Option<PaymentAggregate>> {
+        let mut tx = self.pool.begin().await?;
+
+        let events = load(&mut tx, payment_id).await?;
+        let aggregate = PaymentAggregate::replay(payment_id, &events)?;
+        if let Some(aggregate) = &aggregate {
+            project_row(&mut tx, aggregate).await?;
+        }
+
+        tx.commit().await?;
+        Ok(aggregate)
+    }
+
+    /// Payments with recorded events, in id order, for rebuilding in batches.
+    pub async fn payment_ids(&self, after: Option<Uuid>, limit: i64) -> Result<Vec<Uuid>> {
+        let ids = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            SELECT DISTINCT payment_id FROM payment_events
+            WHERE ($1::UUID IS NULL OR payment_id > $1)
+            ORDER BY payment_id
+            LIMIT $2
+            "#,
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(ids)
+    }
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payment_events (
+            id BIGSERIAL PRIMARY KEY,
+            payment_id UUID NOT NULL,
+            version INTEGER NOT NULL,
+            event_type VARCHAR(50) NOT NULL,
+            data JSONB NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL,
+            UNIQUE (payment_id, version)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payment_events table")?;
+
+    sqlx::query(
+        r#"
+        ALTER TABLE payments
+            ADD COLUMN IF NOT EXISTS refunded_amount DOUBLE PRECISION NOT NULL DEFAULT 0
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to add refunded_amount to payments table")?;
+
+    // Payments written before events were recorded start their stream from a snapshot
+    sqlx::query(
+        r#"
+        INSERT INTO payment_events (payment_id, version, event_type, data, created_at)
+        SELECT p.id, 1, 'imported',
+               jsonb_build_object(
+                   'provider', CASE
+                       WHEN p.paypal_order_id IS NOT NULL THEN 'paypal'
+                       WHEN p.stripe_session_id IS NOT NULL THEN 'stripe'
+                       ELSE 'unknown'
+                   END,
+                   'order_id', p.order_id,
+                   'amount', p.amount::DOUBLE PRECISION,
+                   'currency', p.currency,
+                   'settlement_amount', p.settlement_amount,
+                   'settlement_currency', p.settlement_currency,
+                   'fx_rate', p.fx_rate,
+                   'fx_rate_as_of', p.fx_rate_as_of,
+                   'status', p.status,
+                   'provider_reference', COALESCE(p.paypal_order_id, p.stripe_session_id),
+                   'capture_reference', COALESCE(p.paypal_capture_id, p.stripe_payment_intent),
+                   'created_at', p.created_at AT TIME ZONE 'UTC'
+               ),
+               p.updated_at AT TIME ZONE 'UTC'
+        FROM payments p
+        WHERE NOT EXISTS (SELECT 1 FROM payment_events e WHERE e.payment_id = p.id)
+        ON CONFLICT (payment_id, version) DO NOTHING
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to import existing payments into payment_events")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn created() -> PaymentEvent {
+        PaymentEvent::Created(PaymentDetails {
+            provider: PROVIDER_SANDBOX.to_string(),
+            order_id: Some("order-1".to_string()),
+            user_id: None,
+            amount: 25.0,
+            currency: "USD".to_string(),
+            settlement_amount: None,
+            settlement_currency: None,
+            fx_rate: None,
+            fx_rate_as_of: None,
+        })
+    }
+
+    fn authorized() -> PaymentEvent {
+        PaymentEvent::Authorized {
+            transaction_id: "txn-1".to_string(),
+            expires_at: Utc::now(),
+        }
+    }
+
+    fn captured(reference: Option<&str>) -> PaymentEvent {
+        PaymentEvent::Captured {
+            reference: reference.map(String::from),
+        }
+    }
+
+    fn voided() -> PaymentEvent {
+        PaymentEvent::Voided {
+            reason: "released".to_string(),
+        }
+    }
+
+    fn after(events: &[PaymentEvent]) -> PaymentAggregate {
+        let mut aggregate = PaymentAggregate::default();
+        for event in events {
+            assert!(aggregate.accepts(event), "{} was rejected", event.event_type());
+            aggregate.apply(event, Utc::now());
+        }
+        aggregate
+    }
+
+    #[test]
+    fn a_payment_is_opened_only_once() {
+        let fresh = PaymentAggregate::default();
+        assert!(fresh.accepts(&created()));
+        assert!(!fresh.accepts(&authorized()));
+
+        assert!(!after(&[created()]).accepts(&created()));
+    }
+
+    #[test]
+    fn a_hold_is_captured_or_voided_once() {
+        let held = after(&[created(), authorized()]);
+        assert!(!held.accepts(&authorized()));
+        assert!(held.accepts(&captured(None)));
+        assert!(held.accepts(&voided()));
+
+        let released = after(&[created(), authorized(), voided()]);
+        assert!(!released.accepts(&voided()));
+        assert!(!released.accepts(&captured(None)));
+    }
+
+    #[test]
+    fn a_completed_payment_only_takes_its_first_capture_reference() {
+        let completed = after(&[created(), authorized(), captured(None)]);
+        assert!(!completed.accepts(&captured(None)));
+        assert!(completed.accepts(&captured(Some("pi_1"))));
+
+        let referenced = after(&[created(), captured(Some("pi_1"))]);
+        assert!(!referenced.accepts(&captured(Some("pi_2"))));
+    }
+
+    #[test]
+    fn money_taken_after_giving_up_is_still_recorded() {
+        let failed = after(&[
+            created(),
+            PaymentEvent::Failed {
+                reason: "declined".to_string(),
+            },
+        ]);
+        assert!(failed.accepts(&captured(Some("pi_1"))));
+        assert!(!failed.accepts(&voided()));
+    }
+
+    #[test]
+    fn repeated_refunds_and_dispute_updates_are_dropped() {
+        let refund = PaymentEvent::Refunded {
+            reference: "re_1".to_string(),
+            amount: 5.0,
+            currency: "USD".to_string(),
+        };
+        let refunded = after(&[created(), captured(Some("pi_1")), refund.clone()]);
+        assert!(!refunded.accepts(&refund));
+
+        let disputed = after(&[
+            created(),
+            captured(Some("pi_1")),
+            PaymentEvent::Disputed {
+                dispute_status: "needs_response".to_string(),
+            },
+        ]);
+        assert!(!disputed.accepts(&PaymentEvent::Disputed {
+            dispute_status: "under_review".to_string(),
+        }));
+        assert!(disputed.accepts(&PaymentEvent::Disputed {
+            dispute_status: STATUS_WON.to_string(),
+        }));
+    }
+}
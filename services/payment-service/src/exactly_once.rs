@@ -0,0 +1,375 @@
+use anyhow::anyhow;
+use rdkafka::client::ClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{Consumer, ConsumerContext, Rebalance, StreamConsumer};
+use rdkafka::error::KafkaError;
+use rdkafka::message::{Message, OwnedMessage};
+use rdkafka::statistics::Statistics;
+use rdkafka::types::RDKafkaErrorCode;
+use rdkafka::{Offset, TopicPartitionList};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot::error::TryRecvError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::consumer_health::CONSUMER_HEALTH;
+use crate::dead_letters::DeadLetterService;
+use crate::kafka::{
+    describe_partitions, lane_key, KafkaProducer, MessageHandler, DEFAULT_MAX_POLL_INTERVAL,
+    SOURCE_TOPICS, STATISTICS_INTERVAL,
+};
+use crate::metrics::PAYMENT_METRICS;
+use crate::outbox::{self, OutboxStore, SourceOffset};
+use crate::payment::PaymentService;
+use crate::wire_format::PayloadEncoder;
+
+/// Notes revocations, so a transaction holding work from a partition this
+/// instance no longer owns is aborted rather than committed.
+struct RebalanceFence {
+    /// Consumer group, which names the consumer in health reports
+    group: String,
+    revoked: AtomicBool,
+}
+
+impl ClientContext for RebalanceFence {
+    fn stats(&self, statistics: Statistics) {
+        CONSUMER_HEALTH.record(&self.group, &statistics);
+    }
+}
+
+impl ConsumerContext for RebalanceFence {
+    fn pre_rebalance(&self, rebalance: &Rebalance) {
+        if let Rebalance::Revoke(partitions) = rebalance {
+            info!("Partitions revoked: {}", describe_partitions(partitions));
+            self.revoked.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn post_rebalance(&self, rebalance: &Rebalance) {
+        match rebalance {
+            Rebalance::Assign(partitions) => {
+                info!("Partitions assigned: {}", describe_partitions(partitions))
+            }
+            Rebalance::Error(e) => error!("Consumer rebalance failed: {}", e),
+            Rebalance::Revoke(_) => {}
+        }
+    }
+}
+
+/// Messages taken into one transaction.
+#[derive(Default)]
+struct Batch {
+    /// First offset of each partition, to rewind to if the batch is aborted
+    first: HashMap<(String, i32), i64>,
+    /// Offset after the last message of each partition, committed with the batch
+    next: HashMap<(String, i32), i64>,
+    /// Outbox messages published in the transaction
+    published: Vec<i64>,
+    failure: Option<anyhow::Error>,
+    in_flight: usize,
+}
+
+impl Batch {
+    fn started(&mut self, message: &OwnedMessage) {
+        let partition = (message.topic().to_string(), message.partition());
+        self.first.entry(partition.clone()).or_insert(message.offset());
+        self.next.insert(partition, message.offset() + 1);
+        self.in_flight += 1;
+    }
+
+    fn finished(&mut self, outcome: Result<Vec<i64>, anyhow::Error>) {
+        self.in_flight -= 1;
+        match outcome {
+            Ok(ids) => self.published.extend(ids),
+            Err(e) => {
+                self.failure.get_or_insert(e);
+            }
+        }
+    }
+
+    fn len(&self) -> i64 {
+        self.first
+            .iter()
+            .map(|(partition, first)| self.next[partition] - first)
+            .sum()
+    }
+}
+
+/// Opt-in replacement for the saga consumer that handles each message
+/// exactly once as far as Kafka is concerned. Messages are taken in batches
+/// of one transaction each; everything handling them publishes (saga
+/// responses, lifecycle events, retries, dead letters) goes out in that
+/// transaction together with the batch's offsets, and an aborted batch is
+/// read again. Database writes are still at-least-once and rely on the
+/// handlers being idempotent.
+///
+/// Retry topics are still consumed at-least-once, and readers only get the
+/// guarantee with `isolation.level=read_committed`.
+pub struct ExactlyOnceConsumer {
+    consumer: StreamConsumer<RebalanceFence>,
+    producer: Arc<KafkaProducer>,
+    dead_letters: Arc<DeadLetterService>,
+    outbox: OutboxStore,
+    encoder: PayloadEncoder,
+    max_in_flight: usize,
+    interval: Duration,
+}
+
+impl ExactlyOnceConsumer {
+    /// `producer` must be transactional and used by nothing else, and
+    /// `dead_letters` must publish through it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        brokers: &str,
+        group_id: &str,
+        offset_reset: &str,
+        max_in_flight: usize,
+        interval: Duration,
+        producer: Arc<KafkaProducer>,
+        dead_letters: Arc<DeadLetterService>,
+        outbox: OutboxStore,
+        encoder: PayloadEncoder,
+    ) -> Result<Self, KafkaError> {
+        let consumer: StreamConsumer<RebalanceFence> = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", group_id)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", offset_reset)
+            // Skip what aborted transactions wrote to the topics we read
+            .set("isolation.level", "read_committed")
+            .set("statistics.interval.ms", STATISTICS_INTERVAL.as_millis().to_string())
+            .create_with_context(RebalanceFence {
+                group: group_id.to_string(),
+                revoked: AtomicBool::new(false),
+            })?;
+        CONSUMER_HEALTH.watch(group_id, DEFAULT_MAX_POLL_INTERVAL);
+
+        Ok(Self {
+            consumer,
+            producer,
+            dead_letters,
+            outbox,
+            encoder,
+            max_in_flight: max_in_flight.max(1),
+            interval,
+        })
+    }
+
+    pub async fn consume_payment_requests(&self, payment_service: Arc<PaymentService>) {
+        self.consumer
+            .subscribe(&SOURCE_TOPICS)
+            .expect("Failed to subscribe to topics");
+
+        info!("Exactly-once Kafka consumer started, listening to payment-process, payment-rollback and order-completed topics");
+
+        let handler = MessageHandler::new(
+            Arc::clone(&self.producer),
+            Arc::clone(&self.dead_letters),
+            payment_service,
+            true,
+        );
+
+        loop {
+            if let Err(e) = self.run_batch(&handler).await {
+                // Fenced by another instance with our transactional id, or
+                // otherwise unable to go on transacting
+                error!("Exactly-once consumer stopped: {}", e);
+                CONSUMER_HEALTH.stopped(&self.consumer.context().group, &e.to_string());
+                return;
+            }
+        }
+    }
+
+    /// Takes messages for one interval, waits for them all to finish, then
+    /// commits or aborts. Only fatal producer errors are returned.
+    async fn run_batch(&self, handler: &MessageHandler) -> Result<(), KafkaError> {
+        // Nothing is opened until there is something to put in it
+        let first = match self.consumer.recv().await {
+            Ok(message) => message.detach(),
+            Err(e) => {
+                error!("Error receiving message: {}", e);
+                return Ok(());
+            }
+        };
+        self.consumer.context().revoked.store(false, Ordering::SeqCst);
+        self.producer.begin_transaction()?;
+
+        let (finished_tx, mut finished_rx) = mpsc::unbounded_channel();
+        let mut lanes: HashMap<String, oneshot::Receiver<()>> = HashMap::new();
+        let mut batch = Batch::default();
+        self.start(first, handler, &mut batch, &mut lanes, &finished_tx);
+
+        let deadline = Instant::now() + self.interval;
+        loop {
+            tokio::select! {
+                Some(outcome) = finished_rx.recv() => batch.finished(outcome),
+                received = self.consumer.recv(), if batch.in_flight < self.max_in_flight => match received {
+                    Ok(message) => {
+                        self.start(message.detach(), handler, &mut batch, &mut lanes, &finished_tx)
+                    }
+                    Err(e) => error!("Error receiving message: {}", e),
+                },
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+        while batch.in_flight > 0 {
+            match finished_rx.recv().await {
+                Some(outcome) => batch.finished(outcome),
+                None => break,
+            }
+        }
+
+        if self.consumer.context().revoked.load(Ordering::SeqCst) {
+            return self.abort(&batch, &anyhow!("partitions were revoked"));
+        }
+        if let Some(failure) = &batch.failure {
+            return self.abort(&batch, failure);
+        }
+        if let Err(e) = self.commit(&batch) {
+            if is_fatal(&e) {
+                return Err(e);
+            }
+            return self.abort(&batch, &e.into());
+        }
+
+        PAYMENT_METRICS
+            .kafka_transactions
+            .with_label_values(&["committed"])
+            .inc();
+        if let Err(e) = self.outbox.mark_committed(&batch.published).await {
+            // They are never relayed, so this only leaves them to be purged later
+            warn!("Failed to mark committed outbox messages delivered: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Handles the message in its own task, after the order's previous one.
+    fn start(
+        &self,
+        message: OwnedMessage,
+        handler: &MessageHandler,
+        batch: &mut Batch,
+        lanes: &mut HashMap<String, oneshot::Receiver<()>>,
+        finished_tx: &mpsc::UnboundedSender<Result<Vec<i64>, anyhow::Error>>,
+    ) {
+        batch.started(&message);
+
+        lanes.retain(|_, done| matches!(done.try_recv(), Err(TryRecvError::Empty)));
+        let (done_tx, done_rx) = oneshot::channel();
+        let previous = lanes.insert(lane_key(&message), done_rx);
+
+        let handler = handler.clone();
+        let producer = Arc::clone(&self.producer);
+        let outbox = self.outbox.clone();
+        let encoder = self.encoder.clone();
+        let finished_tx = finished_tx.clone();
+        tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            let outcome = handle(&message, &handler, &producer, &outbox, &encoder).await;
+
+            let _ = done_tx.send(());
+            let _ = finished_tx.send(outcome);
+        });
+    }
+
+    fn commit(&self, batch: &Batch) -> Result<(), KafkaError> {
+        let mut offsets = TopicPartitionList::new();
+        for ((topic, partition), next) in &batch.next {
+            offsets.add_partition_offset(topic, *partition, Offset::Offset(*next))?;
+        }
+        let metadata = self
+            .consumer
+            .group_metadata()
+            .ok_or(KafkaError::Global(RDKafkaErrorCode::State))?;
+
+        self.producer.send_offsets_to_transaction(&offsets, &metadata)?;
+        self.producer.commit_transaction()?;
+
+        info!(
+            "Committed transaction of {} messages on {}",
+            batch.len(),
+            describe_partitions(&offsets)
+        );
+        Ok(())
+    }
+
+    /// Throws away everything the batch published and reads it again from
+    /// the first message of each partition.
+    fn abort(&self, batch: &Batch, reason: &anyhow::Error) -> Result<(), KafkaError> {
+        warn!(
+            "Aborting transaction of {} messages: {:#}",
+            batch.len(),
+            reason
+        );
+        PAYMENT_METRICS
+            .kafka_transactions
+            .with_label_values(&["aborted"])
+            .inc();
+
+        if let Err(e) = self.producer.abort_transaction() {
+            if is_fatal(&e) {
+                return Err(e);
+            }
+            warn!("Failed to abort transaction: {}", e);
+        }
+
+        for ((topic, partition), first) in &batch.first {
+            // Revoked partitions restart from the committed offset anyway
+            if let Err(e) = self.consumer.seek(
+                topic,
+                *partition,
+                Offset::Offset(*first),
+                Duration::from_secs(5),
+            ) {
+                warn!("Failed to rewind {}[{}] to {}: {}", topic, partition, first, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the message and publishes what it queued in the outbox. A message
+/// whose outbox messages are already there was handled by an aborted
+/// transaction; only those are published again.
+async fn handle(
+    message: &OwnedMessage,
+    handler: &MessageHandler,
+    producer: &KafkaProducer,
+    outbox: &OutboxStore,
+    encoder: &PayloadEncoder,
+) -> Result<Vec<i64>, anyhow::Error> {
+    let source = SourceOffset {
+        topic: message.topic().to_string(),
+        partition: message.partition(),
+        offset: message.offset(),
+    };
+
+    let mut captured = outbox.captured(&source).await?;
+    if captured.is_empty() {
+        outbox::capture(source.clone(), handler.handle(message)).await?;
+        captured = outbox.captured(&source).await?;
+    } else {
+        info!(
+            "{}[{}] offset {} was handled before, republishing its {} messages",
+            source.topic,
+            source.partition,
+            source.offset,
+            captured.len()
+        );
+    }
+
+    for pending in &captured {
+        outbox::publish(producer, encoder, pending).await?;
+    }
+    Ok(captured.iter().map(|pending| pending.id).collect())
+}
+
+fn is_fatal(e: &KafkaError) -> bool {
+    matches!(e, KafkaError::Transaction(err) if err.is_fatal())
+}
@@ -0,0 +1,309 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::ledger::{self, Journal};
+use crate::models::Money;
+
+pub const KIND_CAPTURE: &str = "capture";
+pub const KIND_REFUND: &str = "refund";
+
+/// Gross, fee and net for one capture or refund. Refund amounts are negative so
+/// that sums give what we actually kept.
+#[derive(Debug, Clone)]
+pub struct NewFee {
+    pub payment_id: Option<Uuid>,
+    pub provider: String,
+    pub kind: String,
+    pub provider_reference: String,
+    pub gross: Money,
+    pub fee: Money,
+    pub net: Money,
+}
+
+impl NewFee {
+    /// Ledger journal for the fee. The captured or refunded money itself is
+    /// booked with the payment's Captured or Refunded event.
+    fn journal(&self) -> Journal {
+        Journal::provider_fee(&self.provider, self.payment_id, &self.provider_reference, &self.fee)
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+struct FeeRow {
+    id: Uuid,
+    payment_id: Option<Uuid>,
+    provider: String,
+    kind: String,
+    provider_reference: String,
+    currency: String,
+    gross_amount: f64,
+    fee_amount: f64,
+    net_amount: f64,
+    recorded_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeeEntry {
+    pub id: Uuid,
+    pub payment_id: Option<Uuid>,
+    pub provider: String,
+    pub kind: String,
+    pub provider_reference: String,
+    pub gross: Money,
+    pub fee: Money,
+    pub net: Money,
+    pub recorded_at: DateTime<Utc>,
+}
+
+impl From<FeeRow> for FeeEntry {
+    fn from(row: FeeRow) -> Self {
+        Self {
+            id: row.id,
+            payment_id: row.payment_id,
+            provider: row.provider,
+            kind: row.kind,
+            provider_reference: row.provider_reference,
+            gross: Money::new(row.gross_amount, &row.currency),
+            fee: Money::new(row.fee_amount, &row.currency),
+            net: Money::new(row.net_amount, &row.currency),
+            recorded_at: row.recorded_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+struct FeeSummaryRow {
+    day: NaiveDate,
+    provider: String,
+    currency: String,
+    gross: f64,
+    fee: f64,
+    net: f64,
+    captures: i64,
+    refunds: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeeSummary {
+    pub day: NaiveDate,
+    pub provider: String,
+    pub gross: Money,
+    pub fee: Money,
+    pub net: Money,
+    pub captures: i64,
+    pub refunds: i64,
+}
+
+impl From<FeeSummaryRow> for FeeSummary {
+    fn from(row: FeeSummaryRow) -> Self {
+        Self {
+            day: row.day,
+            provider: row.provider,
+            gross: Money::new(row.gross, &row.currency),
+            fee: Money::new(row.fee, &row.currency),
+            net: Money::new(row.net, &row.currency),
+            captures: row.captures,
+            refunds: row.refunds,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeeSummaryQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+/// Fee charged by the simulated gateway: a percentage of the gross plus a fixed amount.
+#[derive(Debug, Clone, Copy)]
+pub struct SandboxFeeSchedule {
+    pub percent: f64,
+    pub fixed: f64,
+}
+
+impl SandboxFeeSchedule {
+    pub fn fee_for(&self, gross: f64) -> f64 {
+        ((gross * self.percent / 100.0 + self.fixed) * 100.0).round() / 100.0
+    }
+
+    pub fn capture(&self, payment_id: Uuid, transaction_id: &str, gross: &Money) -> NewFee {
+        let fee = self.fee_for(gross.amount);
+
+        NewFee {
+            payment_id: Some(payment_id),
+            provider: "sandbox".to_string(),
+            kind: KIND_CAPTURE.to_string(),
+            provider_reference: transaction_id.to_string(),
+            gross: gross.clone(),
+            fee: Money::new(fee, &gross.currency),
+            net: Money::new(gross.amount - fee, &gross.currency),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FeeStore {
+    pool: PgPool,
+}
+
+impl FeeStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Records fees for a capture or refund. Providers report the same
+    /// breakdown more than once (API response and webhook), so repeats are ignored.
+    pub async fn record(&self, fee: NewFee) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query(
+            r#"
+            INSERT INTO payment_fees
+                (id, payment_id, provider, kind, provider_reference, currency,
+                 gross_amount, fee_amount, net_amount, recorded_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            ON CONFLICT (provider, kind, provider_reference) DO NOTHING
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(fee.payment_id)
+        .bind(&fee.provider)
+        .bind(&fee.kind)
+        .bind(&fee.provider_reference)
+        .bind(&fee.gross.currency)
+        .bind(fee.gross.amount)
+        .bind(fee.fee.amount)
+        .bind(fee.net.amount)
+        .bind(Utc::now())
+        .execute(&mut *tx)
+        .await
+        .context("Failed to record payment fee")?;
+
+        if result.rows_affected() == 1 {
+            ledger::post(&mut tx, &fee.journal()).await?;
+        }
+        tx.commit().await?;
+
+        if result.rows_affected() == 1 {
+            tracing::info!(
+                "Recorded {} {} fee {} {} (net {}) for {}",
+                fee.provider,
+                fee.kind,
+                fee.fee.amount,
+                fee.fee.currency,
+                fee.net.amount,
+                fee.provider_reference
+            );
+        }
+
+        Ok(())
+    }
+
+    pub async fn list_for_payment(&self, payment_id: Uuid) -> Result<Vec<FeeEntry>> {
+        let rows = sqlx::query_as::<_, FeeRow>(
+            r#"
+            SELECT * FROM payment_fees WHERE payment_id = $1 ORDER BY recorded_at
+            "#,
+        )
+        .bind(payment_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(FeeEntry::from).collect())
+    }
+
+    /// Totals per UTC day, provider and currency. `to` is inclusive.
+    pub async fn summary(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<FeeSummary>> {
+        let rows = sqlx::query_as::<_, FeeSummaryRow>(
+            r#"
+            SELECT (recorded_at AT TIME ZONE 'UTC')::DATE AS day,
+                   provider,
+                   currency,
+                   SUM(gross_amount) AS gross,
+                   SUM(fee_amount) AS fee,
+                   SUM(net_amount) AS net,
+                   COUNT(*) FILTER (WHERE kind = 'capture') AS captures,
+                   COUNT(*) FILTER (WHERE kind = 'refund') AS refunds
+            FROM payment_fees
+            WHERE (recorded_at AT TIME ZONE 'UTC')::DATE BETWEEN $1 AND $2
+            GROUP BY 1, 2, 3
+            ORDER BY 1, 2, 3
+            "#,
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(FeeSummary::from).collect())
+    }
+}
+
+/// Reads a PayPal `{ "currency_code": "USD", "value": "10.00" }` amount.
+pub fn paypal_money(value: &serde_json::Value) -> Option<Money> {
+    let amount = value["value"].as_str()?.parse::<f64>().ok()?;
+    let currency = value["currency_code"].as_str()?;
+    Some(Money::new(amount, currency))
+}
+
+/// Builds a fee entry from a Stripe balance transaction, whose amounts are in
+/// the smallest currency unit and already negative for refunds.
+pub fn from_stripe_balance_transaction(
+    payment_id: Option<Uuid>,
+    kind: &str,
+    txn: &serde_json::Value,
+) -> Option<NewFee> {
+    let id = txn["id"].as_str()?;
+    let currency = txn["currency"].as_str()?.to_uppercase();
+    let amount = txn["amount"].as_i64()? as f64 / 100.0;
+    let net = txn["net"].as_i64()? as f64 / 100.0;
+    // Stripe reports the fee as a positive cost; a returned fee on refund is negative
+    let fee = txn["fee"].as_i64().unwrap_or(0) as f64 / 100.0;
+
+    Some(NewFee {
+        payment_id,
+        provider: "stripe".to_string(),
+        kind: kind.to_string(),
+        provider_reference: id.to_string(),
+        gross: Money::new(amount, &currency),
+        fee: Money::new(fee, &currency),
+        net: Money::new(net, &currency),
+    })
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payment_fees (
+            id UUID PRIMARY KEY,
+            payment_id UUID,
+            provider VARCHAR(50) NOT NULL,
+            kind VARCHAR(20) NOT NULL,
+            provider_reference VARCHAR(255) NOT NULL,
+            currency VARCHAR(3) NOT NULL,
+            gross_amount DOUBLE PRECISION NOT NULL,
+            fee_amount DOUBLE PRECISION NOT NULL,
+            net_amount DOUBLE PRECISION NOT NULL,
+            recorded_at TIMESTAMPTZ NOT NULL,
+            UNIQUE (provider, kind, provider_reference)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payment_fees table")?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_payment_fees_payment_id ON payment_fees (payment_id)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+
+use crate::models::{FxConversion, Money};
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct FxRate {
+    pub currency: String,
+    /// Units of the settlement currency bought by one unit of `currency`
+    pub rate: f64,
+    pub source: String,
+    pub as_of: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Format of the file named by `FX_RATES_FILE`.
+#[derive(Debug, Deserialize)]
+struct FxRatesFile {
+    as_of: Option<DateTime<Utc>>,
+    rates: HashMap<String, f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateFxRateRequest {
+    pub rate: f64,
+    pub as_of: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QuoteQuery {
+    pub amount: f64,
+    pub currency: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FxQuote {
+    #[serde(flatten)]
+    pub conversion: FxConversion,
+    pub quoted_at: DateTime<Utc>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FxError {
+    #[error("Invalid currency code '{0}'")]
+    InvalidCurrency(String),
+    #[error("No FX rate for {0}")]
+    UnknownCurrency(String),
+    #[error("FX rate for {currency} is stale (as of {as_of})")]
+    StaleRate {
+        currency: String,
+        as_of: DateTime<Utc>,
+    },
+    #[error("Invalid FX rate: {0}")]
+    InvalidRate(String),
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for FxError {
+    fn from(e: sqlx::Error) -> Self {
+        FxError::Failed(e.into())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FxService {
+    pool: PgPool,
+    settlement_currency: String,
+    max_rate_age: chrono::Duration,
+}
+
+impl FxService {
+    pub fn new(pool: PgPool, settlement_currency: &str, max_rate_age: chrono::Duration) -> Self {
+        Self {
+            pool,
+            settlement_currency: settlement_currency.to_uppercase(),
+            max_rate_age,
+        }
+    }
+
+    pub fn settlement_currency(&self) -> &str {
+        &self.settlement_currency
+    }
+
+    /// Seeds the rate table from a JSON file of the form
+    /// `{"as_of": "...", "rates": {"EUR": 1.08, ...}}`.
+    pub async fn load_file(&self, path: &str) -> Result<usize> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read FX rates file {}", path))?;
+        let file: FxRatesFile = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse FX rates file {}", path))?;
+
+        let as_of = file.as_of.unwrap_or_else(Utc::now);
+        for (currency, rate) in &file.rates {
+            self.upsert_rate(currency, *rate, "file", as_of).await?;
+        }
+
+        Ok(file.rates.len())
+    }
+
+    pub async fn upsert_rate(
+        &self,
+        currency: &str,
+        rate: f64,
+        source: &str,
+        as_of: DateTime<Utc>,
+    ) -> Result<FxRate, FxError> {
+        let currency = normalize_currency(currency)?;
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err(FxError::InvalidRate(format!("{} for {}", rate, currency)));
+        }
+        if currency == self.settlement_currency && rate != 1.0 {
+            return Err(FxError::InvalidRate(format!(
+                "settlement currency {} must have a rate of 1",
+                currency
+            )));
+        }
+
+        let record = sqlx::query_as::<_, FxRate>(
+            r#"
+            INSERT INTO fx_rates (currency, rate, source, as_of, updated_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (currency) DO UPDATE
+            SET rate = EXCLUDED.rate,
+                source = EXCLUDED.source,
+                as_of = EXCLUDED.as_of,
+                updated_at = EXCLUDED.updated_at
+            RETURNING *
+            "#,
+        )
+        .bind(&currency)
+        .bind(rate)
+        .bind(source)
+        .bind(as_of)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        tracing::info!("FX rate {} -> {} set to {} ({})", currency, self.settlement_currency, rate, source);
+        Ok(record)
+    }
+
+    pub async fn list_rates(&self) -> Result<Vec<FxRate>> {
+        let rates = sqlx::query_as::<_, FxRate>(
+            r#"
+            SELECT * FROM fx_rates ORDER BY currency
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rates)
+    }
+
+    /// Converts a presentment amount into the settlement currency using the
+    /// latest stored rate.
+    pub async fn convert(&self, amount: f64, currency: &str) -> Result<FxConversion, FxError> {
+        let currency = normalize_currency(currency)?;
+
+        if currency == self.settlement_currency {
+            return Ok(FxConversion {
+                presentment: Money::new(amount, &currency),
+                settlement: Money::new(amount, &currency),
+                rate: 1.0,
+                rate_as_of: Utc::now(),
+            });
+        }
+
+        let rate = sqlx::query_as::<_, FxRate>(
+            r#"
+            SELECT * FROM fx_rates WHERE currency = $1
+            "#,
+        )
+        .bind(&currency)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| FxError::UnknownCurrency(currency.clone()))?;
+
+        if Utc::now() - rate.as_of > self.max_rate_age {
+            return Err(FxError::StaleRate {
+                currency,
+                as_of: rate.as_of,
+            });
+        }
+
+        let settlement_amount = (amount * rate.rate * 100.0).round() / 100.0;
+
+        Ok(FxConversion {
+            presentment: Money::new(amount, &currency),
+            settlement: Money::new(settlement_amount, &self.settlement_currency),
+            rate: rate.rate,
+            rate_as_of: rate.as_of,
+        })
+    }
+}
+
+fn normalize_currency(currency: &str) -> Result<String, FxError> {
+    let code = currency.trim().to_uppercase();
+    if code.len() != 3 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(FxError::InvalidCurrency(currency.to_string()));
+    }
+    Ok(code)
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS fx_rates (
+            currency VARCHAR(3) PRIMARY KEY,
+            rate DOUBLE PRECISION NOT NULL,
+            source VARCHAR(50) NOT NULL,
+            as_of TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create fx_rates table")?;
+
+    Ok(())
+}
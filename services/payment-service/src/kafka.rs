@@ -1,16 +1,91 @@
+use chrono::{DateTime, TimeZone, Utc};
+use rdkafka::client::ClientContext;
 use rdkafka::config::ClientConfig;
-use rdkafka::consumer::{Consumer, StreamConsumer};
-use rdkafka::message::Message;
-use rdkafka::producer::{FutureProducer, FutureRecord};
-use std::sync::Arc;
-use std::time::Duration;
-use tracing::{error, info};
+use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance, StreamConsumer};
+use rdkafka::message::{Header, Headers, Message, OwnedHeaders, OwnedMessage};
+use rdkafka::consumer::ConsumerGroupMetadata;
+use rdkafka::error::KafkaResult;
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use rdkafka::statistics::Statistics;
+use rdkafka::{Offset, TopicPartitionList};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot::error::TryRecvError;
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use opentelemetry::trace::{FutureExt, SpanKind, Status, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
 
+use crate::authorization::AuthorizationError;
+use crate::consumer_health::CONSUMER_HEALTH;
+use crate::dead_letters::DeadLetterService;
+use crate::metrics::PAYMENT_METRICS;
 use crate::models::SagaEvent;
-use crate::payment::PaymentService;
+use crate::payment::{self, PaymentService};
+use crate::trace_context;
+use crate::wire_format::{decode_saga_event, CONTENT_TYPE_HEADER};
+
+/// Topics the saga sends us.
+pub const SOURCE_TOPICS: [&str; 3] = ["payment-process", "payment-rollback", "order-completed"];
+
+/// Headers a message carries while it waits on a retry topic.
+pub const RETRY_HEADER_PREFIX: &str = "retry.";
+/// Topic the message first arrived on
+pub const HEADER_RETRY_TOPIC: &str = "retry.topic";
+pub const HEADER_RETRY_ATTEMPTS: &str = "retry.attempts";
+/// Milliseconds since the epoch before which the message must not run again
+pub const HEADER_RETRY_NOT_BEFORE: &str = "retry.not-before";
+pub const HEADER_RETRY_ERROR: &str = "retry.error";
+
+pub struct RetryTier {
+    pub topic: &'static str,
+    pub delay: Duration,
+}
+
+/// Transiently failing messages wait on each of these in turn before they
+/// are dead-lettered.
+pub const RETRY_TIERS: [RetryTier; 3] = [
+    RetryTier {
+        topic: "payment-process.retry.5s",
+        delay: Duration::from_secs(5),
+    },
+    RetryTier {
+        topic: "payment-process.retry.1m",
+        delay: Duration::from_secs(60),
+    },
+    RetryTier {
+        topic: "payment-process.retry.10m",
+        delay: Duration::from_secs(600),
+    },
+];
+
+/// How long transaction calls may block.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often librdkafka reports lag and queue depths.
+pub const STATISTICS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// librdkafka's default `max.poll.interval.ms`; a consumer still behind
+/// after this long without moving has stalled.
+pub const DEFAULT_MAX_POLL_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Exports a producer's queue depth from its statistics.
+pub struct ProducerStats {
+    client: &'static str,
+}
+
+impl ClientContext for ProducerStats {
+    fn stats(&self, statistics: Statistics) {
+        PAYMENT_METRICS
+            .kafka_producer_queue
+            .with_label_values(&[self.client])
+            .set(statistics.msg_cnt as i64);
+    }
+}
 
 pub struct KafkaProducer {
-    producer: FutureProducer,
+    producer: FutureProducer<ProducerStats>,
 }
 
 impl std::fmt::Debug for KafkaProducer {
@@ -21,28 +96,93 @@ impl std::fmt::Debug for KafkaProducer {
 
 impl KafkaProducer {
     pub fn new(brokers: &str) -> Result<Self, rdkafka::error::KafkaError> {
-        let producer: FutureProducer = ClientConfig::new()
+        let producer: FutureProducer<ProducerStats> = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "5000")
+            .set("statistics.interval.ms", STATISTICS_INTERVAL.as_millis().to_string())
+            .create_with_context(ProducerStats { client: "producer" })?;
+
+        Ok(Self { producer })
+    }
+
+    /// A producer whose sends only become visible to `read_committed`
+    /// consumers when the transaction they were made in commits. Starting it
+    /// fences off any earlier instance with the same transactional id.
+    pub fn transactional(
+        brokers: &str,
+        transactional_id: &str,
+    ) -> Result<Self, rdkafka::error::KafkaError> {
+        let producer: FutureProducer<ProducerStats> = ClientConfig::new()
             .set("bootstrap.servers", brokers)
             .set("message.timeout.ms", "5000")
-            .create()?;
+            .set("transactional.id", transactional_id)
+            .set("statistics.interval.ms", STATISTICS_INTERVAL.as_millis().to_string())
+            .create_with_context(ProducerStats {
+                client: "transactional",
+            })?;
+        producer.init_transactions(TRANSACTION_TIMEOUT)?;
 
         Ok(Self { producer })
     }
 
-    pub async fn send_message(
+    pub fn begin_transaction(&self) -> KafkaResult<()> {
+        self.producer.begin_transaction()
+    }
+
+    /// Adds the consumer's offsets to the open transaction, so they are
+    /// committed with what it produced. Fails if the group has rebalanced
+    /// since `metadata` was taken.
+    pub fn send_offsets_to_transaction(
+        &self,
+        offsets: &TopicPartitionList,
+        metadata: &ConsumerGroupMetadata,
+    ) -> KafkaResult<()> {
+        tokio::task::block_in_place(|| {
+            self.producer
+                .send_offsets_to_transaction(offsets, metadata, TRANSACTION_TIMEOUT)
+        })
+    }
+
+    pub fn commit_transaction(&self) -> KafkaResult<()> {
+        tokio::task::block_in_place(|| self.producer.commit_transaction(TRANSACTION_TIMEOUT))
+    }
+
+    pub fn abort_transaction(&self) -> KafkaResult<()> {
+        tokio::task::block_in_place(|| self.producer.abort_transaction(TRANSACTION_TIMEOUT))
+    }
+
+    /// Sends raw bytes with headers, such as the payload's content type.
+    pub async fn send_with_headers(
         &self,
         topic: &str,
-        key: &str,
-        payload: &str,
+        key: Option<&str>,
+        payload: &[u8],
+        headers: OwnedHeaders,
     ) -> Result<(), anyhow::Error> {
-        let record = FutureRecord::to(topic).key(key).payload(payload);
+        let mut record = FutureRecord::<str, [u8]>::to(topic)
+            .payload(payload)
+            .headers(headers);
+        if let Some(key) = key {
+            record = record.key(key);
+        }
+
+        let started = Instant::now();
+        let delivery = self.producer.send(record, Duration::from_secs(5)).await;
+        PAYMENT_METRICS
+            .kafka_producer_delivery_seconds
+            .with_label_values(&[topic])
+            .observe(started.elapsed().as_secs_f64());
 
-        match self.producer.send(record, Duration::from_secs(5)).await {
+        match delivery {
             Ok(_) => {
                 info!("Message sent to topic: {}", topic);
                 Ok(())
             }
             Err((e, _)) => {
+                PAYMENT_METRICS
+                    .kafka_producer_errors
+                    .with_label_values(&[topic])
+                    .inc();
                 error!("Failed to send message: {:?}", e);
                 Err(anyhow::anyhow!("Kafka error: {:?}", e))
             }
@@ -50,56 +190,750 @@ impl KafkaProducer {
     }
 }
 
+/// Where a partition's processing stands. Messages finish out of order, so
+/// the committed offset only moves past the oldest one still in flight.
+struct PartitionProgress {
+    in_flight: BTreeSet<i64>,
+    next: i64,
+    committed: i64,
+}
+
+/// Tracks in-flight offsets for the poll loop and logs partition movement.
+/// Revoked partitions are forgotten, so work that finishes after losing a
+/// partition is not committed and the new owner runs it again.
+struct PartitionTracker {
+    /// Consumer group, which names the consumer in health reports
+    group: String,
+    partitions: Mutex<HashMap<(String, i32), PartitionProgress>>,
+}
+
+impl PartitionTracker {
+    fn new(group: String) -> Self {
+        Self {
+            group,
+            partitions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn started(&self, topic: &str, partition: i32, offset: i64) {
+        let mut partitions = self.partitions.lock().expect("partition tracker poisoned");
+        let progress = partitions
+            .entry((topic.to_string(), partition))
+            .or_insert_with(|| PartitionProgress {
+                in_flight: BTreeSet::new(),
+                next: offset,
+                committed: offset,
+            });
+        progress.in_flight.insert(offset);
+        progress.next = offset + 1;
+    }
+
+    /// Returns the offset to commit when this message moved the partition forward.
+    fn finished(&self, topic: &str, partition: i32, offset: i64) -> Option<i64> {
+        let mut partitions = self.partitions.lock().expect("partition tracker poisoned");
+        let progress = partitions.get_mut(&(topic.to_string(), partition))?;
+        progress.in_flight.remove(&offset);
+
+        let commit = progress.in_flight.first().copied().unwrap_or(progress.next);
+        if commit <= progress.committed {
+            return None;
+        }
+        progress.committed = commit;
+        Some(commit)
+    }
+}
+
+impl ClientContext for PartitionTracker {
+    fn stats(&self, statistics: Statistics) {
+        CONSUMER_HEALTH.record(&self.group, &statistics);
+    }
+}
+
+impl ConsumerContext for PartitionTracker {
+    fn pre_rebalance(&self, rebalance: &Rebalance) {
+        if let Rebalance::Revoke(partitions) = rebalance {
+            info!("Partitions revoked: {}", describe_partitions(partitions));
+
+            let mut progress = self.partitions.lock().expect("partition tracker poisoned");
+            for p in partitions.elements() {
+                progress.remove(&(p.topic().to_string(), p.partition()));
+            }
+        }
+    }
+
+    fn post_rebalance(&self, rebalance: &Rebalance) {
+        match rebalance {
+            Rebalance::Assign(partitions) => {
+                info!("Partitions assigned: {}", describe_partitions(partitions))
+            }
+            Rebalance::Error(e) => error!("Consumer rebalance failed: {}", e),
+            Rebalance::Revoke(_) => {}
+        }
+    }
+}
+
+pub fn describe_partitions(partitions: &TopicPartitionList) -> String {
+    partitions
+        .elements()
+        .iter()
+        .map(|p| format!("{}[{}]", p.topic(), p.partition()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A message whose handling is over, reported back to the poll loop.
+struct Finished {
+    topic: String,
+    partition: i32,
+    offset: i64,
+}
+
+/// Messages for the same order run one at a time, in the order received.
+pub fn lane_key(message: &OwnedMessage) -> String {
+    let headers = header_map(message);
+
+    message
+        .payload()
+        .and_then(|payload| {
+            decode_saga_event(headers.get(CONTENT_TYPE_HEADER).map(String::as_str), payload).ok()
+        })
+        .map(|event| event.order_id)
+        .unwrap_or_else(|| format!("{}[{}]", message.topic(), message.partition()))
+}
+
+/// Wait between attempts to hand a message on to a retry topic or the DLQ.
+fn publish_backoff(attempt: u32) -> Duration {
+    Duration::from_secs(2_u64.saturating_pow(attempt).min(30))
+}
+
+/// Header values as text; saga events only carry textual headers.
+pub fn header_map(message: &OwnedMessage) -> BTreeMap<String, String> {
+    message
+        .headers()
+        .map(|headers| {
+            headers
+                .iter()
+                .map(|h| {
+                    let value = h.value.map(String::from_utf8_lossy).unwrap_or_default();
+                    (h.key.to_string(), value.into_owned())
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn owned_headers(headers: &BTreeMap<String, String>) -> OwnedHeaders {
+    headers.iter().fold(OwnedHeaders::new(), |owned, (key, value)| {
+        owned.insert(Header { key, value: Some(value) })
+    })
+}
+
+/// Where a retried message stands, read from its headers.
+struct RetryState {
+    /// Topic the message first arrived on
+    topic: String,
+    /// Attempts made before this one
+    attempts: u32,
+    not_before: Option<DateTime<Utc>>,
+}
+
+impl RetryState {
+    fn of(message: &OwnedMessage, headers: &BTreeMap<String, String>) -> Self {
+        if !RETRY_TIERS.iter().any(|tier| tier.topic == message.topic()) {
+            return Self {
+                topic: message.topic().to_string(),
+                attempts: 0,
+                not_before: None,
+            };
+        }
+
+        Self {
+            topic: headers
+                .get(HEADER_RETRY_TOPIC)
+                .cloned()
+                .unwrap_or_else(|| message.topic().to_string()),
+            attempts: headers
+                .get(HEADER_RETRY_ATTEMPTS)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            not_before: headers
+                .get(HEADER_RETRY_NOT_BEFORE)
+                .and_then(|v| v.parse().ok())
+                .and_then(|millis| Utc.timestamp_millis_opt(millis).single()),
+        }
+    }
+
+    /// Headers for the next attempt: the message's own headers with this
+    /// state and the error in place of any earlier retry headers.
+    fn headers(&self, headers: &BTreeMap<String, String>, error: &str) -> BTreeMap<String, String> {
+        let mut retry_headers: BTreeMap<String, String> = headers
+            .iter()
+            .filter(|(key, _)| !key.starts_with(RETRY_HEADER_PREFIX))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        retry_headers.insert(HEADER_RETRY_TOPIC.to_string(), self.topic.clone());
+        retry_headers.insert(HEADER_RETRY_ATTEMPTS.to_string(), self.attempts.to_string());
+        if let Some(not_before) = self.not_before {
+            retry_headers.insert(
+                HEADER_RETRY_NOT_BEFORE.to_string(),
+                not_before.timestamp_millis().to_string(),
+            );
+        }
+        retry_headers.insert(HEADER_RETRY_ERROR.to_string(), error.to_string());
+        retry_headers
+    }
+}
+
 pub struct KafkaConsumer {
-    consumer: StreamConsumer,
+    consumer: StreamConsumer<PartitionTracker>,
+    producer: Arc<KafkaProducer>,
+    dead_letters: Arc<DeadLetterService>,
+    max_in_flight: usize,
 }
 
 impl KafkaConsumer {
-    pub fn new(brokers: &str, group_id: &str) -> Result<Self, rdkafka::error::KafkaError> {
-        let consumer: StreamConsumer = ClientConfig::new()
+    /// `offset_reset` applies only when the group has no committed offset
+    /// for a partition, e.g. on first start.
+    pub fn new(
+        brokers: &str,
+        group_id: &str,
+        offset_reset: &str,
+        max_in_flight: usize,
+        producer: Arc<KafkaProducer>,
+        dead_letters: Arc<DeadLetterService>,
+    ) -> Result<Self, rdkafka::error::KafkaError> {
+        let consumer: StreamConsumer<PartitionTracker> = ClientConfig::new()
             .set("bootstrap.servers", brokers)
             .set("group.id", group_id)
-            .set("enable.auto.commit", "true")
-            .set("auto.offset.reset", "latest")
-            .create()?;
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", offset_reset)
+            .set("statistics.interval.ms", STATISTICS_INTERVAL.as_millis().to_string())
+            .create_with_context(PartitionTracker::new(group_id.to_string()))?;
+        CONSUMER_HEALTH.watch(group_id, DEFAULT_MAX_POLL_INTERVAL);
 
-        Ok(Self { consumer })
+        Ok(Self {
+            consumer,
+            producer,
+            dead_letters,
+            max_in_flight: max_in_flight.max(1),
+        })
+    }
+
+    /// Consumer for one retry tier, in its own group. Messages are held until
+    /// due, so polls may be up to the tier's delay apart.
+    pub fn for_retry_tier(
+        brokers: &str,
+        group_id: &str,
+        tier: &RetryTier,
+        max_in_flight: usize,
+        producer: Arc<KafkaProducer>,
+        dead_letters: Arc<DeadLetterService>,
+    ) -> Result<Self, rdkafka::error::KafkaError> {
+        let max_poll_interval = tier.delay + DEFAULT_MAX_POLL_INTERVAL;
+        let group_id = format!("{}.{}", group_id, tier.topic);
+
+        let consumer: StreamConsumer<PartitionTracker> = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", &group_id)
+            .set("enable.auto.commit", "false")
+            // Parked messages are never skipped, whatever the main consumer does
+            .set("auto.offset.reset", "earliest")
+            .set("max.poll.interval.ms", max_poll_interval.as_millis().to_string())
+            .set("statistics.interval.ms", STATISTICS_INTERVAL.as_millis().to_string())
+            .create_with_context(PartitionTracker::new(group_id.clone()))?;
+        // Parked messages keep it behind for up to the tier's delay
+        CONSUMER_HEALTH.watch(&group_id, max_poll_interval);
+
+        Ok(Self {
+            consumer,
+            producer,
+            dead_letters,
+            max_in_flight: max_in_flight.max(1),
+        })
     }
 
     pub async fn consume_payment_requests(&self, payment_service: Arc<PaymentService>) {
         self.consumer
-            .subscribe(&["payment-process"])
-            .expect("Failed to subscribe to topic");
+            .subscribe(&SOURCE_TOPICS)
+            .expect("Failed to subscribe to topics");
+
+        info!("Kafka consumer started, listening to payment-process, payment-rollback and order-completed topics");
+
+        self.run(payment_service).await;
+    }
+
+    pub async fn consume_retries(&self, tier: &RetryTier, payment_service: Arc<PaymentService>) {
+        self.consumer
+            .subscribe(&[tier.topic])
+            .expect("Failed to subscribe to retry topic");
+
+        info!("Kafka retry consumer started, listening to {}", tier.topic);
+
+        self.run(payment_service).await;
+    }
 
-        info!("Kafka consumer started, listening to payment-process topic");
+    /// Polls and hands each message to its own task, up to `max_in_flight` at
+    /// once. Offsets are committed as the oldest in-flight message of a
+    /// partition finishes, so a crash redelivers anything not yet handled.
+    async fn run(&self, payment_service: Arc<PaymentService>) {
+        let handler = MessageHandler::new(
+            Arc::clone(&self.producer),
+            Arc::clone(&self.dead_letters),
+            payment_service,
+            false,
+        );
+        let permits = Arc::new(Semaphore::new(self.max_in_flight));
+        let (finished_tx, mut finished_rx) = mpsc::unbounded_channel();
+        let mut lanes: HashMap<String, oneshot::Receiver<()>> = HashMap::new();
 
         loop {
-            match self.consumer.recv().await {
-                Ok(message) => {
-                    if let Some(payload) = message.payload() {
-                        match serde_json::from_slice::<SagaEvent>(payload) {
-                            Ok(event) => {
-                                info!(
-                                    "Received payment request for saga_id: {}",
-                                    event.saga_id
-                                );
-                                
-                                // Process payment
-                                let result = payment_service
-                                    .process_saga_payment(event.clone())
-                                    .await;
-
-                                match result {
-                                    Ok(_) => info!("Payment processed successfully"),
-                                    Err(e) => error!("Payment processing failed: {}", e),
-                                }
+            tokio::select! {
+                Some(finished) = finished_rx.recv() => self.commit_finished(finished),
+                received = self.consumer.recv() => match received {
+                    Ok(message) => {
+                        let message = message.detach();
+                        let permit = self.reserve(&permits, &mut finished_rx).await;
+                        self.consumer
+                            .context()
+                            .started(message.topic(), message.partition(), message.offset());
+
+                        // Forget orders whose last message is done
+                        lanes.retain(|_, done| matches!(done.try_recv(), Err(TryRecvError::Empty)));
+                        let (done_tx, done_rx) = oneshot::channel();
+                        let previous = lanes.insert(lane_key(&message), done_rx);
+
+                        let handler = handler.clone();
+                        let finished_tx = finished_tx.clone();
+                        tokio::spawn(async move {
+                            if let Some(previous) = previous {
+                                let _ = previous.await;
                             }
-                            Err(e) => error!("Failed to parse saga event: {}", e),
-                        }
+                            // Retries and dead letters are published however
+                            // long it takes, so handling can't fail here
+                            let _ = handler.handle(&message).await;
+
+                            let _ = done_tx.send(());
+                            let _ = finished_tx.send(Finished {
+                                topic: message.topic().to_string(),
+                                partition: message.partition(),
+                                offset: message.offset(),
+                            });
+                            drop(permit);
+                        });
                     }
+                    Err(e) => error!("Error receiving message: {}", e),
+                },
+            }
+        }
+    }
+
+    /// Takes an in-flight slot. When none is free, fetching is paused on every
+    /// assigned partition until a message finishes.
+    async fn reserve(
+        &self,
+        permits: &Arc<Semaphore>,
+        finished_rx: &mut mpsc::UnboundedReceiver<Finished>,
+    ) -> OwnedSemaphorePermit {
+        if let Ok(permit) = Arc::clone(permits).try_acquire_owned() {
+            return permit;
+        }
+
+        let assignment = self.consumer.assignment().unwrap_or_default();
+        if let Err(e) = self.consumer.pause(&assignment) {
+            warn!("Failed to pause partitions: {}", e);
+        }
+        info!(
+            "{} messages in flight, paused {}",
+            self.max_in_flight,
+            describe_partitions(&assignment)
+        );
+
+        let permit = loop {
+            tokio::select! {
+                permit = Arc::clone(permits).acquire_owned() => {
+                    break permit.expect("in-flight semaphore closed");
                 }
-                Err(e) => error!("Error receiving message: {}", e),
+                Some(finished) = finished_rx.recv() => self.commit_finished(finished),
             }
+        };
+
+        if let Err(e) = self.consumer.resume(&assignment) {
+            warn!("Failed to resume partitions: {}", e);
+        }
+        permit
+    }
+
+    fn commit_finished(&self, finished: Finished) {
+        let Some(offset) = self.consumer.context().finished(
+            &finished.topic,
+            finished.partition,
+            finished.offset,
+        ) else {
+            return;
+        };
+
+        let mut offsets = TopicPartitionList::new();
+        let committed = offsets
+            .add_partition_offset(&finished.topic, finished.partition, Offset::Offset(offset))
+            .and_then(|_| self.consumer.commit(&offsets, CommitMode::Async));
+        if let Err(e) = committed {
+            error!(
+                "Failed to commit offset {} on {}[{}]: {}",
+                offset, finished.topic, finished.partition, e
+            );
+        }
+    }
+}
+
+/// What a message needs once it leaves the poll loop.
+#[derive(Clone)]
+pub struct MessageHandler {
+    producer: Arc<KafkaProducer>,
+    dead_letters: Arc<DeadLetterService>,
+    payment_service: Arc<PaymentService>,
+    /// Publishes into a Kafka transaction, which a failed send dooms, so
+    /// retrying the send is pointless
+    transactional: bool,
+}
+
+impl MessageHandler {
+    pub fn new(
+        producer: Arc<KafkaProducer>,
+        dead_letters: Arc<DeadLetterService>,
+        payment_service: Arc<PaymentService>,
+        transactional: bool,
+    ) -> Self {
+        Self {
+            producer,
+            dead_letters,
+            payment_service,
+            transactional,
         }
     }
+
+    /// Runs a message once. Transient failures move it to the next retry tier
+    /// and, past the last one, to the DLQ; unparseable messages go straight there.
+    /// Returns once the message is handled or handed on, or with the error
+    /// that kept a transactional handler from handing it on.
+    pub async fn handle(&self, message: &OwnedMessage) -> Result<(), anyhow::Error> {
+        PAYMENT_METRICS
+            .kafka_messages_consumed
+            .with_label_values(&[message.topic()])
+            .inc();
+
+        let headers = header_map(message);
+        let retry = RetryState::of(message, &headers);
+
+        // Each tier holds messages in arrival order with the same delay, so
+        // waiting for one delays nothing queued behind it
+        if let Some(wait) = retry.not_before.and_then(|at| (at - Utc::now()).to_std().ok()) {
+            tokio::time::sleep(wait).await;
+        }
+
+        // Processing joins the trace the message was sent in; its log lines
+        // carry the trace and, once known, the saga and order
+        let parent = trace_context::extract(&headers);
+        let span = trace_context::message_span(
+            message.topic(),
+            "process",
+            SpanKind::Consumer,
+            vec![
+                KeyValue::new("messaging.kafka.destination.partition", i64::from(message.partition())),
+                KeyValue::new("messaging.kafka.message.offset", message.offset()),
+            ],
+            &parent,
+        );
+        let cx = parent.with_span(span);
+        let log_span = info_span!(
+            "kafka_message",
+            topic = message.topic(),
+            partition = message.partition(),
+            offset = message.offset(),
+            trace_id = %trace_context::trace_id(&cx),
+            saga_id = field::Empty,
+            order_id = field::Empty,
+        );
+
+        let started = Instant::now();
+        let result = self
+            .process(message, &headers, &retry)
+            .with_context(cx.clone())
+            .instrument(log_span)
+            .await;
+        PAYMENT_METRICS
+            .kafka_message_processing_seconds
+            .with_label_values(&[message.topic()])
+            .observe(started.elapsed().as_secs_f64());
+
+        if let Err(e) = &result {
+            cx.span().set_status(Status::error(e.to_string()));
+        }
+        cx.span().end();
+        result
+    }
+
+    async fn process(
+        &self,
+        message: &OwnedMessage,
+        headers: &BTreeMap<String, String>,
+        retry: &RetryState,
+    ) -> Result<(), anyhow::Error> {
+        let Some(payload) = message.payload() else {
+            return Ok(());
+        };
+
+        let content_type = headers.get(CONTENT_TYPE_HEADER).map(String::as_str);
+        let event = match decode_saga_event(content_type, payload) {
+            Ok(event) => event,
+            Err(e) => {
+                PAYMENT_METRICS
+                    .kafka_parse_failures
+                    .with_label_values(&[message.topic()])
+                    .inc();
+                error!("Failed to parse saga event: {:#}", e);
+                let error = format!("Failed to parse saga event: {:#}", e);
+                return self.dead_letter(message, &error, retry.attempts + 1).await;
+            }
+        };
+
+        let current = Span::current();
+        current.record("saga_id", event.saga_id.as_str());
+        current.record("order_id", event.order_id.as_str());
+        info!(
+            "Received {} event for saga_id: {}",
+            retry.topic,
+            event.saga_id
+        );
+
+        let saga_id = event.saga_id.clone();
+        let attempts = retry.attempts + 1;
+        match self.dispatch(&retry.topic, event).await {
+            Ok(()) => {
+                info!("{} event processed successfully", retry.topic);
+                Ok(())
+            }
+            Err(e) => {
+                Context::current()
+                    .span()
+                    .set_status(Status::error(e.to_string()));
+
+                // Anything but an outage could fail the same way again, or
+                // would send a gateway call that already went through twice
+                let tier = if payment::is_transient(&e) {
+                    RETRY_TIERS.get(retry.attempts as usize)
+                } else {
+                    None
+                };
+                match tier {
+                    Some(tier) => {
+                        warn!(
+                            "{} event for saga {} failed (attempt {}), retrying via {}: {}",
+                            retry.topic, saga_id, attempts, tier.topic, e
+                        );
+                        self.schedule_retry(message, headers, &retry.topic, attempts, tier, &e.to_string())
+                            .await
+                    }
+                    None => {
+                        error!(
+                            "{} event for saga {} failed after {} attempts: {}",
+                            retry.topic, saga_id, attempts, e
+                        );
+                        self.dead_letter(message, &e.to_string(), attempts).await
+                    }
+                }
+            }
+        }
+    }
+
+    /// Parks the message on a retry topic, keeping its key and headers.
+    /// Keeps trying until it is published; committing past it before then
+    /// would lose it. A transactional handler gives up and lets the
+    /// transaction abort instead.
+    async fn schedule_retry(
+        &self,
+        message: &OwnedMessage,
+        headers: &BTreeMap<String, String>,
+        topic: &str,
+        attempts: u32,
+        tier: &RetryTier,
+        error: &str,
+    ) -> Result<(), anyhow::Error> {
+        let not_before = Utc::now() + chrono::Duration::from_std(tier.delay).unwrap_or_default();
+
+        let mut retry_headers = RetryState {
+            topic: topic.to_string(),
+            attempts,
+            not_before: Some(not_before),
+        }
+        .headers(headers, error);
+        // The next attempt is traced as following on from this one
+        trace_context::inject(&Context::current(), &mut retry_headers);
+
+        let key = message.key().map(String::from_utf8_lossy);
+        let payload = message.payload().unwrap_or_default();
+
+        let mut attempt = 1;
+        while let Err(e) = self
+            .producer
+            .send_with_headers(tier.topic, key.as_deref(), payload, owned_headers(&retry_headers))
+            .await
+        {
+            error!(
+                "Failed to move {}[{}] offset {} to {}: {}",
+                message.topic(),
+                message.partition(),
+                message.offset(),
+                tier.topic,
+                e
+            );
+            if self.transactional {
+                return Err(e);
+            }
+            tokio::time::sleep(publish_backoff(attempt)).await;
+            attempt += 1;
+        }
+
+        PAYMENT_METRICS
+            .messages_retried
+            .with_label_values(&[tier.topic])
+            .inc();
+        Ok(())
+    }
+
+    /// Keeps trying until the message is in the DLQ; committing past it
+    /// before then would lose it. A transactional handler gives up instead.
+    async fn dead_letter(
+        &self,
+        message: &OwnedMessage,
+        error: &str,
+        attempts: u32,
+    ) -> Result<(), anyhow::Error> {
+        let mut attempt = 1;
+        while let Err(e) = self.dead_letters.forward(message, error, attempts).await {
+            error!(
+                "Failed to dead-letter {}[{}] offset {}: {}",
+                message.topic(),
+                message.partition(),
+                message.offset(),
+                e
+            );
+            if self.transactional {
+                return Err(e);
+            }
+            tokio::time::sleep(publish_backoff(attempt)).await;
+            attempt += 1;
+        }
+        Ok(())
+    }
+
+    async fn dispatch(&self, topic: &str, event: SagaEvent) -> Result<(), anyhow::Error> {
+        let payment_service = &self.payment_service;
+        match topic {
+            // Compensation: release the authorization hold
+            "payment-rollback" => payment_service.process_saga_rollback(event).await,
+            // Order confirmed: take the money that was put on hold
+            "order-completed" => match payment_service.capture_order_payment(&event.order_id).await {
+                Ok(_) => Ok(()),
+                // Redelivered completions find the hold already captured
+                Err(e @ (AuthorizationError::NotFound | AuthorizationError::InvalidState(_))) => {
+                    warn!("Not capturing payment for order {}: {}", event.order_id, e);
+                    Ok(())
+                }
+                Err(AuthorizationError::Failed(e)) => Err(e),
+            },
+            _ => payment_service.process_saga_payment(event).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::Timestamp;
+
+    fn message(topic: &str, headers: &BTreeMap<String, String>) -> OwnedMessage {
+        OwnedMessage::new(
+            Some(b"{}".to_vec()),
+            Some(b"order-1".to_vec()),
+            topic.to_string(),
+            Timestamp::NotAvailable,
+            0,
+            42,
+            Some(owned_headers(headers)),
+        )
+    }
+
+    #[test]
+    fn commits_up_to_the_oldest_message_still_in_flight() {
+        let tracker = PartitionTracker::new("group".to_string());
+        for offset in 10..13 {
+            tracker.started("payment-process", 0, offset);
+        }
+
+        assert_eq!(tracker.finished("payment-process", 0, 11), None);
+        assert_eq!(tracker.finished("payment-process", 0, 10), Some(12));
+        assert_eq!(tracker.finished("payment-process", 0, 12), Some(13));
+    }
+
+    #[test]
+    fn partitions_are_tracked_separately() {
+        let tracker = PartitionTracker::new("group".to_string());
+        tracker.started("payment-process", 0, 5);
+        tracker.started("payment-process", 1, 7);
+
+        assert_eq!(tracker.finished("payment-process", 1, 7), Some(8));
+        assert_eq!(tracker.finished("payment-process", 0, 5), Some(6));
+    }
+
+    #[test]
+    fn work_finished_after_a_revoke_is_not_committed() {
+        let tracker = PartitionTracker::new("group".to_string());
+        tracker.started("payment-process", 0, 5);
+        tracker.started("payment-process", 1, 7);
+
+        let mut revoked = TopicPartitionList::new();
+        revoked.add_partition("payment-process", 0);
+        tracker.pre_rebalance(&Rebalance::Revoke(&revoked));
+
+        assert_eq!(tracker.finished("payment-process", 0, 5), None);
+        assert_eq!(tracker.finished("payment-process", 1, 7), Some(8));
+    }
+
+    #[test]
+    fn retry_state_round_trips_through_headers() {
+        let not_before = Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+        let original = BTreeMap::from([
+            ("traceparent".to_string(), "00-abc-def-01".to_string()),
+            (HEADER_RETRY_ERROR.to_string(), "stale".to_string()),
+        ]);
+        let headers = RetryState {
+            topic: "payment-process".to_string(),
+            attempts: 2,
+            not_before: Some(not_before),
+        }
+        .headers(&original, "gateway unavailable");
+
+        assert_eq!(headers.get("traceparent").map(String::as_str), Some("00-abc-def-01"));
+        assert_eq!(headers.get(HEADER_RETRY_ERROR).map(String::as_str), Some("gateway unavailable"));
+
+        let retried = message(RETRY_TIERS[1].topic, &headers);
+        let state = RetryState::of(&retried, &header_map(&retried));
+        assert_eq!(state.topic, "payment-process");
+        assert_eq!(state.attempts, 2);
+        assert_eq!(state.not_before, Some(not_before));
+    }
+
+    #[test]
+    fn retry_headers_are_ignored_outside_the_retry_topics() {
+        let headers = BTreeMap::from([
+            (HEADER_RETRY_TOPIC.to_string(), "payment-rollback".to_string()),
+            (HEADER_RETRY_ATTEMPTS.to_string(), "3".to_string()),
+        ]);
+
+        let fresh = message("payment-process", &headers);
+        let state = RetryState::of(&fresh, &header_map(&fresh));
+        assert_eq!(state.topic, "payment-process");
+        assert_eq!(state.attempts, 0);
+        assert_eq!(state.not_before, None);
+    }
 }
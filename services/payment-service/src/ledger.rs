@@ -0,0 +1,693 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::metrics::PAYMENT_METRICS;
+use crate::models::Money;
+
+pub const JOURNAL_CAPTURE: &str = "capture";
+pub const JOURNAL_PROVIDER_FEE: &str = "provider_fee";
+pub const JOURNAL_REFUND: &str = "refund";
+pub const JOURNAL_SELLER_SHARE: &str = "seller_share";
+pub const JOURNAL_PAYOUT: &str = "payout";
+
+/// Funds received from customers that have not been refunded or passed on to sellers
+pub const ACCOUNT_CUSTOMER: &str = "customer";
+/// Money a provider has collected for us and not yet settled, per provider
+pub const ACCOUNT_GATEWAY_CLEARING: &str = "gateway_clearing";
+/// Processing fees charged by a provider, per provider
+pub const ACCOUNT_PROVIDER_FEES: &str = "provider_fees";
+/// Money returned to customers
+pub const ACCOUNT_REFUNDS: &str = "refunds";
+/// What the platform owes a seller, per seller
+pub const ACCOUNT_SELLER_PAYABLE: &str = "seller_payable";
+/// Money sent out through a payout provider, per provider
+pub const ACCOUNT_PAYOUT_CLEARING: &str = "payout_clearing";
+
+/// Amounts are stored in minor units so that debits and credits compare exactly.
+fn to_minor(amount: f64) -> i64 {
+    (amount * 100.0).round() as i64
+}
+
+fn from_minor(minor: i64) -> f64 {
+    minor as f64 / 100.0
+}
+
+fn account(base: &str, key: impl std::fmt::Display) -> String {
+    format!("{}:{}", base, key)
+}
+
+fn account_type(code: &str) -> &'static str {
+    match code.split(':').next().unwrap_or(code) {
+        ACCOUNT_GATEWAY_CLEARING | ACCOUNT_PAYOUT_CLEARING => "asset",
+        ACCOUNT_CUSTOMER | ACCOUNT_SELLER_PAYABLE => "liability",
+        ACCOUNT_PROVIDER_FEES | ACCOUNT_REFUNDS => "expense",
+        _ => "other",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Debit,
+    Credit,
+}
+
+#[derive(Debug, Clone)]
+pub struct JournalLine {
+    pub account: String,
+    pub side: Side,
+    pub amount_minor: i64,
+}
+
+/// A balanced set of ledger lines for one money movement. `kind` and
+/// `reference` identify the movement, so posting it twice is a no-op.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    pub kind: &'static str,
+    pub reference: String,
+    pub payment_id: Option<Uuid>,
+    pub currency: String,
+    pub description: String,
+    pub lines: Vec<JournalLine>,
+}
+
+impl Journal {
+    fn new(kind: &'static str, reference: String, payment_id: Option<Uuid>, currency: &str) -> Self {
+        Self {
+            kind,
+            reference,
+            payment_id,
+            currency: currency.to_string(),
+            description: String::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    fn describe(mut self, description: String) -> Self {
+        self.description = description;
+        self
+    }
+
+    /// Moves `amount` from `credit` to `debit`. Negative amounts reverse the direction.
+    fn transfer(mut self, debit: String, credit: String, amount: f64) -> Self {
+        let minor = to_minor(amount);
+        let (debit, credit) = if minor < 0 { (credit, debit) } else { (debit, credit) };
+
+        self.lines.push(JournalLine {
+            account: debit,
+            side: Side::Debit,
+            amount_minor: minor.abs(),
+        });
+        self.lines.push(JournalLine {
+            account: credit,
+            side: Side::Credit,
+            amount_minor: minor.abs(),
+        });
+        self
+    }
+
+    fn is_balanced(&self) -> bool {
+        let (debits, credits) = self.lines.iter().fold((0, 0), |(d, c), line| match line.side {
+            Side::Debit => (d + line.amount_minor, c),
+            Side::Credit => (d, c + line.amount_minor),
+        });
+        debits == credits
+    }
+
+    /// The provider collected the customer's money.
+    pub fn capture(provider: &str, payment_id: Uuid, amount: &Money) -> Self {
+        Self::new(JOURNAL_CAPTURE, payment_id.to_string(), Some(payment_id), &amount.currency)
+            .describe(format!("{} capture", provider))
+            .transfer(
+                account(ACCOUNT_GATEWAY_CLEARING, provider),
+                ACCOUNT_CUSTOMER.to_string(),
+                amount.amount,
+            )
+    }
+
+    /// The provider kept a fee out of a capture; a negative fee is a fee
+    /// returned on refund.
+    pub fn provider_fee(provider: &str, payment_id: Option<Uuid>, reference: &str, fee: &Money) -> Self {
+        Self::new(
+            JOURNAL_PROVIDER_FEE,
+            format!("{}:{}", provider, reference),
+            payment_id,
+            &fee.currency,
+        )
+        .describe(format!("{} fee for {}", provider, reference))
+        .transfer(
+            account(ACCOUNT_PROVIDER_FEES, provider),
+            account(ACCOUNT_GATEWAY_CLEARING, provider),
+            fee.amount,
+        )
+    }
+
+    /// The provider paid money back to the customer.
+    pub fn refund(provider: &str, payment_id: Option<Uuid>, reference: &str, amount: &Money) -> Self {
+        Self::new(
+            JOURNAL_REFUND,
+            format!("{}:{}", provider, reference),
+            payment_id,
+            &amount.currency,
+        )
+        .describe(format!("{} refund {}", provider, reference))
+        .transfer(
+            ACCOUNT_REFUNDS.to_string(),
+            account(ACCOUNT_GATEWAY_CLEARING, provider),
+            amount.amount,
+        )
+    }
+
+    /// A seller's net share of a captured marketplace payment.
+    pub fn seller_share(split_id: Uuid, payment_id: Uuid, seller_id: Uuid, net: &Money) -> Self {
+        Self::new(JOURNAL_SELLER_SHARE, split_id.to_string(), Some(payment_id), &net.currency)
+            .describe(format!("seller {} share", seller_id))
+            .transfer(
+                ACCOUNT_CUSTOMER.to_string(),
+                account(ACCOUNT_SELLER_PAYABLE, seller_id),
+                net.amount,
+            )
+    }
+
+    /// A seller balance left through the payout provider.
+    pub fn payout(provider: &str, payout_id: Uuid, seller_id: Uuid, amount: &Money) -> Self {
+        Self::new(JOURNAL_PAYOUT, payout_id.to_string(), None, &amount.currency)
+            .describe(format!("payout to seller {}", seller_id))
+            .transfer(
+                account(ACCOUNT_SELLER_PAYABLE, seller_id),
+                account(ACCOUNT_PAYOUT_CLEARING, provider),
+                amount.amount,
+            )
+    }
+}
+
+/// Appends a journal inside the caller's transaction, so the ledger commits or
+/// rolls back with the state change it describes. Returns false if the
+/// movement was already posted.
+pub async fn post(tx: &mut Transaction<'_, Postgres>, journal: &Journal) -> Result<bool> {
+    if !journal.is_balanced() {
+        anyhow::bail!("Unbalanced {} journal {}", journal.kind, journal.reference);
+    }
+    if journal.lines.iter().all(|line| line.amount_minor == 0) {
+        return Ok(false);
+    }
+
+    let now = Utc::now();
+    let journal_id = sqlx::query_scalar::<_, Uuid>(
+        r#"
+        INSERT INTO ledger_journals (id, kind, reference, payment_id, currency, description, posted_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (kind, reference) DO NOTHING
+        RETURNING id
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(journal.kind)
+    .bind(&journal.reference)
+    .bind(journal.payment_id)
+    .bind(&journal.currency)
+    .bind(&journal.description)
+    .bind(now)
+    .fetch_optional(&mut **tx)
+    .await
+    .context("Failed to insert ledger journal")?;
+
+    let Some(journal_id) = journal_id else {
+        return Ok(false);
+    };
+
+    for line in &journal.lines {
+        sqlx::query(
+            r#"
+            INSERT INTO ledger_accounts (code, account_type, created_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (code) DO NOTHING
+            "#,
+        )
+        .bind(&line.account)
+        .bind(account_type(&line.account))
+        .bind(now)
+        .execute(&mut **tx)
+        .await?;
+
+        let (debit, credit) = match line.side {
+            Side::Debit => (line.amount_minor, 0),
+            Side::Credit => (0, line.amount_minor),
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO ledger_entries
+                (journal_id, account, currency, debit_minor, credit_minor, posted_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+        )
+        .bind(journal_id)
+        .bind(&line.account)
+        .bind(&journal.currency)
+        .bind(debit)
+        .bind(credit)
+        .bind(now)
+        .execute(&mut **tx)
+        .await
+        .context("Failed to insert ledger entry")?;
+    }
+
+    Ok(true)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountBalance {
+    pub account: String,
+    pub account_type: String,
+    pub currency: String,
+    pub debits: f64,
+    pub credits: f64,
+    /// Debits minus credits
+    pub balance: f64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct AccountBalanceRow {
+    account: String,
+    account_type: String,
+    currency: String,
+    debits: i64,
+    credits: i64,
+}
+
+impl From<AccountBalanceRow> for AccountBalance {
+    fn from(row: AccountBalanceRow) -> Self {
+        Self {
+            account: row.account,
+            account_type: row.account_type,
+            currency: row.currency,
+            debits: from_minor(row.debits),
+            credits: from_minor(row.credits),
+            balance: from_minor(row.debits - row.credits),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BalanceQuery {
+    /// Exact account code, or a prefix such as `seller_payable` for every seller
+    pub account: Option<String>,
+    pub currency: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LedgerEntry {
+    pub account: String,
+    pub side: Side,
+    pub amount: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LedgerJournal {
+    pub id: Uuid,
+    pub kind: String,
+    pub reference: String,
+    pub payment_id: Option<Uuid>,
+    pub currency: String,
+    pub description: String,
+    pub posted_at: DateTime<Utc>,
+    pub entries: Vec<LedgerEntry>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct JournalRow {
+    id: Uuid,
+    kind: String,
+    reference: String,
+    payment_id: Option<Uuid>,
+    currency: String,
+    description: String,
+    posted_at: DateTime<Utc>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct EntryRow {
+    journal_id: Uuid,
+    account: String,
+    debit_minor: i64,
+    credit_minor: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CurrencyTotals {
+    pub currency: String,
+    pub debits: f64,
+    pub credits: f64,
+}
+
+/// Result of checking that the ledger balances.
+#[derive(Debug, Clone, Serialize)]
+pub struct InvariantReport {
+    pub balanced: bool,
+    pub journals: i64,
+    pub entries: i64,
+    pub totals: Vec<CurrencyTotals>,
+    /// Journals whose debits and credits differ
+    pub unbalanced_journals: Vec<Uuid>,
+    /// Journals without any entries
+    pub empty_journals: Vec<Uuid>,
+    pub checked_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LedgerStore {
+    pool: PgPool,
+}
+
+impl LedgerStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn balances(&self, query: &BalanceQuery) -> Result<Vec<AccountBalance>> {
+        let rows = sqlx::query_as::<_, AccountBalanceRow>(
+            r#"
+            SELECT e.account,
+                   a.account_type,
+                   e.currency,
+                   SUM(e.debit_minor)::BIGINT AS debits,
+                   SUM(e.credit_minor)::BIGINT AS credits
+            FROM ledger_entries e
+            JOIN ledger_accounts a ON a.code = e.account
+            WHERE ($1::VARCHAR IS NULL OR e.account = $1 OR e.account LIKE $1 || ':%')
+              AND ($2::VARCHAR IS NULL OR e.currency = $2)
+            GROUP BY e.account, a.account_type, e.currency
+            ORDER BY e.account, e.currency
+            "#,
+        )
+        .bind(&query.account)
+        .bind(query.currency.as_ref().map(|c| c.to_uppercase()))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(AccountBalance::from).collect())
+    }
+
+    pub async fn journals_for_payment(&self, payment_id: Uuid) -> Result<Vec<LedgerJournal>> {
+        let journals = sqlx::query_as::<_, JournalRow>(
+            r#"
+            SELECT id, kind, reference, payment_id, currency, description, posted_at
+            FROM ledger_journals WHERE payment_id = $1 ORDER BY posted_at
+            "#,
+        )
+        .bind(payment_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let ids: Vec<Uuid> = journals.iter().map(|j| j.id).collect();
+        let entries = sqlx::query_as::<_, EntryRow>(
+            r#"
+            SELECT journal_id, account, debit_minor, credit_minor
+            FROM ledger_entries WHERE journal_id = ANY($1) ORDER BY id
+            "#,
+        )
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(journals
+            .into_iter()
+            .map(|j| LedgerJournal {
+                entries: entries
+                    .iter()
+                    .filter(|e| e.journal_id == j.id)
+                    .map(|e| LedgerEntry {
+                        account: e.account.clone(),
+                        side: if e.debit_minor > 0 { Side::Debit } else { Side::Credit },
+                        amount: from_minor(e.debit_minor + e.credit_minor),
+                    })
+                    .collect(),
+                id: j.id,
+                kind: j.kind,
+                reference: j.reference,
+                payment_id: j.payment_id,
+                currency: j.currency,
+                description: j.description,
+                posted_at: j.posted_at,
+            })
+            .collect())
+    }
+
+    /// Verifies that every journal and the ledger as a whole balance.
+    pub async fn check_invariants(&self) -> Result<InvariantReport> {
+        let unbalanced_journals = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            SELECT journal_id FROM ledger_entries
+            GROUP BY journal_id
+            HAVING SUM(debit_minor) <> SUM(credit_minor)
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let empty_journals = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            SELECT j.id FROM ledger_journals j
+            WHERE NOT EXISTS (SELECT 1 FROM ledger_entries e WHERE e.journal_id = j.id)
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let totals = sqlx::query_as::<_, (String, i64, i64)>(
+            r#"
+            SELECT currency, SUM(debit_minor)::BIGINT, SUM(credit_minor)::BIGINT
+            FROM ledger_entries GROUP BY currency ORDER BY currency
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let (journals, entries) = sqlx::query_as::<_, (i64, i64)>(
+            r#"
+            SELECT (SELECT COUNT(*) FROM ledger_journals), (SELECT COUNT(*) FROM ledger_entries)
+            "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        let balanced = unbalanced_journals.is_empty()
+            && empty_journals.is_empty()
+            && totals.iter().all(|(_, debits, credits)| debits == credits);
+
+        Ok(InvariantReport {
+            balanced,
+            journals,
+            entries,
+            totals: totals
+                .into_iter()
+                .map(|(currency, debits, credits)| CurrencyTotals {
+                    currency,
+                    debits: from_minor(debits),
+                    credits: from_minor(credits),
+                })
+                .collect(),
+            unbalanced_journals,
+            empty_journals,
+            checked_at: Utc::now(),
+        })
+    }
+}
+
+/// Periodically checks that debits equal credits and reports any breakage.
+pub async fn run_invariant_checker(ledger: Arc<LedgerStore>, interval: Duration) {
+    info!("Ledger invariant checker started, interval {:?}", interval);
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        match ledger.check_invariants().await {
+            Ok(report) => {
+                PAYMENT_METRICS
+                    .ledger_unbalanced_journals
+                    .set(report.unbalanced_journals.len() as i64);
+                if !report.balanced {
+                    error!(
+                        "Ledger out of balance: {} unbalanced and {} empty journals",
+                        report.unbalanced_journals.len(),
+                        report.empty_journals.len()
+                    );
+                }
+            }
+            Err(e) => error!("Ledger invariant check failed: {}", e),
+        }
+    }
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS ledger_accounts (
+            code VARCHAR(255) PRIMARY KEY,
+            account_type VARCHAR(20) NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create ledger_accounts table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS ledger_journals (
+            id UUID PRIMARY KEY,
+            kind VARCHAR(50) NOT NULL,
+            reference VARCHAR(255) NOT NULL,
+            payment_id UUID,
+            currency VARCHAR(3) NOT NULL,
+            description TEXT NOT NULL,
+            posted_at TIMESTAMPTZ NOT NULL,
+            UNIQUE (kind, reference)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create ledger_journals table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS ledger_entries (
+            id BIGSERIAL PRIMARY KEY,
+            journal_id UUID NOT NULL REFERENCES ledger_journals(id),
+            account VARCHAR(255) NOT NULL REFERENCES ledger_accounts(code),
+            currency VARCHAR(3) NOT NULL,
+            debit_minor BIGINT NOT NULL CHECK (debit_minor >= 0),
+            credit_minor BIGINT NOT NULL CHECK (credit_minor >= 0),
+            posted_at TIMESTAMPTZ NOT NULL,
+            CHECK ((debit_minor = 0) <> (credit_minor = 0))
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create ledger_entries table")?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_ledger_entries_account ON ledger_entries (account, currency)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_ledger_entries_journal_id ON ledger_entries (journal_id)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_ledger_journals_payment_id ON ledger_journals (payment_id)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // The ledger is append-only; corrections are posted as new journals
+    sqlx::query(
+        r#"
+        CREATE OR REPLACE FUNCTION ledger_append_only() RETURNS trigger AS $$
+        BEGIN
+            RAISE EXCEPTION 'ledger table % is append-only', TG_TABLE_NAME;
+        END;
+        $$ LANGUAGE plpgsql
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create ledger append-only function")?;
+
+    for table in ["ledger_journals", "ledger_entries"] {
+        sqlx::query(&format!(
+            "CREATE OR REPLACE TRIGGER {table}_append_only \
+             BEFORE UPDATE OR DELETE ON {table} \
+             FOR EACH ROW EXECUTE FUNCTION ledger_append_only()"
+        ))
+        .execute(pool)
+        .await
+        .with_context(|| format!("Failed to protect {} from changes", table))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usd(amount: f64) -> Money {
+        Money {
+            amount,
+            currency: "USD".to_string(),
+        }
+    }
+
+    fn sides(journal: &Journal) -> Vec<(&str, Side, i64)> {
+        journal
+            .lines
+            .iter()
+            .map(|line| (line.account.as_str(), line.side, line.amount_minor))
+            .collect()
+    }
+
+    #[test]
+    fn transfer_debits_and_credits_the_same_minor_amount() {
+        let journal = Journal::new(JOURNAL_CAPTURE, "ref".to_string(), None, "USD")
+            .transfer("a".to_string(), "b".to_string(), 12.345);
+
+        assert_eq!(sides(&journal), vec![("a", Side::Debit, 1235), ("b", Side::Credit, 1235)]);
+        assert!(journal.is_balanced());
+    }
+
+    #[test]
+    fn negative_transfer_reverses_the_direction() {
+        let journal = Journal::new(JOURNAL_PROVIDER_FEE, "ref".to_string(), None, "USD")
+            .transfer("a".to_string(), "b".to_string(), -0.3);
+
+        assert_eq!(sides(&journal), vec![("b", Side::Debit, 30), ("a", Side::Credit, 30)]);
+        assert!(journal.is_balanced());
+    }
+
+    #[test]
+    fn returned_provider_fee_credits_the_fee_account() {
+        let journal = Journal::provider_fee("stripe", None, "re_1", &usd(-0.59));
+
+        assert_eq!(
+            sides(&journal),
+            vec![("gateway_clearing:stripe", Side::Debit, 59), ("provider_fees:stripe", Side::Credit, 59)]
+        );
+    }
+
+    #[test]
+    fn unequal_lines_are_not_balanced() {
+        let mut journal = Journal::new(JOURNAL_CAPTURE, "ref".to_string(), None, "USD")
+            .transfer("a".to_string(), "b".to_string(), 5.0);
+        assert!(journal.is_balanced());
+
+        journal.lines.push(JournalLine {
+            account: "c".to_string(),
+            side: Side::Debit,
+            amount_minor: 1,
+        });
+        assert!(!journal.is_balanced());
+    }
+}
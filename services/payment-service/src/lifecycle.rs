@@ -0,0 +1,139 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::authorization::STATUS_EXPIRED;
+use crate::events::{PaymentAggregate, PaymentEvent};
+use crate::outbox::OutboxMessage;
+
+pub const TOPIC_PAYMENT_PROCESSED: &str = "payment-processed";
+pub const TOPIC_PAYMENT_FAILED: &str = "payment-failed";
+pub const TOPIC_PAYMENT_REFUNDED: &str = "payment-refunded";
+pub const TOPIC_PAYMENT_EXPIRED: &str = "payment-expired";
+
+/// Bumped only for changes existing consumers can't ignore. Adding optional
+/// fields keeps the version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Payload of the payment lifecycle topics, keyed by payment id so each
+/// payment's events arrive in order:
+///
+/// - `payment-processed`: money was captured, by the sandbox gateway or a
+///   PayPal/Stripe webhook
+/// - `payment-failed`: the gateway or provider declined the payment
+/// - `payment-refunded`: a refund went through; sent once per refund
+/// - `payment-expired`: the buyer abandoned checkout, or an authorization
+///   hold lapsed without being captured
+///
+/// Placing a hold, voiding it on saga rollback and disputes are not
+/// published here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentLifecycleEvent {
+    /// `{payment_id}:{version}`; the same change always has the same id, so
+    /// consumers can drop redeliveries
+    pub event_id: String,
+    /// Same as the topic
+    pub event_type: String,
+    pub schema_version: u32,
+    pub payment_id: String,
+    pub order_id: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+    pub data: PaymentLifecycleData,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentLifecycleData {
+    pub payment_id: String,
+    pub order_id: Option<String>,
+    pub user_id: Option<String>,
+    /// `sandbox`, `paypal` or `stripe`
+    pub provider: String,
+    /// Payment status after the change, e.g. `completed` or `refunded`
+    pub status: String,
+    pub amount: f64,
+    pub currency: String,
+    pub settlement_amount: Option<f64>,
+    pub settlement_currency: Option<String>,
+    /// Provider's capture or payment intent, or the gateway transaction
+    pub reference: Option<String>,
+    /// Why the payment failed or expired
+    pub reason: Option<String>,
+    /// Set on `payment-refunded` only
+    pub refund: Option<RefundDetails>,
+    /// Total refunded so far, in the payment's currency
+    pub refunded_amount: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefundDetails {
+    pub reference: String,
+    pub amount: f64,
+    pub currency: String,
+}
+
+/// The lifecycle topic an event is published to, if any.
+fn topic_for(event: &PaymentEvent) -> Option<&'static str> {
+    match event {
+        PaymentEvent::Captured { .. } => Some(TOPIC_PAYMENT_PROCESSED),
+        PaymentEvent::Failed { .. } => Some(TOPIC_PAYMENT_FAILED),
+        PaymentEvent::Refunded { .. } => Some(TOPIC_PAYMENT_REFUNDED),
+        PaymentEvent::Expired => Some(TOPIC_PAYMENT_EXPIRED),
+        PaymentEvent::Voided { reason } if reason == STATUS_EXPIRED => Some(TOPIC_PAYMENT_EXPIRED),
+        _ => None,
+    }
+}
+
+/// The message announcing an event that was just applied to `payment`.
+pub fn message(payment: &PaymentAggregate, event: &PaymentEvent) -> Result<Option<OutboxMessage>> {
+    let Some(topic) = topic_for(event) else {
+        return Ok(None);
+    };
+
+    let payment_id = payment.id.to_string();
+    let reason = match event {
+        PaymentEvent::Failed { reason } | PaymentEvent::Voided { reason } => Some(reason.clone()),
+        PaymentEvent::Expired => Some("checkout abandoned".to_string()),
+        _ => None,
+    };
+    let refund = match event {
+        PaymentEvent::Refunded {
+            reference,
+            amount,
+            currency,
+        } => Some(RefundDetails {
+            reference: reference.clone(),
+            amount: *amount,
+            currency: currency.clone(),
+        }),
+        _ => None,
+    };
+
+    let lifecycle_event = PaymentLifecycleEvent {
+        event_id: format!("{}:{}", payment_id, payment.version),
+        event_type: topic.to_string(),
+        schema_version: SCHEMA_VERSION,
+        payment_id: payment_id.clone(),
+        order_id: payment.order_id.clone(),
+        occurred_at: payment.updated_at,
+        data: PaymentLifecycleData {
+            payment_id: payment_id.clone(),
+            order_id: payment.order_id.clone(),
+            user_id: payment.user_id.clone(),
+            provider: payment.provider.clone(),
+            status: payment.status.clone(),
+            amount: payment.amount,
+            currency: payment.currency.clone(),
+            settlement_amount: payment.settlement_amount,
+            settlement_currency: payment.settlement_currency.clone(),
+            reference: payment
+                .capture_reference
+                .clone()
+                .or_else(|| payment.transaction_id.clone()),
+            reason,
+            refund,
+            refunded_amount: payment.refunded_amount,
+        },
+    };
+
+    OutboxMessage::json(topic, &payment_id, &lifecycle_event).map(Some)
+}
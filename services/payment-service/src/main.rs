@@ -1,8 +1,8 @@
 use axum::{
-    extract::{Json, Path},
+    extract::{Json, Path, Query},
     http::{StatusCode, HeaderMap},
     response::IntoResponse,
-    routing::{get, post},
+    routing::{get, post, put},
     Router,
 };
 use std::sync::Arc;
@@ -13,25 +13,92 @@ use tracing::{info, Level};
 use tracing_subscriber;
 use sqlx::PgPool;
 
+mod attempts;
+mod authorization;
 mod circuit_breaker;
 mod config;
+mod consumer_health;
+mod dead_letters;
+mod disputes;
+mod dunning;
+mod events;
+mod exactly_once;
+mod fees;
+mod fx;
 mod kafka;
+mod ledger;
+mod lifecycle;
+mod marketplace;
 mod metrics;
 mod models;
+mod outbox;
 mod payment;
+mod pending;
+mod reconciliation;
 mod redis_client;
+mod replay;
 mod paypal_handler;
+mod schema_registry;
+mod stripe_handler;
+mod subscriptions;
+mod trace_context;
+mod vault;
+mod wire_format;
 
+use attempts::AttemptStore;
+use authorization::AuthorizationError;
 use config::Config;
+use dead_letters::{DeadLetterError, DeadLetterService, ListDeadLettersQuery};
+use disputes::{DisputeError, DisputeService, ListDisputesQuery, SubmitEvidenceRequest};
+use dunning::{DunningService, ListRetriesQuery};
+use exactly_once::ExactlyOnceConsumer;
+use fees::{FeeStore, FeeSummaryQuery, SandboxFeeSchedule};
+use fx::{FxError, FxQuote, FxService, QuoteQuery, UpdateFxRateRequest};
 use kafka::{KafkaConsumer, KafkaProducer};
+use ledger::{BalanceQuery, LedgerStore};
+use marketplace::{
+    CreateSellerRequest, ListPayoutsQuery, MarketplaceError, MarketplaceService, PayoutReportQuery,
+    UpdateSellerRequest,
+};
 use metrics::{init_metrics, metrics_handler};
+use outbox::OutboxStore;
 use payment::PaymentService;
+use pending::PendingPaymentSweeper;
+use reconciliation::{
+    IngestReportQuery, ListDiscrepanciesQuery, ReconciliationError, ReconciliationService,
+};
 use redis_client::RedisClient;
+use replay::{ReplayError, ReplayService, StartReplayRequest};
 use paypal_handler::{PayPalHandler, CreatePaymentRequest, init_db};
+use schema_registry::{SchemaError, SchemaRegistry};
+use stripe_handler::StripeHandler;
+use subscriptions::{
+    ChangePlanRequest, CreatePlanRequest, CreateSubscriptionRequest, ListSubscriptionsQuery,
+    SubscriptionError, SubscriptionService,
+};
+use vault::{
+    CreatePaymentMethodRequest, ListPaymentMethodsQuery, UpdatePaymentMethodRequest, VaultError,
+    VaultStore,
+};
+use wire_format::PayloadEncoder;
 
 
 static PAYMENT_SERVICE: OnceCell<Arc<payment::PaymentService>> = OnceCell::new();
 static PAYPAL_HANDLER: OnceCell<Arc<PayPalHandler>> = OnceCell::new();
+static STRIPE_HANDLER: OnceCell<Arc<StripeHandler>> = OnceCell::new();
+static VAULT_STORE: OnceCell<VaultStore> = OnceCell::new();
+static DISPUTE_SERVICE: OnceCell<Arc<DisputeService>> = OnceCell::new();
+static FX_SERVICE: OnceCell<FxService> = OnceCell::new();
+static FEE_STORE: OnceCell<FeeStore> = OnceCell::new();
+static ATTEMPT_STORE: OnceCell<AttemptStore> = OnceCell::new();
+static SUBSCRIPTION_SERVICE: OnceCell<Arc<SubscriptionService>> = OnceCell::new();
+static DUNNING_SERVICE: OnceCell<Arc<DunningService>> = OnceCell::new();
+static MARKETPLACE_SERVICE: OnceCell<Arc<MarketplaceService>> = OnceCell::new();
+static LEDGER_STORE: OnceCell<Arc<LedgerStore>> = OnceCell::new();
+static RECONCILIATION_SERVICE: OnceCell<Arc<ReconciliationService>> = OnceCell::new();
+static DEAD_LETTER_SERVICE: OnceCell<Arc<DeadLetterService>> = OnceCell::new();
+static SCHEMA_REGISTRY: OnceCell<SchemaRegistry> = OnceCell::new();
+static REPLAY_SERVICE: OnceCell<ReplayService> = OnceCell::new();
 
 #[tokio::main]
 async fn main() {
@@ -45,6 +112,11 @@ async fn main() {
     // Load configuration
     let config = Config::from_env();
 
+    // Export spans to Jaeger; Kafka messages carry their trace in W3C headers
+    if let Err(e) = trace_context::init_tracer(&config.jaeger_agent_host, config.jaeger_agent_port) {
+        tracing::warn!("Failed to initialize Jaeger tracing: {}", e);
+    }
+
     // Initialize metrics
     init_metrics();
 
@@ -59,6 +131,115 @@ async fn main() {
     // Run migrations
     info!("Initializing database schema");
     init_db(&pool).await.expect("Failed to initialize database");
+    stripe_handler::init_db(&pool)
+        .await
+        .expect("Failed to initialize Stripe schema");
+    events::init_db(&pool)
+        .await
+        .expect("Failed to initialize payment event schema");
+    outbox::init_db(&pool)
+        .await
+        .expect("Failed to initialize outbox schema");
+    dead_letters::init_db(&pool)
+        .await
+        .expect("Failed to initialize dead letter schema");
+    schema_registry::init_db(&pool)
+        .await
+        .expect("Failed to initialize schema registry");
+    replay::init_db(&pool)
+        .await
+        .expect("Failed to initialize replay schema");
+    authorization::init_db(&pool)
+        .await
+        .expect("Failed to initialize authorization schema");
+    vault::init_db(&pool)
+        .await
+        .expect("Failed to initialize payment method vault schema");
+    disputes::init_db(&pool)
+        .await
+        .expect("Failed to initialize dispute schema");
+    fx::init_db(&pool)
+        .await
+        .expect("Failed to initialize FX rate schema");
+    ledger::init_db(&pool)
+        .await
+        .expect("Failed to initialize ledger schema");
+    fees::init_db(&pool)
+        .await
+        .expect("Failed to initialize payment fee schema");
+    attempts::init_db(&pool)
+        .await
+        .expect("Failed to initialize payment attempt schema");
+    subscriptions::init_db(&pool)
+        .await
+        .expect("Failed to initialize subscription schema");
+    dunning::init_db(&pool)
+        .await
+        .expect("Failed to initialize dunning schema");
+    marketplace::init_db(&pool)
+        .await
+        .expect("Failed to initialize marketplace schema");
+    reconciliation::init_db(&pool)
+        .await
+        .expect("Failed to initialize reconciliation schema");
+
+    // FX rates convert presentment amounts into the settlement currency
+    let fx_service = FxService::new(
+        pool.clone(),
+        &config.settlement_currency,
+        chrono::Duration::hours(config.fx_rate_max_age_hours),
+    );
+    if let Some(path) = &config.fx_rates_file {
+        let loaded = fx_service
+            .load_file(path)
+            .await
+            .expect("Failed to load FX rates file");
+        info!("Loaded {} FX rates from {}", loaded, path);
+    }
+    FX_SERVICE.set(fx_service.clone())
+        .expect("Failed to set global FX service");
+
+    // Initialize Kafka; everything else publishes by writing to the outbox
+    let kafka_producer = Arc::new(
+        KafkaProducer::new(&config.kafka_brokers).expect("Failed to create Kafka producer"),
+    );
+
+    // Event schemas must stay compatible with every version already registered
+    let schema_registry = SchemaRegistry::new(pool.clone());
+    let saga_event_schema = schema_registry
+        .register(&wire_format::saga_event_schema())
+        .await
+        .expect("Failed to register SagaEvent schema");
+    let lifecycle_schema = schema_registry
+        .register(&wire_format::payment_lifecycle_schema())
+        .await
+        .expect("Failed to register PaymentLifecycleEvent schema");
+    SCHEMA_REGISTRY.set(schema_registry)
+        .expect("Failed to set global schema registry");
+    let payload_encoder = PayloadEncoder::new(
+        config.kafka_protobuf_topics.clone(),
+        saga_event_schema.id,
+        lifecycle_schema.id,
+    );
+
+    let relay_interval = std::time::Duration::from_millis(config.outbox_relay_interval_ms);
+    tokio::spawn(outbox::run_outbox_relay(
+        OutboxStore::new(pool.clone()),
+        Arc::clone(&kafka_producer),
+        payload_encoder.clone(),
+        relay_interval,
+        chrono::Duration::hours(config.outbox_retention_hours),
+    ));
+
+    // Saga events that can't be handled are parked on the DLQ for re-driving
+    let dead_letter_service = Arc::new(DeadLetterService::new(pool.clone(), Arc::clone(&kafka_producer)));
+    DEAD_LETTER_SERVICE.set(Arc::clone(&dead_letter_service))
+        .expect("Failed to set global dead letter service");
+
+    // Dispute notifications arrive through the PayPal and Stripe webhooks
+    let dispute_service = Arc::new(DisputeService::new(pool.clone()));
+    DISPUTE_SERVICE.set(Arc::clone(&dispute_service))
+        .expect("Failed to set global dispute service");
 
     // Initialize PayPal handler
     let paypal_client_id = std::env::var("PAYPAL_CLIENT_ID")
@@ -75,37 +256,206 @@ async fn main() {
         paypal_client_secret,
         paypal_sandbox,
         pool.clone(),
+        Arc::clone(&dispute_service),
+        fx_service.clone(),
     ));
     
     PAYPAL_HANDLER.set(Arc::clone(&paypal_handler))
         .expect("Failed to set global PayPal handler");
 
+    // Initialize Stripe handler
+    let stripe_api_key = std::env::var("STRIPE_SECRET_KEY")
+        .unwrap_or_else(|_| "dummy_api_key".to_string());
+    let stripe_webhook_secret = std::env::var("STRIPE_WEBHOOK_SECRET")
+        .unwrap_or_else(|_| "dummy_webhook_secret".to_string());
+
+    let stripe_handler = Arc::new(StripeHandler::new(
+        stripe_api_key,
+        stripe_webhook_secret,
+        pool.clone(),
+        Arc::clone(&dispute_service),
+        fx_service.clone(),
+    ));
+
+    STRIPE_HANDLER.set(Arc::clone(&stripe_handler))
+        .expect("Failed to set global Stripe handler");
+
+    // Expire checkouts the buyer abandoned; webhooks alone never arrive for those
+    let pending_sweeper = PendingPaymentSweeper::new(
+        pool.clone(),
+        Arc::clone(&paypal_handler),
+        stripe_handler,
+        chrono::Duration::minutes(config.pending_payment_max_age_minutes),
+    );
+    let pending_interval = std::time::Duration::from_secs(config.pending_payment_sweep_interval_secs);
+    tokio::spawn(pending::run_pending_sweeper(pending_sweeper, pending_interval));
+
+    VAULT_STORE.set(VaultStore::new(pool.clone()))
+        .expect("Failed to set global vault store");
+
+    FEE_STORE.set(FeeStore::new(pool.clone()))
+        .expect("Failed to set global fee store");
+
+    ATTEMPT_STORE.set(AttemptStore::new(pool.clone()))
+        .expect("Failed to set global attempt store");
+
     // Initialize Redis
     let redis_client = RedisClient::new(&config.redis_host)
         .await
         .expect("Failed to connect to Redis");
 
-    // Initialize Kafka
-    let kafka_producer = KafkaProducer::new(&config.kafka_brokers)
-        .expect("Failed to create Kafka producer");
+    // Failed charges are retried on the dunning schedule
+    let retry_schedule = dunning::parse_schedule(&config.dunning_retry_schedule)
+        .expect("Invalid DUNNING_RETRY_SCHEDULE");
+    let dunning_service = Arc::new(DunningService::new(
+        pool.clone(),
+        retry_schedule,
+    ));
+    DUNNING_SERVICE.set(Arc::clone(&dunning_service))
+        .expect("Failed to set global dunning service");
 
     // Initialize payment service and wrap in Arc for sharing
-    let payment_service = std::sync::Arc::new(PaymentService::new(redis_client, kafka_producer));
+    let payment_service = std::sync::Arc::new(PaymentService::new(
+        redis_client,
+        pool.clone(),
+        chrono::Duration::hours(config.authorization_hold_hours),
+        fx_service,
+        SandboxFeeSchedule {
+            percent: config.sandbox_fee_percent,
+            fixed: config.sandbox_fee_fixed,
+        },
+        Arc::clone(&dunning_service),
+    ));
 
     // Start Kafka consumer: pass an Arc<PaymentService> directly
     let consumer_service = Arc::clone(&payment_service);
-    tokio::spawn(async move {
-        let consumer = KafkaConsumer::new(&config.kafka_brokers, "payment-service-group")
-            .expect("Failed to create Kafka consumer");
-        consumer.consume_payment_requests(consumer_service).await;
-    });
+    if config.kafka_exactly_once {
+        // Publishes only through its own transactional producer, dead letters included
+        let transactional_producer = Arc::new(
+            KafkaProducer::transactional(&config.kafka_brokers, &config.kafka_transactional_id)
+                .expect("Failed to create transactional Kafka producer"),
+        );
+        let consumer = ExactlyOnceConsumer::new(
+            &config.kafka_brokers,
+            "payment-service-group",
+            &config.kafka_auto_offset_reset,
+            config.kafka_max_in_flight,
+            std::time::Duration::from_millis(config.kafka_transaction_interval_ms),
+            Arc::clone(&transactional_producer),
+            Arc::new(DeadLetterService::new(pool.clone(), transactional_producer)),
+            OutboxStore::new(pool.clone()),
+            payload_encoder,
+        )
+        .expect("Failed to create exactly-once Kafka consumer");
+        tokio::spawn(async move {
+            consumer.consume_payment_requests(consumer_service).await;
+        });
+    } else {
+        let consumer = KafkaConsumer::new(
+            &config.kafka_brokers,
+            "payment-service-group",
+            &config.kafka_auto_offset_reset,
+            config.kafka_max_in_flight,
+            Arc::clone(&kafka_producer),
+            Arc::clone(&dead_letter_service),
+        )
+        .expect("Failed to create Kafka consumer");
+        tokio::spawn(async move {
+            consumer.consume_payment_requests(consumer_service).await;
+        });
+    }
+
+    // Transient failures come back through the retry topics, one consumer per delay
+    for tier in &kafka::RETRY_TIERS {
+        let consumer = KafkaConsumer::for_retry_tier(
+            &config.kafka_brokers,
+            "payment-service-group",
+            tier,
+            config.kafka_max_in_flight,
+            Arc::clone(&kafka_producer),
+            Arc::clone(&dead_letter_service),
+        )
+        .expect("Failed to create Kafka retry consumer");
+        let consumer_service = Arc::clone(&payment_service);
+        tokio::spawn(async move {
+            consumer.consume_retries(tier, consumer_service).await;
+        });
+    }
+
+    // Release authorization holds that were never captured
+    let sweeper_service = Arc::clone(&payment_service);
+    let sweep_interval = std::time::Duration::from_secs(config.authorization_sweep_interval_secs);
+    tokio::spawn(authorization::run_expiry_sweeper(sweeper_service, sweep_interval));
+
+    // Subscriptions are charged through the payment service on every billing cycle
+    let subscription_service = Arc::new(SubscriptionService::new(
+        pool.clone(),
+        Arc::clone(&payment_service),
+        Arc::clone(&dunning_service),
+    ));
+    SUBSCRIPTION_SERVICE.set(Arc::clone(&subscription_service))
+        .expect("Failed to set global subscription service");
+    let billing_interval = std::time::Duration::from_secs(config.subscription_billing_interval_secs);
+    tokio::spawn(subscriptions::run_billing_scheduler(
+        Arc::clone(&subscription_service),
+        billing_interval,
+    ));
+
+    let dunning_interval = std::time::Duration::from_secs(config.dunning_interval_secs);
+    tokio::spawn(dunning::run_dunning_scheduler(
+        dunning_service,
+        Arc::clone(&payment_service),
+        subscription_service,
+        dunning_interval,
+    ));
+
+    // Seller shares clear the hold period before they are paid out
+    let marketplace_service = Arc::new(MarketplaceService::new(
+        pool.clone(),
+        chrono::Duration::days(config.seller_balance_hold_days),
+    ));
+    MARKETPLACE_SERVICE.set(Arc::clone(&marketplace_service))
+        .expect("Failed to set global marketplace service");
+    let payout_interval = std::time::Duration::from_secs(config.payout_interval_secs);
+    tokio::spawn(marketplace::run_payout_scheduler(marketplace_service, payout_interval));
+
+    // Every money movement is booked in the ledger; check that it still balances
+    let ledger_store = Arc::new(LedgerStore::new(pool.clone()));
+    LEDGER_STORE.set(Arc::clone(&ledger_store))
+        .expect("Failed to set global ledger store");
+    let ledger_check_interval = std::time::Duration::from_secs(config.ledger_check_interval_secs);
+    tokio::spawn(ledger::run_invariant_checker(ledger_store, ledger_check_interval));
+
+    // Provider settlement reports are uploaded through the API or dropped in the inbox
+    let reconciliation_service = Arc::new(ReconciliationService::new(pool.clone()));
+    RECONCILIATION_SERVICE.set(Arc::clone(&reconciliation_service))
+        .expect("Failed to set global reconciliation service");
+    if let Some(dir) = &config.reconciliation_inbox_dir {
+        let reconciliation_interval =
+            std::time::Duration::from_secs(config.reconciliation_interval_secs);
+        tokio::spawn(reconciliation::run_reconciliation_inbox(
+            reconciliation_service,
+            dir.into(),
+            reconciliation_interval,
+        ));
+    }
 
     // Store the payment service in a global OnceCell so handlers can access it
     PAYMENT_SERVICE.set(Arc::clone(&payment_service)).expect("Failed to set global payment service");
 
+    // Payment requests can be replayed after an outage, each replay in a group of its own
+    REPLAY_SERVICE.set(ReplayService::new(
+        pool.clone(),
+        &config.kafka_brokers,
+        "payment-service-group",
+        Arc::clone(&payment_service),
+    ))
+    .expect("Failed to set global replay service");
+
     // Build application
     let app = Router::new()
         .route("/health", get(health_check))
+        .route("/ready", get(readiness_check))
         .route("/metrics", get(metrics_handler))
         .route("/api/payments", post(|Json(payload): Json<models::PaymentRequest>| async move {
             let svc = PAYMENT_SERVICE.get().expect("payment service not initialized");
@@ -125,11 +475,75 @@ async fn main() {
                 }
             }
         }))
+        .route("/api/payments/authorize", post(authorize_payment))
+        .route("/api/payments/:id/capture", post(capture_payment))
+        .route("/api/payments/:id/void", post(void_payment))
+        .route("/api/payments/:id/attempts", get(list_payment_attempts))
+        // Payment event history and projections
+        .route("/api/payments/:id/events", get(list_payment_events))
+        .route("/api/payments/:id/rebuild", post(rebuild_payment_projection))
+        .route("/api/payments/projections/rebuild", post(rebuild_payment_projections))
+        // Saved payment methods
+        .route("/api/payments/methods", post(create_payment_method).get(list_payment_methods))
+        .route(
+            "/api/payments/methods/:method_id",
+            get(get_payment_method).put(update_payment_method).delete(delete_payment_method),
+        )
+        // Disputes and chargebacks
+        .route("/api/payments/disputes", get(list_disputes))
+        .route("/api/payments/disputes/:dispute_id", get(get_dispute))
+        .route("/api/payments/disputes/:dispute_id/evidence", post(submit_dispute_evidence))
+        // FX rates and quotes
+        .route("/api/payments/fx/rates", get(list_fx_rates))
+        .route("/api/payments/fx/rates/:currency", put(update_fx_rate))
+        .route("/api/payments/fx/quote", get(fx_quote))
+        // Provider fees and net settlement
+        .route("/api/payments/fees/summary", get(fee_summary))
+        .route("/api/payments/:id/fees", get(list_payment_fees))
+        // Subscriptions
+        .route("/api/payments/plans", post(create_plan).get(list_plans))
+        .route("/api/payments/subscriptions", post(create_subscription).get(list_subscriptions))
+        .route("/api/payments/subscriptions/:subscription_id", get(get_subscription))
+        .route("/api/payments/subscriptions/:subscription_id/plan", put(change_subscription_plan))
+        .route("/api/payments/subscriptions/:subscription_id/cancel", post(cancel_subscription))
+        // Marketplace sellers and payouts
+        .route("/api/payments/sellers", post(create_seller).get(list_sellers))
+        .route("/api/payments/sellers/:seller_id", get(get_seller).put(update_seller))
+        .route("/api/payments/sellers/:seller_id/balance", get(seller_balance))
+        .route("/api/payments/:id/splits", get(list_payment_splits))
+        .route("/api/payments/payouts", get(list_payouts))
+        .route("/api/payments/payouts/run", post(run_payouts))
+        .route("/api/payments/payouts/report", get(payout_report))
+        // Double-entry ledger
+        .route("/api/payments/ledger/balances", get(ledger_balances))
+        .route("/api/payments/ledger/check", get(ledger_check))
+        .route("/api/payments/:id/ledger", get(payment_ledger))
+        // Settlement report reconciliation
+        .route("/api/payments/reconciliation/runs", post(ingest_settlement_report).get(list_reconciliation_runs))
+        .route("/api/payments/reconciliation/runs/:run_id", get(get_reconciliation_run))
+        .route("/api/payments/reconciliation/discrepancies", get(list_discrepancies))
+        // Dunning
+        .route("/api/payments/retries", get(list_payment_retries))
+        // Dead letters
+        .route("/api/payments/dead-letters", get(list_dead_letters))
+        .route("/api/payments/dead-letters/:id/redrive", post(redrive_dead_letter))
+        // Saga request replays
+        .route("/api/payments/replays", post(start_replay).get(list_replays))
+        .route("/api/payments/replays/:id", get(get_replay))
+        // Event schemas, in the Confluent schema registry shape
+        .route("/api/payments/schemas/ids/:id", get(get_schema_by_id))
+        .route("/api/payments/schemas/subjects", get(list_schema_subjects))
+        .route("/api/payments/schemas/subjects/:subject/versions", get(list_schema_versions))
+        .route("/api/payments/schemas/subjects/:subject/versions/:version", get(get_schema_version))
         // PayPal endpoints
         .route("/api/payments/paypal/create-order", post(create_paypal_order))
         .route("/api/payments/paypal/capture/:order_id", post(capture_paypal_order))
         .route("/api/payments/paypal/webhook", post(paypal_webhook))
         .route("/api/payments/paypal/:payment_id", get(get_paypal_payment))
+        // Stripe endpoints
+        .route("/api/payments/stripe/create-session", post(create_stripe_session))
+        .route("/api/payments/stripe/webhook", post(stripe_webhook))
+        .route("/api/payments/stripe/:payment_id", get(get_stripe_payment))
         .layer(CorsLayer::permissive())
         .layer(TraceLayer::new_for_http());    // Start server
     let addr = format!("0.0.0.0:{}", config.port);
@@ -146,6 +560,17 @@ async fn health_check() -> (StatusCode, &'static str) {
     (StatusCode::OK, "healthy")
 }
 
+/// Ready while every Kafka consumer is in its group and keeping up.
+async fn readiness_check() -> impl IntoResponse {
+    let readiness = consumer_health::CONSUMER_HEALTH.readiness();
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
 async fn process_payment(
     Json(payload): Json<models::PaymentRequest>,
 ) -> impl IntoResponse {
@@ -170,6 +595,171 @@ async fn get_payment_status(
     }
 }
 
+async fn authorize_payment(
+    Json(payload): Json<models::PaymentRequest>,
+) -> impl IntoResponse {
+    let svc = PAYMENT_SERVICE.get().expect("payment service not initialized");
+    match svc.authorize_payment(payload).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn capture_payment(
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let svc = PAYMENT_SERVICE.get().expect("payment service not initialized");
+    match svc.capture_payment(&id).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => authorization_error_response(e),
+    }
+}
+
+async fn void_payment(
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let svc = PAYMENT_SERVICE.get().expect("payment service not initialized");
+    match svc.void_payment(&id).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => authorization_error_response(e),
+    }
+}
+
+async fn list_payment_attempts(
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let attempts = ATTEMPT_STORE.get().expect("attempt store not initialized");
+
+    match attempts.get_with_attempts(id).await {
+        Ok(Some(details)) => (StatusCode::OK, Json(details)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Payment not found").into_response(),
+        Err(e) => {
+            tracing::error!("Failed to list payment attempts: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+async fn list_payment_events(
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let svc = PAYMENT_SERVICE.get().expect("payment service not initialized");
+
+    match svc.payment_events(id).await {
+        Ok(events) if events.is_empty() => (StatusCode::NOT_FOUND, "Payment not found").into_response(),
+        Ok(events) => (StatusCode::OK, Json(events)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to list events of payment {}: {}", id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+async fn rebuild_payment_projection(
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let svc = PAYMENT_SERVICE.get().expect("payment service not initialized");
+
+    match svc.rebuild_projection(id).await {
+        Ok(Some(payment)) => (StatusCode::OK, Json(payment)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Payment not found").into_response(),
+        Err(e) => {
+            tracing::error!("Failed to rebuild payment {}: {}", id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+async fn rebuild_payment_projections() -> impl IntoResponse {
+    let svc = PAYMENT_SERVICE.get().expect("payment service not initialized");
+
+    match svc.rebuild_projections().await {
+        Ok(rebuilt) => (StatusCode::OK, Json(serde_json::json!({ "rebuilt": rebuilt }))).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to rebuild payment projections: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+fn authorization_error_response(e: AuthorizationError) -> axum::response::Response {
+    match e {
+        AuthorizationError::NotFound => {
+            (StatusCode::NOT_FOUND, "Authorization not found").into_response()
+        }
+        AuthorizationError::InvalidState(_) => {
+            (StatusCode::CONFLICT, e.to_string()).into_response()
+        }
+        AuthorizationError::Failed(e) => {
+            tracing::error!("Authorization operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)).into_response()
+        }
+    }
+}
+
+async fn create_payment_method(
+    Json(payload): Json<CreatePaymentMethodRequest>,
+) -> impl IntoResponse {
+    let vault = VAULT_STORE.get().expect("vault store not initialized");
+    match vault.create(payload).await {
+        Ok(method) => (StatusCode::CREATED, Json(method)).into_response(),
+        Err(e) => vault_error_response(e),
+    }
+}
+
+async fn list_payment_methods(
+    Query(query): Query<ListPaymentMethodsQuery>,
+) -> impl IntoResponse {
+    let vault = VAULT_STORE.get().expect("vault store not initialized");
+    match vault.list_for_user(&query.user_id).await {
+        Ok(methods) => (StatusCode::OK, Json(methods)).into_response(),
+        Err(e) => vault_error_response(e.into()),
+    }
+}
+
+async fn get_payment_method(
+    Path(method_id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let vault = VAULT_STORE.get().expect("vault store not initialized");
+    match vault.get(method_id).await {
+        Ok(Some(method)) => (StatusCode::OK, Json(method)).into_response(),
+        Ok(None) => vault_error_response(VaultError::NotFound),
+        Err(e) => vault_error_response(e.into()),
+    }
+}
+
+async fn update_payment_method(
+    Path(method_id): Path<uuid::Uuid>,
+    Json(payload): Json<UpdatePaymentMethodRequest>,
+) -> impl IntoResponse {
+    let vault = VAULT_STORE.get().expect("vault store not initialized");
+    match vault.update(method_id, payload).await {
+        Ok(method) => (StatusCode::OK, Json(method)).into_response(),
+        Err(e) => vault_error_response(e),
+    }
+}
+
+async fn delete_payment_method(
+    Path(method_id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let vault = VAULT_STORE.get().expect("vault store not initialized");
+    match vault.delete(method_id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => vault_error_response(e),
+    }
+}
+
+fn vault_error_response(e: VaultError) -> axum::response::Response {
+    match e {
+        VaultError::NotFound => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        VaultError::Invalid(_) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        VaultError::Failed(e) => {
+            tracing::error!("Payment method operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
@@ -194,6 +784,7 @@ async fn shutdown_signal() {
     }
 
     info!("Shutdown signal received");
+    trace_context::shutdown_tracer();
 }
 
 // PayPal handler functions
@@ -258,3 +849,646 @@ async fn get_paypal_payment(
         }
     }
 }
+
+// Stripe handler functions
+async fn create_stripe_session(
+    Json(payload): Json<stripe_handler::CreatePaymentRequest>,
+) -> impl IntoResponse {
+    let handler = STRIPE_HANDLER.get().expect("Stripe handler not initialized");
+
+    match handler.create_checkout_session(payload).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to create Stripe checkout session: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)).into_response()
+        }
+    }
+}
+
+async fn stripe_webhook(
+    headers: HeaderMap,
+    body: String,
+) -> impl IntoResponse {
+    let handler = STRIPE_HANDLER.get().expect("Stripe handler not initialized");
+
+    let signature = headers
+        .get("Stripe-Signature")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+
+    match handler.handle_webhook(&body, signature).await {
+        Ok(_) => (StatusCode::OK, "Webhook processed").into_response(),
+        Err(e) => {
+            tracing::error!("Stripe webhook processing failed: {}", e);
+            (StatusCode::BAD_REQUEST, format!("Error: {}", e)).into_response()
+        }
+    }
+}
+
+async fn get_stripe_payment(
+    Path(payment_id): Path<String>,
+) -> impl IntoResponse {
+    let handler = STRIPE_HANDLER.get().expect("Stripe handler not initialized");
+
+    let payment_uuid = match uuid::Uuid::parse_str(&payment_id) {
+        Ok(uuid) => uuid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid payment ID").into_response(),
+    };
+
+    match handler.get_payment(payment_uuid).await {
+        Ok(Some(payment)) => (StatusCode::OK, Json(payment)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Payment not found").into_response(),
+        Err(e) => {
+            tracing::error!("Failed to get payment: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+// Dispute handler functions
+async fn list_disputes(
+    Query(query): Query<ListDisputesQuery>,
+) -> impl IntoResponse {
+    let service = DISPUTE_SERVICE.get().expect("dispute service not initialized");
+
+    match service.list(&query).await {
+        Ok(disputes) => (StatusCode::OK, Json(disputes)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to list disputes: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+async fn get_dispute(
+    Path(dispute_id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let service = DISPUTE_SERVICE.get().expect("dispute service not initialized");
+
+    match service.get(dispute_id).await {
+        Ok(Some(dispute)) => (StatusCode::OK, Json(dispute)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Dispute not found").into_response(),
+        Err(e) => {
+            tracing::error!("Failed to get dispute: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+async fn submit_dispute_evidence(
+    Path(dispute_id): Path<uuid::Uuid>,
+    Json(payload): Json<SubmitEvidenceRequest>,
+) -> impl IntoResponse {
+    let service = DISPUTE_SERVICE.get().expect("dispute service not initialized");
+
+    match service.submit_evidence(dispute_id, payload).await {
+        Ok(evidence) => (StatusCode::CREATED, Json(evidence)).into_response(),
+        Err(DisputeError::NotFound) => (StatusCode::NOT_FOUND, "Dispute not found").into_response(),
+        Err(e @ DisputeError::Rejected(_)) => (StatusCode::CONFLICT, e.to_string()).into_response(),
+        Err(DisputeError::Failed(e)) => {
+            tracing::error!("Failed to submit dispute evidence: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+// FX handler functions
+async fn list_fx_rates() -> impl IntoResponse {
+    let fx = FX_SERVICE.get().expect("FX service not initialized");
+
+    match fx.list_rates().await {
+        Ok(rates) => (StatusCode::OK, Json(rates)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to list FX rates: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+async fn update_fx_rate(
+    Path(currency): Path<String>,
+    Json(payload): Json<UpdateFxRateRequest>,
+) -> impl IntoResponse {
+    let fx = FX_SERVICE.get().expect("FX service not initialized");
+    let as_of = payload.as_of.unwrap_or_else(chrono::Utc::now);
+
+    match fx.upsert_rate(&currency, payload.rate, "admin", as_of).await {
+        Ok(rate) => (StatusCode::OK, Json(rate)).into_response(),
+        Err(e) => fx_error_response(e),
+    }
+}
+
+async fn fx_quote(
+    Query(query): Query<QuoteQuery>,
+) -> impl IntoResponse {
+    let fx = FX_SERVICE.get().expect("FX service not initialized");
+
+    match fx.convert(query.amount, &query.currency).await {
+        Ok(conversion) => {
+            let quote = FxQuote {
+                conversion,
+                quoted_at: chrono::Utc::now(),
+            };
+            (StatusCode::OK, Json(quote)).into_response()
+        }
+        Err(e) => fx_error_response(e),
+    }
+}
+
+// Subscription handler functions
+async fn create_plan(
+    Json(payload): Json<CreatePlanRequest>,
+) -> impl IntoResponse {
+    let service = SUBSCRIPTION_SERVICE.get().expect("subscription service not initialized");
+
+    match service.create_plan(payload).await {
+        Ok(plan) => (StatusCode::CREATED, Json(plan)).into_response(),
+        Err(e) => subscription_error_response(e),
+    }
+}
+
+async fn list_plans() -> impl IntoResponse {
+    let service = SUBSCRIPTION_SERVICE.get().expect("subscription service not initialized");
+
+    match service.list_plans().await {
+        Ok(plans) => (StatusCode::OK, Json(plans)).into_response(),
+        Err(e) => subscription_error_response(e.into()),
+    }
+}
+
+async fn create_subscription(
+    Json(payload): Json<CreateSubscriptionRequest>,
+) -> impl IntoResponse {
+    let service = SUBSCRIPTION_SERVICE.get().expect("subscription service not initialized");
+
+    match service.create(payload).await {
+        Ok(details) => (StatusCode::CREATED, Json(details)).into_response(),
+        Err(e) => subscription_error_response(e),
+    }
+}
+
+async fn list_subscriptions(
+    Query(query): Query<ListSubscriptionsQuery>,
+) -> impl IntoResponse {
+    let service = SUBSCRIPTION_SERVICE.get().expect("subscription service not initialized");
+
+    match service.list_for_user(&query.user_id).await {
+        Ok(subscriptions) => (StatusCode::OK, Json(subscriptions)).into_response(),
+        Err(e) => subscription_error_response(e.into()),
+    }
+}
+
+async fn get_subscription(
+    Path(subscription_id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let service = SUBSCRIPTION_SERVICE.get().expect("subscription service not initialized");
+
+    match service.get(subscription_id).await {
+        Ok(Some(details)) => (StatusCode::OK, Json(details)).into_response(),
+        Ok(None) => subscription_error_response(SubscriptionError::NotFound),
+        Err(e) => subscription_error_response(e.into()),
+    }
+}
+
+async fn change_subscription_plan(
+    Path(subscription_id): Path<uuid::Uuid>,
+    Json(payload): Json<ChangePlanRequest>,
+) -> impl IntoResponse {
+    let service = SUBSCRIPTION_SERVICE.get().expect("subscription service not initialized");
+
+    match service.change_plan(subscription_id, payload.plan_id).await {
+        Ok(subscription) => (StatusCode::OK, Json(subscription)).into_response(),
+        Err(e) => subscription_error_response(e),
+    }
+}
+
+async fn cancel_subscription(
+    Path(subscription_id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let service = SUBSCRIPTION_SERVICE.get().expect("subscription service not initialized");
+
+    match service.cancel(subscription_id).await {
+        Ok(subscription) => (StatusCode::OK, Json(subscription)).into_response(),
+        Err(e) => subscription_error_response(e),
+    }
+}
+
+fn subscription_error_response(e: SubscriptionError) -> axum::response::Response {
+    match e {
+        SubscriptionError::NotFound => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        SubscriptionError::Invalid(_) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        SubscriptionError::Failed(e) => {
+            tracing::error!("Subscription operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+// Marketplace handler functions
+async fn create_seller(
+    Json(payload): Json<CreateSellerRequest>,
+) -> impl IntoResponse {
+    let marketplace = MARKETPLACE_SERVICE.get().expect("marketplace service not initialized");
+
+    match marketplace.create_seller(payload).await {
+        Ok(seller) => (StatusCode::CREATED, Json(seller)).into_response(),
+        Err(e) => marketplace_error_response(e),
+    }
+}
+
+async fn list_sellers() -> impl IntoResponse {
+    let marketplace = MARKETPLACE_SERVICE.get().expect("marketplace service not initialized");
+
+    match marketplace.list_sellers().await {
+        Ok(sellers) => (StatusCode::OK, Json(sellers)).into_response(),
+        Err(e) => marketplace_error_response(e.into()),
+    }
+}
+
+async fn get_seller(
+    Path(seller_id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let marketplace = MARKETPLACE_SERVICE.get().expect("marketplace service not initialized");
+
+    match marketplace.get_seller(seller_id).await {
+        Ok(seller) => (StatusCode::OK, Json(seller)).into_response(),
+        Err(e) => marketplace_error_response(e),
+    }
+}
+
+async fn update_seller(
+    Path(seller_id): Path<uuid::Uuid>,
+    Json(payload): Json<UpdateSellerRequest>,
+) -> impl IntoResponse {
+    let marketplace = MARKETPLACE_SERVICE.get().expect("marketplace service not initialized");
+
+    match marketplace.update_seller(seller_id, payload).await {
+        Ok(seller) => (StatusCode::OK, Json(seller)).into_response(),
+        Err(e) => marketplace_error_response(e),
+    }
+}
+
+async fn seller_balance(
+    Path(seller_id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let marketplace = MARKETPLACE_SERVICE.get().expect("marketplace service not initialized");
+
+    match marketplace.balance(seller_id).await {
+        Ok(balances) => (StatusCode::OK, Json(balances)).into_response(),
+        Err(e) => marketplace_error_response(e),
+    }
+}
+
+async fn list_payment_splits(
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let marketplace = MARKETPLACE_SERVICE.get().expect("marketplace service not initialized");
+
+    match marketplace.splits_for_payment(id).await {
+        Ok(splits) => (StatusCode::OK, Json(splits)).into_response(),
+        Err(e) => marketplace_error_response(e.into()),
+    }
+}
+
+async fn list_payouts(
+    Query(query): Query<ListPayoutsQuery>,
+) -> impl IntoResponse {
+    let marketplace = MARKETPLACE_SERVICE.get().expect("marketplace service not initialized");
+
+    match marketplace.list_payouts(&query).await {
+        Ok(payouts) => (StatusCode::OK, Json(payouts)).into_response(),
+        Err(e) => marketplace_error_response(e.into()),
+    }
+}
+
+async fn run_payouts() -> impl IntoResponse {
+    let marketplace = MARKETPLACE_SERVICE.get().expect("marketplace service not initialized");
+
+    match marketplace.run_payout_batch().await {
+        Ok(batch) => (StatusCode::OK, Json(batch)).into_response(),
+        Err(e) => marketplace_error_response(e.into()),
+    }
+}
+
+async fn payout_report(
+    Query(query): Query<PayoutReportQuery>,
+) -> impl IntoResponse {
+    let marketplace = MARKETPLACE_SERVICE.get().expect("marketplace service not initialized");
+
+    // Defaults to the last 30 days, inclusive of today
+    let to = query.to.unwrap_or_else(|| chrono::Utc::now().date_naive());
+    let from = query.from.unwrap_or(to - chrono::Duration::days(30));
+    if from > to {
+        return (StatusCode::BAD_REQUEST, "from must not be after to").into_response();
+    }
+
+    match marketplace.payout_report(from, to).await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => marketplace_error_response(e.into()),
+    }
+}
+
+fn marketplace_error_response(e: MarketplaceError) -> axum::response::Response {
+    match e {
+        MarketplaceError::NotFound => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        MarketplaceError::Invalid(_) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        MarketplaceError::Failed(e) => {
+            tracing::error!("Marketplace operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+// Ledger handler functions
+async fn ledger_balances(
+    Query(query): Query<BalanceQuery>,
+) -> impl IntoResponse {
+    let ledger = LEDGER_STORE.get().expect("ledger store not initialized");
+
+    match ledger.balances(&query).await {
+        Ok(balances) => (StatusCode::OK, Json(balances)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to read ledger balances: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+async fn ledger_check() -> impl IntoResponse {
+    let ledger = LEDGER_STORE.get().expect("ledger store not initialized");
+
+    match ledger.check_invariants().await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to check ledger invariants: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+async fn payment_ledger(
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let ledger = LEDGER_STORE.get().expect("ledger store not initialized");
+
+    match ledger.journals_for_payment(id).await {
+        Ok(journals) => (StatusCode::OK, Json(journals)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to read ledger for payment {}: {}", id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+// Reconciliation handler functions
+async fn ingest_settlement_report(
+    Query(query): Query<IngestReportQuery>,
+    body: String,
+) -> impl IntoResponse {
+    let reconciliation = RECONCILIATION_SERVICE.get().expect("reconciliation service not initialized");
+    let source = query.source.as_deref().unwrap_or("upload");
+
+    match reconciliation.ingest(&query.provider, source, &body).await {
+        Ok(report) => (StatusCode::CREATED, Json(report)).into_response(),
+        Err(e) => reconciliation_error_response(e),
+    }
+}
+
+async fn list_reconciliation_runs() -> impl IntoResponse {
+    let reconciliation = RECONCILIATION_SERVICE.get().expect("reconciliation service not initialized");
+
+    match reconciliation.list_runs().await {
+        Ok(runs) => (StatusCode::OK, Json(runs)).into_response(),
+        Err(e) => reconciliation_error_response(e.into()),
+    }
+}
+
+async fn get_reconciliation_run(
+    Path(run_id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let reconciliation = RECONCILIATION_SERVICE.get().expect("reconciliation service not initialized");
+
+    match reconciliation.get_run(run_id).await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => reconciliation_error_response(e),
+    }
+}
+
+async fn list_discrepancies(
+    Query(query): Query<ListDiscrepanciesQuery>,
+) -> impl IntoResponse {
+    let reconciliation = RECONCILIATION_SERVICE.get().expect("reconciliation service not initialized");
+
+    match reconciliation.open_discrepancies(&query).await {
+        Ok(items) => (StatusCode::OK, Json(items)).into_response(),
+        Err(e) => reconciliation_error_response(e.into()),
+    }
+}
+
+fn reconciliation_error_response(e: ReconciliationError) -> axum::response::Response {
+    match e {
+        ReconciliationError::NotFound => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        ReconciliationError::Invalid(_) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        ReconciliationError::Failed(e) => {
+            tracing::error!("Reconciliation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+// Dunning handler functions
+async fn list_payment_retries(
+    Query(query): Query<ListRetriesQuery>,
+) -> impl IntoResponse {
+    let dunning = DUNNING_SERVICE.get().expect("dunning service not initialized");
+
+    match dunning.list(&query).await {
+        Ok(retries) => (StatusCode::OK, Json(retries)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to list payment retries: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+// Dead-letter handler functions
+async fn list_dead_letters(
+    Query(query): Query<ListDeadLettersQuery>,
+) -> impl IntoResponse {
+    let dead_letters = DEAD_LETTER_SERVICE.get().expect("dead letter service not initialized");
+
+    match dead_letters.list(&query).await {
+        Ok(letters) => (StatusCode::OK, Json(letters)).into_response(),
+        Err(e) => dead_letter_error_response(e),
+    }
+}
+
+async fn redrive_dead_letter(
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let dead_letters = DEAD_LETTER_SERVICE.get().expect("dead letter service not initialized");
+
+    match dead_letters.redrive(id).await {
+        Ok(letter) => (StatusCode::OK, Json(letter)).into_response(),
+        Err(e) => dead_letter_error_response(e),
+    }
+}
+
+fn dead_letter_error_response(e: DeadLetterError) -> axum::response::Response {
+    match e {
+        DeadLetterError::NotFound => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        DeadLetterError::Invalid(_) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        DeadLetterError::Failed(e) => {
+            tracing::error!("Dead letter operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+// Replay handler functions
+async fn start_replay(
+    Json(request): Json<StartReplayRequest>,
+) -> impl IntoResponse {
+    let replays = REPLAY_SERVICE.get().expect("replay service not initialized");
+
+    match replays.start(request).await {
+        Ok(replay) => (StatusCode::ACCEPTED, Json(replay)).into_response(),
+        Err(e) => replay_error_response(e),
+    }
+}
+
+async fn list_replays() -> impl IntoResponse {
+    let replays = REPLAY_SERVICE.get().expect("replay service not initialized");
+
+    match replays.list().await {
+        Ok(list) => (StatusCode::OK, Json(list)).into_response(),
+        Err(e) => replay_error_response(e),
+    }
+}
+
+async fn get_replay(
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let replays = REPLAY_SERVICE.get().expect("replay service not initialized");
+
+    match replays.report(id).await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => replay_error_response(e),
+    }
+}
+
+fn replay_error_response(e: ReplayError) -> axum::response::Response {
+    match e {
+        ReplayError::NotFound => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        ReplayError::Invalid(_) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        ReplayError::Failed(e) => {
+            tracing::error!("Replay operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+// Schema registry handler functions
+async fn get_schema_by_id(
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    let registry = SCHEMA_REGISTRY.get().expect("schema registry not initialized");
+
+    match registry.by_id(id).await {
+        Ok(schema) => (StatusCode::OK, Json(schema)).into_response(),
+        Err(e) => schema_error_response(e),
+    }
+}
+
+async fn list_schema_subjects() -> impl IntoResponse {
+    let registry = SCHEMA_REGISTRY.get().expect("schema registry not initialized");
+
+    match registry.subjects().await {
+        Ok(subjects) => (StatusCode::OK, Json(subjects)).into_response(),
+        Err(e) => schema_error_response(e),
+    }
+}
+
+async fn list_schema_versions(
+    Path(subject): Path<String>,
+) -> impl IntoResponse {
+    let registry = SCHEMA_REGISTRY.get().expect("schema registry not initialized");
+
+    match registry.versions(&subject).await {
+        Ok(versions) => (StatusCode::OK, Json(versions)).into_response(),
+        Err(e) => schema_error_response(e),
+    }
+}
+
+async fn get_schema_version(
+    Path((subject, version)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let registry = SCHEMA_REGISTRY.get().expect("schema registry not initialized");
+
+    match registry.version(&subject, &version).await {
+        Ok(schema) => (StatusCode::OK, Json(schema)).into_response(),
+        Err(e) => schema_error_response(e),
+    }
+}
+
+fn schema_error_response(e: SchemaError) -> axum::response::Response {
+    match e {
+        SchemaError::NotFound => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        SchemaError::Invalid(_) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        SchemaError::Incompatible { .. } => (StatusCode::CONFLICT, e.to_string()).into_response(),
+        SchemaError::Failed(e) => {
+            tracing::error!("Schema registry operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+// Fee handler functions
+async fn list_payment_fees(
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let fees = FEE_STORE.get().expect("fee store not initialized");
+
+    match fees.list_for_payment(id).await {
+        Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to list payment fees: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+async fn fee_summary(
+    Query(query): Query<FeeSummaryQuery>,
+) -> impl IntoResponse {
+    let fees = FEE_STORE.get().expect("fee store not initialized");
+
+    // Defaults to the last 30 days, inclusive of today
+    let to = query.to.unwrap_or_else(|| chrono::Utc::now().date_naive());
+    let from = query.from.unwrap_or(to - chrono::Duration::days(30));
+    if from > to {
+        return (StatusCode::BAD_REQUEST, "from must not be after to").into_response();
+    }
+
+    match fees.summary(from, to).await {
+        Ok(summary) => (StatusCode::OK, Json(summary)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to summarize payment fees: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+fn fx_error_response(e: FxError) -> axum::response::Response {
+    match e {
+        FxError::Failed(e) => {
+            tracing::error!("FX operation failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+        FxError::UnknownCurrency(_) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        FxError::StaleRate { .. } => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response(),
+        FxError::InvalidCurrency(_) | FxError::InvalidRate(_) => {
+            (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+        }
+    }
+}
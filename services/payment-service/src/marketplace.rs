@@ -0,0 +1,1129 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::ledger::{self, Journal};
+use crate::metrics::PAYMENT_METRICS;
+use crate::models::{FxConversion, Money, SellerSplit, SplitInstructions};
+
+pub const SELLER_ACTIVE: &str = "active";
+pub const SELLER_SUSPENDED: &str = "suspended";
+
+pub const SPLIT_PENDING: &str = "pending";
+pub const SPLIT_CAPTURED: &str = "captured";
+pub const SPLIT_CANCELED: &str = "canceled";
+
+pub const PAYOUT_PENDING: &str = "pending";
+pub const PAYOUT_PAID: &str = "paid";
+pub const PAYOUT_FAILED: &str = "failed";
+
+/// A payout still pending this long after it was created was left behind by a
+/// batch that died between claiming its splits and recording the transfer
+const STALE_PAYOUT_AFTER_MINUTES: i64 = 15;
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Seller {
+    pub id: Uuid,
+    pub name: String,
+    pub email: String,
+    /// Bank account or wallet reference at the payout provider
+    pub payout_destination: String,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSellerRequest {
+    pub name: String,
+    pub email: String,
+    pub payout_destination: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateSellerRequest {
+    pub payout_destination: Option<String>,
+    pub status: Option<String>,
+}
+
+/// One seller's share of a payment.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PaymentSplit {
+    pub id: Uuid,
+    pub payment_id: Uuid,
+    pub seller_id: Uuid,
+    pub gross_amount: f64,
+    pub platform_fee: f64,
+    pub net_amount: f64,
+    pub currency: String,
+    pub status: String,
+    pub captured_at: Option<DateTime<Utc>>,
+    pub payout_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct SellerBalance {
+    pub currency: String,
+    /// Captured but still inside the hold period
+    pub on_hold: f64,
+    /// Past the hold period and waiting for the next payout
+    pub available: f64,
+    /// Included in a payout that is being sent
+    pub in_transit: f64,
+    pub paid_out: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Payout {
+    pub id: Uuid,
+    pub batch_id: Uuid,
+    pub seller_id: Uuid,
+    pub amount: f64,
+    pub currency: String,
+    pub split_count: i32,
+    pub status: String,
+    pub provider: String,
+    pub provider_reference: Option<String>,
+    pub failure_reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PayoutBatch {
+    pub id: Uuid,
+    /// Payouts the provider accepted
+    pub payouts: i32,
+    pub failed: i32,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListPayoutsQuery {
+    pub seller_id: Option<Uuid>,
+    pub batch_id: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PayoutReportQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+/// Sales and payouts per seller and currency over a date range.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct PayoutReportRow {
+    pub seller_id: Uuid,
+    pub currency: String,
+    pub gross: f64,
+    pub platform_fee: f64,
+    pub net: f64,
+    pub paid_out: f64,
+    pub payouts: i64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MarketplaceError {
+    #[error("Not found")]
+    NotFound,
+    #[error("Invalid marketplace request: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for MarketplaceError {
+    fn from(e: sqlx::Error) -> Self {
+        MarketplaceError::Failed(e.into())
+    }
+}
+
+fn round_cents(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+/// A seller's share after the platform's commission.
+#[derive(Debug, Clone, PartialEq)]
+struct Allocation {
+    seller_id: Uuid,
+    gross: f64,
+    platform_fee: f64,
+    net: f64,
+}
+
+/// Works out each seller's share of `total`. Whatever is not allocated to a
+/// seller stays with the platform along with its commission.
+fn allocate(total: f64, instructions: &SplitInstructions) -> Result<Vec<Allocation>, String> {
+    if instructions.sellers.is_empty() {
+        return Err("at least one seller split is required".to_string());
+    }
+    if !(0.0..=100.0).contains(&instructions.platform_fee_percent) {
+        return Err("platform_fee_percent must be between 0 and 100".to_string());
+    }
+
+    let mut allocations: Vec<Allocation> = Vec::with_capacity(instructions.sellers.len());
+    for split in &instructions.sellers {
+        if allocations.iter().any(|a| a.seller_id == split.seller_id) {
+            return Err(format!("seller {} appears more than once", split.seller_id));
+        }
+
+        let gross = match (split.amount, split.percent) {
+            (Some(amount), None) if amount > 0.0 => round_cents(amount),
+            (None, Some(percent)) if percent > 0.0 && percent <= 100.0 => {
+                round_cents(total * percent / 100.0)
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                return Err(format!("share for seller {} is out of range", split.seller_id))
+            }
+            _ => {
+                return Err(format!(
+                    "seller {} needs exactly one of amount or percent",
+                    split.seller_id
+                ))
+            }
+        };
+        let platform_fee = round_cents(gross * instructions.platform_fee_percent / 100.0);
+
+        allocations.push(Allocation {
+            seller_id: split.seller_id,
+            gross,
+            platform_fee,
+            net: round_cents(gross - platform_fee),
+        });
+    }
+
+    let allocated: f64 = allocations.iter().map(|a| a.gross).sum();
+    if round_cents(allocated) > round_cents(total) {
+        return Err(format!(
+            "seller shares {:.2} exceed the payment amount {:.2}",
+            allocated, total
+        ));
+    }
+
+    Ok(allocations)
+}
+
+/// Seller amounts are given in the currency the customer pays in; converts
+/// them at the payment's rate. Percentages are unaffected.
+fn in_settlement(instructions: &SplitInstructions, rate: f64) -> SplitInstructions {
+    SplitInstructions {
+        sellers: instructions
+            .sellers
+            .iter()
+            .map(|split| SellerSplit {
+                amount: split.amount.map(|amount| round_cents(amount * rate)),
+                ..split.clone()
+            })
+            .collect(),
+        platform_fee_percent: instructions.platform_fee_percent,
+    }
+}
+
+/// Split bookkeeping for the payment paths: splits are written before the
+/// gateway is called and follow the payment through capture or release.
+#[derive(Debug, Clone)]
+pub struct SplitStore {
+    pool: PgPool,
+}
+
+impl SplitStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Validates the instructions against the payment amount and stores a
+    /// pending split per seller. Splits are kept in the settlement currency,
+    /// which the capture is booked and sellers are paid in.
+    pub async fn prepare(
+        &self,
+        payment_id: Uuid,
+        conversion: &FxConversion,
+        instructions: &SplitInstructions,
+    ) -> Result<Vec<PaymentSplit>, MarketplaceError> {
+        let amount = &conversion.settlement;
+        let allocations = allocate(amount.amount, &in_settlement(instructions, conversion.rate))
+            .map_err(MarketplaceError::Invalid)?;
+        let seller_ids: Vec<Uuid> = allocations.iter().map(|a| a.seller_id).collect();
+
+        let active = sqlx::query_scalar::<_, i64>(
+            r#"
+            SELECT COUNT(*) FROM sellers WHERE id = ANY($1) AND status = 'active'
+            "#,
+        )
+        .bind(&seller_ids)
+        .fetch_one(&self.pool)
+        .await?;
+
+        if active != seller_ids.len() as i64 {
+            return Err(MarketplaceError::Invalid(
+                "every split must name an active seller".to_string(),
+            ));
+        }
+
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+        let mut splits = Vec::with_capacity(allocations.len());
+
+        for allocation in allocations {
+            let split = sqlx::query_as::<_, PaymentSplit>(
+                r#"
+                INSERT INTO payment_splits
+                    (id, payment_id, seller_id, gross_amount, platform_fee, net_amount, currency,
+                     status, created_at, updated_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9)
+                RETURNING *
+                "#,
+            )
+            .bind(Uuid::new_v4())
+            .bind(payment_id)
+            .bind(allocation.seller_id)
+            .bind(allocation.gross)
+            .bind(allocation.platform_fee)
+            .bind(allocation.net)
+            .bind(&amount.currency)
+            .bind(SPLIT_PENDING)
+            .bind(now)
+            .fetch_one(&mut *tx)
+            .await?;
+
+            splits.push(split);
+        }
+
+        tx.commit().await?;
+        Ok(splits)
+    }
+
+    pub async fn list_for_payment(&self, payment_id: Uuid) -> Result<Vec<PaymentSplit>> {
+        let splits = sqlx::query_as::<_, PaymentSplit>(
+            r#"
+            SELECT * FROM payment_splits WHERE payment_id = $1 ORDER BY created_at
+            "#,
+        )
+        .bind(payment_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(splits)
+    }
+}
+
+/// Credits the sellers in the caller's transaction, which also records and
+/// books the capture; the hold period starts now.
+pub async fn capture_splits(tx: &mut Transaction<'_, Postgres>, payment_id: Uuid) -> Result<u64> {
+    let now = Utc::now();
+
+    let captured = sqlx::query_as::<_, PaymentSplit>(
+        r#"
+        UPDATE payment_splits SET status = $1, captured_at = $2, updated_at = $2
+        WHERE payment_id = $3 AND status = 'pending'
+        RETURNING *
+        "#,
+    )
+    .bind(SPLIT_CAPTURED)
+    .bind(now)
+    .bind(payment_id)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    for split in &captured {
+        let net = Money::new(split.net_amount, &split.currency);
+        ledger::post(
+            tx,
+            &Journal::seller_share(split.id, split.payment_id, split.seller_id, &net),
+        )
+        .await?;
+    }
+
+    Ok(captured.len() as u64)
+}
+
+/// Drops the splits of a payment that was declined, voided or expired, in the
+/// transaction that records why.
+pub async fn cancel_splits(tx: &mut Transaction<'_, Postgres>, payment_id: Uuid) -> Result<u64> {
+    let result = sqlx::query(
+        r#"
+        UPDATE payment_splits SET status = $1, updated_at = $2
+        WHERE payment_id = $3 AND status = 'pending'
+        "#,
+    )
+    .bind(SPLIT_CANCELED)
+    .bind(Utc::now())
+    .bind(payment_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Simulated payout rail. Destinations starting with `fail_` are rejected so
+/// failed payouts can be exercised.
+#[derive(Debug, Clone, Default)]
+pub struct SandboxPayoutProvider;
+
+impl SandboxPayoutProvider {
+    pub const NAME: &'static str = "sandbox";
+
+    /// The payout id is the transfer's idempotency key, so sending a payout
+    /// again returns the original transfer instead of paying twice.
+    async fn send(&self, seller: &Seller, payout: &Payout) -> Result<String> {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        if seller.payout_destination.starts_with("fail_") {
+            anyhow::bail!("destination {} rejected the transfer", seller.payout_destination);
+        }
+
+        let reference = format!("PO-{}", payout.id);
+        info!(
+            "Payout provider sent {} {} to seller {}: {}",
+            payout.amount, payout.currency, seller.id, reference
+        );
+
+        Ok(reference)
+    }
+}
+
+#[derive(Debug)]
+pub struct MarketplaceService {
+    pool: PgPool,
+    splits: SplitStore,
+    provider: SandboxPayoutProvider,
+    hold_period: chrono::Duration,
+}
+
+impl MarketplaceService {
+    pub fn new(pool: PgPool, hold_period: chrono::Duration) -> Self {
+        Self {
+            splits: SplitStore::new(pool.clone()),
+            pool,
+            provider: SandboxPayoutProvider,
+            hold_period,
+        }
+    }
+
+    pub async fn create_seller(&self, req: CreateSellerRequest) -> Result<Seller, MarketplaceError> {
+        if req.name.trim().is_empty() {
+            return Err(MarketplaceError::Invalid("name is required".to_string()));
+        }
+        if !req.email.contains('@') {
+            return Err(MarketplaceError::Invalid("email is invalid".to_string()));
+        }
+        if req.payout_destination.trim().is_empty() {
+            return Err(MarketplaceError::Invalid("payout_destination is required".to_string()));
+        }
+
+        let now = Utc::now();
+        let seller = sqlx::query_as::<_, Seller>(
+            r#"
+            INSERT INTO sellers (id, name, email, payout_destination, status, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $6)
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(req.name.trim())
+        .bind(req.email.trim())
+        .bind(req.payout_destination.trim())
+        .bind(SELLER_ACTIVE)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await?;
+
+        info!("Created seller {} ({})", seller.id, seller.name);
+        Ok(seller)
+    }
+
+    pub async fn list_sellers(&self) -> Result<Vec<Seller>> {
+        let sellers = sqlx::query_as::<_, Seller>(
+            r#"
+            SELECT * FROM sellers ORDER BY created_at
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sellers)
+    }
+
+    pub async fn get_seller(&self, id: Uuid) -> Result<Seller, MarketplaceError> {
+        sqlx::query_as::<_, Seller>(
+            r#"
+            SELECT * FROM sellers WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(MarketplaceError::NotFound)
+    }
+
+    /// Suspended sellers keep their balance but receive no new splits or payouts.
+    pub async fn update_seller(
+        &self,
+        id: Uuid,
+        req: UpdateSellerRequest,
+    ) -> Result<Seller, MarketplaceError> {
+        if let Some(status) = &req.status {
+            if status != SELLER_ACTIVE && status != SELLER_SUSPENDED {
+                return Err(MarketplaceError::Invalid(format!("unknown status '{}'", status)));
+            }
+        }
+        if matches!(&req.payout_destination, Some(d) if d.trim().is_empty()) {
+            return Err(MarketplaceError::Invalid("payout_destination must not be empty".to_string()));
+        }
+
+        sqlx::query_as::<_, Seller>(
+            r#"
+            UPDATE sellers
+            SET payout_destination = COALESCE($1, payout_destination),
+                status = COALESCE($2, status),
+                updated_at = $3
+            WHERE id = $4
+            RETURNING *
+            "#,
+        )
+        .bind(req.payout_destination.as_deref().map(str::trim))
+        .bind(&req.status)
+        .bind(Utc::now())
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(MarketplaceError::NotFound)
+    }
+
+    pub async fn balance(&self, seller_id: Uuid) -> Result<Vec<SellerBalance>, MarketplaceError> {
+        self.get_seller(seller_id).await?;
+        let available_before = Utc::now() - self.hold_period;
+
+        let balances = sqlx::query_as::<_, SellerBalance>(
+            r#"
+            SELECT s.currency,
+                   COALESCE(SUM(s.net_amount) FILTER (
+                       WHERE s.payout_id IS NULL AND s.captured_at > $2), 0) AS on_hold,
+                   COALESCE(SUM(s.net_amount) FILTER (
+                       WHERE s.payout_id IS NULL AND s.captured_at <= $2), 0) AS available,
+                   COALESCE(SUM(s.net_amount) FILTER (WHERE p.status = 'pending'), 0) AS in_transit,
+                   COALESCE(SUM(s.net_amount) FILTER (WHERE p.status = 'paid'), 0) AS paid_out
+            FROM payment_splits s
+            LEFT JOIN seller_payouts p ON p.id = s.payout_id
+            WHERE s.seller_id = $1 AND s.status = 'captured'
+            GROUP BY s.currency
+            ORDER BY s.currency
+            "#,
+        )
+        .bind(seller_id)
+        .bind(available_before)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(balances)
+    }
+
+    pub async fn splits_for_payment(&self, payment_id: Uuid) -> Result<Vec<PaymentSplit>> {
+        self.splits.list_for_payment(payment_id).await
+    }
+
+    /// Pays every active seller its available balance, one payout per currency.
+    /// Splits of a failed payout are released for the next batch. Payouts an
+    /// earlier batch left pending are resent first.
+    pub async fn run_payout_batch(&self) -> Result<PayoutBatch> {
+        self.recover_stale_payouts().await?;
+
+        let available_before = Utc::now() - self.hold_period;
+
+        let batch = sqlx::query_as::<_, PayoutBatch>(
+            r#"
+            INSERT INTO payout_batches (id, payouts, failed, started_at)
+            VALUES ($1, 0, 0, $2)
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await
+        .context("Failed to open payout batch")?;
+
+        let due = sqlx::query_as::<_, (Uuid, String)>(
+            r#"
+            SELECT DISTINCT s.seller_id, s.currency
+            FROM payment_splits s
+            JOIN sellers ON sellers.id = s.seller_id
+            WHERE s.status = 'captured' AND s.payout_id IS NULL AND s.captured_at <= $1
+              AND sellers.status = 'active'
+            "#,
+        )
+        .bind(available_before)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let (mut payouts, mut failed) = (0, 0);
+        for (seller_id, currency) in due {
+            match self.pay_out(batch.id, seller_id, &currency, available_before).await {
+                Ok(Some(payout)) if payout.status == PAYOUT_PAID => payouts += 1,
+                Ok(Some(_)) => failed += 1,
+                Ok(None) => {}
+                Err(e) => {
+                    failed += 1;
+                    warn!("Failed to pay out seller {} in {}: {}", seller_id, currency, e);
+                }
+            }
+        }
+
+        let batch = sqlx::query_as::<_, PayoutBatch>(
+            r#"
+            UPDATE payout_batches SET payouts = $1, failed = $2, completed_at = $3
+            WHERE id = $4
+            RETURNING *
+            "#,
+        )
+        .bind(payouts)
+        .bind(failed)
+        .bind(Utc::now())
+        .bind(batch.id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(batch)
+    }
+
+    /// Claims the seller's available splits into a payout and sends it. Returns
+    /// None when another batch already claimed them.
+    async fn pay_out(
+        &self,
+        batch_id: Uuid,
+        seller_id: Uuid,
+        currency: &str,
+        available_before: DateTime<Utc>,
+    ) -> Result<Option<Payout>> {
+        let seller = sqlx::query_as::<_, Seller>(
+            r#"
+            SELECT * FROM sellers WHERE id = $1
+            "#,
+        )
+        .bind(seller_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let payout_id = Uuid::new_v4();
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO seller_payouts
+                (id, batch_id, seller_id, amount, currency, split_count, status, provider, created_at)
+            VALUES ($1, $2, $3, 0, $4, 0, $5, $6, $7)
+            "#,
+        )
+        .bind(payout_id)
+        .bind(batch_id)
+        .bind(seller_id)
+        .bind(currency)
+        .bind(PAYOUT_PENDING)
+        .bind(SandboxPayoutProvider::NAME)
+        .bind(Utc::now())
+        .execute(&mut *tx)
+        .await?;
+
+        let claimed = sqlx::query_scalar::<_, f64>(
+            r#"
+            UPDATE payment_splits SET payout_id = $1, updated_at = $2
+            WHERE id IN (
+                SELECT id FROM payment_splits
+                WHERE seller_id = $3 AND currency = $4 AND status = 'captured'
+                  AND payout_id IS NULL AND captured_at <= $5
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING net_amount
+            "#,
+        )
+        .bind(payout_id)
+        .bind(Utc::now())
+        .bind(seller_id)
+        .bind(currency)
+        .bind(available_before)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        if claimed.is_empty() {
+            tx.rollback().await?;
+            return Ok(None);
+        }
+
+        let payout = sqlx::query_as::<_, Payout>(
+            r#"
+            UPDATE seller_payouts SET amount = $1, split_count = $2 WHERE id = $3
+            RETURNING *
+            "#,
+        )
+        .bind(round_cents(claimed.iter().sum()))
+        .bind(claimed.len() as i32)
+        .bind(payout_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.send_payout(&seller, &payout).await.map(Some)
+    }
+
+    /// Resends payouts whose batch died before recording the provider's answer.
+    /// The provider deduplicates on the payout id, so a transfer that did go
+    /// out is only recorded, not paid again.
+    async fn recover_stale_payouts(&self) -> Result<()> {
+        let stale_before = Utc::now() - chrono::Duration::minutes(STALE_PAYOUT_AFTER_MINUTES);
+
+        let stale = sqlx::query_as::<_, Payout>(
+            r#"
+            SELECT * FROM seller_payouts
+            WHERE status = $1 AND created_at <= $2
+            ORDER BY created_at
+            "#,
+        )
+        .bind(PAYOUT_PENDING)
+        .bind(stale_before)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to load stale payouts")?;
+
+        for payout in stale {
+            warn!(
+                "Payout {} to seller {} has been pending since {}, resending",
+                payout.id, payout.seller_id, payout.created_at
+            );
+
+            let seller = sqlx::query_as::<_, Seller>(
+                r#"
+                SELECT * FROM sellers WHERE id = $1
+                "#,
+            )
+            .bind(payout.seller_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+            if let Err(e) = self.send_payout(&seller, &payout).await {
+                warn!("Failed to recover payout {}: {}", payout.id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a claimed payout and records the provider's answer.
+    async fn send_payout(&self, seller: &Seller, payout: &Payout) -> Result<Payout> {
+        match self.provider.send(seller, payout).await {
+            Ok(reference) => {
+                PAYMENT_METRICS.payouts_sent.inc();
+                self.complete_payout(payout, &reference).await
+            }
+            Err(e) => {
+                PAYMENT_METRICS.payouts_failed.inc();
+                warn!("Payout {} to seller {} failed: {}", payout.id, seller.id, e);
+                self.fail_payout(payout.id, &e.to_string()).await
+            }
+        }
+    }
+
+    /// Marks the payout paid and books it against the seller's payable balance.
+    /// A payout another run already settled is returned as it is.
+    async fn complete_payout(&self, payout: &Payout, reference: &str) -> Result<Payout> {
+        let mut tx = self.pool.begin().await?;
+
+        let paid = sqlx::query_as::<_, Payout>(
+            r#"
+            UPDATE seller_payouts
+            SET status = $1, provider_reference = $2, completed_at = $3
+            WHERE id = $4 AND status = $5
+            RETURNING *
+            "#,
+        )
+        .bind(PAYOUT_PAID)
+        .bind(reference)
+        .bind(Utc::now())
+        .bind(payout.id)
+        .bind(PAYOUT_PENDING)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(paid) = paid else {
+            tx.rollback().await?;
+            return self.get_payout(payout.id).await;
+        };
+
+        let amount = Money::new(paid.amount, &paid.currency);
+        ledger::post(
+            &mut tx,
+            &Journal::payout(&paid.provider, paid.id, paid.seller_id, &amount),
+        )
+        .await?;
+
+        tx.commit().await?;
+        Ok(paid)
+    }
+
+    async fn fail_payout(&self, payout_id: Uuid, reason: &str) -> Result<Payout> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        let payout = sqlx::query_as::<_, Payout>(
+            r#"
+            UPDATE seller_payouts SET status = $1, failure_reason = $2, completed_at = $3
+            WHERE id = $4 AND status = $5
+            RETURNING *
+            "#,
+        )
+        .bind(PAYOUT_FAILED)
+        .bind(reason)
+        .bind(now)
+        .bind(payout_id)
+        .bind(PAYOUT_PENDING)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(payout) = payout else {
+            tx.rollback().await?;
+            return self.get_payout(payout_id).await;
+        };
+
+        sqlx::query(
+            r#"
+            UPDATE payment_splits SET payout_id = NULL, updated_at = $1 WHERE payout_id = $2
+            "#,
+        )
+        .bind(now)
+        .bind(payout_id)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(payout)
+    }
+
+    async fn get_payout(&self, payout_id: Uuid) -> Result<Payout> {
+        let payout = sqlx::query_as::<_, Payout>(
+            r#"
+            SELECT * FROM seller_payouts WHERE id = $1
+            "#,
+        )
+        .bind(payout_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(payout)
+    }
+
+    pub async fn list_payouts(&self, query: &ListPayoutsQuery) -> Result<Vec<Payout>> {
+        let payouts = sqlx::query_as::<_, Payout>(
+            r#"
+            SELECT * FROM seller_payouts
+            WHERE ($1::UUID IS NULL OR seller_id = $1)
+              AND ($2::UUID IS NULL OR batch_id = $2)
+            ORDER BY created_at DESC
+            LIMIT 200
+            "#,
+        )
+        .bind(query.seller_id)
+        .bind(query.batch_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(payouts)
+    }
+
+    /// Seller sales captured and payouts paid between two UTC days, inclusive.
+    pub async fn payout_report(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<PayoutReportRow>> {
+        let rows = sqlx::query_as::<_, PayoutReportRow>(
+            r#"
+            WITH sales AS (
+                SELECT seller_id, currency,
+                       SUM(gross_amount) AS gross,
+                       SUM(platform_fee) AS platform_fee,
+                       SUM(net_amount) AS net
+                FROM payment_splits
+                WHERE status = 'captured'
+                  AND (captured_at AT TIME ZONE 'UTC')::DATE BETWEEN $1 AND $2
+                GROUP BY 1, 2
+            ),
+            paid AS (
+                SELECT seller_id, currency, SUM(amount) AS paid_out, COUNT(*) AS payouts
+                FROM seller_payouts
+                WHERE status = 'paid'
+                  AND (completed_at AT TIME ZONE 'UTC')::DATE BETWEEN $1 AND $2
+                GROUP BY 1, 2
+            )
+            SELECT COALESCE(sales.seller_id, paid.seller_id) AS seller_id,
+                   COALESCE(sales.currency, paid.currency) AS currency,
+                   COALESCE(sales.gross, 0) AS gross,
+                   COALESCE(sales.platform_fee, 0) AS platform_fee,
+                   COALESCE(sales.net, 0) AS net,
+                   COALESCE(paid.paid_out, 0) AS paid_out,
+                   COALESCE(paid.payouts, 0) AS payouts
+            FROM sales
+            FULL OUTER JOIN paid
+              ON paid.seller_id = sales.seller_id AND paid.currency = sales.currency
+            ORDER BY 1, 2
+            "#,
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+}
+
+/// Periodically pays sellers the balance that has cleared the hold period.
+pub async fn run_payout_scheduler(service: Arc<MarketplaceService>, interval: Duration) {
+    info!(
+        "Seller payout scheduler started, interval {:?}, hold period {}",
+        interval, service.hold_period
+    );
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        match service.run_payout_batch().await {
+            Ok(PayoutBatch { payouts: 0, failed: 0, .. }) => {}
+            Ok(batch) => info!(
+                "Payout batch {}: {} payouts, {} failed",
+                batch.id, batch.payouts, batch.failed
+            ),
+            Err(e) => error!("Payout batch failed: {}", e),
+        }
+    }
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS sellers (
+            id UUID PRIMARY KEY,
+            name VARCHAR(255) NOT NULL,
+            email VARCHAR(255) NOT NULL,
+            payout_destination VARCHAR(255) NOT NULL,
+            status VARCHAR(20) NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create sellers table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payout_batches (
+            id UUID PRIMARY KEY,
+            payouts INTEGER NOT NULL,
+            failed INTEGER NOT NULL,
+            started_at TIMESTAMPTZ NOT NULL,
+            completed_at TIMESTAMPTZ
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payout_batches table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS seller_payouts (
+            id UUID PRIMARY KEY,
+            batch_id UUID NOT NULL REFERENCES payout_batches(id),
+            seller_id UUID NOT NULL REFERENCES sellers(id),
+            amount DOUBLE PRECISION NOT NULL,
+            currency VARCHAR(3) NOT NULL,
+            split_count INTEGER NOT NULL,
+            status VARCHAR(20) NOT NULL,
+            provider VARCHAR(50) NOT NULL,
+            provider_reference VARCHAR(255),
+            failure_reason TEXT,
+            created_at TIMESTAMPTZ NOT NULL,
+            completed_at TIMESTAMPTZ
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create seller_payouts table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payment_splits (
+            id UUID PRIMARY KEY,
+            payment_id UUID NOT NULL,
+            seller_id UUID NOT NULL REFERENCES sellers(id),
+            gross_amount DOUBLE PRECISION NOT NULL,
+            platform_fee DOUBLE PRECISION NOT NULL,
+            net_amount DOUBLE PRECISION NOT NULL,
+            currency VARCHAR(3) NOT NULL,
+            status VARCHAR(20) NOT NULL,
+            captured_at TIMESTAMPTZ,
+            payout_id UUID REFERENCES seller_payouts(id),
+            created_at TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payment_splits table")?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_payment_splits_payment_id ON payment_splits (payment_id)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_payment_splits_seller ON payment_splits (seller_id, status, payout_id)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seller(n: u128) -> Uuid {
+        Uuid::from_u128(n)
+    }
+
+    fn by_amount(n: u128, amount: f64) -> SellerSplit {
+        SellerSplit {
+            seller_id: seller(n),
+            amount: Some(amount),
+            percent: None,
+        }
+    }
+
+    fn by_percent(n: u128, percent: f64) -> SellerSplit {
+        SellerSplit {
+            seller_id: seller(n),
+            amount: None,
+            percent: Some(percent),
+        }
+    }
+
+    fn instructions(sellers: Vec<SellerSplit>, platform_fee_percent: f64) -> SplitInstructions {
+        SplitInstructions {
+            sellers,
+            platform_fee_percent,
+        }
+    }
+
+    #[test]
+    fn rounds_percent_shares_and_fees_to_cents() {
+        let allocations = allocate(10.0, &instructions(vec![by_percent(1, 100.0 / 3.0)], 12.5)).unwrap();
+
+        assert_eq!(
+            allocations,
+            vec![Allocation {
+                seller_id: seller(1),
+                gross: 3.33,
+                platform_fee: 0.42,
+                net: 2.91,
+            }]
+        );
+    }
+
+    #[test]
+    fn rounds_amount_shares_to_cents() {
+        let allocations = allocate(50.0, &instructions(vec![by_amount(1, 12.3456)], 10.0)).unwrap();
+
+        assert_eq!(allocations[0].gross, 12.35);
+        assert_eq!(allocations[0].platform_fee, 1.24);
+        assert_eq!(allocations[0].net, 11.11);
+    }
+
+    #[test]
+    fn mixes_amount_and_percent_shares() {
+        let splits = vec![by_amount(1, 25.0), by_percent(2, 50.0)];
+        let allocations = allocate(80.0, &instructions(splits, 0.0)).unwrap();
+
+        let gross: Vec<f64> = allocations.iter().map(|a| a.gross).collect();
+        assert_eq!(gross, vec![25.0, 40.0]);
+        assert!(allocations.iter().all(|a| a.platform_fee == 0.0 && a.net == a.gross));
+    }
+
+    #[test]
+    fn allows_shares_that_round_to_the_total() {
+        let thirds = vec![
+            by_percent(1, 100.0 / 3.0),
+            by_percent(2, 100.0 / 3.0),
+            by_percent(3, 100.0 / 3.0),
+        ];
+        assert!(allocate(100.0, &instructions(thirds, 5.0)).is_ok());
+
+        let halves = vec![by_amount(1, 50.004), by_amount(2, 50.0)];
+        assert!(allocate(100.0, &instructions(halves, 5.0)).is_ok());
+    }
+
+    #[test]
+    fn rejects_over_allocation() {
+        let percents = vec![by_percent(1, 60.0), by_percent(2, 50.0)];
+        assert!(allocate(100.0, &instructions(percents, 0.0)).is_err());
+
+        let amounts = vec![by_amount(1, 50.01), by_amount(2, 50.0)];
+        assert!(allocate(100.0, &instructions(amounts, 0.0)).is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_sellers() {
+        let splits = vec![by_amount(1, 10.0), by_percent(1, 10.0)];
+        let error = allocate(100.0, &instructions(splits, 0.0)).unwrap_err();
+        assert!(error.contains("more than once"), "{}", error);
+    }
+
+    #[test]
+    fn converts_seller_amounts_to_the_settlement_currency() {
+        let splits = instructions(vec![by_amount(1, 10.0), by_percent(2, 50.0)], 5.0);
+
+        let converted = in_settlement(&splits, 1.0857);
+
+        assert_eq!(converted.sellers[0].amount, Some(10.86));
+        assert_eq!(converted.sellers[1].amount, None);
+        assert_eq!(converted.sellers[1].percent, Some(50.0));
+        assert_eq!(converted.platform_fee_percent, 5.0);
+    }
+
+    #[test]
+    fn rejects_malformed_shares() {
+        assert!(allocate(100.0, &instructions(vec![], 0.0)).is_err());
+        assert!(allocate(100.0, &instructions(vec![by_amount(1, 10.0)], 100.5)).is_err());
+        assert!(allocate(100.0, &instructions(vec![by_amount(1, 0.0)], 0.0)).is_err());
+        assert!(allocate(100.0, &instructions(vec![by_percent(1, 101.0)], 0.0)).is_err());
+
+        let both = SellerSplit {
+            seller_id: seller(1),
+            amount: Some(10.0),
+            percent: Some(10.0),
+        };
+        assert!(allocate(100.0, &instructions(vec![both], 0.0)).is_err());
+
+        let neither = SellerSplit {
+            seller_id: seller(1),
+            amount: None,
+            percent: None,
+        };
+        assert!(allocate(100.0, &instructions(vec![neither], 0.0)).is_err());
+    }
+}
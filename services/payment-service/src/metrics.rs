@@ -1,7 +1,8 @@
 use axum::{http::StatusCode, response::IntoResponse};
 use lazy_static::lazy_static;
 use prometheus::{
-    register_counter, register_histogram, register_int_gauge, Counter, Histogram, IntGauge,
+    register_counter, register_counter_vec, register_histogram_vec, register_int_gauge,
+    register_int_gauge_vec, Counter, CounterVec, HistogramVec, IntGauge, IntGaugeVec,
     TextEncoder, Encoder,
 };
 
@@ -12,9 +13,32 @@ lazy_static! {
 pub struct PaymentMetrics {
     pub payments_processed: Counter,
     pub payments_failed: Counter,
-    pub payment_amounts: Histogram,
+    pub payments_authorized: Counter,
+    pub payments_voided: Counter,
+    pub payments_expired: Counter,
+    pub disputes_opened: Counter,
+    pub chargebacks: Counter,
+    pub payouts_sent: Counter,
+    pub payouts_failed: Counter,
+    pub payment_amounts: HistogramVec,
     pub active_payments: IntGauge,
     pub circuit_breaker_state: IntGauge,
+    pub ledger_unbalanced_journals: IntGauge,
+    pub reconciliation_runs: CounterVec,
+    pub reconciliation_discrepancies: IntGaugeVec,
+    pub outbox_published: Counter,
+    pub outbox_publish_failures: Counter,
+    pub outbox_backlog: IntGauge,
+    pub messages_dead_lettered: CounterVec,
+    pub messages_retried: CounterVec,
+    pub kafka_transactions: CounterVec,
+    pub kafka_consumer_lag: IntGaugeVec,
+    pub kafka_messages_consumed: CounterVec,
+    pub kafka_message_processing_seconds: HistogramVec,
+    pub kafka_parse_failures: CounterVec,
+    pub kafka_producer_delivery_seconds: HistogramVec,
+    pub kafka_producer_errors: CounterVec,
+    pub kafka_producer_queue: IntGaugeVec,
 }
 
 impl PaymentMetrics {
@@ -30,9 +54,45 @@ impl PaymentMetrics {
                 "Total number of failed payments"
             )
             .unwrap(),
-            payment_amounts: register_histogram!(
+            payments_authorized: register_counter!(
+                "payments_authorized_total",
+                "Total number of authorization holds placed"
+            )
+            .unwrap(),
+            payments_voided: register_counter!(
+                "payments_voided_total",
+                "Total number of authorization holds voided or expired"
+            )
+            .unwrap(),
+            payments_expired: register_counter!(
+                "payments_expired_total",
+                "Total number of pending checkouts expired after the buyer abandoned them"
+            )
+            .unwrap(),
+            disputes_opened: register_counter!(
+                "payment_disputes_opened_total",
+                "Total number of disputes opened by customers"
+            )
+            .unwrap(),
+            chargebacks: register_counter!(
+                "payment_chargebacks_total",
+                "Total number of disputes lost to the customer"
+            )
+            .unwrap(),
+            payouts_sent: register_counter!(
+                "seller_payouts_sent_total",
+                "Total number of seller payouts sent"
+            )
+            .unwrap(),
+            payouts_failed: register_counter!(
+                "seller_payouts_failed_total",
+                "Total number of seller payouts rejected by the payout provider"
+            )
+            .unwrap(),
+            payment_amounts: register_histogram_vec!(
                 "payment_amounts",
-                "Distribution of payment amounts",
+                "Distribution of payment amounts in their presentment currency",
+                &["currency"],
                 vec![10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0, 10000.0]
             )
             .unwrap(),
@@ -46,6 +106,100 @@ impl PaymentMetrics {
                 "Circuit breaker state (0=Closed, 1=Open, 2=HalfOpen)"
             )
             .unwrap(),
+            ledger_unbalanced_journals: register_int_gauge!(
+                "ledger_unbalanced_journals",
+                "Ledger journals whose debits and credits differ, as of the last check"
+            )
+            .unwrap(),
+            reconciliation_runs: register_counter_vec!(
+                "reconciliation_runs_total",
+                "Total number of provider settlement reports reconciled",
+                &["provider"]
+            )
+            .unwrap(),
+            reconciliation_discrepancies: register_int_gauge_vec!(
+                "reconciliation_discrepancies",
+                "Discrepancies found by the latest settlement report reconciliation",
+                &["provider", "kind"]
+            )
+            .unwrap(),
+            outbox_published: register_counter!(
+                "outbox_messages_published_total",
+                "Total number of outbox messages delivered to Kafka"
+            )
+            .unwrap(),
+            outbox_publish_failures: register_counter!(
+                "outbox_publish_failures_total",
+                "Total number of failed outbox delivery attempts"
+            )
+            .unwrap(),
+            outbox_backlog: register_int_gauge!(
+                "outbox_backlog",
+                "Outbox messages not yet delivered to Kafka"
+            )
+            .unwrap(),
+            messages_dead_lettered: register_counter_vec!(
+                "kafka_messages_dead_lettered_total",
+                "Total number of consumed messages forwarded to the dead-letter topic",
+                &["topic"]
+            )
+            .unwrap(),
+            messages_retried: register_counter_vec!(
+                "kafka_messages_retried_total",
+                "Total number of consumed messages moved to a retry topic after a transient failure",
+                &["retry_topic"]
+            )
+            .unwrap(),
+            kafka_transactions: register_counter_vec!(
+                "kafka_transactions_total",
+                "Total number of exactly-once consumer transactions, by outcome",
+                &["outcome"]
+            )
+            .unwrap(),
+            kafka_consumer_lag: register_int_gauge_vec!(
+                "kafka_consumer_lag",
+                "Messages between a partition's high watermark and the group's committed offset, from librdkafka statistics",
+                &["topic", "partition"]
+            )
+            .unwrap(),
+            kafka_messages_consumed: register_counter_vec!(
+                "kafka_messages_consumed_total",
+                "Total number of messages taken off Kafka for processing",
+                &["topic"]
+            )
+            .unwrap(),
+            kafka_message_processing_seconds: register_histogram_vec!(
+                "kafka_message_processing_seconds",
+                "Time to handle a consumed message, including handing it on to a retry topic or the DLQ",
+                &["topic"],
+                vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+            )
+            .unwrap(),
+            kafka_parse_failures: register_counter_vec!(
+                "kafka_message_parse_failures_total",
+                "Total number of consumed messages that could not be decoded",
+                &["topic"]
+            )
+            .unwrap(),
+            kafka_producer_delivery_seconds: register_histogram_vec!(
+                "kafka_producer_delivery_seconds",
+                "Time from sending a message until the broker acknowledged or rejected it",
+                &["topic"],
+                vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+            )
+            .unwrap(),
+            kafka_producer_errors: register_counter_vec!(
+                "kafka_producer_errors_total",
+                "Total number of messages Kafka failed to deliver",
+                &["topic"]
+            )
+            .unwrap(),
+            kafka_producer_queue: register_int_gauge_vec!(
+                "kafka_producer_queue_messages",
+                "Messages waiting in a producer's queue, from librdkafka statistics",
+                &["client"]
+            )
+            .unwrap(),
         }
     }
 }
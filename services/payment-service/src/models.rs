@@ -1,12 +1,63 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Money {
+    pub amount: f64,
+    pub currency: String,
+}
+
+impl Money {
+    pub fn new(amount: f64, currency: &str) -> Self {
+        Self {
+            amount,
+            currency: currency.to_string(),
+        }
+    }
+}
+
+/// Amount the customer was charged in, what we settle in, and the rate between them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FxConversion {
+    pub presentment: Money,
+    pub settlement: Money,
+    pub rate: f64,
+    pub rate_as_of: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PaymentRequest {
     pub user_id: String,
     pub total_amount: f64,
+    /// Presentment currency; defaults to the settlement currency
+    #[serde(default)]
+    pub currency: Option<String>,
     pub payment_method: String,
     pub order_id: Option<String>,
+    /// Saved method from the customer vault to charge instead of fresh details
+    #[serde(default)]
+    pub payment_method_id: Option<String>,
+    /// How a marketplace payment is shared between sellers and the platform
+    #[serde(default)]
+    pub splits: Option<SplitInstructions>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SplitInstructions {
+    pub sellers: Vec<SellerSplit>,
+    /// Commission the platform keeps from each seller's share
+    #[serde(default)]
+    pub platform_fee_percent: f64,
+}
+
+/// A seller's share of the payment, as a fixed amount or a percentage of the total.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SellerSplit {
+    pub seller_id: uuid::Uuid,
+    #[serde(default)]
+    pub amount: Option<f64>,
+    #[serde(default)]
+    pub percent: Option<f64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +66,10 @@ pub struct PaymentResponse {
     pub status: String,
     pub transaction_id: Option<String>,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authorization_expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversion: Option<FxConversion>,
     pub timestamp: DateTime<Utc>,
 }
 
@@ -24,6 +79,10 @@ pub struct PaymentStatus {
     pub status: String,
     pub amount: f64,
     pub transaction_id: Option<String>,
+    #[serde(default)]
+    pub authorization_expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub conversion: Option<FxConversion>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -39,27 +98,269 @@ pub struct SagaEvent {
     pub timestamp: DateTime<Utc>,
 }
 
-impl PaymentStatus {
-    pub fn new(payment_id: String, amount: f64) -> Self {
-        let now = Utc::now();
+/// Error codes a rejected payment request is answered with.
+pub const INVALID_PAYLOAD: &str = "invalid_payload";
+pub const INVALID_USER: &str = "invalid_user";
+pub const INVALID_AMOUNT: &str = "invalid_amount";
+pub const INVALID_CURRENCY: &str = "invalid_currency";
+pub const INVALID_PAYMENT_METHOD: &str = "invalid_payment_method";
+pub const INVALID_ITEMS: &str = "invalid_items";
+pub const AMOUNT_MISMATCH: &str = "amount_mismatch";
+
+/// Largest gap between the order total and the sum of its items, for rounding.
+const AMOUNT_TOLERANCE: f64 = 0.005;
+
+/// `data` of a `payment-process` saga event. Every field but the saved method
+/// and splits is required; nothing is filled in for the sender.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentProcessCommand {
+    pub user_id: String,
+    pub total_amount: f64,
+    /// ISO 4217 code the total is in
+    pub currency: String,
+    /// Kind of payment details, e.g. `credit_card`
+    pub payment_method: String,
+    /// Saved method from the customer vault to charge
+    #[serde(default)]
+    pub payment_method_id: Option<String>,
+    pub items: Vec<OrderItem>,
+    #[serde(default)]
+    pub splits: Option<SplitInstructions>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderItem {
+    pub product_id: String,
+    pub quantity: i64,
+    /// Unit price
+    pub price: f64,
+}
+
+/// A saga payment request that is malformed or doesn't add up. Returned
+/// inside the `anyhow::Error` of the saga path, like `PaymentDeclined`.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Invalid payment request: {message}")]
+pub struct InvalidCommand {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl InvalidCommand {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
         Self {
-            payment_id,
-            status: "PENDING".to_string(),
-            amount,
-            transaction_id: None,
-            created_at: now,
-            updated_at: now,
+            code,
+            message: message.into(),
         }
     }
+}
+
+impl PaymentProcessCommand {
+    pub fn from_event(event: &SagaEvent) -> Result<Self, InvalidCommand> {
+        let command: Self = serde_json::from_value(event.data.clone())
+            .map_err(|e| InvalidCommand::new(INVALID_PAYLOAD, e.to_string()))?;
+        command.validate()?;
+        Ok(command)
+    }
+
+    fn validate(&self) -> Result<(), InvalidCommand> {
+        if self.user_id.trim().is_empty() {
+            return Err(InvalidCommand::new(INVALID_USER, "user_id is empty"));
+        }
+
+        if !self.total_amount.is_finite() || self.total_amount <= 0.0 {
+            return Err(InvalidCommand::new(
+                INVALID_AMOUNT,
+                format!("total_amount must be positive, got {}", self.total_amount),
+            ));
+        }
+
+        if self.currency.len() != 3 || !self.currency.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(InvalidCommand::new(
+                INVALID_CURRENCY,
+                format!("currency must be an ISO 4217 code, got {:?}", self.currency),
+            ));
+        }
+
+        if self.payment_method.trim().is_empty() {
+            return Err(InvalidCommand::new(INVALID_PAYMENT_METHOD, "payment_method is empty"));
+        }
+        if self.payment_method_id.as_deref().is_some_and(|id| id.trim().is_empty()) {
+            return Err(InvalidCommand::new(INVALID_PAYMENT_METHOD, "payment_method_id is empty"));
+        }
+
+        if self.items.is_empty() {
+            return Err(InvalidCommand::new(INVALID_ITEMS, "order has no items"));
+        }
+        for item in &self.items {
+            if item.product_id.trim().is_empty()
+                || item.quantity <= 0
+                || !item.price.is_finite()
+                || item.price < 0.0
+            {
+                return Err(InvalidCommand::new(
+                    INVALID_ITEMS,
+                    format!(
+                        "item {:?} has quantity {} and price {}",
+                        item.product_id, item.quantity, item.price
+                    ),
+                ));
+            }
+        }
+
+        let items_total: f64 = self.items.iter().map(|i| i.price * i.quantity as f64).sum();
+        if (items_total - self.total_amount).abs() > AMOUNT_TOLERANCE {
+            return Err(InvalidCommand::new(
+                AMOUNT_MISMATCH,
+                format!(
+                    "items add up to {:.2}, total_amount is {:.2}",
+                    items_total, self.total_amount
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn into_request(self, order_id: &str) -> PaymentRequest {
+        PaymentRequest {
+            user_id: self.user_id,
+            total_amount: self.total_amount,
+            currency: Some(self.currency),
+            payment_method: self.payment_method,
+            order_id: Some(order_id.to_string()),
+            payment_method_id: self.payment_method_id,
+            splits: self.splits,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> PaymentProcessCommand {
+        PaymentProcessCommand {
+            user_id: "user-1".to_string(),
+            total_amount: 30.0,
+            currency: "USD".to_string(),
+            payment_method: "credit_card".to_string(),
+            payment_method_id: None,
+            items: vec![
+                OrderItem {
+                    product_id: "p-1".to_string(),
+                    quantity: 2,
+                    price: 10.0,
+                },
+                OrderItem {
+                    product_id: "p-2".to_string(),
+                    quantity: 1,
+                    price: 10.0,
+                },
+            ],
+            splits: None,
+        }
+    }
+
+    fn event(data: serde_json::Value) -> SagaEvent {
+        SagaEvent {
+            saga_id: "saga-1".to_string(),
+            order_id: "order-1".to_string(),
+            step: "PAYMENT_PROCESS".to_string(),
+            success: true,
+            message: String::new(),
+            data,
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn rejection(command: PaymentProcessCommand) -> &'static str {
+        command.validate().expect_err("command should be rejected").code
+    }
+
+    #[test]
+    fn accepts_a_consistent_command() {
+        assert!(command().validate().is_ok());
+    }
+
+    #[test]
+    fn accepts_totals_within_rounding_of_the_items() {
+        let mut command = command();
+        command.items = vec![OrderItem {
+            product_id: "p-1".to_string(),
+            quantity: 3,
+            price: 0.1,
+        }];
+        command.total_amount = 0.3;
+        assert!(command.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_payloads_with_missing_fields() {
+        let mut data = serde_json::to_value(command()).unwrap();
+        data.as_object_mut().unwrap().remove("user_id");
+        let invalid = PaymentProcessCommand::from_event(&event(data)).unwrap_err();
+        assert_eq!(invalid.code, INVALID_PAYLOAD);
+
+        let invalid = PaymentProcessCommand::from_event(&event(serde_json::Value::Null)).unwrap_err();
+        assert_eq!(invalid.code, INVALID_PAYLOAD);
+    }
+
+    #[test]
+    fn rejects_blank_user_and_payment_method() {
+        let mut blank_user = command();
+        blank_user.user_id = "  ".to_string();
+        assert_eq!(rejection(blank_user), INVALID_USER);
+
+        let mut blank_method = command();
+        blank_method.payment_method = String::new();
+        assert_eq!(rejection(blank_method), INVALID_PAYMENT_METHOD);
+
+        let mut blank_method_id = command();
+        blank_method_id.payment_method_id = Some(String::new());
+        assert_eq!(rejection(blank_method_id), INVALID_PAYMENT_METHOD);
+    }
+
+    #[test]
+    fn rejects_zero_negative_and_non_finite_amounts() {
+        for amount in [0.0, -30.0, f64::NAN, f64::INFINITY] {
+            let mut command = command();
+            command.total_amount = amount;
+            assert_eq!(rejection(command), INVALID_AMOUNT);
+        }
+    }
+
+    #[test]
+    fn rejects_currencies_that_are_not_iso_codes() {
+        for currency in ["", "usd", "US", "USDT", "U5D"] {
+            let mut command = command();
+            command.currency = currency.to_string();
+            assert_eq!(rejection(command), INVALID_CURRENCY);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_and_invalid_items() {
+        let mut empty = command();
+        empty.items.clear();
+        assert_eq!(rejection(empty), INVALID_ITEMS);
+
+        let mut no_quantity = command();
+        no_quantity.items[0].quantity = 0;
+        assert_eq!(rejection(no_quantity), INVALID_ITEMS);
+
+        let mut negative_price = command();
+        negative_price.items[0].price = -10.0;
+        assert_eq!(rejection(negative_price), INVALID_ITEMS);
 
-    pub fn complete(&mut self, transaction_id: String) {
-        self.status = "COMPLETED".to_string();
-        self.transaction_id = Some(transaction_id);
-        self.updated_at = Utc::now();
+        let mut no_product = command();
+        no_product.items[1].product_id = String::new();
+        assert_eq!(rejection(no_product), INVALID_ITEMS);
     }
 
-    pub fn fail(&mut self, reason: &str) {
-        self.status = format!("FAILED: {}", reason);
-        self.updated_at = Utc::now();
+    #[test]
+    fn rejects_totals_that_do_not_match_the_items() {
+        let mut command = command();
+        command.total_amount = 30.01;
+        assert_eq!(rejection(command), AMOUNT_MISMATCH);
     }
 }
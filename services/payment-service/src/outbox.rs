@@ -0,0 +1,429 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt};
+use opentelemetry::KeyValue;
+use serde::Serialize;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::kafka::{owned_headers, KafkaProducer};
+use crate::metrics::PAYMENT_METRICS;
+use crate::trace_context;
+use crate::wire_format::{PayloadEncoder, CONTENT_TYPE_HEADER};
+
+/// How long a claimed message is reserved for the relay that claimed it.
+/// A relay that dies mid-batch releases its messages when the lease runs out.
+const CLAIM_LEASE_SECS: i64 = 60;
+/// Longest wait between delivery attempts of one message.
+const MAX_BACKOFF_SECS: i64 = 300;
+
+/// A Kafka message waiting to be published.
+#[derive(Debug, Clone)]
+pub struct OutboxMessage {
+    pub topic: String,
+    pub key: String,
+    pub payload: String,
+}
+
+impl OutboxMessage {
+    pub fn json(topic: &str, key: &str, event: &impl Serialize) -> Result<Self> {
+        Ok(Self {
+            topic: topic.to_string(),
+            key: key.to_string(),
+            payload: serde_json::to_string(event)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PendingMessage {
+    pub id: i64,
+    pub topic: String,
+    pub key: String,
+    pub payload: String,
+    pub attempts: i32,
+    /// Trace context of whoever queued it
+    pub headers: Option<serde_json::Value>,
+}
+
+/// A consumed Kafka message, identified by where it was read from.
+#[derive(Debug, Clone)]
+pub struct SourceOffset {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+tokio::task_local! {
+    /// Message the exactly-once consumer is handling in this task
+    static SOURCE: SourceOffset;
+}
+
+/// Runs `handling` with every message it queues tagged with `source`. Tagged
+/// messages are left to the exactly-once consumer, which publishes them in
+/// the Kafka transaction that commits `source`.
+pub async fn capture<F: Future>(source: SourceOffset, handling: F) -> F::Output {
+    SOURCE.scope(source, handling).await
+}
+
+/// Writes a message in the caller's transaction, so it is published if and
+/// only if the change it describes commits.
+pub async fn enqueue(tx: &mut Transaction<'_, Postgres>, message: &OutboxMessage) -> Result<()> {
+    let source = SOURCE.try_with(SourceOffset::clone).ok();
+    let headers = serde_json::to_value(trace_context::current_headers())?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO outbox_messages
+            (topic, key, payload, headers, attempts, next_attempt_at, created_at,
+             source_topic, source_partition, source_offset)
+        VALUES ($1, $2, $3, $4, 0, $5, $5, $6, $7, $8)
+        "#,
+    )
+    .bind(&message.topic)
+    .bind(&message.key)
+    .bind(&message.payload)
+    .bind(headers)
+    .bind(Utc::now())
+    .bind(source.as_ref().map(|s| &s.topic))
+    .bind(source.as_ref().map(|s| s.partition))
+    .bind(source.as_ref().map(|s| s.offset))
+    .execute(&mut **tx)
+    .await
+    .context("Failed to write outbox message")?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxStore {
+    pool: PgPool,
+}
+
+impl OutboxStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Queues a message on its own, for events that follow a change already committed.
+    pub async fn enqueue(&self, message: &OutboxMessage) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        enqueue(&mut tx, message).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Leases the next due messages. A message is skipped while an older one
+    /// with the same topic and key is undelivered, so each key stays in order.
+    /// Messages captured by the exactly-once consumer are not the relay's.
+    async fn claim(&self, limit: i64) -> Result<Vec<PendingMessage>> {
+        let now = Utc::now();
+
+        let messages = sqlx::query_as::<_, PendingMessage>(
+            r#"
+            UPDATE outbox_messages
+            SET next_attempt_at = $2
+            WHERE id IN (
+                SELECT o.id FROM outbox_messages o
+                WHERE o.delivered_at IS NULL
+                  AND o.source_topic IS NULL
+                  AND o.next_attempt_at <= $1
+                  AND NOT EXISTS (
+                      SELECT 1 FROM outbox_messages earlier
+                      WHERE earlier.topic = o.topic
+                        AND earlier.key = o.key
+                        AND earlier.delivered_at IS NULL
+                        AND earlier.source_topic IS NULL
+                        AND earlier.id < o.id
+                  )
+                ORDER BY o.id
+                LIMIT $3
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, topic, key, payload, attempts, headers
+            "#,
+        )
+        .bind(now)
+        .bind(now + chrono::Duration::seconds(CLAIM_LEASE_SECS))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(messages)
+    }
+
+    /// Messages queued while handling `source`, delivered or not, since the
+    /// transaction that published them may have been aborted.
+    pub async fn captured(&self, source: &SourceOffset) -> Result<Vec<PendingMessage>> {
+        let messages = sqlx::query_as::<_, PendingMessage>(
+            r#"
+            SELECT id, topic, key, payload, attempts, headers FROM outbox_messages
+            WHERE source_topic = $1 AND source_partition = $2 AND source_offset = $3
+            ORDER BY id
+            "#,
+        )
+        .bind(&source.topic)
+        .bind(source.partition)
+        .bind(source.offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(messages)
+    }
+
+    /// Records captured messages as delivered once their transaction commits.
+    pub async fn mark_committed(&self, ids: &[i64]) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE outbox_messages
+            SET delivered_at = $1, attempts = attempts + 1
+            WHERE id = ANY($2) AND delivered_at IS NULL
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(ids)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn mark_delivered(&self, id: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE outbox_messages
+            SET delivered_at = $1, attempts = attempts + 1, last_error = NULL
+            WHERE id = $2
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Backs off exponentially, capped at a few minutes; messages are never dropped.
+    async fn mark_failed(&self, message: &PendingMessage, error: &str) -> Result<DateTime<Utc>> {
+        let delay = 2_i64.saturating_pow(message.attempts.clamp(0, 16) as u32).min(MAX_BACKOFF_SECS);
+        let next_attempt_at = Utc::now() + chrono::Duration::seconds(delay);
+
+        sqlx::query(
+            r#"
+            UPDATE outbox_messages
+            SET attempts = attempts + 1, last_error = $1, next_attempt_at = $2
+            WHERE id = $3
+            "#,
+        )
+        .bind(error)
+        .bind(next_attempt_at)
+        .bind(message.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(next_attempt_at)
+    }
+
+    async fn backlog(&self) -> Result<i64> {
+        let count = sqlx::query_scalar::<_, i64>(
+            r#"
+            SELECT COUNT(*) FROM outbox_messages
+            WHERE delivered_at IS NULL AND source_topic IS NULL
+            "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count)
+    }
+
+    /// Captured messages count as delivered here even if never marked: their
+    /// transaction either committed or was replayed long ago.
+    async fn purge_delivered(&self, retention: chrono::Duration) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM outbox_messages
+            WHERE delivered_at < $1 OR (source_topic IS NOT NULL AND created_at < $1)
+            "#,
+        )
+        .bind(Utc::now() - retention)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+/// Sends a message in the configured encoding, saying which in its headers.
+/// Sending is traced as a child of the span the message was queued in.
+pub async fn publish(
+    producer: &KafkaProducer,
+    encoder: &PayloadEncoder,
+    message: &PendingMessage,
+) -> Result<()> {
+    let encoded = encoder.encode(&message.topic, &message.payload)?;
+
+    let mut headers: BTreeMap<String, String> = match &message.headers {
+        Some(headers) => serde_json::from_value(headers.clone())?,
+        None => BTreeMap::new(),
+    };
+    headers.insert(CONTENT_TYPE_HEADER.to_string(), encoded.content_type.to_string());
+
+    let parent = trace_context::extract(&headers);
+    let cx = parent.span().span_context().is_valid().then(|| {
+        let span = trace_context::message_span(
+            &message.topic,
+            "publish",
+            SpanKind::Producer,
+            vec![KeyValue::new("messaging.kafka.message.key", message.key.clone())],
+            &parent,
+        );
+        parent.with_span(span)
+    });
+    if let Some(cx) = &cx {
+        trace_context::inject(cx, &mut headers);
+    }
+
+    let sent = producer
+        .send_with_headers(&message.topic, Some(&message.key), &encoded.payload, owned_headers(&headers))
+        .await;
+
+    if let Some(cx) = cx {
+        if let Err(e) = &sent {
+            cx.span().set_status(Status::error(e.to_string()));
+        }
+        cx.span().end();
+    }
+    sent
+}
+
+/// Publishes one batch, at most one message per key. Returns how many went out.
+async fn relay_batch(
+    store: &OutboxStore,
+    producer: &KafkaProducer,
+    encoder: &PayloadEncoder,
+) -> Result<usize> {
+    let messages = store.claim(100).await?;
+    let mut published = 0;
+
+    for message in &messages {
+        match publish(producer, encoder, message).await {
+            Ok(()) => {
+                store.mark_delivered(message.id).await?;
+                PAYMENT_METRICS.outbox_published.inc();
+                published += 1;
+            }
+            Err(e) => {
+                PAYMENT_METRICS.outbox_publish_failures.inc();
+
+                let retry_at = store.mark_failed(message, &e.to_string()).await?;
+                warn!(
+                    "Failed to publish outbox message {} to {} (attempt {}), retrying at {}: {}",
+                    message.id,
+                    message.topic,
+                    message.attempts + 1,
+                    retry_at,
+                    e
+                );
+            }
+        }
+    }
+
+    Ok(published)
+}
+
+/// Publishes queued messages to Kafka, draining the backlog each tick.
+pub async fn run_outbox_relay(
+    store: OutboxStore,
+    producer: Arc<KafkaProducer>,
+    encoder: PayloadEncoder,
+    interval: Duration,
+    retention: chrono::Duration,
+) {
+    info!("Outbox relay started, interval {:?}", interval);
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        loop {
+            match relay_batch(&store, &producer, &encoder).await {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(e) => {
+                    error!("Outbox relay failed: {}", e);
+                    break;
+                }
+            }
+        }
+
+        match store.backlog().await {
+            Ok(count) => PAYMENT_METRICS.outbox_backlog.set(count),
+            Err(e) => warn!("Failed to count outbox backlog: {}", e),
+        }
+
+        if let Err(e) = store.purge_delivered(retention).await {
+            warn!("Failed to purge delivered outbox messages: {}", e);
+        }
+    }
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS outbox_messages (
+            id BIGSERIAL PRIMARY KEY,
+            topic VARCHAR(255) NOT NULL,
+            key VARCHAR(255) NOT NULL,
+            payload TEXT NOT NULL,
+            attempts INTEGER NOT NULL,
+            last_error TEXT,
+            next_attempt_at TIMESTAMPTZ NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL,
+            delivered_at TIMESTAMPTZ
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create outbox_messages table")?;
+
+    sqlx::query(
+        r#"
+        ALTER TABLE outbox_messages
+            ADD COLUMN IF NOT EXISTS source_topic VARCHAR(255),
+            ADD COLUMN IF NOT EXISTS source_partition INTEGER,
+            ADD COLUMN IF NOT EXISTS source_offset BIGINT,
+            ADD COLUMN IF NOT EXISTS headers JSONB
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_outbox_messages_source
+        ON outbox_messages (source_topic, source_partition, source_offset)
+        WHERE source_topic IS NOT NULL
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_outbox_messages_undelivered
+        ON outbox_messages (topic, key, id) WHERE delivered_at IS NULL
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -1,29 +1,198 @@
+use crate::attempts::{
+    AttemptStore, NewAttempt, ATTEMPT_AUTHORIZED, ATTEMPT_FAILED, ATTEMPT_SUCCEEDED,
+};
+use crate::authorization::{
+    self, AuthorizationError, AuthorizationRecord, AuthorizationStore, STATUS_AUTHORIZED,
+    STATUS_CAPTURED, STATUS_EXPIRED, STATUS_VOIDED,
+};
 use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerError};
-use crate::kafka::KafkaProducer;
+use crate::dunning::{
+    DunningDecision, DunningService, PaymentFailure, PaymentRetry, KIND_SAGA_PAYMENT,
+};
+use crate::events::{
+    self, EventStore, PaymentAggregate, PaymentDetails, PaymentEvent, StoredEvent, PROVIDER_SANDBOX,
+};
+use crate::fees::{FeeStore, SandboxFeeSchedule};
+use crate::fx::{FxError, FxService};
+use crate::ledger::{self, Journal};
+use crate::marketplace::{self, MarketplaceError, SplitStore};
 use crate::metrics::PAYMENT_METRICS;
-use crate::models::{PaymentRequest, PaymentResponse, PaymentStatus, SagaEvent};
+use crate::models::{
+    FxConversion, InvalidCommand, PaymentProcessCommand, PaymentRequest, PaymentResponse,
+    PaymentStatus, SagaEvent,
+};
+use crate::outbox::{self, OutboxMessage, OutboxStore};
 use crate::redis_client::RedisClient;
+use crate::vault::{SavedPaymentMethod, VaultError, VaultStore};
 use chrono::Utc;
+use redis::RedisError;
+use sqlx::{PgPool, Postgres, Transaction};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::Mutex;
-use tracing::info;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
+/// Decline codes for failures on our side of the gateway call.
+const DECLINE_GATEWAY_UNAVAILABLE: &str = "gateway_unavailable";
+const DECLINE_PROCESSING_ERROR: &str = "processing_error";
+/// The gateway acted but we could not record it. Final, since trying again
+/// would place a second hold or charge.
+pub const DECLINE_NOT_RECORDED: &str = "not_recorded";
+/// No rate, or no valid code, for the payment's currency. Final.
+pub const DECLINE_UNSUPPORTED_CURRENCY: &str = "unsupported_currency";
+/// The payment's currency has a rate, but it is too old to use. Retried on
+/// the dunning schedule, since rates are refreshed hours apart rather than
+/// minutes.
+const DECLINE_RATE_STALE: &str = "fx_rate_stale";
+/// A failure on our side that is not an outage, e.g. a request that does not
+/// deserialize or breaks a constraint. Final; trying again fails the same way.
+pub const DECLINE_INTERNAL_ERROR: &str = "internal_error";
+
+/// A charge or hold the gateway refused. Returned inside the `anyhow::Error`
+/// of the payment paths so callers can tell soft declines from hard ones.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{message}")]
+pub struct PaymentDeclined {
+    pub code: String,
+    pub message: String,
+}
+
+impl PaymentDeclined {
+    fn during(self, action: &str) -> anyhow::Error {
+        anyhow::Error::new(PaymentDeclined {
+            message: format!("{} failed: {}", action, self.message),
+            ..self
+        })
+    }
+}
+
+impl From<CircuitBreakerError> for PaymentDeclined {
+    fn from(e: CircuitBreakerError) -> Self {
+        let code = match e {
+            CircuitBreakerError::CircuitOpen => DECLINE_GATEWAY_UNAVAILABLE,
+            CircuitBreakerError::ExecutionFailed(_) => DECLINE_PROCESSING_ERROR,
+        };
+        let message = match e {
+            CircuitBreakerError::CircuitOpen => "Circuit breaker open".to_string(),
+            CircuitBreakerError::ExecutionFailed(msg) => msg,
+        };
+
+        Self {
+            code: code.to_string(),
+            message,
+        }
+    }
+}
+
+/// Decline code for a failed payment. Failures that never reached the
+/// gateway are classified by their cause.
+pub fn decline_code(e: &anyhow::Error) -> String {
+    if let Some(declined) = e.downcast_ref::<PaymentDeclined>() {
+        return declined.code.clone();
+    }
+
+    if let Some(invalid) = e.downcast_ref::<InvalidCommand>() {
+        return invalid.code.to_string();
+    }
+
+    if let Some(MarketplaceError::Invalid(_)) = e.downcast_ref::<MarketplaceError>() {
+        return "invalid_split".to_string();
+    }
+
+    match e.downcast_ref::<VaultError>() {
+        Some(VaultError::NotFound) => return "payment_method_unavailable".to_string(),
+        Some(VaultError::Invalid(_)) => return "expired_card".to_string(),
+        _ => {}
+    }
+
+    match e.downcast_ref::<FxError>() {
+        Some(FxError::InvalidCurrency(_) | FxError::UnknownCurrency(_) | FxError::InvalidRate(_)) => {
+            return DECLINE_UNSUPPORTED_CURRENCY.to_string()
+        }
+        Some(FxError::StaleRate { .. }) => return DECLINE_RATE_STALE.to_string(),
+        _ => {}
+    }
+
+    if is_transient(e) {
+        DECLINE_PROCESSING_ERROR.to_string()
+    } else {
+        DECLINE_INTERNAL_ERROR.to_string()
+    }
+}
+
+/// Whether a failure is likely to clear up within minutes and is safe to try
+/// again from the start: the gateway being unreachable, rates being
+/// unavailable, or a lost database or Redis connection before the gateway
+/// acted. Declines, invalid requests and unknown errors are not.
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    if let Some(declined) = e.downcast_ref::<PaymentDeclined>() {
+        return matches!(
+            declined.code.as_str(),
+            DECLINE_GATEWAY_UNAVAILABLE | DECLINE_PROCESSING_ERROR
+        );
+    }
+
+    e.chain().any(|cause| {
+        matches!(cause.downcast_ref::<FxError>(), Some(FxError::Failed(_)))
+            || cause.downcast_ref::<sqlx::Error>().is_some_and(|e| {
+                matches!(
+                    e,
+                    sqlx::Error::Io(_)
+                        | sqlx::Error::PoolTimedOut
+                        | sqlx::Error::PoolClosed
+                        | sqlx::Error::WorkerCrashed
+                )
+            })
+            || cause.downcast_ref::<RedisError>().is_some_and(|e| {
+                e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout()
+            })
+    })
+}
+
 #[derive(Clone, Debug)]
 pub struct PaymentService {
     redis_client: Arc<Mutex<RedisClient>>,
-    kafka_producer: Arc<KafkaProducer>,
+    pool: PgPool,
+    outbox: OutboxStore,
     circuit_breaker: Arc<CircuitBreaker>,
+    authorizations: AuthorizationStore,
+    attempts: AttemptStore,
+    authorization_ttl: chrono::Duration,
+    vault: VaultStore,
+    fx: FxService,
+    fees: FeeStore,
+    sandbox_fees: SandboxFeeSchedule,
+    dunning: Arc<DunningService>,
+    splits: SplitStore,
+    events: EventStore,
 }
 
 impl PaymentService {
-    pub fn new(redis_client: RedisClient, kafka_producer: KafkaProducer) -> Self {
+    pub fn new(
+        redis_client: RedisClient,
+        pool: PgPool,
+        authorization_ttl: chrono::Duration,
+        fx: FxService,
+        sandbox_fees: SandboxFeeSchedule,
+        dunning: Arc<DunningService>,
+    ) -> Self {
         Self {
             redis_client: Arc::new(Mutex::new(redis_client)),
-            kafka_producer: Arc::new(kafka_producer),
+            outbox: OutboxStore::new(pool.clone()),
             // Circuit breaker: 5 failures within 30 seconds opens circuit for 60 seconds
             circuit_breaker: Arc::new(CircuitBreaker::new(5, Duration::from_secs(60))),
+            authorizations: AuthorizationStore::new(pool.clone()),
+            attempts: AttemptStore::new(pool.clone()),
+            authorization_ttl,
+            vault: VaultStore::new(pool.clone()),
+            fx,
+            splits: SplitStore::new(pool.clone()),
+            events: EventStore::new(pool.clone()),
+            fees: FeeStore::new(pool.clone()),
+            pool,
+            sandbox_fees,
+            dunning,
         }
     }
 
@@ -31,58 +200,77 @@ impl PaymentService {
         &self,
         request: PaymentRequest,
     ) -> Result<PaymentResponse, anyhow::Error> {
-        let payment_id = Uuid::new_v4().to_string();
+        let payment_uuid = Uuid::new_v4();
+        let payment_id = payment_uuid.to_string();
 
         info!(
             "Processing payment {} for user {} amount {}",
             payment_id, request.user_id, request.total_amount
         );
 
-        // Create payment status
-        let mut status = PaymentStatus::new(payment_id.clone(), request.total_amount);
+        let saved_method = self.resolve_payment_method(&request).await?;
+        let conversion = self.convert_to_settlement(&request).await?;
+        let intent_id = self
+            .attempts
+            .open_intent(request.order_id.as_deref(), Some(&request.user_id), &conversion.presentment)
+            .await?;
+        self.prepare_splits(payment_uuid, &request, &conversion).await?;
+        self.open_payment(payment_uuid, &request, &conversion).await?;
 
         // Use circuit breaker for external payment gateway call
-        match self
+        let started_at = Utc::now();
+        let result = self
             .circuit_breaker
-            .call(self.call_payment_gateway(&request))
+            .call(self.call_payment_gateway(&request, saved_method.as_ref()))
             .await
-        {
+            .map_err(PaymentDeclined::from)
+            .and_then(|outcome| outcome);
+        self.record_attempt(intent_id, payment_uuid, "charge", &request, &result, started_at)
+            .await;
+
+        match result {
             Ok(transaction_id) => {
-                status.complete(transaction_id.clone());
-                
-                // Store in Redis
-                self.store_payment_status(&status).await?;
+                let captured = PaymentEvent::Captured {
+                    reference: Some(transaction_id.clone()),
+                };
+                if let Err(e) = self.record_capture(payment_uuid, &captured, &conversion).await {
+                    error!(
+                        "Payment {} was charged as {} but could not be recorded: {}",
+                        payment_id, transaction_id, e
+                    );
+                    return Err(PaymentDeclined {
+                        code: DECLINE_NOT_RECORDED.to_string(),
+                        message: format!("Charge {} could not be recorded: {}", transaction_id, e),
+                    }
+                    .into());
+                }
 
                 PAYMENT_METRICS.payments_processed.inc();
                 PAYMENT_METRICS
                     .payment_amounts
+                    .with_label_values(&[&conversion.presentment.currency])
                     .observe(request.total_amount);
 
+                self.record_sandbox_fee(&payment_id, &transaction_id, &conversion)
+                    .await;
+
                 Ok(PaymentResponse {
                     payment_id,
                     status: "COMPLETED".to_string(),
                     transaction_id: Some(transaction_id),
                     message: "Payment processed successfully".to_string(),
+                    authorization_expires_at: None,
+                    conversion: Some(conversion),
                     timestamp: Utc::now(),
                 })
             }
-            Err(e) => {
-                let error_msg = match &e {
-                    CircuitBreakerError::CircuitOpen => {
-                        status.fail("Payment service temporarily unavailable");
-                        "Circuit breaker open".to_string()
-                    }
-                    CircuitBreakerError::ExecutionFailed(ref msg) => {
-                        status.fail(msg);
-                        msg.clone()
-                    }
-                };
-
-                self.store_payment_status(&status).await?;
+            Err(declined) => {
+                self.record_unpaid(payment_uuid, &Self::failed_event(&declined))
+                    .await?;
 
                 PAYMENT_METRICS.payments_failed.inc();
 
-                Err(anyhow::anyhow!("Payment failed: {}", error_msg))
+                Err(declined.during("Payment"))
             }
         }
     }
@@ -93,67 +281,943 @@ impl PaymentService {
     ) -> Result<(), anyhow::Error> {
         info!("Processing saga payment for order: {}", event.order_id);
 
-        // Extract payment details from event data
-        let user_id = event.data["user_id"].as_str().unwrap_or("unknown");
-        let total_amount = event.data["total_amount"].as_f64().unwrap_or(0.0);
+        // A redelivered request finds its hold already placed. The answer was
+        // queued with that hold, but is queued again in case the saga missed it
+        if let Some(record) = self.authorizations.get_by_order(&event.order_id).await? {
+            if record.status == STATUS_AUTHORIZED || record.status == STATUS_CAPTURED {
+                info!(
+                    "Order {} already has payment {} ({}), answering the saga again",
+                    event.order_id, record.payment_id, record.status
+                );
+                let response =
+                    Self::authorization_response(&record, "Payment authorized successfully");
+                let reply =
+                    Self::saga_payment_result(&event.saga_id, &event.order_id, Ok(&response))?;
+                return self.outbox.enqueue(&reply).await;
+            }
+        }
+
+        // A request redelivered after its decline was recorded belongs to
+        // dunning, which answers the saga when it is done
+        if self.dunning.is_scheduled(KIND_SAGA_PAYMENT, &event.order_id).await? {
+            info!(
+                "Payment for order {} is already scheduled for retry, ignoring the redelivery",
+                event.order_id
+            );
+            return Ok(());
+        }
 
-        let request = PaymentRequest {
-            user_id: user_id.to_string(),
-            total_amount,
-            payment_method: "credit_card".to_string(),
-            order_id: Some(event.order_id.clone()),
+        let request = match PaymentProcessCommand::from_event(&event) {
+            Ok(command) => command.into_request(&event.order_id),
+            Err(invalid) => {
+                warn!("Rejecting payment for order {}: {}", event.order_id, invalid);
+                return self
+                    .send_saga_payment_failure(&event.saga_id, &event.order_id, invalid.into())
+                    .await;
+            }
         };
 
-        let result = self.process_payment(request).await;
+        // Only place a hold here; the money is captured once the order completes.
+        // A successful hold answers the saga in the same transaction.
+        let result = self.authorize(request.clone(), Some(&event.saga_id)).await;
+
+        if let Err(e) = self.attempts.link_saga(&event.order_id, &event.saga_id).await {
+            warn!("Failed to link saga {} to order {}: {}", event.saga_id, event.order_id, e);
+        }
+
+        let Err(e) = result else {
+            return Ok(());
+        };
+
+        // Outages are retried through the consumer's retry topics before the
+        // saga or dunning hear about them
+        if is_transient(&e) {
+            return Err(e);
+        }
+
+        // Soft declines are retried on the dunning schedule; the saga only
+        // hears about the failure once dunning gives up
+        let failure = PaymentFailure {
+            kind: KIND_SAGA_PAYMENT,
+            reference: event.order_id.clone(),
+            saga_id: Some(event.saga_id.clone()),
+            user_id: request.user_id.clone(),
+            request: serde_json::to_value(&request)?,
+            decline_code: decline_code(&e),
+            error: e.to_string(),
+        };
+
+        let mut tx = self.pool.begin().await?;
+        match self.dunning.record_failure(&mut tx, failure).await {
+            Ok(DunningDecision::Retrying(at)) => {
+                tx.commit().await?;
+                info!(
+                    "Payment for order {} declined ({}), retrying at {}",
+                    event.order_id, e, at
+                );
+                Ok(())
+            }
+            Ok(DunningDecision::GaveUp) => {
+                let reply = Self::saga_payment_result(&event.saga_id, &event.order_id, Err(&e))?;
+                outbox::enqueue(&mut tx, &reply).await?;
+                tx.commit().await?;
+                Ok(())
+            }
+            Err(dunning_error) => {
+                error!(
+                    "Failed to schedule retry for order {}: {}",
+                    event.order_id, dunning_error
+                );
+                self.send_saga_payment_failure(&event.saga_id, &event.order_id, e)
+                    .await
+            }
+        }
+    }
+
+    /// Re-attempts a saga payment that dunning scheduled, answering the saga
+    /// once the hold is placed or the schedule is exhausted.
+    pub async fn retry_saga_payment(&self, retry: &PaymentRetry) -> Result<(), anyhow::Error> {
+        let saga_id = retry
+            .saga_id
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Retry {} has no saga", retry.id))?;
+        let request: PaymentRequest = serde_json::from_value(retry.request.clone())?;
+
+        // A redelivered request may have placed the hold since the retry was
+        // scheduled; it answered the saga then
+        if let Some(record) = self.authorizations.get_by_order(&retry.reference).await? {
+            if record.status == STATUS_AUTHORIZED || record.status == STATUS_CAPTURED {
+                info!(
+                    "Order {} already has payment {} ({}), ending its retries",
+                    retry.reference, record.payment_id, record.status
+                );
+                let mut tx = self.pool.begin().await?;
+                self.dunning.retry_succeeded(&mut tx, retry).await?;
+                tx.commit().await?;
+                return Ok(());
+            }
+        }
+
+        info!(
+            "Retrying payment for order {} (attempt {})",
+            retry.reference, retry.attempt_count
+        );
+
+        let result = self.authorize(request, Some(&saga_id)).await;
+        let mut tx = self.pool.begin().await?;
+        match result {
+            Ok(_) => {
+                self.dunning.retry_succeeded(&mut tx, retry).await?;
+                tx.commit().await?;
+                Ok(())
+            }
+            Err(e) => {
+                let decision = self
+                    .dunning
+                    .retry_failed(&mut tx, retry, &decline_code(&e), &e.to_string())
+                    .await?;
+
+                // Giving up and telling the saga commit together
+                if decision == DunningDecision::GaveUp {
+                    let reply = Self::saga_payment_result(&saga_id, &retry.reference, Err(&e))?;
+                    outbox::enqueue(&mut tx, &reply).await?;
+                }
+                tx.commit().await?;
 
-        // Send saga response
+                Ok(())
+            }
+        }
+    }
+
+    /// Queues a failure answer for the saga. Successful holds answer from `authorize`.
+    async fn send_saga_payment_failure(
+        &self,
+        saga_id: &str,
+        order_id: &str,
+        e: anyhow::Error,
+    ) -> Result<(), anyhow::Error> {
+        let message = Self::saga_payment_result(saga_id, order_id, Err(&e))?;
+        self.outbox.enqueue(&message).await
+    }
+
+    fn saga_payment_result(
+        saga_id: &str,
+        order_id: &str,
+        result: Result<&PaymentResponse, &anyhow::Error>,
+    ) -> Result<OutboxMessage, anyhow::Error> {
         let response_event = match result {
             Ok(payment_response) => SagaEvent {
-                saga_id: event.saga_id.clone(),
-                order_id: event.order_id.clone(),
+                saga_id: saga_id.to_string(),
+                order_id: order_id.to_string(),
                 step: "PAYMENT_PROCESSED".to_string(),
                 success: true,
-                message: "Payment processed successfully".to_string(),
+                message: "Payment authorized successfully".to_string(),
                 data: serde_json::json!({
                     "payment_id": payment_response.payment_id,
-                    "transaction_id": payment_response.transaction_id
+                    "transaction_id": payment_response.transaction_id,
+                    "authorization_expires_at": payment_response.authorization_expires_at
                 }),
                 timestamp: Utc::now(),
             },
             Err(e) => SagaEvent {
-                saga_id: event.saga_id.clone(),
-                order_id: event.order_id.clone(),
+                saga_id: saga_id.to_string(),
+                order_id: order_id.to_string(),
                 step: "PAYMENT_PROCESSED".to_string(),
                 success: false,
                 message: format!("Payment failed: {}", e),
+                data: serde_json::json!({ "decline_code": decline_code(e) }),
+                timestamp: Utc::now(),
+            },
+        };
+
+        OutboxMessage::json("saga-response", saga_id, &response_event)
+    }
+
+    /// Places an authorization hold for the requested amount without taking the money.
+    pub async fn authorize_payment(
+        &self,
+        request: PaymentRequest,
+    ) -> Result<PaymentResponse, anyhow::Error> {
+        self.authorize(request, None).await
+    }
+
+    /// Places the hold and, for saga payments, queues the saga's answer with
+    /// the authorization so the order hears about it even if Kafka is down.
+    async fn authorize(
+        &self,
+        request: PaymentRequest,
+        saga_id: Option<&str>,
+    ) -> Result<PaymentResponse, anyhow::Error> {
+        let payment_uuid = Uuid::new_v4();
+        let payment_id = payment_uuid.to_string();
+
+        info!(
+            "Authorizing payment {} for user {} amount {}",
+            payment_id, request.user_id, request.total_amount
+        );
+
+        let saved_method = self.resolve_payment_method(&request).await?;
+        let conversion = self.convert_to_settlement(&request).await?;
+
+        let intent_id = self
+            .attempts
+            .open_intent(request.order_id.as_deref(), Some(&request.user_id), &conversion.presentment)
+            .await?;
+        self.prepare_splits(payment_uuid, &request, &conversion).await?;
+        self.open_payment(payment_uuid, &request, &conversion).await?;
+
+        let started_at = Utc::now();
+        let result = self
+            .circuit_breaker
+            .call(self.call_gateway_authorize(&request, saved_method.as_ref()))
+            .await
+            .map_err(PaymentDeclined::from)
+            .and_then(|outcome| outcome);
+        self.record_attempt(intent_id, payment_uuid, "authorize", &request, &result, started_at)
+            .await;
+
+        match result {
+            Ok(transaction_id) => {
+                let now = Utc::now();
+                let expires_at = now + self.authorization_ttl;
+                let record = AuthorizationRecord {
+                    payment_id: payment_uuid,
+                    order_id: request.order_id.clone(),
+                    user_id: request.user_id.clone(),
+                    amount: conversion.presentment.amount,
+                    currency: conversion.presentment.currency.clone(),
+                    settlement_amount: conversion.settlement.amount,
+                    settlement_currency: conversion.settlement.currency.clone(),
+                    fx_rate: conversion.rate,
+                    fx_rate_as_of: Some(conversion.rate_as_of),
+                    transaction_id: transaction_id.clone(),
+                    status: STATUS_AUTHORIZED.to_string(),
+                    expires_at,
+                    captured_at: None,
+                    voided_at: None,
+                    created_at: now,
+                    updated_at: now,
+                };
+
+                let response = PaymentResponse {
+                    payment_id,
+                    status: "AUTHORIZED".to_string(),
+                    transaction_id: Some(transaction_id.clone()),
+                    message: "Payment authorized successfully".to_string(),
+                    authorization_expires_at: Some(expires_at),
+                    conversion: Some(conversion),
+                    timestamp: Utc::now(),
+                };
+
+                let authorized = PaymentEvent::Authorized {
+                    transaction_id,
+                    expires_at,
+                };
+                let recorded = async {
+                    let reply = match saga_id {
+                        Some(saga_id) => {
+                            let order_id = request.order_id.as_deref().unwrap_or_default();
+                            Some(Self::saga_payment_result(saga_id, order_id, Ok(&response))?)
+                        }
+                        None => None,
+                    };
+                    self.record_authorization(&record, &authorized, reply.as_ref()).await
+                }
+                .await;
+
+                let payment = match recorded {
+                    Ok(payment) => payment,
+                    Err(e) => return Err(self.release_unrecorded_hold(&record, e).await),
+                };
+                self.cache_status(payment_uuid, payment).await;
+
+                PAYMENT_METRICS.payments_authorized.inc();
+
+                Ok(response)
+            }
+            Err(declined) => {
+                self.record_unpaid(payment_uuid, &Self::failed_event(&declined))
+                    .await?;
+
+                PAYMENT_METRICS.payments_failed.inc();
+
+                Err(declined.during("Authorization"))
+            }
+        }
+    }
+
+    /// Stores the hold, its event and the saga's answer together, so a
+    /// redelivery after a crash finds either all of them or none.
+    async fn record_authorization(
+        &self,
+        record: &AuthorizationRecord,
+        authorized: &PaymentEvent,
+        reply: Option<&OutboxMessage>,
+    ) -> Result<Option<PaymentAggregate>, anyhow::Error> {
+        let mut tx = self.pool.begin().await?;
+        authorization::insert(&mut tx, record).await?;
+        let payment = events::append(&mut tx, record.payment_id, authorized).await?;
+        if let Some(reply) = reply {
+            outbox::enqueue(&mut tx, reply).await?;
+        }
+        tx.commit().await?;
+        Ok(payment)
+    }
+
+    /// Releases a hold the gateway placed but we could not record. The
+    /// failure is final, so nothing places the hold a second time.
+    async fn release_unrecorded_hold(
+        &self,
+        record: &AuthorizationRecord,
+        e: anyhow::Error,
+    ) -> anyhow::Error {
+        error!(
+            "Hold {} for payment {} could not be recorded: {}",
+            record.transaction_id, record.payment_id, e
+        );
+        if let Err(void_error) = self
+            .circuit_breaker
+            .call(self.call_gateway_void(&record.transaction_id))
+            .await
+        {
+            error!(
+                "Failed to release unrecorded hold {}: {}",
+                record.transaction_id, void_error
+            );
+        }
+        PAYMENT_METRICS.payments_failed.inc();
+
+        PaymentDeclined {
+            code: DECLINE_NOT_RECORDED.to_string(),
+            message: format!("Authorization could not be recorded: {}", e),
+        }
+        .into()
+    }
+
+    /// Takes the money for a previously authorized payment. Capturing twice is a no-op.
+    /// The hold stays locked from before the gateway call until the capture is
+    /// recorded, so a void or the expiry sweeper cannot release it meanwhile.
+    pub async fn capture_payment(
+        &self,
+        payment_id: &str,
+    ) -> Result<PaymentResponse, AuthorizationError> {
+        let payment_uuid = Uuid::parse_str(payment_id).map_err(|_| AuthorizationError::NotFound)?;
+        let mut tx = self.pool.begin().await.map_err(anyhow::Error::from)?;
+        let record = authorization::lock(&mut tx, payment_uuid)
+            .await?
+            .ok_or(AuthorizationError::NotFound)?;
+
+        match record.status.as_str() {
+            STATUS_AUTHORIZED => {}
+            STATUS_CAPTURED => return Ok(Self::authorization_response(&record, "Payment already captured")),
+            other => return Err(AuthorizationError::InvalidState(other.to_string())),
+        }
+
+        if record.expires_at < Utc::now() {
+            return Err(AuthorizationError::InvalidState(STATUS_EXPIRED.to_string()));
+        }
+
+        self.circuit_breaker
+            .call(self.call_gateway_capture(&record.transaction_id))
+            .await
+            .map_err(|e| PaymentDeclined::from(e).during("Capture"))?;
+
+        let conversion = record.conversion();
+        let recorded = async {
+            authorization::transition(&mut tx, record.payment_id, STATUS_CAPTURED).await?;
+            let captured = PaymentEvent::Captured { reference: None };
+            let payment = Self::append_capture(&mut tx, record.payment_id, &captured, &conversion).await?;
+            tx.commit().await?;
+            Ok::<_, anyhow::Error>(payment)
+        }
+        .await;
+        let payment = recorded.map_err(|e| Self::unrecorded("capture", &record, e))?;
+        self.cache_status(record.payment_id, payment).await;
+
+        PAYMENT_METRICS.payments_processed.inc();
+        PAYMENT_METRICS
+            .payment_amounts
+            .with_label_values(&[&record.currency])
+            .observe(record.amount);
+
+        self.record_sandbox_fee(payment_id, &record.transaction_id, &conversion)
+            .await;
+
+        info!("Captured payment {} amount {}", payment_id, record.amount);
+
+        let mut record = record;
+        record.status = STATUS_CAPTURED.to_string();
+        Ok(Self::authorization_response(&record, "Payment captured successfully"))
+    }
+
+    /// A capture or void the gateway carried out but we could not record.
+    /// Final, so the saga consumer dead-letters it instead of sending the
+    /// gateway call again.
+    fn unrecorded(action: &str, record: &AuthorizationRecord, e: anyhow::Error) -> AuthorizationError {
+        error!(
+            "Gateway {} of hold {} for payment {} could not be recorded: {}",
+            action, record.transaction_id, record.payment_id, e
+        );
+
+        AuthorizationError::Failed(
+            PaymentDeclined {
+                code: DECLINE_NOT_RECORDED.to_string(),
+                message: format!("Gateway {} of {} could not be recorded: {}", action, record.transaction_id, e),
+            }
+            .into(),
+        )
+    }
+
+    /// Releases an authorization hold. Voiding an already released hold is a no-op.
+    pub async fn void_payment(
+        &self,
+        payment_id: &str,
+    ) -> Result<PaymentResponse, AuthorizationError> {
+        let payment_uuid = Uuid::parse_str(payment_id).map_err(|_| AuthorizationError::NotFound)?;
+        self.release_authorization(payment_uuid, STATUS_VOIDED).await
+    }
+
+    /// Captures the hold placed for an order once order-service reports it completed.
+    pub async fn capture_order_payment(
+        &self,
+        order_id: &str,
+    ) -> Result<PaymentResponse, AuthorizationError> {
+        let record = self
+            .authorizations
+            .get_by_order(order_id)
+            .await?
+            .ok_or(AuthorizationError::NotFound)?;
+
+        self.capture_payment(&record.payment_id.to_string()).await
+    }
+
+    /// Compensating step of the saga: release the hold placed for the order.
+    pub async fn process_saga_rollback(
+        &self,
+        event: SagaEvent,
+    ) -> Result<(), anyhow::Error> {
+        info!("Rolling back saga payment for order: {}", event.order_id);
+
+        if let Err(e) = self.dunning.cancel(KIND_SAGA_PAYMENT, &event.order_id).await {
+            warn!("Failed to cancel payment retries for order {}: {}", event.order_id, e);
+        }
+
+        let result = match self.authorizations.get_by_order(&event.order_id).await? {
+            Some(record) => self
+                .release_authorization(record.payment_id, STATUS_VOIDED)
+                .await
+                .map(Some),
+            None => Ok(None),
+        };
+
+        let response_event = match result {
+            Ok(payment_response) => SagaEvent {
+                saga_id: event.saga_id.clone(),
+                order_id: event.order_id.clone(),
+                step: "PAYMENT_ROLLBACK".to_string(),
+                success: true,
+                message: "Payment authorization released".to_string(),
+                data: serde_json::json!({
+                    "payment_id": payment_response.map(|r| r.payment_id)
+                }),
+                timestamp: Utc::now(),
+            },
+            Err(e) => SagaEvent {
+                saga_id: event.saga_id.clone(),
+                order_id: event.order_id.clone(),
+                step: "PAYMENT_ROLLBACK".to_string(),
+                success: false,
+                message: format!("Payment rollback failed: {}", e),
                 data: serde_json::json!({}),
                 timestamp: Utc::now(),
             },
         };
 
-        let payload = serde_json::to_string(&response_event)?;
-        self.kafka_producer
-            .send_message("saga-response", &event.saga_id, &payload)
+        let message = OutboxMessage::json("saga-response", &event.saga_id, &response_event)?;
+        self.outbox.enqueue(&message).await
+    }
+
+    /// Voids holds whose expiry has passed. Returns the number of holds released.
+    pub async fn expire_stale_authorizations(&self) -> Result<usize, anyhow::Error> {
+        let stale = self.authorizations.find_expired(100).await?;
+        let mut released = 0;
+
+        for record in stale {
+            let payment_id = record.payment_id;
+            match self.release_authorization(payment_id, STATUS_EXPIRED).await {
+                Ok(_) => released += 1,
+                Err(e) => warn!("Failed to void expired authorization {}: {}", payment_id, e),
+            }
+        }
+
+        Ok(released)
+    }
+
+    /// Voids the hold at the gateway, then records the release, its event and
+    /// the dropped split together. The hold stays locked throughout, like a
+    /// capture.
+    async fn release_authorization(
+        &self,
+        payment_id: Uuid,
+        final_status: &str,
+    ) -> Result<PaymentResponse, AuthorizationError> {
+        let mut tx = self.pool.begin().await.map_err(anyhow::Error::from)?;
+        let record = authorization::lock(&mut tx, payment_id)
+            .await?
+            .ok_or(AuthorizationError::NotFound)?;
+
+        match record.status.as_str() {
+            STATUS_AUTHORIZED => {}
+            STATUS_VOIDED | STATUS_EXPIRED => {
+                return Ok(Self::authorization_response(&record, "Authorization already released"))
+            }
+            other => return Err(AuthorizationError::InvalidState(other.to_string())),
+        }
+
+        self.circuit_breaker
+            .call(self.call_gateway_void(&record.transaction_id))
+            .await
+            .map_err(|e| PaymentDeclined::from(e).during("Void"))?;
+
+        let recorded = async {
+            authorization::transition(&mut tx, record.payment_id, final_status).await?;
+            let voided = PaymentEvent::Voided {
+                reason: final_status.to_string(),
+            };
+            let payment = Self::append_unpaid(&mut tx, record.payment_id, &voided).await?;
+            tx.commit().await?;
+            Ok::<_, anyhow::Error>(payment)
+        }
+        .await;
+        let payment = recorded.map_err(|e| Self::unrecorded("void", &record, e))?;
+        self.cache_status(record.payment_id, payment).await;
+
+        PAYMENT_METRICS.payments_voided.inc();
+
+        info!(
+            "Released authorization {} ({})",
+            record.payment_id, final_status
+        );
+
+        let mut record = record;
+        record.status = final_status.to_string();
+        Ok(Self::authorization_response(&record, "Authorization released"))
+    }
+
+    fn authorization_response(record: &AuthorizationRecord, message: &str) -> PaymentResponse {
+        let (status, expires_at) = match record.status.as_str() {
+            STATUS_CAPTURED => ("COMPLETED", None),
+            STATUS_VOIDED | STATUS_EXPIRED => ("VOIDED", None),
+            _ => ("AUTHORIZED", Some(record.expires_at)),
+        };
+
+        PaymentResponse {
+            payment_id: record.payment_id.to_string(),
+            status: status.to_string(),
+            transaction_id: Some(record.transaction_id.clone()),
+            message: message.to_string(),
+            authorization_expires_at: expires_at,
+            conversion: Some(record.conversion()),
+            timestamp: Utc::now(),
+        }
+    }
+
+    /// Adds a gateway call to the intent's history. Like fees, the outcome of
+    /// the call stands even if the history cannot be written.
+    async fn record_attempt(
+        &self,
+        intent_id: Uuid,
+        payment_id: Uuid,
+        operation: &str,
+        request: &PaymentRequest,
+        result: &Result<String, PaymentDeclined>,
+        started_at: chrono::DateTime<Utc>,
+    ) {
+        let (status, response_summary, decline_code) = match result {
+            Ok(transaction_id) => (
+                if operation == "authorize" { ATTEMPT_AUTHORIZED } else { ATTEMPT_SUCCEEDED },
+                serde_json::json!({ "transaction_id": transaction_id }),
+                None,
+            ),
+            Err(declined) => (
+                ATTEMPT_FAILED,
+                serde_json::json!({ "error": declined.message }),
+                Some(declined.code.clone()),
+            ),
+        };
+
+        let attempt = NewAttempt {
+            intent_id,
+            payment_id,
+            gateway: "sandbox".to_string(),
+            operation: operation.to_string(),
+            status: status.to_string(),
+            request_summary: serde_json::json!({
+                "amount": request.total_amount,
+                "currency": request.currency,
+                "payment_method": request.payment_method,
+                "payment_method_id": request.payment_method_id,
+            }),
+            response_summary,
+            decline_code,
+            started_at,
+            completed_at: Utc::now(),
+        };
+
+        if let Err(e) = self.attempts.record(attempt).await {
+            warn!("Failed to record {} attempt for payment {}: {}", operation, payment_id, e);
+        }
+    }
+
+    /// Stores the marketplace split before any money moves, so an invalid
+    /// split rejects the payment instead of leaving sellers unpaid.
+    async fn prepare_splits(
+        &self,
+        payment_id: Uuid,
+        request: &PaymentRequest,
+        conversion: &FxConversion,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(instructions) = &request.splits {
+            self.splits
+                .prepare(payment_id, conversion, instructions)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Records a payment that ended without taking money.
+    async fn record_unpaid(
+        &self,
+        payment_id: Uuid,
+        event: &PaymentEvent,
+    ) -> Result<(), anyhow::Error> {
+        let mut tx = self.pool.begin().await?;
+        let payment = Self::append_unpaid(&mut tx, payment_id, event).await?;
+        tx.commit().await?;
+
+        self.cache_status(payment_id, payment).await;
+        Ok(())
+    }
+
+    /// Appends the event that ends the payment without taking money and drops
+    /// its marketplace split.
+    async fn append_unpaid(
+        tx: &mut Transaction<'_, Postgres>,
+        payment_id: Uuid,
+        event: &PaymentEvent,
+    ) -> Result<Option<PaymentAggregate>, anyhow::Error> {
+        marketplace::cancel_splits(tx, payment_id).await?;
+        events::append(tx, payment_id, event).await
+    }
+
+    fn failed_event(declined: &PaymentDeclined) -> PaymentEvent {
+        let reason = if declined.code == DECLINE_GATEWAY_UNAVAILABLE {
+            "Payment service temporarily unavailable".to_string()
+        } else {
+            declined.message.clone()
+        };
+
+        PaymentEvent::Failed { reason }
+    }
+
+    /// Starts the payment's event stream before the gateway is called.
+    async fn open_payment(
+        &self,
+        payment_id: Uuid,
+        request: &PaymentRequest,
+        conversion: &FxConversion,
+    ) -> Result<(), anyhow::Error> {
+        let details = PaymentDetails::new(
+            PROVIDER_SANDBOX,
+            request.order_id.as_deref(),
+            Some(&request.user_id),
+            conversion,
+        );
+        self.record_events(payment_id, &[PaymentEvent::Created(details)])
+            .await
+    }
+
+    /// Records a sandbox capture and books it in the same transaction.
+    async fn record_capture(
+        &self,
+        payment_id: Uuid,
+        captured: &PaymentEvent,
+        conversion: &FxConversion,
+    ) -> Result<(), anyhow::Error> {
+        let mut tx = self.pool.begin().await?;
+        let payment = Self::append_capture(&mut tx, payment_id, captured, conversion).await?;
+        tx.commit().await?;
+
+        self.cache_status(payment_id, payment).await;
+        Ok(())
+    }
+
+    /// Appends the Captured event, books the capture and credits the sellers'
+    /// split. Sandbox journals are kept in the settlement currency, like the
+    /// fees charged on them.
+    async fn append_capture(
+        tx: &mut Transaction<'_, Postgres>,
+        payment_id: Uuid,
+        captured: &PaymentEvent,
+        conversion: &FxConversion,
+    ) -> Result<Option<PaymentAggregate>, anyhow::Error> {
+        let payment = events::append(tx, payment_id, captured).await?;
+        if payment.is_some() {
+            ledger::post(tx, &Journal::capture("sandbox", payment_id, &conversion.settlement)).await?;
+        }
+        marketplace::capture_splits(tx, payment_id).await?;
+        Ok(payment)
+    }
+
+    /// Refreshes the payment's Redis view after the gateway has already acted.
+    /// The database is the record, so a failure here must not send the request
+    /// back to the gateway.
+    async fn cache_status(&self, payment_id: Uuid, payment: Option<PaymentAggregate>) {
+        let Some(payment) = payment else {
+            return;
+        };
+        if let Err(e) = self.store_payment_status(&payment.to_status()).await {
+            warn!("Failed to cache status of payment {}: {}", payment_id, e);
+        }
+    }
+
+    /// Appends events to the payment and refreshes its Redis view from the result.
+    async fn record_events(
+        &self,
+        payment_id: Uuid,
+        events: &[PaymentEvent],
+    ) -> Result<(), anyhow::Error> {
+        self.record_events_with_messages(payment_id, events, &[]).await
+    }
+
+    async fn record_events_with_messages(
+        &self,
+        payment_id: Uuid,
+        events: &[PaymentEvent],
+        messages: &[OutboxMessage],
+    ) -> Result<(), anyhow::Error> {
+        let recorded = self
+            .events
+            .append_with_messages(payment_id, events, messages)
             .await?;
 
+        if let Some(payment) = recorded {
+            self.store_payment_status(&payment.to_status()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn payment_events(&self, payment_id: Uuid) -> Result<Vec<StoredEvent>, anyhow::Error> {
+        self.events.history(payment_id).await
+    }
+
+    /// Replays a payment's events over its `payments` row or Redis view.
+    pub async fn rebuild_projection(
+        &self,
+        payment_id: Uuid,
+    ) -> Result<Option<PaymentAggregate>, anyhow::Error> {
+        let payment = self.events.rebuild(payment_id).await?;
+
+        if let Some(payment) = payment.as_ref().filter(|p| p.provider == PROVIDER_SANDBOX) {
+            self.store_payment_status(&payment.to_status()).await?;
+        }
+
+        Ok(payment)
+    }
+
+    /// Rebuilds the projections of every payment with recorded events.
+    /// Returns the number of payments rebuilt.
+    pub async fn rebuild_projections(&self) -> Result<usize, anyhow::Error> {
+        let mut rebuilt = 0;
+        let mut after = None;
+
+        loop {
+            let batch = self.events.payment_ids(after, 500).await?;
+            let Some(last) = batch.last().copied() else {
+                break;
+            };
+
+            for payment_id in batch {
+                self.rebuild_projection(payment_id).await?;
+                rebuilt += 1;
+            }
+            after = Some(last);
+        }
+
+        info!("Rebuilt projections of {} payments", rebuilt);
+        Ok(rebuilt)
+    }
+
+    /// Books the simulated gateway's fee against the settled amount. The charge
+    /// already went through, so a failure here is logged rather than returned.
+    async fn record_sandbox_fee(
+        &self,
+        payment_id: &str,
+        transaction_id: &str,
+        conversion: &FxConversion,
+    ) {
+        let Ok(payment_uuid) = Uuid::parse_str(payment_id) else {
+            return;
+        };
+
+        let fee = self
+            .sandbox_fees
+            .capture(payment_uuid, transaction_id, &conversion.settlement);
+        if let Err(e) = self.fees.record(fee).await {
+            warn!("Failed to record fee for payment {}: {}", payment_id, e);
+        }
+    }
+
+    /// Converts the requested amount into the settlement currency at the current rate.
+    async fn convert_to_settlement(
+        &self,
+        request: &PaymentRequest,
+    ) -> Result<FxConversion, anyhow::Error> {
+        let currency = request
+            .currency
+            .as_deref()
+            .unwrap_or_else(|| self.fx.settlement_currency());
+
+        Ok(self.fx.convert(request.total_amount, currency).await?)
+    }
+
+    /// Loads the vaulted method referenced by the request, if any.
+    async fn resolve_payment_method(
+        &self,
+        request: &PaymentRequest,
+    ) -> Result<Option<SavedPaymentMethod>, anyhow::Error> {
+        match &request.payment_method_id {
+            Some(id) => Ok(Some(self.vault.resolve(&request.user_id, id).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn call_gateway_authorize(
+        &self,
+        _request: &PaymentRequest,
+        saved_method: Option<&SavedPaymentMethod>,
+    ) -> Result<Result<String, PaymentDeclined>, anyhow::Error> {
+        // Simulate placing a hold with the external payment gateway
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        if let Some(method) = saved_method {
+            info!(
+                "Charging saved {} method {} ({}, token {})",
+                method.provider,
+                method.id,
+                method.brand,
+                method.masked_token()
+            );
+
+            if let Some(declined) = Self::simulated_decline(method) {
+                return Ok(Err(declined));
+            }
+        }
+
+        let transaction_id = format!("AUTH-{}", Uuid::new_v4());
+        info!("Payment gateway placed authorization: {}", transaction_id);
+
+        Ok(Ok(transaction_id))
+    }
+
+    /// Saved methods whose token is `tok_decline_<code>` are declined with that
+    /// code, so declines and dunning can be exercised against the sandbox.
+    fn simulated_decline(method: &SavedPaymentMethod) -> Option<PaymentDeclined> {
+        let code = method.provider_token.strip_prefix("tok_decline_")?;
+        info!("Payment gateway declined method {}: {}", method.id, code);
+
+        Some(PaymentDeclined {
+            code: code.to_string(),
+            message: format!("Card declined ({})", code),
+        })
+    }
+
+    async fn call_gateway_capture(&self, transaction_id: &str) -> Result<(), anyhow::Error> {
+        // Simulate capturing a hold with the external payment gateway
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        info!("Payment gateway captured authorization: {}", transaction_id);
+        Ok(())
+    }
+
+    async fn call_gateway_void(&self, transaction_id: &str) -> Result<(), anyhow::Error> {
+        // Simulate releasing a hold with the external payment gateway
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        info!("Payment gateway voided authorization: {}", transaction_id);
         Ok(())
     }
 
     async fn call_payment_gateway(
         &self,
         _request: &PaymentRequest,
-    ) -> Result<String, anyhow::Error> {
+        saved_method: Option<&SavedPaymentMethod>,
+    ) -> Result<Result<String, PaymentDeclined>, anyhow::Error> {
         // Simulate external payment gateway call
         // In production, this would call a real payment processor
         
         tokio::time::sleep(Duration::from_millis(100)).await;
 
+        if let Some(method) = saved_method {
+            info!(
+                "Charging saved {} method {} ({}, token {})",
+                method.provider,
+                method.id,
+                method.brand,
+                method.masked_token()
+            );
+
+            if let Some(declined) = Self::simulated_decline(method) {
+                return Ok(Err(declined));
+            }
+        }
+
         // Always succeed in this simulation
         // In production, add proper error handling
         let transaction_id = format!("TXN-{}", Uuid::new_v4());
         info!("Payment gateway returned transaction: {}", transaction_id);
 
-        Ok(transaction_id)
+        Ok(Ok(transaction_id))
     }
 
     async fn store_payment_status(
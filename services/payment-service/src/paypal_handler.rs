@@ -1,10 +1,23 @@
 use anyhow::{Context, Result};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::sync::Arc;
 use uuid::Uuid;
 use base64::{Engine as _, engine::general_purpose};
 
+use crate::attempts::{AttemptStore, NewAttempt, ATTEMPT_FAILED, ATTEMPT_PENDING};
+use crate::disputes::{
+    DisputeService, DisputeUpdate, STATUS_CLOSED, STATUS_LOST, STATUS_NEEDS_RESPONSE,
+    STATUS_UNDER_REVIEW, STATUS_WON,
+};
+use crate::events::{self, EventStore, PaymentDetails, PaymentEvent};
+use crate::fees::{self, FeeStore, NewFee, KIND_CAPTURE, KIND_REFUND};
+use crate::fx::FxService;
+use crate::ledger::{self, Journal};
+use crate::models::Money;
+use crate::pending::PendingResolution;
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct PaymentRecord {
     pub id: Uuid,
@@ -59,10 +72,22 @@ pub struct PayPalHandler {
     api_base: String,
     pool: PgPool,
     client: reqwest::Client,
+    disputes: Arc<DisputeService>,
+    fx: FxService,
+    fees: FeeStore,
+    attempts: AttemptStore,
+    events: EventStore,
 }
 
 impl PayPalHandler {
-    pub fn new(client_id: String, client_secret: String, sandbox: bool, pool: PgPool) -> Self {
+    pub fn new(
+        client_id: String,
+        client_secret: String,
+        sandbox: bool,
+        pool: PgPool,
+        disputes: Arc<DisputeService>,
+        fx: FxService,
+    ) -> Self {
         let api_base = if sandbox {
             "https://api-m.sandbox.paypal.com".to_string()
         } else {
@@ -73,8 +98,13 @@ impl PayPalHandler {
             client_id,
             client_secret,
             api_base,
-            pool,
+            pool: pool.clone(),
             client: reqwest::Client::new(),
+            disputes,
+            fx,
+            fees: FeeStore::new(pool.clone()),
+            attempts: AttemptStore::new(pool.clone()),
+            events: EventStore::new(pool),
         }
     }
 
@@ -106,25 +136,72 @@ impl PayPalHandler {
     ) -> Result<PaymentResponse> {
         // Create payment record
         let payment_id = Uuid::new_v4();
-        let now = Utc::now();
+        let conversion = self.fx.convert(req.amount, &req.currency).await?;
 
-        sqlx::query(
-            r#"
-            INSERT INTO payments (id, order_id, amount, currency, status, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
-            "#,
-        )
-        .bind(&payment_id)
-        .bind(&req.order_id)
-        .bind(req.amount)
-        .bind(&req.currency)
-        .bind("pending")
-        .bind(now)
-        .bind(now)
-        .execute(&self.pool)
-        .await
-        .context("Failed to insert payment record")?;
+        let created = PaymentDetails::new("paypal", Some(&req.order_id), None, &conversion);
+        self.events
+            .append(payment_id, &[PaymentEvent::Created(created)])
+            .await
+            .context("Failed to insert payment record")?;
+
+        let intent_id = self
+            .attempts
+            .open_intent(Some(&req.order_id), None, &conversion.presentment)
+            .await?;
+        let started_at = Utc::now();
+
+        let result = self.send_create_order(&req).await;
+
+        let (status, response_summary, decline_code) = match &result {
+            Ok(order) => (
+                ATTEMPT_PENDING,
+                serde_json::json!({ "paypal_order_id": order.id, "status": order.status }),
+                None,
+            ),
+            // PayPal error bodies carry a machine-readable `name`, e.g. INSTRUMENT_DECLINED
+            Err(e) => (
+                ATTEMPT_FAILED,
+                serde_json::json!({ "error": e.to_string() }),
+                Some(Self::error_name(&e.to_string())),
+            ),
+        };
+        let attempt = NewAttempt {
+            intent_id,
+            payment_id,
+            gateway: "paypal".to_string(),
+            operation: "create_order".to_string(),
+            status: status.to_string(),
+            request_summary: serde_json::json!({ "amount": req.amount, "currency": req.currency }),
+            response_summary,
+            decline_code,
+            started_at,
+            completed_at: Utc::now(),
+        };
+        if let Err(e) = self.attempts.record(attempt).await {
+            tracing::warn!("Failed to record PayPal attempt for payment {}: {}", payment_id, e);
+        }
+
+        let paypal_order = result?;
+
+        // Find approval URL
+        let approval_url = paypal_order.links.iter()
+            .find(|link| link.rel == "approve")
+            .map(|link| link.href.clone());
+
+        // Update payment with PayPal order ID
+        let started = PaymentEvent::CheckoutStarted {
+            reference: paypal_order.id.clone(),
+        };
+        self.events.append(payment_id, &[started]).await?;
 
+        Ok(PaymentResponse {
+            payment_id: payment_id.to_string(),
+            approval_url,
+            status: paypal_order.status.to_lowercase(),
+        })
+    }
+
+    async fn send_create_order(&self, req: &CreatePaymentRequest) -> Result<PayPalOrderResponse> {
         // Get access token
         let access_token = self.get_access_token().await?;
 
@@ -162,30 +239,15 @@ impl PayPalHandler {
         let paypal_order: PayPalOrderResponse = response.json().await
             .context("Failed to parse PayPal response")?;
 
-        // Find approval URL
-        let approval_url = paypal_order.links.iter()
-            .find(|link| link.rel == "approve")
-            .map(|link| link.href.clone());
-
-        // Update payment with PayPal order ID
-        sqlx::query(
-            r#"
-            UPDATE payments 
-            SET paypal_order_id = $1, updated_at = $2
-            WHERE id = $3
-            "#,
-        )
-        .bind(&paypal_order.id)
-        .bind(Utc::now())
-        .bind(&payment_id)
-        .execute(&self.pool)
-        .await?;
+        Ok(paypal_order)
+    }
 
-        Ok(PaymentResponse {
-            payment_id: payment_id.to_string(),
-            approval_url,
-            status: paypal_order.status.to_lowercase(),
-        })
+    fn error_name(error: &str) -> String {
+        error
+            .split_once('{')
+            .and_then(|(_, rest)| serde_json::from_str::<serde_json::Value>(&format!("{{{}", rest)).ok())
+            .and_then(|body| body["name"].as_str().map(String::from))
+            .unwrap_or_else(|| "processing_error".to_string())
     }
 
     pub async fn capture_order(&self, paypal_order_id: &str) -> Result<()> {
@@ -204,23 +266,62 @@ impl PayPalHandler {
             anyhow::bail!("PayPal capture error: {}", error);
         }
 
+        let capture: serde_json::Value = response.json().await
+            .context("Failed to parse PayPal capture response")?;
+        let capture = &capture["purchase_units"][0]["payments"]["captures"][0];
+        let capture_id = capture["id"].as_str();
+
         // Update payment status
-        sqlx::query(
-            r#"
-            UPDATE payments 
-            SET status = 'completed', updated_at = $1
-            WHERE paypal_order_id = $2
-            "#,
-        )
-        .bind(Utc::now())
-        .bind(paypal_order_id)
-        .execute(&self.pool)
-        .await?;
+        self.handle_payment_completed(paypal_order_id, capture_id).await?;
+
+        // The order is captured either way; the webhook reports the breakdown again
+        if let Err(e) = self.record_capture_fees(capture).await {
+            tracing::warn!("Failed to record PayPal fees for order {}: {}", paypal_order_id, e);
+        }
 
         tracing::info!("Payment captured for PayPal order: {}", paypal_order_id);
         Ok(())
     }
 
+    /// Asks PayPal where an order stands after the buyer failed to come back
+    /// from the approval page. Approved orders are captured.
+    pub async fn reconcile_pending(&self, paypal_order_id: &str) -> Result<PendingResolution> {
+        let access_token = self.get_access_token().await?;
+
+        let response = self.client
+            .get(format!("{}/v2/checkout/orders/{}", self.api_base, paypal_order_id))
+            .header("Authorization", format!("Bearer {}", access_token))
+            .send()
+            .await
+            .context("Failed to fetch PayPal order")?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            // PayPal drops orders that were never approved
+            return Ok(PendingResolution::Abandoned);
+        }
+        if !response.status().is_success() {
+            let error = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            anyhow::bail!("PayPal API error: {}", error);
+        }
+
+        let order: serde_json::Value = response.json().await
+            .context("Failed to parse PayPal order")?;
+
+        match order["status"].as_str().unwrap_or("") {
+            "COMPLETED" => {
+                let capture = &order["purchase_units"][0]["payments"]["captures"][0];
+                self.handle_payment_completed(paypal_order_id, capture["id"].as_str()).await?;
+                self.record_capture_fees(capture).await?;
+                Ok(PendingResolution::Completed)
+            }
+            "APPROVED" => {
+                self.capture_order(paypal_order_id).await?;
+                Ok(PendingResolution::Completed)
+            }
+            _ => Ok(PendingResolution::Abandoned),
+        }
+    }
+
     pub async fn handle_webhook(&self, payload: &str) -> Result<()> {
         // Parse webhook event
         let event: serde_json::Value = serde_json::from_str(payload)?;
@@ -237,14 +338,22 @@ impl PayPalHandler {
             }
             "PAYMENT.CAPTURE.COMPLETED" => {
                 if let Some(order_id) = event["resource"]["supplementary_data"]["related_ids"]["order_id"].as_str() {
-                    self.handle_payment_completed(order_id).await?;
+                    self.handle_payment_completed(order_id, event["resource"]["id"].as_str()).await?;
                 }
+                self.record_capture_fees(&event["resource"]).await?;
+            }
+            "PAYMENT.CAPTURE.REFUNDED" => {
+                self.handle_refund(&event["resource"]).await?;
+                self.record_refund_fees(&event["resource"]).await?;
             }
             "PAYMENT.CAPTURE.DENIED" | "CHECKOUT.ORDER.VOIDED" => {
                 if let Some(order_id) = event["resource"]["id"].as_str() {
-                    self.handle_payment_failed(order_id).await?;
+                    self.handle_payment_failed(order_id, event_type).await?;
                 }
             }
+            "CUSTOMER.DISPUTE.CREATED" | "CUSTOMER.DISPUTE.UPDATED" | "CUSTOMER.DISPUTE.RESOLVED" => {
+                self.handle_dispute(&event["resource"]).await?;
+            }
             _ => {
                 tracing::info!("Unhandled event type: {}", event_type);
             }
@@ -253,37 +362,233 @@ impl PayPalHandler {
         Ok(())
     }
 
-    async fn handle_payment_completed(&self, paypal_order_id: &str) -> Result<()> {
-        sqlx::query(
+    /// Marks the payment completed and books the capture in the same transaction.
+    /// Repeated notifications for the same capture are booked once.
+    async fn handle_payment_completed(&self, paypal_order_id: &str, capture_id: Option<&str>) -> Result<()> {
+        let Some(payment_id) = self.payment_id_for_order(paypal_order_id).await? else {
+            tracing::warn!("PayPal order {} does not match a known payment", paypal_order_id);
+            return Ok(());
+        };
+
+        let mut tx = self.pool.begin().await?;
+
+        let captured = PaymentEvent::Captured {
+            reference: capture_id.map(String::from),
+        };
+        if let Some(payment) = events::append(&mut tx, payment_id, &captured).await? {
+            let journal = Journal::capture("paypal", payment_id, &Money::new(payment.amount, &payment.currency));
+            ledger::post(&mut tx, &journal).await?;
+        }
+        tx.commit().await?;
+
+        tracing::info!("Payment completed for PayPal order: {}", paypal_order_id);
+        Ok(())
+    }
+
+    async fn handle_payment_failed(&self, paypal_order_id: &str, reason: &str) -> Result<()> {
+        let Some(payment_id) = self.payment_id_for_order(paypal_order_id).await? else {
+            return Ok(());
+        };
+
+        let failed = PaymentEvent::Failed {
+            reason: reason.to_string(),
+        };
+        self.events.append(payment_id, &[failed]).await?;
+
+        tracing::info!("Payment failed for PayPal order: {}", paypal_order_id);
+        Ok(())
+    }
+
+    /// Records a completed refund against the refunded capture's payment.
+    async fn handle_refund(&self, refund: &serde_json::Value) -> Result<()> {
+        let (Some(refund_id), Some(amount)) = (
+            refund["id"].as_str(),
+            fees::paypal_money(&refund["amount"]),
+        ) else {
+            return Ok(());
+        };
+        if refund["status"].as_str() != Some("COMPLETED") {
+            return Ok(());
+        }
+
+        let Some(payment_id) = self.payment_id_for_refund(refund).await? else {
+            tracing::warn!("PayPal refund {} does not match a known payment", refund_id);
+            return Ok(());
+        };
+
+        let mut tx = self.pool.begin().await?;
+
+        let refunded = PaymentEvent::Refunded {
+            reference: refund_id.to_string(),
+            amount: amount.amount,
+            currency: amount.currency.clone(),
+        };
+        if events::append(&mut tx, payment_id, &refunded).await?.is_some() {
+            let journal = Journal::refund("paypal", Some(payment_id), refund_id, &amount);
+            ledger::post(&mut tx, &journal).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Records gross, fee and net from a capture's `seller_receivable_breakdown`.
+    async fn record_capture_fees(&self, capture: &serde_json::Value) -> Result<()> {
+        let (Some(capture_id), Some(breakdown)) = (
+            capture["id"].as_str(),
+            Self::parse_breakdown(&capture["seller_receivable_breakdown"]),
+        ) else {
+            return Ok(());
+        };
+        let (gross, fee, net) = breakdown;
+
+        self.fees
+            .record(NewFee {
+                payment_id: self.payment_id_for_capture(capture_id).await?,
+                provider: "paypal".to_string(),
+                kind: KIND_CAPTURE.to_string(),
+                provider_reference: capture_id.to_string(),
+                gross,
+                fee,
+                net,
+            })
+            .await
+    }
+
+    /// Records a refund's `seller_payable_breakdown`, where `paypal_fee` is the
+    /// part of the original fee PayPal gives back. Stored negated.
+    async fn record_refund_fees(&self, refund: &serde_json::Value) -> Result<()> {
+        let (Some(refund_id), Some(breakdown)) = (
+            refund["id"].as_str(),
+            Self::parse_breakdown(&refund["seller_payable_breakdown"]),
+        ) else {
+            return Ok(());
+        };
+        let (gross, fee, net) = breakdown;
+
+        let payment_id = self.payment_id_for_refund(refund).await?;
+
+        self.fees
+            .record(NewFee {
+                payment_id,
+                provider: "paypal".to_string(),
+                kind: KIND_REFUND.to_string(),
+                provider_reference: refund_id.to_string(),
+                gross: Money::new(-gross.amount, &gross.currency),
+                fee: Money::new(-fee.amount, &fee.currency),
+                net: Money::new(-net.amount, &net.currency),
+            })
+            .await
+    }
+
+    fn parse_breakdown(breakdown: &serde_json::Value) -> Option<(Money, Money, Money)> {
+        let gross = fees::paypal_money(&breakdown["gross_amount"])?;
+        let fee = fees::paypal_money(&breakdown["paypal_fee"])
+            .unwrap_or_else(|| Money::new(0.0, &gross.currency));
+        let net = fees::paypal_money(&breakdown["net_amount"])
+            .unwrap_or_else(|| Money::new(gross.amount - fee.amount, &gross.currency));
+        Some((gross, fee, net))
+    }
+
+    async fn payment_id_for_refund(&self, refund: &serde_json::Value) -> Result<Option<Uuid>> {
+        // The refunded capture is only referenced through the "up" link
+        let capture_id = refund["links"]
+            .as_array()
+            .and_then(|links| links.iter().find(|link| link["rel"] == "up"))
+            .and_then(|link| link["href"].as_str())
+            .and_then(|href| href.rsplit('/').next());
+
+        match capture_id {
+            Some(capture_id) => self.payment_id_for_capture(capture_id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn payment_id_for_order(&self, paypal_order_id: &str) -> Result<Option<Uuid>> {
+        let payment_id = sqlx::query_scalar::<_, Uuid>(
             r#"
-            UPDATE payments 
-            SET status = 'completed', updated_at = $1
-            WHERE paypal_order_id = $2
+            SELECT id FROM payments WHERE paypal_order_id = $1
             "#,
         )
-        .bind(Utc::now())
         .bind(paypal_order_id)
-        .execute(&self.pool)
+        .fetch_optional(&self.pool)
         .await?;
 
-        tracing::info!("Payment completed for PayPal order: {}", paypal_order_id);
-        Ok(())
+        Ok(payment_id)
     }
 
-    async fn handle_payment_failed(&self, paypal_order_id: &str) -> Result<()> {
-        sqlx::query(
+    async fn payment_id_for_capture(&self, capture_id: &str) -> Result<Option<Uuid>> {
+        let payment_id = sqlx::query_scalar::<_, Uuid>(
             r#"
-            UPDATE payments 
-            SET status = 'failed', updated_at = $1
-            WHERE paypal_order_id = $2
+            SELECT id FROM payments WHERE paypal_capture_id = $1
             "#,
         )
-        .bind(Utc::now())
-        .bind(paypal_order_id)
-        .execute(&self.pool)
+        .bind(capture_id)
+        .fetch_optional(&self.pool)
         .await?;
 
-        tracing::info!("Payment failed for PayPal order: {}", paypal_order_id);
+        Ok(payment_id)
+    }
+
+    async fn handle_dispute(&self, resource: &serde_json::Value) -> Result<()> {
+        let dispute_id = resource["dispute_id"]
+            .as_str()
+            .context("Dispute webhook without dispute_id")?;
+
+        // Disputes reference the capture, which we record when the order is captured
+        let linked = match resource["disputed_transactions"][0]["seller_transaction_id"].as_str() {
+            Some(capture_id) => sqlx::query_as::<_, (Uuid, String)>(
+                r#"
+                SELECT id, order_id FROM payments WHERE paypal_capture_id = $1
+                "#,
+            )
+            .bind(capture_id)
+            .fetch_optional(&self.pool)
+            .await?,
+            None => None,
+        };
+
+        if linked.is_none() {
+            tracing::warn!("PayPal dispute {} does not match a known payment", dispute_id);
+        }
+
+        let status = match resource["status"].as_str().unwrap_or("") {
+            "RESOLVED" => match resource["dispute_outcome"]["outcome_code"].as_str() {
+                Some("RESOLVED_SELLER_FAVOUR") => STATUS_WON,
+                Some("RESOLVED_BUYER_FAVOUR") => STATUS_LOST,
+                _ => STATUS_CLOSED,
+            },
+            "OPEN" | "WAITING_FOR_SELLER_RESPONSE" => STATUS_NEEDS_RESPONSE,
+            _ => STATUS_UNDER_REVIEW,
+        };
+
+        let amount = resource["dispute_amount"]["value"]
+            .as_str()
+            .and_then(|v| v.parse::<f64>().ok())
+            .unwrap_or(0.0);
+
+        let evidence_due_by = resource["seller_response_due_date"]
+            .as_str()
+            .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+            .map(|d| d.with_timezone(&Utc));
+
+        self.disputes
+            .record(DisputeUpdate {
+                provider: "paypal".to_string(),
+                provider_dispute_id: dispute_id.to_string(),
+                payment_id: linked.as_ref().map(|(id, _)| *id),
+                order_id: linked.map(|(_, order_id)| order_id),
+                amount,
+                currency: resource["dispute_amount"]["currency_code"]
+                    .as_str()
+                    .unwrap_or("USD")
+                    .to_string(),
+                reason: resource["reason"].as_str().map(String::from),
+                status: status.to_string(),
+                evidence_due_by,
+            })
+            .await?;
+
         Ok(())
     }
 
@@ -335,5 +640,19 @@ pub async fn init_db(pool: &PgPool) -> Result<()> {
     .await
     .context("Failed to create payments table")?;
 
+    // Settlement amount and the FX rate used, recorded for every payment
+    sqlx::query(
+        r#"
+        ALTER TABLE payments
+            ADD COLUMN IF NOT EXISTS settlement_amount DOUBLE PRECISION,
+            ADD COLUMN IF NOT EXISTS settlement_currency VARCHAR(3),
+            ADD COLUMN IF NOT EXISTS fx_rate DOUBLE PRECISION,
+            ADD COLUMN IF NOT EXISTS fx_rate_as_of TIMESTAMPTZ
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to add settlement columns to payments table")?;
+
     Ok(())
 }
@@ -0,0 +1,178 @@
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::attempts::AttemptStore;
+use crate::events::{EventStore, PaymentEvent};
+use crate::metrics::PAYMENT_METRICS;
+use crate::models::SagaEvent;
+use crate::outbox::OutboxMessage;
+use crate::paypal_handler::PayPalHandler;
+use crate::stripe_handler::StripeHandler;
+
+/// What the provider says about a payment that has been pending too long.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PendingResolution {
+    /// The buyer paid; the handler has already marked the payment completed
+    Completed,
+    /// The provider is still waiting on the payment method to settle
+    StillPending,
+    /// The buyer never finished checkout
+    Abandoned,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct PendingPayment {
+    id: Uuid,
+    order_id: String,
+    paypal_order_id: Option<String>,
+    stripe_session_id: Option<String>,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Default)]
+pub struct SweepOutcome {
+    pub completed: usize,
+    pub expired: usize,
+}
+
+/// Expires PayPal orders and Stripe checkout sessions the buyer walked away from.
+#[derive(Debug)]
+pub struct PendingPaymentSweeper {
+    pool: PgPool,
+    paypal: Arc<PayPalHandler>,
+    stripe: Arc<StripeHandler>,
+    attempts: AttemptStore,
+    events: EventStore,
+    max_age: chrono::Duration,
+}
+
+impl PendingPaymentSweeper {
+    pub fn new(
+        pool: PgPool,
+        paypal: Arc<PayPalHandler>,
+        stripe: Arc<StripeHandler>,
+        max_age: chrono::Duration,
+    ) -> Self {
+        Self {
+            attempts: AttemptStore::new(pool.clone()),
+            events: EventStore::new(pool.clone()),
+            pool,
+            paypal,
+            stripe,
+            max_age,
+        }
+    }
+
+    pub async fn sweep(&self) -> Result<SweepOutcome> {
+        // payments.created_at is a TIMESTAMP holding UTC
+        let cutoff = (Utc::now() - self.max_age).naive_utc();
+
+        let pending = sqlx::query_as::<_, PendingPayment>(
+            r#"
+            SELECT id, order_id, paypal_order_id, stripe_session_id, created_at
+            FROM payments
+            WHERE status = 'pending' AND created_at < $1
+            ORDER BY created_at
+            LIMIT 100
+            "#,
+        )
+        .bind(cutoff)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut outcome = SweepOutcome::default();
+
+        for payment in pending {
+            let resolution = match (&payment.paypal_order_id, &payment.stripe_session_id) {
+                (Some(paypal_order_id), _) => self.paypal.reconcile_pending(paypal_order_id).await,
+                (None, Some(session_id)) => self.stripe.reconcile_pending(session_id).await,
+                // The provider call failed before anything was created there
+                (None, None) => Ok(PendingResolution::Abandoned),
+            };
+
+            match resolution {
+                Ok(PendingResolution::Completed) => {
+                    info!("Pending payment {} was completed at the provider", payment.id);
+                    outcome.completed += 1;
+                }
+                Ok(PendingResolution::StillPending) => {}
+                Ok(PendingResolution::Abandoned) => match self.expire(&payment).await {
+                    Ok(true) => outcome.expired += 1,
+                    Ok(false) => {}
+                    Err(e) => warn!("Failed to expire payment {}: {}", payment.id, e),
+                },
+                Err(e) => warn!("Failed to check pending payment {}: {}", payment.id, e),
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    /// Marks the payment expired unless a webhook got there first, and queues
+    /// the answer to the order's saga with it so the order and its inventory
+    /// are released.
+    async fn expire(&self, payment: &PendingPayment) -> Result<bool> {
+        let replies = match self.attempts.saga_for_order(&payment.order_id).await? {
+            Some(saga_id) => {
+                let event = SagaEvent {
+                    saga_id: saga_id.clone(),
+                    order_id: payment.order_id.clone(),
+                    step: "PAYMENT_PROCESSED".to_string(),
+                    success: false,
+                    message: "Payment expired: checkout was abandoned".to_string(),
+                    data: serde_json::json!({
+                        "payment_id": payment.id,
+                        "reason": "expired"
+                    }),
+                    timestamp: Utc::now(),
+                };
+                vec![OutboxMessage::json("saga-response", &saga_id, &event)?]
+            }
+            None => Vec::new(),
+        };
+
+        // A webhook may have settled the payment since it was selected
+        let expired = self
+            .events
+            .append_with_messages(payment.id, &[PaymentEvent::Expired], &replies)
+            .await?;
+        if expired.is_none() {
+            return Ok(false);
+        }
+
+        PAYMENT_METRICS.payments_expired.inc();
+        info!(
+            "Expired payment {} for order {} pending since {}",
+            payment.id, payment.order_id, payment.created_at
+        );
+
+        Ok(true)
+    }
+}
+
+/// Periodically resolves provider payments left pending past the configured age.
+pub async fn run_pending_sweeper(sweeper: PendingPaymentSweeper, interval: Duration) {
+    info!(
+        "Pending payment sweeper started, interval {:?}, max age {}",
+        interval, sweeper.max_age
+    );
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        match sweeper.sweep().await {
+            Ok(SweepOutcome { completed: 0, expired: 0 }) => {}
+            Ok(outcome) => info!(
+                "Pending payment sweep: {} completed, {} expired",
+                outcome.completed, outcome.expired
+            ),
+            Err(e) => error!("Pending payment sweep failed: {}", e),
+        }
+    }
+}
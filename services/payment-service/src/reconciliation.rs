@@ -0,0 +1,751 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::events::{STATUS_COMPLETED, STATUS_REFUNDED};
+use crate::metrics::PAYMENT_METRICS;
+
+pub const PROVIDERS: [&str; 2] = ["paypal", "stripe"];
+
+pub const MISSING_LOCALLY: &str = "missing_locally";
+pub const MISSING_AT_PROVIDER: &str = "missing_at_provider";
+pub const AMOUNT_MISMATCH: &str = "amount_mismatch";
+pub const STATUS_MISMATCH: &str = "status_mismatch";
+
+const DISCREPANCY_KINDS: [&str; 4] = [MISSING_LOCALLY, MISSING_AT_PROVIDER, AMOUNT_MISMATCH, STATUS_MISMATCH];
+
+/// Splits CSV text into records. Handles quoted fields, doubled quotes and
+/// line breaks inside quotes, which is all provider reports use.
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, in_quotes) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', true) => in_quotes = false,
+            ('"', false) if field.is_empty() => in_quotes = true,
+            (',', false) => record.push(std::mem::take(&mut field)),
+            ('\r', false) => {}
+            ('\n', false) => {
+                record.push(std::mem::take(&mut field));
+                if record.iter().any(|f| !f.is_empty()) {
+                    records.push(std::mem::take(&mut record));
+                }
+                record.clear();
+            }
+            _ => field.push(c),
+        }
+    }
+
+    record.push(field);
+    if record.iter().any(|f| !f.is_empty()) {
+        records.push(record);
+    }
+    records
+}
+
+/// A settled charge as the provider reports it.
+#[derive(Debug, Clone)]
+struct ReportRow {
+    reference: String,
+    amount: f64,
+    currency: String,
+    status: String,
+    day: Option<NaiveDate>,
+}
+
+/// Column names of a provider's report, first match wins.
+struct ReportFormat {
+    reference: &'static [&'static str],
+    amount: &'static [&'static str],
+    currency: &'static [&'static str],
+    status: &'static [&'static str],
+    kind: &'static [&'static str],
+    date: &'static [&'static str],
+    /// Values of the kind column that are charges; other rows are skipped
+    charge_kinds: &'static [&'static str],
+}
+
+/// Stripe's itemized balance change report.
+const STRIPE_FORMAT: ReportFormat = ReportFormat {
+    reference: &["payment_intent_id", "source_id"],
+    amount: &["gross", "amount"],
+    currency: &["currency"],
+    status: &["status"],
+    kind: &["reporting_category", "type"],
+    date: &["created_utc", "created"],
+    charge_kinds: &["charge", "payment"],
+};
+
+/// PayPal's activity download / transaction detail report.
+const PAYPAL_FORMAT: ReportFormat = ReportFormat {
+    reference: &["Transaction ID", "transaction_id"],
+    amount: &["Gross", "Gross Transaction Amount"],
+    currency: &["Currency", "Gross Transaction Currency"],
+    status: &["Status", "Transaction Status"],
+    kind: &["Type", "Transaction Event Code"],
+    date: &["Date", "Transaction Initiation Date"],
+    charge_kinds: &["Express Checkout Payment", "PayPal Checkout Payment", "T0006", "T0001"],
+};
+
+fn format_for(provider: &str) -> &'static ReportFormat {
+    match provider {
+        "paypal" => &PAYPAL_FORMAT,
+        _ => &STRIPE_FORMAT,
+    }
+}
+
+fn parse_amount(value: &str) -> Option<f64> {
+    value.trim().replace(',', "").parse().ok()
+}
+
+fn parse_day(value: &str) -> Option<NaiveDate> {
+    let value = value.trim();
+    let date_part = value.split([' ', 'T']).next().unwrap_or(value);
+
+    NaiveDate::parse_from_str(date_part, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(date_part, "%m/%d/%Y"))
+        .ok()
+        .or_else(|| {
+            // Stripe exports can carry unix timestamps
+            value
+                .parse::<i64>()
+                .ok()
+                .and_then(|secs| DateTime::from_timestamp(secs, 0))
+                .map(|ts| ts.date_naive())
+        })
+}
+
+/// Reads the charge rows of a report. Returns the rows and the number of
+/// non-charge rows (refunds, fees, transfers) that were skipped.
+fn parse_report(provider: &str, text: &str) -> Result<(Vec<ReportRow>, usize), ReconciliationError> {
+    let format = format_for(provider);
+    let mut records = parse_csv(text).into_iter();
+    let header = records
+        .next()
+        .ok_or_else(|| ReconciliationError::Invalid("report is empty".to_string()))?;
+
+    let column = |names: &[&str]| {
+        header
+            .iter()
+            .position(|h| names.iter().any(|n| h.trim().eq_ignore_ascii_case(n)))
+    };
+    let required = |names: &[&str]| {
+        column(names).ok_or_else(|| {
+            ReconciliationError::Invalid(format!("report has no {} column", names[0]))
+        })
+    };
+
+    let reference = required(format.reference)?;
+    let amount = required(format.amount)?;
+    let currency = required(format.currency)?;
+    let (status, kind, date) = (column(format.status), column(format.kind), column(format.date));
+
+    let mut rows = Vec::new();
+    let mut skipped = 0;
+
+    for (line, record) in records.enumerate() {
+        let get = |i: usize| record.get(i).map(|v| v.trim()).unwrap_or("");
+
+        if let Some(kind) = kind {
+            if !format.charge_kinds.iter().any(|k| get(kind).eq_ignore_ascii_case(k)) {
+                skipped += 1;
+                continue;
+            }
+        }
+        if get(reference).is_empty() {
+            skipped += 1;
+            continue;
+        }
+
+        let row_amount = parse_amount(get(amount)).ok_or_else(|| {
+            ReconciliationError::Invalid(format!("row {} has an invalid amount", line + 2))
+        })?;
+
+        rows.push(ReportRow {
+            reference: get(reference).to_string(),
+            amount: row_amount,
+            currency: get(currency).to_uppercase(),
+            status: status.map(|s| get(s).to_lowercase()).unwrap_or_default(),
+            day: date.and_then(|d| parse_day(get(d))),
+        });
+    }
+
+    Ok((rows, skipped))
+}
+
+/// Local statuses of a payment whose charge went through, refunded or not.
+fn is_collected(status: &str) -> bool {
+    matches!(status, STATUS_COMPLETED | STATUS_REFUNDED)
+}
+
+/// Provider statuses that mean the money was collected.
+fn is_settled(status: &str) -> bool {
+    matches!(status, "" | "completed" | "succeeded" | "success" | "available" | "s")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ReconciliationRun {
+    pub id: Uuid,
+    pub provider: String,
+    pub source: String,
+    pub period_start: Option<NaiveDate>,
+    pub period_end: Option<NaiveDate>,
+    pub rows_total: i32,
+    pub rows_skipped: i32,
+    pub rows_matched: i32,
+    pub discrepancies: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Discrepancy {
+    pub id: Uuid,
+    pub run_id: Uuid,
+    pub kind: String,
+    pub provider: String,
+    pub provider_reference: Option<String>,
+    pub payment_id: Option<Uuid>,
+    pub local_amount: Option<f64>,
+    pub reported_amount: Option<f64>,
+    pub currency: Option<String>,
+    pub local_status: Option<String>,
+    pub reported_status: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReconciliationReport {
+    #[serde(flatten)]
+    pub run: ReconciliationRun,
+    pub items: Vec<Discrepancy>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IngestReportQuery {
+    pub provider: String,
+    /// File name or other label for where the report came from
+    pub source: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListDiscrepanciesQuery {
+    pub provider: Option<String>,
+    pub kind: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReconciliationError {
+    #[error("Reconciliation run not found")]
+    NotFound,
+    #[error("Invalid settlement report: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for ReconciliationError {
+    fn from(e: sqlx::Error) -> Self {
+        ReconciliationError::Failed(e.into())
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct LocalPayment {
+    id: Uuid,
+    reference: String,
+    amount: f64,
+    currency: String,
+    status: String,
+}
+
+#[derive(Debug, Default)]
+struct NewDiscrepancy {
+    kind: &'static str,
+    provider_reference: Option<String>,
+    payment_id: Option<Uuid>,
+    local_amount: Option<f64>,
+    reported_amount: Option<f64>,
+    currency: Option<String>,
+    local_status: Option<String>,
+    reported_status: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReconciliationService {
+    pool: PgPool,
+}
+
+impl ReconciliationService {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Matches a provider report against the `payments` table and stores the
+    /// differences. Local payments are only reported missing at the provider
+    /// when they fall inside the days the report covers.
+    pub async fn ingest(
+        &self,
+        provider: &str,
+        source: &str,
+        report: &str,
+    ) -> Result<ReconciliationReport, ReconciliationError> {
+        if !PROVIDERS.contains(&provider) {
+            return Err(ReconciliationError::Invalid(format!("unknown provider '{}'", provider)));
+        }
+
+        let (rows, skipped) = parse_report(provider, report)?;
+        let period_start = rows.iter().filter_map(|r| r.day).min();
+        let period_end = rows.iter().filter_map(|r| r.day).max();
+
+        let local = self.local_payments(provider, &rows, period_start, period_end).await?;
+        let by_reference: HashMap<&str, &LocalPayment> =
+            local.iter().map(|p| (p.reference.as_str(), p)).collect();
+
+        let mut found = Vec::new();
+        let mut matched = 0;
+        let mut reported = HashSet::new();
+
+        for row in &rows {
+            reported.insert(row.reference.as_str());
+
+            let Some(payment) = by_reference.get(row.reference.as_str()) else {
+                found.push(NewDiscrepancy {
+                    kind: MISSING_LOCALLY,
+                    provider_reference: Some(row.reference.clone()),
+                    reported_amount: Some(row.amount),
+                    currency: Some(row.currency.clone()),
+                    reported_status: Some(row.status.clone()),
+                    ..Default::default()
+                });
+                continue;
+            };
+
+            let discrepancy = |kind| NewDiscrepancy {
+                kind,
+                provider_reference: Some(row.reference.clone()),
+                payment_id: Some(payment.id),
+                local_amount: Some(payment.amount),
+                reported_amount: Some(row.amount),
+                currency: Some(row.currency.clone()),
+                local_status: Some(payment.status.clone()),
+                reported_status: Some(row.status.clone()),
+            };
+
+            let amount_differs = (payment.amount - row.amount).abs() >= 0.005
+                || !payment.currency.eq_ignore_ascii_case(&row.currency);
+            let status_differs = is_settled(&row.status) != is_collected(&payment.status);
+
+            if amount_differs {
+                found.push(discrepancy(AMOUNT_MISMATCH));
+            }
+            if status_differs {
+                found.push(discrepancy(STATUS_MISMATCH));
+            }
+            if !amount_differs && !status_differs {
+                matched += 1;
+            }
+        }
+
+        for payment in &local {
+            if is_collected(&payment.status) && !reported.contains(payment.reference.as_str()) {
+                found.push(NewDiscrepancy {
+                    kind: MISSING_AT_PROVIDER,
+                    provider_reference: Some(payment.reference.clone()),
+                    payment_id: Some(payment.id),
+                    local_amount: Some(payment.amount),
+                    currency: Some(payment.currency.clone()),
+                    local_status: Some(payment.status.clone()),
+                    ..Default::default()
+                });
+            }
+        }
+
+        let report = self
+            .store_run(provider, source, period_start, period_end, &rows, skipped, matched, found)
+            .await?;
+        update_metrics(provider, &report.items);
+
+        info!(
+            "Reconciled {} report {}: {} rows, {} matched, {} discrepancies",
+            provider, source, report.run.rows_total, report.run.rows_matched, report.run.discrepancies
+        );
+        Ok(report)
+    }
+
+    /// Payments referenced by the report, plus completed payments created in
+    /// the report's period.
+    async fn local_payments(
+        &self,
+        provider: &str,
+        rows: &[ReportRow],
+        period_start: Option<NaiveDate>,
+        period_end: Option<NaiveDate>,
+    ) -> Result<Vec<LocalPayment>> {
+        let references: Vec<String> = rows.iter().map(|r| r.reference.clone()).collect();
+        let column = match provider {
+            "paypal" => "paypal_capture_id",
+            _ => "stripe_payment_intent",
+        };
+
+        let payments = sqlx::query_as::<_, LocalPayment>(&format!(
+            r#"
+            SELECT id, {column} AS reference, amount::DOUBLE PRECISION AS amount, currency, status
+            FROM payments
+            WHERE {column} = ANY($1)
+               OR ({column} IS NOT NULL AND status IN ('completed', 'refunded')
+                   AND created_at::DATE BETWEEN $2 AND $3)
+            "#
+        ))
+        .bind(&references)
+        .bind(period_start)
+        .bind(period_end)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(payments)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn store_run(
+        &self,
+        provider: &str,
+        source: &str,
+        period_start: Option<NaiveDate>,
+        period_end: Option<NaiveDate>,
+        rows: &[ReportRow],
+        skipped: usize,
+        matched: usize,
+        found: Vec<NewDiscrepancy>,
+    ) -> Result<ReconciliationReport> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        let run = sqlx::query_as::<_, ReconciliationRun>(
+            r#"
+            INSERT INTO reconciliation_runs
+                (id, provider, source, period_start, period_end, rows_total, rows_skipped,
+                 rows_matched, discrepancies, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(provider)
+        .bind(source)
+        .bind(period_start)
+        .bind(period_end)
+        .bind(rows.len() as i32)
+        .bind(skipped as i32)
+        .bind(matched as i32)
+        .bind(found.len() as i32)
+        .bind(now)
+        .fetch_one(&mut *tx)
+        .await
+        .context("Failed to store reconciliation run")?;
+
+        let mut items = Vec::with_capacity(found.len());
+        for d in found {
+            let item = sqlx::query_as::<_, Discrepancy>(
+                r#"
+                INSERT INTO reconciliation_discrepancies
+                    (id, run_id, kind, provider, provider_reference, payment_id, local_amount,
+                     reported_amount, currency, local_status, reported_status, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+                RETURNING *
+                "#,
+            )
+            .bind(Uuid::new_v4())
+            .bind(run.id)
+            .bind(d.kind)
+            .bind(provider)
+            .bind(&d.provider_reference)
+            .bind(d.payment_id)
+            .bind(d.local_amount)
+            .bind(d.reported_amount)
+            .bind(&d.currency)
+            .bind(&d.local_status)
+            .bind(&d.reported_status)
+            .bind(now)
+            .fetch_one(&mut *tx)
+            .await?;
+
+            items.push(item);
+        }
+
+        tx.commit().await?;
+        Ok(ReconciliationReport { run, items })
+    }
+
+    pub async fn list_runs(&self) -> Result<Vec<ReconciliationRun>> {
+        let runs = sqlx::query_as::<_, ReconciliationRun>(
+            r#"
+            SELECT * FROM reconciliation_runs ORDER BY created_at DESC LIMIT 100
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(runs)
+    }
+
+    pub async fn get_run(&self, id: Uuid) -> Result<ReconciliationReport, ReconciliationError> {
+        let run = sqlx::query_as::<_, ReconciliationRun>(
+            r#"
+            SELECT * FROM reconciliation_runs WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(ReconciliationError::NotFound)?;
+
+        let items = sqlx::query_as::<_, Discrepancy>(
+            r#"
+            SELECT * FROM reconciliation_discrepancies WHERE run_id = $1 ORDER BY kind, created_at
+            "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(ReconciliationReport { run, items })
+    }
+
+    /// Discrepancies from the latest run of each provider.
+    pub async fn open_discrepancies(&self, query: &ListDiscrepanciesQuery) -> Result<Vec<Discrepancy>> {
+        let items = sqlx::query_as::<_, Discrepancy>(
+            r#"
+            SELECT d.* FROM reconciliation_discrepancies d
+            WHERE d.run_id IN (
+                SELECT DISTINCT ON (provider) id FROM reconciliation_runs
+                ORDER BY provider, created_at DESC
+            )
+              AND ($1::VARCHAR IS NULL OR d.provider = $1)
+              AND ($2::VARCHAR IS NULL OR d.kind = $2)
+            ORDER BY d.provider, d.kind, d.created_at
+            "#,
+        )
+        .bind(&query.provider)
+        .bind(&query.kind)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(items)
+    }
+}
+
+/// Publishes the latest run's counts per kind, so alerts can fire on any non-zero value.
+fn update_metrics(provider: &str, items: &[Discrepancy]) {
+    PAYMENT_METRICS.reconciliation_runs.with_label_values(&[provider]).inc();
+
+    for kind in DISCREPANCY_KINDS {
+        let count = items.iter().filter(|d| d.kind == kind).count();
+        PAYMENT_METRICS
+            .reconciliation_discrepancies
+            .with_label_values(&[provider, kind])
+            .set(count as i64);
+    }
+}
+
+/// The provider a report file belongs to, from its name (`stripe-2024-01-31.csv`).
+fn provider_for_file(path: &Path) -> Option<&'static str> {
+    let name = path.file_name()?.to_str()?.to_lowercase();
+    if !name.ends_with(".csv") {
+        return None;
+    }
+    PROVIDERS.into_iter().find(|p| name.starts_with(p))
+}
+
+/// Ingests report files dropped into `inbox`, moving each into `processed/`
+/// or `failed/` so it is only read once.
+pub async fn run_reconciliation_inbox(
+    service: Arc<ReconciliationService>,
+    inbox: PathBuf,
+    interval: Duration,
+) {
+    info!("Reconciliation inbox {:?} watched, interval {:?}", inbox, interval);
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        if let Err(e) = process_inbox(&service, &inbox).await {
+            error!("Failed to scan reconciliation inbox {:?}: {}", inbox, e);
+        }
+    }
+}
+
+async fn process_inbox(service: &ReconciliationService, inbox: &Path) -> Result<()> {
+    let mut entries = tokio::fs::read_dir(inbox).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let Some(provider) = provider_for_file(&path) else {
+            continue;
+        };
+        let source = entry.file_name().to_string_lossy().to_string();
+
+        let contents = tokio::fs::read_to_string(&path).await?;
+        let outcome = match service.ingest(provider, &source, &contents).await {
+            Ok(_) => "processed",
+            Err(ReconciliationError::Failed(e)) => {
+                // Leave the file for the next scan
+                warn!("Failed to reconcile {}: {}", source, e);
+                continue;
+            }
+            Err(e) => {
+                warn!("Rejected settlement report {}: {}", source, e);
+                "failed"
+            }
+        };
+
+        let target = inbox.join(outcome);
+        tokio::fs::create_dir_all(&target).await?;
+        tokio::fs::rename(&path, target.join(&source)).await?;
+    }
+
+    Ok(())
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS reconciliation_runs (
+            id UUID PRIMARY KEY,
+            provider VARCHAR(50) NOT NULL,
+            source VARCHAR(255) NOT NULL,
+            period_start DATE,
+            period_end DATE,
+            rows_total INTEGER NOT NULL,
+            rows_skipped INTEGER NOT NULL,
+            rows_matched INTEGER NOT NULL,
+            discrepancies INTEGER NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create reconciliation_runs table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS reconciliation_discrepancies (
+            id UUID PRIMARY KEY,
+            run_id UUID NOT NULL REFERENCES reconciliation_runs(id),
+            kind VARCHAR(50) NOT NULL,
+            provider VARCHAR(50) NOT NULL,
+            provider_reference VARCHAR(255),
+            payment_id UUID,
+            local_amount DOUBLE PRECISION,
+            reported_amount DOUBLE PRECISION,
+            currency VARCHAR(3),
+            local_status VARCHAR(50),
+            reported_status VARCHAR(50),
+            created_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create reconciliation_discrepancies table")?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_reconciliation_discrepancies_run_id
+        ON reconciliation_discrepancies (run_id)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(records: &[&[&str]]) -> Vec<Vec<String>> {
+        records
+            .iter()
+            .map(|r| r.iter().map(|f| f.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn splits_plain_records() {
+        assert_eq!(
+            parse_csv("id,amount\nch_1,10.00\nch_2,,\n"),
+            rows(&[&["id", "amount"], &["ch_1", "10.00"], &["ch_2", "", ""]])
+        );
+    }
+
+    #[test]
+    fn reads_the_last_record_without_a_trailing_newline() {
+        assert_eq!(parse_csv("id\nch_1"), rows(&[&["id"], &["ch_1"]]));
+    }
+
+    #[test]
+    fn handles_crlf_line_endings_and_blank_lines() {
+        assert_eq!(
+            parse_csv("id,amount\r\n\r\nch_1,10.00\r\n,\r\n"),
+            rows(&[&["id", "amount"], &["ch_1", "10.00"]])
+        );
+    }
+
+    #[test]
+    fn keeps_commas_and_doubled_quotes_inside_quotes() {
+        assert_eq!(
+            parse_csv("\"Gross\",\"Name\"\n\"1,234.50\",\"The \"\"Best\"\" Shop\"\n"),
+            rows(&[&["Gross", "Name"], &["1,234.50", "The \"Best\" Shop"]])
+        );
+    }
+
+    #[test]
+    fn keeps_line_breaks_inside_quotes() {
+        assert_eq!(
+            parse_csv("id,note\nch_1,\"line one\nline two\"\nch_2,\"a\r\nb\"\n"),
+            rows(&[
+                &["id", "note"],
+                &["ch_1", "line one\nline two"],
+                &["ch_2", "a\r\nb"],
+            ])
+        );
+    }
+
+    #[test]
+    fn strips_a_leading_byte_order_mark() {
+        assert_eq!(
+            parse_csv("\u{feff}\"Transaction ID\",Gross\nTX1,5.00\n"),
+            rows(&[&["Transaction ID", "Gross"], &["TX1", "5.00"]])
+        );
+    }
+
+    #[test]
+    fn keeps_quotes_in_the_middle_of_an_unquoted_field() {
+        assert_eq!(parse_csv("5\" screen,1\n"), rows(&[&["5\" screen", "1"]]));
+    }
+
+    #[test]
+    fn returns_nothing_for_empty_input() {
+        assert!(parse_csv("").is_empty());
+        assert!(parse_csv("\u{feff}\n\n").is_empty());
+    }
+}
@@ -0,0 +1,856 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::message::{Message, OwnedMessage};
+use rdkafka::{Offset, TopicPartitionList};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::attempts::{ATTEMPT_AUTHORIZED, ATTEMPT_SUCCEEDED};
+use crate::authorization::{STATUS_AUTHORIZED, STATUS_CAPTURED, STATUS_EXPIRED, STATUS_VOIDED};
+use crate::dunning::{KIND_SAGA_PAYMENT, RETRY_CANCELED, RETRY_GAVE_UP, RETRY_SCHEDULED};
+use crate::kafka::header_map;
+use crate::models::{PaymentProcessCommand, SagaEvent};
+use crate::payment::PaymentService;
+use crate::wire_format::{decode_saga_event, CONTENT_TYPE_HEADER};
+
+/// The only topic replays read.
+pub const REPLAY_TOPIC: &str = "payment-process";
+/// Where the answers to the saga's payment requests are queued
+const SAGA_RESPONSE_TOPIC: &str = "saga-response";
+const STEP_PAYMENT_PROCESSED: &str = "PAYMENT_PROCESSED";
+
+pub const STATUS_RUNNING: &str = "running";
+pub const STATUS_COMPLETED: &str = "completed";
+pub const STATUS_FAILED: &str = "failed";
+
+/// What a dry run would have done.
+pub const OUTCOME_WOULD_PROCESS: &str = "would_process";
+pub const OUTCOME_AUTHORIZED: &str = "authorized";
+/// Declined and answered to the saga
+pub const OUTCOME_DECLINED: &str = "declined";
+/// Declined and handed to dunning
+pub const OUTCOME_RETRYING: &str = "retrying";
+/// Processing errored; the order may be replayed again
+pub const OUTCOME_FAILED: &str = "failed";
+pub const OUTCOME_INVALID: &str = "invalid";
+pub const OUTCOME_ALREADY_PAID: &str = "already_paid";
+/// Dunning already owns the order's payment
+pub const OUTCOME_RETRY_SCHEDULED: &str = "retry_scheduled";
+/// The saga was already told the payment failed
+pub const OUTCOME_ALREADY_FAILED: &str = "already_failed";
+/// The order's hold was released or its retries canceled, e.g. by the saga's
+/// compensation
+pub const OUTCOME_COMPENSATED: &str = "compensated";
+/// An earlier replay, or an earlier message in this one, took the order
+pub const OUTCOME_ALREADY_REPLAYED: &str = "already_replayed";
+/// Claimed and being processed; left behind if the replay dies midway, until
+/// the claim goes stale
+const OUTCOME_PROCESSING: &str = "processing";
+
+/// A claim still processing this long after it was taken belongs to a replay
+/// that died midway, and the order may be claimed again
+const STALE_CLAIM_AFTER_MINUTES: i64 = 15;
+
+/// Wait for a message before checking whether the partitions are done.
+const POLL_TIMEOUT: Duration = Duration::from_secs(5);
+/// A replay that reads nothing for this long gives up on what is left.
+const MAX_IDLE: Duration = Duration::from_secs(60);
+/// How long metadata, watermark and offset lookups may block.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+pub struct StartReplayRequest {
+    /// Start at the first message at or after this time
+    pub from_timestamp: Option<DateTime<Utc>>,
+    /// Stop before the first message at or after this time
+    pub to_timestamp: Option<DateTime<Utc>>,
+    /// Start at this offset on every selected partition
+    pub from_offset: Option<i64>,
+    /// Last offset to replay, inclusive, on every selected partition
+    pub to_offset: Option<i64>,
+    /// Defaults to every partition of the topic
+    pub partitions: Option<Vec<i32>>,
+    /// Report what would happen to each message without processing any
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Also charge orders whose saga was already told the payment failed, or
+    /// that were compensated
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Offsets of one partition a replay reads, `end` exclusive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartitionRange {
+    pub partition: i32,
+    pub start: i64,
+    pub end: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Replay {
+    pub id: Uuid,
+    pub topic: String,
+    pub consumer_group: String,
+    pub dry_run: bool,
+    pub force: bool,
+    pub from_timestamp: Option<DateTime<Utc>>,
+    pub to_timestamp: Option<DateTime<Utc>>,
+    pub from_offset: Option<i64>,
+    pub to_offset: Option<i64>,
+    /// Offsets read from each partition, fixed when the replay starts
+    pub ranges: serde_json::Value,
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ReplayMessage {
+    pub id: Uuid,
+    pub replay_id: Uuid,
+    pub source_partition: i32,
+    pub source_offset: i64,
+    pub order_id: Option<String>,
+    pub saga_id: Option<String>,
+    pub outcome: String,
+    pub detail: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplayReport {
+    #[serde(flatten)]
+    pub replay: Replay,
+    /// Number of messages with each outcome
+    pub outcomes: BTreeMap<String, i64>,
+    pub items: Vec<ReplayMessage>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayError {
+    #[error("Replay not found")]
+    NotFound,
+    #[error("Invalid replay request: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for ReplayError {
+    fn from(e: sqlx::Error) -> Self {
+        ReplayError::Failed(e.into())
+    }
+}
+
+impl From<rdkafka::error::KafkaError> for ReplayError {
+    fn from(e: rdkafka::error::KafkaError) -> Self {
+        ReplayError::Failed(e.into())
+    }
+}
+
+/// Where an order's payment stands, as far as replaying its request goes.
+#[derive(Debug, sqlx::FromRow)]
+struct OrderPayment {
+    authorization_status: Option<String>,
+    intent_status: Option<String>,
+    retry_status: Option<String>,
+    /// Whether the last answer to the saga's payment request, while the
+    /// outbox still holds it, reported success
+    last_reply_success: Option<bool>,
+}
+
+impl OrderPayment {
+    /// Why the order must not be charged again, if it mustn't.
+    fn settled(&self) -> Option<&'static str> {
+        if matches!(
+            self.authorization_status.as_deref(),
+            Some(STATUS_AUTHORIZED | STATUS_CAPTURED)
+        ) {
+            return Some(OUTCOME_ALREADY_PAID);
+        }
+        // Also covers a gateway hold whose authorization row was never written
+        if matches!(
+            self.intent_status.as_deref(),
+            Some(ATTEMPT_AUTHORIZED | ATTEMPT_SUCCEEDED)
+        ) {
+            return Some(OUTCOME_ALREADY_PAID);
+        }
+        if self.retry_status.as_deref() == Some(RETRY_SCHEDULED) {
+            return Some(OUTCOME_RETRY_SCHEDULED);
+        }
+        None
+    }
+
+    /// Why the saga is already done with the order's payment, if it is. Only
+    /// a forced replay charges such an order.
+    fn closed(&self) -> Option<&'static str> {
+        if matches!(
+            self.authorization_status.as_deref(),
+            Some(STATUS_VOIDED | STATUS_EXPIRED)
+        ) || self.retry_status.as_deref() == Some(RETRY_CANCELED)
+        {
+            return Some(OUTCOME_COMPENSATED);
+        }
+        if self.retry_status.as_deref() == Some(RETRY_GAVE_UP)
+            || self.last_reply_success == Some(false)
+        {
+            return Some(OUTCOME_ALREADY_FAILED);
+        }
+        None
+    }
+}
+
+/// Reprocesses `payment-process` requests from a time or offset range, e.g.
+/// after a gateway outage failed them. Each replay reads in a consumer group
+/// of its own, so the saga consumer's offsets are untouched, and runs its
+/// messages one at a time in the background.
+///
+/// Orders that are already paid or in dunning are skipped, and each order
+/// is charged by at most one replay, so replaying an overlapping range
+/// twice does not double charge. Orders the saga already gave up on or
+/// compensated are skipped too, unless the replay is forced.
+#[derive(Debug, Clone)]
+pub struct ReplayService {
+    pool: PgPool,
+    brokers: String,
+    group_id: String,
+    payment_service: Arc<PaymentService>,
+}
+
+impl ReplayService {
+    /// Replay groups are named after `group_id`, the saga consumer's group.
+    pub fn new(
+        pool: PgPool,
+        brokers: &str,
+        group_id: &str,
+        payment_service: Arc<PaymentService>,
+    ) -> Self {
+        Self {
+            pool,
+            brokers: brokers.to_string(),
+            group_id: group_id.to_string(),
+            payment_service,
+        }
+    }
+
+    /// Fixes the offsets to read and starts reading them. The replay only
+    /// covers messages already on the topic when it starts.
+    pub async fn start(&self, request: StartReplayRequest) -> Result<Replay, ReplayError> {
+        validate(&request)?;
+
+        let id = Uuid::new_v4();
+        let consumer_group = format!("{}.replay.{}", self.group_id, id);
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &self.brokers)
+            .set("group.id", &consumer_group)
+            .set("enable.auto.commit", "false")
+            .set("isolation.level", "read_committed")
+            .create()?;
+
+        let ranges = tokio::task::block_in_place(|| resolve_ranges(&consumer, &request))?;
+
+        let replay = sqlx::query_as::<_, Replay>(
+            r#"
+            INSERT INTO payment_replays
+                (id, topic, consumer_group, dry_run, force, from_timestamp, to_timestamp,
+                 from_offset, to_offset, ranges, status, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            RETURNING *
+            "#,
+        )
+        .bind(id)
+        .bind(REPLAY_TOPIC)
+        .bind(&consumer_group)
+        .bind(request.dry_run)
+        .bind(request.force)
+        .bind(request.from_timestamp)
+        .bind(request.to_timestamp)
+        .bind(request.from_offset)
+        .bind(request.to_offset)
+        .bind(serde_json::to_value(&ranges).context("Failed to serialize replay ranges")?)
+        .bind(STATUS_RUNNING)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        info!(
+            "Starting {}{}replay {} of {} in group {}",
+            if request.force { "forced " } else { "" },
+            if request.dry_run { "dry-run " } else { "" },
+            id,
+            ranges
+                .iter()
+                .map(|r| format!("{}[{}] {}..{}", REPLAY_TOPIC, r.partition, r.start, r.end))
+                .collect::<Vec<_>>()
+                .join(", "),
+            consumer_group
+        );
+
+        let service = self.clone();
+        let (dry_run, force) = (request.dry_run, request.force);
+        tokio::spawn(async move {
+            service.run(id, consumer, ranges, dry_run, force).await;
+        });
+
+        Ok(replay)
+    }
+
+    pub async fn list(&self) -> Result<Vec<Replay>, ReplayError> {
+        let replays = sqlx::query_as::<_, Replay>(
+            r#"
+            SELECT * FROM payment_replays ORDER BY created_at DESC LIMIT 100
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(replays)
+    }
+
+    pub async fn report(&self, id: Uuid) -> Result<ReplayReport, ReplayError> {
+        let replay = sqlx::query_as::<_, Replay>(
+            r#"
+            SELECT * FROM payment_replays WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(ReplayError::NotFound)?;
+
+        let items = sqlx::query_as::<_, ReplayMessage>(
+            r#"
+            SELECT * FROM payment_replay_messages
+            WHERE replay_id = $1
+            ORDER BY source_partition, source_offset
+            "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut outcomes = BTreeMap::new();
+        for item in &items {
+            *outcomes.entry(item.outcome.clone()).or_insert(0) += 1;
+        }
+
+        Ok(ReplayReport {
+            replay,
+            outcomes,
+            items,
+        })
+    }
+
+    async fn run(
+        &self,
+        id: Uuid,
+        consumer: StreamConsumer,
+        ranges: Vec<PartitionRange>,
+        dry_run: bool,
+        force: bool,
+    ) {
+        let result = self.consume(id, &consumer, &ranges, dry_run, force).await;
+
+        let (status, error) = match &result {
+            Ok(()) => {
+                info!("Replay {} completed", id);
+                (STATUS_COMPLETED, None)
+            }
+            Err(e) => {
+                error!("Replay {} failed: {:#}", id, e);
+                (STATUS_FAILED, Some(format!("{:#}", e)))
+            }
+        };
+
+        let finished = sqlx::query(
+            r#"
+            UPDATE payment_replays SET status = $1, error = $2, completed_at = $3 WHERE id = $4
+            "#,
+        )
+        .bind(status)
+        .bind(error)
+        .bind(Utc::now())
+        .bind(id)
+        .execute(&self.pool)
+        .await;
+        if let Err(e) = finished {
+            error!("Failed to record the end of replay {}: {}", id, e);
+        }
+    }
+
+    /// Reads every range to its end, committing to the replay's own group as
+    /// it goes.
+    async fn consume(
+        &self,
+        id: Uuid,
+        consumer: &StreamConsumer,
+        ranges: &[PartitionRange],
+        dry_run: bool,
+        force: bool,
+    ) -> Result<()> {
+        let mut remaining: HashMap<i32, i64> = HashMap::new();
+        let mut assignment = TopicPartitionList::new();
+        for range in ranges.iter().filter(|r| r.start < r.end) {
+            remaining.insert(range.partition, range.end);
+            assignment.add_partition_offset(REPLAY_TOPIC, range.partition, Offset::Offset(range.start))?;
+        }
+        if remaining.is_empty() {
+            return Ok(());
+        }
+        consumer.assign(&assignment)?;
+
+        let mut idle = Duration::ZERO;
+        while !remaining.is_empty() {
+            let message = match tokio::time::timeout(POLL_TIMEOUT, consumer.recv()).await {
+                Ok(Ok(message)) => message.detach(),
+                Ok(Err(e)) => {
+                    warn!("Replay {} failed to receive a message: {}", id, e);
+                    continue;
+                }
+                Err(_) => {
+                    // Transaction markers and compaction leave gaps, so a
+                    // partition may end without a message just before its end
+                    for p in consumer.position()?.elements_for_topic(REPLAY_TOPIC) {
+                        if let (Offset::Offset(position), Some(end)) =
+                            (p.offset(), remaining.get(&p.partition()))
+                        {
+                            if position >= *end {
+                                remaining.remove(&p.partition());
+                            }
+                        }
+                    }
+
+                    idle += POLL_TIMEOUT;
+                    if !remaining.is_empty() && idle >= MAX_IDLE {
+                        let mut partitions: Vec<i32> = remaining.keys().copied().collect();
+                        partitions.sort_unstable();
+                        anyhow::bail!(
+                            "no messages for {}s before the end of partitions {:?}",
+                            idle.as_secs(),
+                            partitions
+                        );
+                    }
+                    continue;
+                }
+            };
+            idle = Duration::ZERO;
+
+            let Some(&end) = remaining.get(&message.partition()) else {
+                continue;
+            };
+            if message.offset() >= end {
+                remaining.remove(&message.partition());
+                continue;
+            }
+
+            self.replay_message(id, &message, dry_run, force).await?;
+
+            if message.offset() + 1 >= end {
+                remaining.remove(&message.partition());
+            }
+            let mut offsets = TopicPartitionList::new();
+            offsets.add_partition_offset(
+                REPLAY_TOPIC,
+                message.partition(),
+                Offset::Offset(message.offset() + 1),
+            )?;
+            if let Err(e) = consumer.commit(&offsets, CommitMode::Async) {
+                warn!("Replay {} failed to commit its progress: {}", id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs one message through the checks and, unless dry, the payment.
+    /// Only database errors are returned; everything else is an outcome.
+    async fn replay_message(
+        &self,
+        id: Uuid,
+        message: &OwnedMessage,
+        dry_run: bool,
+        force: bool,
+    ) -> Result<()> {
+        let headers = header_map(message);
+        let content_type = headers.get(CONTENT_TYPE_HEADER).map(String::as_str);
+        let event = match decode_saga_event(content_type, message.payload().unwrap_or_default()) {
+            Ok(event) => event,
+            Err(e) => {
+                let detail = format!("Failed to parse saga event: {:#}", e);
+                return self.record(id, message, None, OUTCOME_INVALID, Some(&detail)).await;
+            }
+        };
+
+        // Rejected when first consumed, and the saga was told then
+        if let Err(invalid) = PaymentProcessCommand::from_event(&event) {
+            let detail = invalid.to_string();
+            return self.record(id, message, Some(&event), OUTCOME_INVALID, Some(&detail)).await;
+        }
+
+        let payment = self.order_payment(&event.order_id).await?;
+        if let Some(outcome) = payment.settled() {
+            return self.record(id, message, Some(&event), outcome, None).await;
+        }
+        if let Some(outcome) = payment.closed().filter(|_| !force) {
+            return self.record(id, message, Some(&event), outcome, None).await;
+        }
+        if dry_run {
+            return self
+                .record(id, message, Some(&event), OUTCOME_WOULD_PROCESS, None)
+                .await;
+        }
+
+        let Some(claim) = self.claim(id, message, &event).await? else {
+            return self
+                .record(id, message, Some(&event), OUTCOME_ALREADY_REPLAYED, None)
+                .await;
+        };
+
+        info!(
+            "Replay {} processing {}[{}] offset {} for order {}",
+            id,
+            message.topic(),
+            message.partition(),
+            message.offset(),
+            event.order_id
+        );
+        let order_id = event.order_id.clone();
+        let (outcome, detail) = match self.payment_service.process_saga_payment(event).await {
+            Ok(()) => {
+                let payment = self.order_payment(&order_id).await?;
+                let outcome = match payment.settled() {
+                    Some(OUTCOME_ALREADY_PAID) => OUTCOME_AUTHORIZED,
+                    Some(OUTCOME_RETRY_SCHEDULED) => OUTCOME_RETRYING,
+                    _ => OUTCOME_DECLINED,
+                };
+                (outcome, None)
+            }
+            Err(e) => (OUTCOME_FAILED, Some(format!("{:#}", e))),
+        };
+
+        sqlx::query(
+            r#"
+            UPDATE payment_replay_messages SET outcome = $1, detail = $2 WHERE id = $3
+            "#,
+        )
+        .bind(outcome)
+        .bind(detail)
+        .bind(claim)
+        .execute(&self.pool)
+        .await
+        .context("Failed to record replay outcome")?;
+
+        Ok(())
+    }
+
+    async fn order_payment(&self, order_id: &str) -> Result<OrderPayment> {
+        let payment = sqlx::query_as::<_, OrderPayment>(
+            r#"
+            SELECT
+                (SELECT status FROM payment_authorizations
+                 WHERE order_id = $1 ORDER BY created_at DESC LIMIT 1) AS authorization_status,
+                (SELECT status FROM payment_intents WHERE order_id = $1) AS intent_status,
+                (SELECT status FROM payment_retries
+                 WHERE kind = $2 AND reference = $1) AS retry_status,
+                (SELECT (payload::JSONB ->> 'success')::BOOLEAN FROM outbox_messages
+                 WHERE topic = $3 AND payload::JSONB ->> 'order_id' = $1
+                   AND payload::JSONB ->> 'step' = $4
+                 ORDER BY id DESC LIMIT 1) AS last_reply_success
+            "#,
+        )
+        .bind(order_id)
+        .bind(KIND_SAGA_PAYMENT)
+        .bind(SAGA_RESPONSE_TOPIC)
+        .bind(STEP_PAYMENT_PROCESSED)
+        .fetch_one(&self.pool)
+        .await
+        .context("Failed to look up order payment")?;
+
+        Ok(payment)
+    }
+
+    /// Takes the order for this replay. Returns `None` when a replay already
+    /// charged it or is charging it. A claim left processing by a replay that
+    /// died is marked failed and taken over.
+    async fn claim(&self, id: Uuid, message: &OwnedMessage, event: &SagaEvent) -> Result<Option<Uuid>> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        let abandoned = sqlx::query(
+            r#"
+            UPDATE payment_replay_messages SET outcome = $1, detail = $2
+            WHERE order_id = $3 AND outcome = $4 AND created_at <= $5
+            "#,
+        )
+        .bind(OUTCOME_FAILED)
+        .bind("Abandoned by a replay that stopped before recording an outcome")
+        .bind(&event.order_id)
+        .bind(OUTCOME_PROCESSING)
+        .bind(now - chrono::Duration::minutes(STALE_CLAIM_AFTER_MINUTES))
+        .execute(&mut *tx)
+        .await
+        .context("Failed to release abandoned replay claim")?;
+        if abandoned.rows_affected() > 0 {
+            warn!(
+                "Replay {} taking over order {} from a replay that stopped processing it",
+                id, event.order_id
+            );
+        }
+
+        let claim = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            INSERT INTO payment_replay_messages
+                (id, replay_id, source_partition, source_offset, order_id, saga_id, outcome, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT DO NOTHING
+            RETURNING id
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(id)
+        .bind(message.partition())
+        .bind(message.offset())
+        .bind(&event.order_id)
+        .bind(&event.saga_id)
+        .bind(OUTCOME_PROCESSING)
+        .bind(now)
+        .fetch_optional(&mut *tx)
+        .await
+        .context("Failed to claim order for replay")?;
+
+        tx.commit().await?;
+        Ok(claim)
+    }
+
+    async fn record(
+        &self,
+        id: Uuid,
+        message: &OwnedMessage,
+        event: Option<&SagaEvent>,
+        outcome: &str,
+        detail: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO payment_replay_messages
+                (id, replay_id, source_partition, source_offset, order_id, saga_id, outcome,
+                 detail, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(id)
+        .bind(message.partition())
+        .bind(message.offset())
+        .bind(event.map(|e| e.order_id.as_str()))
+        .bind(event.map(|e| e.saga_id.as_str()))
+        .bind(outcome)
+        .bind(detail)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await
+        .context("Failed to record replayed message")?;
+
+        Ok(())
+    }
+}
+
+fn validate(request: &StartReplayRequest) -> Result<(), ReplayError> {
+    match (request.from_timestamp, request.from_offset) {
+        (None, None) => {
+            return Err(ReplayError::Invalid(
+                "from_timestamp or from_offset is required".to_string(),
+            ))
+        }
+        (Some(_), Some(_)) => {
+            return Err(ReplayError::Invalid(
+                "give from_timestamp or from_offset, not both".to_string(),
+            ))
+        }
+        _ => {}
+    }
+    if request.to_timestamp.is_some() && request.to_offset.is_some() {
+        return Err(ReplayError::Invalid(
+            "give to_timestamp or to_offset, not both".to_string(),
+        ));
+    }
+    if let (Some(from), Some(to)) = (request.from_timestamp, request.to_timestamp) {
+        if to <= from {
+            return Err(ReplayError::Invalid("to_timestamp must be after from_timestamp".to_string()));
+        }
+    }
+    if let Some(from) = request.from_offset {
+        if from < 0 {
+            return Err(ReplayError::Invalid("from_offset must not be negative".to_string()));
+        }
+        if request.to_offset.is_some_and(|to| to < from) {
+            return Err(ReplayError::Invalid("to_offset must not be before from_offset".to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Turns the request into offsets on each partition, ending no later than
+/// the high watermark so messages arriving during the replay are left alone.
+fn resolve_ranges(
+    consumer: &StreamConsumer,
+    request: &StartReplayRequest,
+) -> Result<Vec<PartitionRange>, ReplayError> {
+    let metadata = consumer.fetch_metadata(Some(REPLAY_TOPIC), LOOKUP_TIMEOUT)?;
+    let existing: Vec<i32> = metadata
+        .topics()
+        .iter()
+        .filter(|t| t.name() == REPLAY_TOPIC)
+        .flat_map(|t| t.partitions().iter().map(|p| p.id()))
+        .collect();
+
+    let partitions = match &request.partitions {
+        Some(partitions) => {
+            if let Some(unknown) = partitions.iter().find(|p| !existing.contains(p)) {
+                return Err(ReplayError::Invalid(format!(
+                    "{} has no partition {}",
+                    REPLAY_TOPIC, unknown
+                )));
+            }
+            partitions.clone()
+        }
+        None => existing,
+    };
+    if partitions.is_empty() {
+        return Err(ReplayError::Invalid(format!("{} has no partitions", REPLAY_TOPIC)));
+    }
+
+    let from_times = match request.from_timestamp {
+        Some(from) => Some(offsets_for_time(consumer, &partitions, from)?),
+        None => None,
+    };
+    let to_times = match request.to_timestamp {
+        Some(to) => Some(offsets_for_time(consumer, &partitions, to)?),
+        None => None,
+    };
+
+    let mut ranges = Vec::new();
+    for partition in partitions {
+        let (low, high) = consumer.fetch_watermarks(REPLAY_TOPIC, partition, LOOKUP_TIMEOUT)?;
+
+        let start = match (&from_times, request.from_offset) {
+            (Some(offsets), _) => offsets.get(&partition).copied().unwrap_or(high),
+            (None, Some(offset)) => offset,
+            (None, None) => low,
+        };
+        let end = match (&to_times, request.to_offset) {
+            (Some(offsets), _) => offsets.get(&partition).copied().unwrap_or(high),
+            (None, Some(offset)) => offset + 1,
+            (None, None) => high,
+        };
+
+        ranges.push(PartitionRange {
+            partition,
+            // Messages before the low watermark are gone
+            start: start.clamp(low, high),
+            end: end.clamp(low, high),
+        });
+    }
+
+    Ok(ranges)
+}
+
+/// First offset at or after `at` on each partition; partitions with no such
+/// message are left out.
+fn offsets_for_time(
+    consumer: &StreamConsumer,
+    partitions: &[i32],
+    at: DateTime<Utc>,
+) -> Result<HashMap<i32, i64>, ReplayError> {
+    let mut times = TopicPartitionList::new();
+    for partition in partitions {
+        times.add_partition_offset(REPLAY_TOPIC, *partition, Offset::Offset(at.timestamp_millis()))?;
+    }
+
+    let offsets = consumer.offsets_for_times(times, LOOKUP_TIMEOUT)?;
+    Ok(offsets
+        .elements_for_topic(REPLAY_TOPIC)
+        .iter()
+        .filter_map(|p| match p.offset() {
+            Offset::Offset(offset) => Some((p.partition(), offset)),
+            _ => None,
+        })
+        .collect())
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payment_replays (
+            id UUID PRIMARY KEY,
+            topic VARCHAR(255) NOT NULL,
+            consumer_group VARCHAR(255) NOT NULL,
+            dry_run BOOLEAN NOT NULL,
+            force BOOLEAN NOT NULL DEFAULT FALSE,
+            from_timestamp TIMESTAMPTZ,
+            to_timestamp TIMESTAMPTZ,
+            from_offset BIGINT,
+            to_offset BIGINT,
+            ranges JSONB NOT NULL,
+            status VARCHAR(20) NOT NULL,
+            error TEXT,
+            created_at TIMESTAMPTZ NOT NULL,
+            completed_at TIMESTAMPTZ
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payment_replays table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS payment_replay_messages (
+            id UUID PRIMARY KEY,
+            replay_id UUID NOT NULL REFERENCES payment_replays(id),
+            source_partition INTEGER NOT NULL,
+            source_offset BIGINT NOT NULL,
+            order_id VARCHAR(255),
+            saga_id VARCHAR(255),
+            outcome VARCHAR(30) NOT NULL,
+            detail TEXT,
+            created_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create payment_replay_messages table")?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_payment_replay_messages_replay
+        ON payment_replay_messages (replay_id, source_partition, source_offset)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // An order is charged by one replay at most; failed attempts don't count
+    sqlx::query(
+        r#"
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_payment_replay_messages_claim
+        ON payment_replay_messages (order_id)
+        WHERE outcome IN ('processing', 'authorized', 'declined', 'retrying')
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,324 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use tracing::info;
+
+pub const SCHEMA_TYPE_PROTOBUF: &str = "PROTOBUF";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldDef {
+    pub number: u32,
+    pub name: String,
+    /// Scalar type such as `string` or `int64`, or the name of another
+    /// message in the schema
+    pub kind: String,
+    pub optional: bool,
+    #[serde(default)]
+    pub repeated: bool,
+}
+
+impl FieldDef {
+    pub fn new(number: u32, name: &str, kind: &str) -> Self {
+        Self {
+            number,
+            name: name.to_string(),
+            kind: kind.to_string(),
+            optional: false,
+            repeated: false,
+        }
+    }
+
+    pub fn optional(number: u32, name: &str, kind: &str) -> Self {
+        Self {
+            optional: true,
+            ..Self::new(number, name, kind)
+        }
+    }
+
+    pub fn repeated(number: u32, name: &str, kind: &str) -> Self {
+        Self {
+            repeated: true,
+            ..Self::new(number, name, kind)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageDef {
+    pub name: String,
+    pub fields: Vec<FieldDef>,
+}
+
+/// A Protobuf schema as this service writes it. The first message is the
+/// record itself; any others are types its fields use.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SchemaDef {
+    /// Subject it is registered under, `{package}.{record}`
+    pub subject: String,
+    pub package: String,
+    pub messages: Vec<MessageDef>,
+}
+
+impl SchemaDef {
+    /// The schema as `.proto` source, which is what the registry serves.
+    pub fn to_proto(&self) -> String {
+        let mut proto = format!("syntax = \"proto3\";\npackage {};\n", self.package);
+
+        for message in &self.messages {
+            proto.push_str(&format!("\nmessage {} {{\n", message.name));
+            for field in &message.fields {
+                let label = if field.repeated {
+                    "repeated "
+                } else if field.optional {
+                    "optional "
+                } else {
+                    ""
+                };
+                proto.push_str(&format!(
+                    "  {}{} {} = {};\n",
+                    label, field.kind, field.name, field.number
+                ));
+            }
+            proto.push_str("}\n");
+        }
+
+        proto
+    }
+
+    /// Reasons data written with `previous` can't be read with this schema or
+    /// the other way round. Fields may come and go, but a field number keeps
+    /// its type for good and no message may disappear.
+    pub fn incompatibilities(&self, previous: &SchemaDef) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for old_message in &previous.messages {
+            let Some(message) = self.messages.iter().find(|m| m.name == old_message.name) else {
+                problems.push(format!("message {} was removed", old_message.name));
+                continue;
+            };
+
+            let fields: HashMap<u32, &FieldDef> =
+                message.fields.iter().map(|f| (f.number, f)).collect();
+            for old_field in &old_message.fields {
+                if let Some(field) = fields.get(&old_field.number) {
+                    if field.kind != old_field.kind {
+                        problems.push(format!(
+                            "{}.{} (field {}) changed type from {} to {}",
+                            message.name, field.name, field.number, old_field.kind, field.kind
+                        ));
+                    } else if field.repeated != old_field.repeated {
+                        problems.push(format!(
+                            "{}.{} (field {}) changed whether it is repeated",
+                            message.name, field.name, field.number
+                        ));
+                    }
+                }
+            }
+        }
+
+        problems
+    }
+}
+
+/// A schema version as served by the registry, in the Confluent REST shape.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct RegisteredSchema {
+    pub id: i32,
+    pub subject: String,
+    pub version: i32,
+    #[serde(rename = "schemaType")]
+    pub schema_type: String,
+    pub schema: String,
+    #[serde(skip)]
+    pub definition: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SchemaError {
+    #[error("Schema not found")]
+    NotFound,
+    #[error("Invalid schema request: {0}")]
+    Invalid(String),
+    #[error("Schema for {subject} is incompatible with version {version}: {reasons}")]
+    Incompatible {
+        subject: String,
+        version: i32,
+        reasons: String,
+    },
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for SchemaError {
+    fn from(e: sqlx::Error) -> Self {
+        SchemaError::Failed(e.into())
+    }
+}
+
+/// Stand-in for a Confluent schema registry, shared by every instance
+/// through Postgres. Ids are global across subjects, as in Confluent.
+#[derive(Debug, Clone)]
+pub struct SchemaRegistry {
+    pool: PgPool,
+}
+
+impl SchemaRegistry {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Registers the schema as the subject's next version unless it matches
+    /// the latest one. Every earlier version must stay compatible.
+    pub async fn register(&self, schema: &SchemaDef) -> Result<RegisteredSchema, SchemaError> {
+        let mut tx = self.pool.begin().await?;
+
+        // One registration per subject at a time, so versions stay contiguous
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))")
+            .bind(&schema.subject)
+            .execute(&mut *tx)
+            .await?;
+
+        let versions = sqlx::query_as::<_, RegisteredSchema>(
+            r#"
+            SELECT * FROM schema_registry WHERE subject = $1 ORDER BY version
+            "#,
+        )
+        .bind(&schema.subject)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let proto = schema.to_proto();
+        if let Some(latest) = versions.last().filter(|latest| latest.schema == proto) {
+            return Ok(latest.clone());
+        }
+
+        for registered in &versions {
+            let previous: SchemaDef = serde_json::from_value(registered.definition.clone())
+                .with_context(|| format!("Unreadable schema {}", registered.id))?;
+            let problems = schema.incompatibilities(&previous);
+            if !problems.is_empty() {
+                return Err(SchemaError::Incompatible {
+                    subject: schema.subject.clone(),
+                    version: registered.version,
+                    reasons: problems.join("; "),
+                });
+            }
+        }
+
+        let registered = sqlx::query_as::<_, RegisteredSchema>(
+            r#"
+            INSERT INTO schema_registry (subject, version, schema_type, schema, definition, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *
+            "#,
+        )
+        .bind(&schema.subject)
+        .bind(versions.last().map_or(1, |latest| latest.version + 1))
+        .bind(SCHEMA_TYPE_PROTOBUF)
+        .bind(&proto)
+        .bind(serde_json::to_value(schema).context("Failed to serialize schema")?)
+        .bind(Utc::now())
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        info!(
+            "Registered {} version {} as schema {}",
+            registered.subject, registered.version, registered.id
+        );
+        Ok(registered)
+    }
+
+    pub async fn subjects(&self) -> Result<Vec<String>, SchemaError> {
+        let subjects = sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT DISTINCT subject FROM schema_registry ORDER BY subject
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(subjects)
+    }
+
+    pub async fn versions(&self, subject: &str) -> Result<Vec<i32>, SchemaError> {
+        let versions = sqlx::query_scalar::<_, i32>(
+            r#"
+            SELECT version FROM schema_registry WHERE subject = $1 ORDER BY version
+            "#,
+        )
+        .bind(subject)
+        .fetch_all(&self.pool)
+        .await?;
+
+        if versions.is_empty() {
+            return Err(SchemaError::NotFound);
+        }
+        Ok(versions)
+    }
+
+    /// `version` is a number or `latest`.
+    pub async fn version(&self, subject: &str, version: &str) -> Result<RegisteredSchema, SchemaError> {
+        let version: Option<i32> = match version {
+            "latest" => None,
+            number => Some(
+                number
+                    .parse()
+                    .map_err(|_| SchemaError::Invalid(format!("bad version {}", number)))?,
+            ),
+        };
+
+        sqlx::query_as::<_, RegisteredSchema>(
+            r#"
+            SELECT * FROM schema_registry
+            WHERE subject = $1 AND ($2::INTEGER IS NULL OR version = $2)
+            ORDER BY version DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(subject)
+        .bind(version)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(SchemaError::NotFound)
+    }
+
+    pub async fn by_id(&self, id: i32) -> Result<RegisteredSchema, SchemaError> {
+        sqlx::query_as::<_, RegisteredSchema>(
+            r#"
+            SELECT * FROM schema_registry WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(SchemaError::NotFound)
+    }
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_registry (
+            id SERIAL PRIMARY KEY,
+            subject VARCHAR(255) NOT NULL,
+            version INTEGER NOT NULL,
+            schema_type VARCHAR(20) NOT NULL,
+            schema TEXT NOT NULL,
+            definition JSONB NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL,
+            UNIQUE (subject, version)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create schema_registry table")?;
+
+    Ok(())
+}
@@ -1,11 +1,24 @@
 use anyhow::{Context, Result};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::sync::Arc;
 use uuid::Uuid;
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
 
+use crate::attempts::{AttemptStore, NewAttempt, ATTEMPT_FAILED, ATTEMPT_PENDING};
+use crate::disputes::{
+    DisputeService, DisputeUpdate, STATUS_CLOSED, STATUS_LOST, STATUS_NEEDS_RESPONSE,
+    STATUS_UNDER_REVIEW, STATUS_WON,
+};
+use crate::events::{self, EventStore, PaymentDetails, PaymentEvent};
+use crate::fees::{self, FeeStore, KIND_CAPTURE, KIND_REFUND};
+use crate::fx::FxService;
+use crate::ledger::{self, Journal};
+use crate::models::Money;
+use crate::pending::PendingResolution;
+
 type HmacSha256 = Hmac<Sha256>;
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
@@ -49,15 +62,31 @@ pub struct StripeHandler {
     webhook_secret: String,
     pool: PgPool,
     client: reqwest::Client,
+    disputes: Arc<DisputeService>,
+    fx: FxService,
+    fees: FeeStore,
+    attempts: AttemptStore,
+    events: EventStore,
 }
 
 impl StripeHandler {
-    pub fn new(api_key: String, webhook_secret: String, pool: PgPool) -> Self {
+    pub fn new(
+        api_key: String,
+        webhook_secret: String,
+        pool: PgPool,
+        disputes: Arc<DisputeService>,
+        fx: FxService,
+    ) -> Self {
         Self {
             api_key,
             webhook_secret,
-            pool,
+            pool: pool.clone(),
             client: reqwest::Client::new(),
+            disputes,
+            fx,
+            fees: FeeStore::new(pool.clone()),
+            attempts: AttemptStore::new(pool.clone()),
+            events: EventStore::new(pool),
         }
     }
 
@@ -67,25 +96,67 @@ impl StripeHandler {
     ) -> Result<PaymentResponse> {
         // Create payment record
         let payment_id = Uuid::new_v4();
-        let now = Utc::now();
+        let conversion = self.fx.convert(req.amount, &req.currency).await?;
 
-        sqlx::query(
-            r#"
-            INSERT INTO payments (id, order_id, amount, currency, status, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
-            "#,
-        )
-        .bind(&payment_id)
-        .bind(&req.order_id)
-        .bind(req.amount)
-        .bind(&req.currency)
-        .bind("pending")
-        .bind(now)
-        .bind(now)
-        .execute(&self.pool)
-        .await
-        .context("Failed to insert payment record")?;
+        let created = PaymentDetails::new("stripe", Some(&req.order_id), None, &conversion);
+        self.events
+            .append(payment_id, &[PaymentEvent::Created(created)])
+            .await
+            .context("Failed to insert payment record")?;
+
+        let intent_id = self
+            .attempts
+            .open_intent(Some(&req.order_id), None, &conversion.presentment)
+            .await?;
+        let started_at = Utc::now();
+
+        let result = self.send_checkout_session(&req).await;
+
+        let (status, response_summary, decline_code) = match &result {
+            Ok(session) => (
+                ATTEMPT_PENDING,
+                serde_json::json!({ "stripe_session_id": session.id }),
+                None,
+            ),
+            // Stripe errors carry `decline_code` for card declines and `code` otherwise
+            Err(e) => (
+                ATTEMPT_FAILED,
+                serde_json::json!({ "error": e.to_string() }),
+                Some(Self::error_code(&e.to_string())),
+            ),
+        };
+        let attempt = NewAttempt {
+            intent_id,
+            payment_id,
+            gateway: "stripe".to_string(),
+            operation: "create_checkout_session".to_string(),
+            status: status.to_string(),
+            request_summary: serde_json::json!({ "amount": req.amount, "currency": req.currency }),
+            response_summary,
+            decline_code,
+            started_at,
+            completed_at: Utc::now(),
+        };
+        if let Err(e) = self.attempts.record(attempt).await {
+            tracing::warn!("Failed to record Stripe attempt for payment {}: {}", payment_id, e);
+        }
+
+        let session = result?;
+
+        // Update payment with session ID
+        let started = PaymentEvent::CheckoutStarted {
+            reference: session.id.clone(),
+        };
+        self.events.append(payment_id, &[started]).await?;
+
+        Ok(PaymentResponse {
+            payment_id: payment_id.to_string(),
+            checkout_url: session.url,
+            status: "pending".to_string(),
+        })
+    }
 
+    async fn send_checkout_session(&self, req: &CreatePaymentRequest) -> Result<StripeCheckoutSession> {
         // Convert amount to cents
         let amount_cents = (req.amount * 100.0) as i64;
 
@@ -118,25 +189,73 @@ impl StripeHandler {
         let session: StripeCheckoutSession = response.json().await
             .context("Failed to parse Stripe response")?;
 
-        // Update payment with session ID
-        sqlx::query(
-            r#"
-            UPDATE payments 
-            SET stripe_session_id = $1, updated_at = $2
-            WHERE id = $3
-            "#,
-        )
-        .bind(&session.id)
-        .bind(Utc::now())
-        .bind(&payment_id)
-        .execute(&self.pool)
-        .await?;
+        Ok(session)
+    }
 
-        Ok(PaymentResponse {
-            payment_id: payment_id.to_string(),
-            checkout_url: session.url,
-            status: "pending".to_string(),
+    fn error_code(error: &str) -> String {
+        let body = error
+            .split_once('{')
+            .and_then(|(_, rest)| serde_json::from_str::<serde_json::Value>(&format!("{{{}", rest)).ok());
+
+        body.and_then(|body| {
+            body["error"]["decline_code"]
+                .as_str()
+                .or_else(|| body["error"]["code"].as_str())
+                .map(String::from)
         })
+        .unwrap_or_else(|| "processing_error".to_string())
+    }
+
+    /// Asks Stripe where a checkout session stands after the buyer failed to
+    /// return. Open sessions are expired so they can no longer be paid.
+    pub async fn reconcile_pending(&self, session_id: &str) -> Result<PendingResolution> {
+        let response = self.client
+            .get(format!("https://api.stripe.com/v1/checkout/sessions/{}", session_id))
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await
+            .context("Failed to fetch Stripe checkout session")?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            anyhow::bail!("Stripe API error: {}", error_text);
+        }
+
+        let session: serde_json::Value = response.json().await
+            .context("Failed to parse Stripe checkout session")?;
+
+        match (
+            session["status"].as_str().unwrap_or(""),
+            session["payment_status"].as_str().unwrap_or(""),
+        ) {
+            ("complete", "paid") | ("complete", "no_payment_required") => {
+                let payment_intent = session["payment_intent"].as_str();
+                self.handle_payment_success(session_id, payment_intent).await?;
+                if let Some(payment_intent) = payment_intent {
+                    self.record_capture_fees(payment_intent).await?;
+                }
+                Ok(PendingResolution::Completed)
+            }
+            // Delayed payment methods settle later and report through the webhook
+            ("complete", _) => Ok(PendingResolution::StillPending),
+            ("open", _) => {
+                let response = self.client
+                    .post(format!("https://api.stripe.com/v1/checkout/sessions/{}/expire", session_id))
+                    .header("Authorization", format!("Bearer {}", self.api_key))
+                    .send()
+                    .await
+                    .context("Failed to expire Stripe checkout session")?;
+
+                if !response.status().is_success() {
+                    // Most likely paid in the meantime; the next sweep will see it complete
+                    let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+                    anyhow::bail!("Stripe API error: {}", error_text);
+                }
+
+                Ok(PendingResolution::Abandoned)
+            }
+            _ => Ok(PendingResolution::Abandoned),
+        }
     }
 
     pub async fn handle_webhook(&self, payload: &str, signature: &str) -> Result<()> {
@@ -152,14 +271,43 @@ impl StripeHandler {
         match event_type {
             "checkout.session.completed" => {
                 if let Some(session_id) = event["data"]["object"]["id"].as_str() {
-                    self.handle_payment_success(session_id).await?;
+                    let payment_intent = event["data"]["object"]["payment_intent"].as_str();
+                    self.handle_payment_success(session_id, payment_intent).await?;
+
+                    if let Some(payment_intent) = payment_intent {
+                        if let Err(e) = self.record_capture_fees(payment_intent).await {
+                            tracing::warn!("Failed to record Stripe fees for {}: {}", payment_intent, e);
+                        }
+                    }
                 }
             }
+            // Sent once the charge's balance transaction exists, which can be
+            // after the checkout completed
+            "charge.updated" => {
+                let charge = &event["data"]["object"];
+                if let (Some(payment_intent), Some(_)) = (
+                    charge["payment_intent"].as_str(),
+                    charge["balance_transaction"].as_str(),
+                ) {
+                    self.record_capture_fees(payment_intent).await?;
+                }
+            }
+            "refund.created" | "refund.updated" => {
+                self.handle_refund(&event["data"]["object"]).await?;
+                self.record_refund_fees(&event["data"]["object"]).await?;
+            }
             "checkout.session.expired" => {
                 if let Some(session_id) = event["data"]["object"]["id"].as_str() {
                     self.handle_payment_expired(session_id).await?;
                 }
             }
+            "charge.dispute.created"
+            | "charge.dispute.updated"
+            | "charge.dispute.closed"
+            | "charge.dispute.funds_withdrawn"
+            | "charge.dispute.funds_reinstated" => {
+                self.handle_dispute(&event["data"]["object"]).await?;
+            }
             _ => {
                 tracing::info!("Unhandled event type: {}", event_type);
             }
@@ -202,37 +350,219 @@ impl StripeHandler {
         Ok(())
     }
 
-    async fn handle_payment_success(&self, session_id: &str) -> Result<()> {
-        sqlx::query(
+    /// Marks the payment completed and books the capture in the same transaction.
+    async fn handle_payment_success(&self, session_id: &str, payment_intent: Option<&str>) -> Result<()> {
+        let Some(payment_id) = self.payment_id_for_session(session_id).await? else {
+            tracing::warn!("Stripe session {} does not match a known payment", session_id);
+            return Ok(());
+        };
+
+        let mut tx = self.pool.begin().await?;
+
+        let captured = PaymentEvent::Captured {
+            reference: payment_intent.map(String::from),
+        };
+        if let Some(payment) = events::append(&mut tx, payment_id, &captured).await? {
+            let journal = Journal::capture("stripe", payment_id, &Money::new(payment.amount, &payment.currency));
+            ledger::post(&mut tx, &journal).await?;
+        }
+        tx.commit().await?;
+
+        tracing::info!("Payment completed for session: {}", session_id);
+        Ok(())
+    }
+
+    async fn handle_payment_expired(&self, session_id: &str) -> Result<()> {
+        let Some(payment_id) = self.payment_id_for_session(session_id).await? else {
+            return Ok(());
+        };
+
+        self.events.append(payment_id, &[PaymentEvent::Expired]).await?;
+
+        tracing::info!("Payment expired for session: {}", session_id);
+        Ok(())
+    }
+
+    /// Records a succeeded refund against the payment intent's payment.
+    async fn handle_refund(&self, refund: &serde_json::Value) -> Result<()> {
+        let (Some(refund_id), Some(payment_intent), Some(amount)) = (
+            refund["id"].as_str(),
+            refund["payment_intent"].as_str(),
+            refund["amount"].as_i64(),
+        ) else {
+            return Ok(());
+        };
+        if refund["status"].as_str() != Some("succeeded") {
+            return Ok(());
+        }
+
+        let Some(payment_id) = self.payment_id_for_intent(payment_intent).await? else {
+            tracing::warn!("Stripe refund {} does not match a known payment", refund_id);
+            return Ok(());
+        };
+
+        // Stripe reports amounts in the smallest currency unit
+        let amount = Money::new(
+            amount as f64 / 100.0,
+            &refund["currency"].as_str().unwrap_or("usd").to_uppercase(),
+        );
+
+        let mut tx = self.pool.begin().await?;
+
+        let refunded = PaymentEvent::Refunded {
+            reference: refund_id.to_string(),
+            amount: amount.amount,
+            currency: amount.currency.clone(),
+        };
+        if events::append(&mut tx, payment_id, &refunded).await?.is_some() {
+            let journal = Journal::refund("stripe", Some(payment_id), refund_id, &amount);
+            ledger::post(&mut tx, &journal).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Records the balance transaction of the charge behind a completed
+    /// checkout. Recording it twice is a no-op.
+    async fn record_capture_fees(&self, payment_intent: &str) -> Result<()> {
+        let response = self.client
+            .get(format!("https://api.stripe.com/v1/payment_intents/{}", payment_intent))
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .query(&[("expand[]", "latest_charge.balance_transaction")])
+            .send()
+            .await
+            .context("Failed to fetch Stripe payment intent")?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            anyhow::bail!("Stripe API error: {}", error_text);
+        }
+
+        let intent: serde_json::Value = response.json().await
+            .context("Failed to parse Stripe payment intent")?;
+        let txn = &intent["latest_charge"]["balance_transaction"];
+        if !txn.is_object() {
+            // Balance transactions can lag behind the checkout; charge.updated
+            // brings the fees once Stripe attaches one
+            tracing::info!("No balance transaction yet for {}, waiting for charge.updated", payment_intent);
+            return Ok(());
+        }
+
+        let payment_id = self.payment_id_for_intent(payment_intent).await?;
+        match fees::from_stripe_balance_transaction(payment_id, KIND_CAPTURE, txn) {
+            Some(fee) => self.fees.record(fee).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Records the balance transaction of a refund once Stripe has created it.
+    async fn record_refund_fees(&self, refund: &serde_json::Value) -> Result<()> {
+        let Some(balance_transaction) = refund["balance_transaction"].as_str() else {
+            return Ok(());
+        };
+
+        let response = self.client
+            .get(format!("https://api.stripe.com/v1/balance_transactions/{}", balance_transaction))
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await
+            .context("Failed to fetch Stripe balance transaction")?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            anyhow::bail!("Stripe API error: {}", error_text);
+        }
+
+        let txn: serde_json::Value = response.json().await
+            .context("Failed to parse Stripe balance transaction")?;
+
+        let payment_id = match refund["payment_intent"].as_str() {
+            Some(payment_intent) => self.payment_id_for_intent(payment_intent).await?,
+            None => None,
+        };
+
+        match fees::from_stripe_balance_transaction(payment_id, KIND_REFUND, &txn) {
+            Some(fee) => self.fees.record(fee).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn payment_id_for_session(&self, session_id: &str) -> Result<Option<Uuid>> {
+        let payment_id = sqlx::query_scalar::<_, Uuid>(
             r#"
-            UPDATE payments 
-            SET status = 'completed', updated_at = $1
-            WHERE stripe_session_id = $2
+            SELECT id FROM payments WHERE stripe_session_id = $1
             "#,
         )
-        .bind(Utc::now())
         .bind(session_id)
-        .execute(&self.pool)
+        .fetch_optional(&self.pool)
         .await?;
 
-        tracing::info!("Payment completed for session: {}", session_id);
-        Ok(())
+        Ok(payment_id)
     }
 
-    async fn handle_payment_expired(&self, session_id: &str) -> Result<()> {
-        sqlx::query(
+    async fn payment_id_for_intent(&self, payment_intent: &str) -> Result<Option<Uuid>> {
+        let payment_id = sqlx::query_scalar::<_, Uuid>(
             r#"
-            UPDATE payments 
-            SET status = 'expired', updated_at = $1
-            WHERE stripe_session_id = $2
+            SELECT id FROM payments WHERE stripe_payment_intent = $1
             "#,
         )
-        .bind(Utc::now())
-        .bind(session_id)
-        .execute(&self.pool)
+        .bind(payment_intent)
+        .fetch_optional(&self.pool)
         .await?;
 
-        tracing::info!("Payment expired for session: {}", session_id);
+        Ok(payment_id)
+    }
+
+    async fn handle_dispute(&self, dispute: &serde_json::Value) -> Result<()> {
+        let dispute_id = dispute["id"]
+            .as_str()
+            .context("Dispute webhook without id")?;
+
+        // Disputes reference the payment intent stored when checkout completed
+        let linked = match dispute["payment_intent"].as_str() {
+            Some(payment_intent) => sqlx::query_as::<_, (Uuid, String)>(
+                r#"
+                SELECT id, order_id FROM payments WHERE stripe_payment_intent = $1
+                "#,
+            )
+            .bind(payment_intent)
+            .fetch_optional(&self.pool)
+            .await?,
+            None => None,
+        };
+
+        if linked.is_none() {
+            tracing::warn!("Stripe dispute {} does not match a known payment", dispute_id);
+        }
+
+        let status = match dispute["status"].as_str().unwrap_or("") {
+            "won" => STATUS_WON,
+            "lost" => STATUS_LOST,
+            "warning_closed" => STATUS_CLOSED,
+            "warning_needs_response" | "needs_response" => STATUS_NEEDS_RESPONSE,
+            _ => STATUS_UNDER_REVIEW,
+        };
+
+        let evidence_due_by = dispute["evidence_details"]["due_by"]
+            .as_i64()
+            .and_then(|ts| DateTime::from_timestamp(ts, 0));
+
+        self.disputes
+            .record(DisputeUpdate {
+                provider: "stripe".to_string(),
+                provider_dispute_id: dispute_id.to_string(),
+                payment_id: linked.as_ref().map(|(id, _)| *id),
+                order_id: linked.map(|(_, order_id)| order_id),
+                // Stripe reports amounts in the smallest currency unit
+                amount: dispute["amount"].as_i64().unwrap_or(0) as f64 / 100.0,
+                currency: dispute["currency"].as_str().unwrap_or("usd").to_uppercase(),
+                reason: dispute["reason"].as_str().map(String::from),
+                status: status.to_string(),
+                evidence_due_by,
+            })
+            .await?;
+
         Ok(())
     }
 
@@ -284,5 +614,17 @@ pub async fn init_db(pool: &PgPool) -> Result<()> {
     .await
     .context("Failed to create payments table")?;
 
+    // The table may already exist with only the PayPal columns
+    sqlx::query(
+        r#"
+        ALTER TABLE payments
+            ADD COLUMN IF NOT EXISTS stripe_session_id VARCHAR(255),
+            ADD COLUMN IF NOT EXISTS stripe_payment_intent VARCHAR(255)
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to add Stripe columns to payments table")?;
+
     Ok(())
 }
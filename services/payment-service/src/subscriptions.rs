@@ -0,0 +1,1085 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Months, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::dunning::{
+    DunningDecision, DunningService, PaymentFailure, PaymentRetry, KIND_SUBSCRIPTION_INVOICE,
+};
+use crate::events;
+use crate::models::{Money, PaymentRequest};
+use crate::outbox::{self, OutboxMessage};
+use crate::payment::{self, PaymentService};
+use crate::vault::{VaultError, VaultStore};
+
+pub const STATUS_INCOMPLETE: &str = "incomplete";
+pub const STATUS_ACTIVE: &str = "active";
+pub const STATUS_PAST_DUE: &str = "past_due";
+pub const STATUS_CANCELED: &str = "canceled";
+
+pub const INVOICE_OPEN: &str = "open";
+pub const INVOICE_PAID: &str = "paid";
+pub const INVOICE_FAILED: &str = "failed";
+
+const INTERVALS: [&str; 4] = ["day", "week", "month", "year"];
+
+/// An invoice still open this long after it was last touched was left behind
+/// by a run that failed or died before recording the charge's outcome
+const STALE_INVOICE_AFTER_MINUTES: i64 = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SubscriptionPlan {
+    pub id: Uuid,
+    pub name: String,
+    pub amount: f64,
+    pub currency: String,
+    /// One of day, week, month or year
+    pub interval: String,
+    pub interval_count: i32,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl SubscriptionPlan {
+    /// End of a billing period that starts at `start`.
+    fn period_end(&self, start: DateTime<Utc>) -> DateTime<Utc> {
+        let count = self.interval_count as u32;
+        match self.interval.as_str() {
+            "day" => start + chrono::Duration::days(count as i64),
+            "week" => start + chrono::Duration::weeks(count as i64),
+            "year" => start + Months::new(12 * count),
+            _ => start + Months::new(count),
+        }
+    }
+}
+
+/// Share of a price `difference` owed for the `remaining` part of a billing
+/// period, rounded to cents.
+fn prorate(difference: f64, period: chrono::Duration, remaining: chrono::Duration) -> f64 {
+    let period = period.num_seconds();
+    let unused = if period > 0 {
+        (remaining.num_seconds() as f64 / period as f64).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (difference * unused * 100.0).round() / 100.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Subscription {
+    pub id: Uuid,
+    pub user_id: String,
+    pub plan_id: Uuid,
+    pub payment_method_id: Uuid,
+    pub status: String,
+    pub current_period_start: DateTime<Utc>,
+    pub current_period_end: DateTime<Utc>,
+    pub cancel_at_period_end: bool,
+    /// Charge (or credit, when negative) from plan changes, added to the next invoice
+    pub pending_proration: f64,
+    pub canceled_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SubscriptionInvoice {
+    pub id: Uuid,
+    pub subscription_id: Uuid,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub amount: f64,
+    pub proration_amount: f64,
+    pub currency: String,
+    pub status: String,
+    pub payment_id: Option<String>,
+    pub failure_reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubscriptionDetails {
+    #[serde(flatten)]
+    pub subscription: Subscription,
+    pub plan: SubscriptionPlan,
+    pub invoices: Vec<SubscriptionInvoice>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatePlanRequest {
+    pub name: String,
+    pub amount: f64,
+    pub currency: String,
+    pub interval: String,
+    #[serde(default = "default_interval_count")]
+    pub interval_count: i32,
+}
+
+fn default_interval_count() -> i32 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateSubscriptionRequest {
+    pub user_id: String,
+    pub plan_id: Uuid,
+    pub payment_method_id: Uuid,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangePlanRequest {
+    pub plan_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListSubscriptionsQuery {
+    pub user_id: String,
+}
+
+/// Published on the `subscription-events` topic, keyed by subscription id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionEvent {
+    pub event_type: String,
+    pub subscription_id: String,
+    pub user_id: String,
+    pub plan_id: String,
+    pub invoice_id: Option<String>,
+    pub amount: Option<Money>,
+    pub payment_id: Option<String>,
+    pub reason: Option<String>,
+    pub current_period_end: DateTime<Utc>,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SubscriptionError {
+    #[error("Subscription not found")]
+    NotFound,
+    #[error("Invalid subscription request: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for SubscriptionError {
+    fn from(e: sqlx::Error) -> Self {
+        SubscriptionError::Failed(e.into())
+    }
+}
+
+#[derive(Debug)]
+pub struct SubscriptionService {
+    pool: PgPool,
+    payment_service: Arc<PaymentService>,
+    dunning: Arc<DunningService>,
+    vault: VaultStore,
+}
+
+/// What charging an invoice came to, before it is recorded.
+struct Charge {
+    /// The payment, or None when a credit covered the invoice
+    result: Result<Option<String>>,
+    /// Credit left over for the next invoice
+    carried: f64,
+}
+
+/// Why an invoice charge failed.
+#[derive(Debug, Clone)]
+struct ChargeFailure {
+    reason: String,
+    decline_code: String,
+}
+
+impl SubscriptionService {
+    pub fn new(
+        pool: PgPool,
+        payment_service: Arc<PaymentService>,
+        dunning: Arc<DunningService>,
+    ) -> Self {
+        Self {
+            vault: VaultStore::new(pool.clone()),
+            pool,
+            payment_service,
+            dunning,
+        }
+    }
+
+    pub async fn create_plan(&self, req: CreatePlanRequest) -> Result<SubscriptionPlan, SubscriptionError> {
+        if req.name.trim().is_empty() {
+            return Err(SubscriptionError::Invalid("name is required".to_string()));
+        }
+        if !req.amount.is_finite() || req.amount <= 0.0 {
+            return Err(SubscriptionError::Invalid("amount must be positive".to_string()));
+        }
+        if !INTERVALS.contains(&req.interval.as_str()) {
+            return Err(SubscriptionError::Invalid(format!(
+                "interval must be one of {}",
+                INTERVALS.join(", ")
+            )));
+        }
+        if !(1..=36).contains(&req.interval_count) {
+            return Err(SubscriptionError::Invalid("interval_count must be between 1 and 36".to_string()));
+        }
+        let currency = req.currency.trim().to_uppercase();
+        if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(SubscriptionError::Invalid(format!(
+                "currency must be an ISO 4217 code, got {:?}",
+                req.currency
+            )));
+        }
+
+        let plan = sqlx::query_as::<_, SubscriptionPlan>(
+            r#"
+            INSERT INTO subscription_plans
+                (id, name, amount, currency, interval, interval_count, active, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, TRUE, $7)
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(req.name.trim())
+        .bind(req.amount)
+        .bind(&currency)
+        .bind(&req.interval)
+        .bind(req.interval_count)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        info!("Created subscription plan {} ({})", plan.id, plan.name);
+        Ok(plan)
+    }
+
+    pub async fn list_plans(&self) -> Result<Vec<SubscriptionPlan>> {
+        let plans = sqlx::query_as::<_, SubscriptionPlan>(
+            r#"
+            SELECT * FROM subscription_plans WHERE active ORDER BY created_at
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(plans)
+    }
+
+    /// Starts a subscription and charges its first period right away. A failed
+    /// first charge leaves the subscription `incomplete`.
+    pub async fn create(&self, req: CreateSubscriptionRequest) -> Result<SubscriptionDetails, SubscriptionError> {
+        let plan = self.active_plan(req.plan_id).await?;
+
+        self.vault
+            .resolve(&req.user_id, &req.payment_method_id.to_string())
+            .await
+            .map_err(|e| match e {
+                VaultError::Failed(e) => SubscriptionError::Failed(e),
+                other => SubscriptionError::Invalid(other.to_string()),
+            })?;
+
+        let now = Utc::now();
+        let period_end = plan.period_end(now);
+        let mut tx = self.pool.begin().await?;
+
+        let subscription = sqlx::query_as::<_, Subscription>(
+            r#"
+            INSERT INTO subscriptions
+                (id, user_id, plan_id, payment_method_id, status, current_period_start,
+                 current_period_end, cancel_at_period_end, pending_proration, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, FALSE, 0, $6, $6)
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(&req.user_id)
+        .bind(plan.id)
+        .bind(req.payment_method_id)
+        .bind(STATUS_INCOMPLETE)
+        .bind(now)
+        .bind(period_end)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let invoice = Self::insert_invoice(&mut tx, &subscription, &plan, now, period_end)
+            .await?
+            .context("First invoice already exists")?;
+
+        tx.commit().await?;
+
+        info!("Created subscription {} for user {}", subscription.id, subscription.user_id);
+
+        let charge = self.charge(&subscription, &invoice).await?;
+        let mut tx = self.pool.begin().await?;
+        Self::record_charge(&mut tx, &subscription, invoice, charge).await?;
+        tx.commit().await?;
+
+        self.get(subscription.id).await?.ok_or(SubscriptionError::NotFound)
+    }
+
+    pub async fn list_for_user(&self, user_id: &str) -> Result<Vec<Subscription>> {
+        let subscriptions = sqlx::query_as::<_, Subscription>(
+            r#"
+            SELECT * FROM subscriptions WHERE user_id = $1 ORDER BY created_at DESC
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(subscriptions)
+    }
+
+    pub async fn get(&self, id: Uuid) -> Result<Option<SubscriptionDetails>> {
+        let Some(subscription) = self.find(id).await? else {
+            return Ok(None);
+        };
+
+        let plan = sqlx::query_as::<_, SubscriptionPlan>(
+            r#"
+            SELECT * FROM subscription_plans WHERE id = $1
+            "#,
+        )
+        .bind(subscription.plan_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let invoices = sqlx::query_as::<_, SubscriptionInvoice>(
+            r#"
+            SELECT * FROM subscription_invoices WHERE subscription_id = $1 ORDER BY period_start DESC
+            "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Some(SubscriptionDetails {
+            subscription,
+            plan,
+            invoices,
+        }))
+    }
+
+    /// Switches plans immediately. The price difference for the unused part of
+    /// the current period is added to the next invoice.
+    pub async fn change_plan(&self, id: Uuid, plan_id: Uuid) -> Result<Subscription, SubscriptionError> {
+        let new_plan = self.active_plan(plan_id).await?;
+        let mut tx = self.pool.begin().await?;
+
+        let subscription = sqlx::query_as::<_, Subscription>(
+            r#"
+            SELECT * FROM subscriptions WHERE id = $1 FOR UPDATE
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(SubscriptionError::NotFound)?;
+
+        if subscription.status != STATUS_ACTIVE {
+            return Err(SubscriptionError::Invalid(format!(
+                "cannot change plan of a {} subscription",
+                subscription.status
+            )));
+        }
+        if subscription.plan_id == new_plan.id {
+            return Err(SubscriptionError::Invalid("subscription is already on this plan".to_string()));
+        }
+
+        let old_plan = sqlx::query_as::<_, SubscriptionPlan>(
+            r#"
+            SELECT * FROM subscription_plans WHERE id = $1
+            "#,
+        )
+        .bind(subscription.plan_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if old_plan.currency != new_plan.currency {
+            return Err(SubscriptionError::Invalid(format!(
+                "cannot switch from a {} plan to a {} plan",
+                old_plan.currency, new_plan.currency
+            )));
+        }
+
+        let now = Utc::now();
+        let proration = prorate(
+            new_plan.amount - old_plan.amount,
+            subscription.current_period_end - subscription.current_period_start,
+            subscription.current_period_end - now,
+        );
+
+        let updated = sqlx::query_as::<_, Subscription>(
+            r#"
+            UPDATE subscriptions
+            SET plan_id = $1, pending_proration = pending_proration + $2, updated_at = $3
+            WHERE id = $4
+            RETURNING *
+            "#,
+        )
+        .bind(new_plan.id)
+        .bind(proration)
+        .bind(now)
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        info!(
+            "Subscription {} moved from plan {} to {} with proration {} {}",
+            id, old_plan.id, new_plan.id, proration, new_plan.currency
+        );
+        Ok(updated)
+    }
+
+    /// Active subscriptions run until the end of the paid period; ones that
+    /// never got paid are canceled right away.
+    pub async fn cancel(&self, id: Uuid) -> Result<Subscription, SubscriptionError> {
+        let subscription = self.find(id).await?.ok_or(SubscriptionError::NotFound)?;
+        let now = Utc::now();
+
+        let updated = match subscription.status.as_str() {
+            STATUS_CANCELED => return Ok(subscription),
+            STATUS_ACTIVE => {
+                let mut tx = self.pool.begin().await?;
+                let scheduled = sqlx::query_as::<_, Subscription>(
+                    r#"
+                    UPDATE subscriptions SET cancel_at_period_end = TRUE, updated_at = $1
+                    WHERE id = $2
+                    RETURNING *
+                    "#,
+                )
+                .bind(now)
+                .bind(id)
+                .fetch_one(&mut *tx)
+                .await?;
+                Self::publish(&mut tx, "SUBSCRIPTION_CANCEL_SCHEDULED", &scheduled, None, None).await?;
+                tx.commit().await?;
+                scheduled
+            }
+            _ => {
+                let mut tx = self.pool.begin().await?;
+                let canceled = Self::mark_canceled(&mut tx, id).await?;
+                Self::publish(&mut tx, "SUBSCRIPTION_CANCELED", &canceled, None, None).await?;
+                tx.commit().await?;
+                canceled
+            }
+        };
+
+        info!("Subscription {} canceled (at period end: {})", id, updated.cancel_at_period_end);
+        Ok(updated)
+    }
+
+    /// Renews or ends every active subscription whose period is over.
+    pub async fn process_due(&self) -> Result<usize> {
+        let due = sqlx::query_as::<_, Subscription>(
+            r#"
+            SELECT * FROM subscriptions
+            WHERE status = 'active' AND current_period_end <= $1
+            ORDER BY current_period_end
+            LIMIT 50
+            "#,
+        )
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .await?;
+
+        let count = due.len();
+        for subscription in due {
+            let id = subscription.id;
+            if let Err(e) = self.renew(subscription).await {
+                warn!("Failed to renew subscription {}: {}", id, e);
+            }
+        }
+
+        Ok(count)
+    }
+
+    async fn renew(&self, subscription: Subscription) -> Result<()> {
+        if subscription.cancel_at_period_end {
+            let mut tx = self.pool.begin().await?;
+            let canceled = Self::mark_canceled(&mut tx, subscription.id).await?;
+            Self::publish(&mut tx, "SUBSCRIPTION_CANCELED", &canceled, None, None).await?;
+            tx.commit().await?;
+            return Ok(());
+        }
+
+        let plan = sqlx::query_as::<_, SubscriptionPlan>(
+            r#"
+            SELECT * FROM subscription_plans WHERE id = $1
+            "#,
+        )
+        .bind(subscription.plan_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let period_start = subscription.current_period_end;
+        let period_end = plan.period_end(period_start);
+
+        let mut tx = self.pool.begin().await?;
+        let invoice = match Self::insert_invoice(&mut tx, &subscription, &plan, period_start, period_end).await? {
+            Some(invoice) => Some(invoice),
+            None => Self::claim_stale_invoice(&mut tx, subscription.id, period_start).await?,
+        };
+        tx.commit().await?;
+
+        // Another instance is billing this period right now
+        let Some(invoice) = invoice else {
+            return Ok(());
+        };
+
+        let charge = self.charge(&subscription, &invoice).await?;
+        let mut tx = self.pool.begin().await?;
+        let (invoice, updated, failure) =
+            Self::record_charge(&mut tx, &subscription, invoice, charge).await?;
+
+        let Some(failure) = failure else {
+            Self::publish(&mut tx, "SUBSCRIPTION_RENEWED", &updated, Some(&invoice), None).await?;
+            tx.commit().await?;
+            return Ok(());
+        };
+
+        Self::publish(
+            &mut tx,
+            "SUBSCRIPTION_RENEWAL_FAILED",
+            &updated,
+            Some(&invoice),
+            Some(failure.reason.clone()),
+        )
+        .await?;
+
+        let decision = self
+            .dunning
+            .record_failure(&mut tx, PaymentFailure {
+                kind: KIND_SUBSCRIPTION_INVOICE,
+                reference: invoice.id.to_string(),
+                saga_id: None,
+                user_id: updated.user_id.clone(),
+                request: serde_json::json!({ "subscription_id": updated.id }),
+                decline_code: failure.decline_code,
+                error: failure.reason.clone(),
+            })
+            .await?;
+
+        if decision == DunningDecision::GaveUp {
+            Self::give_up(&mut tx, &updated, &invoice, failure.reason).await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Charges a failed renewal invoice again on the dunning schedule.
+    pub async fn retry_invoice(&self, retry: &PaymentRetry) -> Result<()> {
+        let invoice_id = Uuid::parse_str(&retry.reference)?;
+        let invoice = sqlx::query_as::<_, SubscriptionInvoice>(
+            r#"
+            SELECT * FROM subscription_invoices WHERE id = $1
+            "#,
+        )
+        .bind(invoice_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let subscription = self
+            .find(invoice.subscription_id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Subscription {} not found", invoice.subscription_id))?;
+
+        if invoice.status == INVOICE_PAID {
+            let mut tx = self.pool.begin().await?;
+            self.dunning.retry_succeeded(&mut tx, retry).await?;
+            tx.commit().await?;
+            return Ok(());
+        }
+        // Canceled, or the customer fixed things in the meantime
+        if subscription.status != STATUS_PAST_DUE {
+            return self.dunning.cancel_retry(retry).await;
+        }
+
+        info!(
+            "Retrying invoice {} for subscription {} (attempt {})",
+            invoice.id, subscription.id, retry.attempt_count
+        );
+
+        let charge = self.charge(&subscription, &invoice).await?;
+        let mut tx = self.pool.begin().await?;
+        let (invoice, updated, failure) =
+            Self::record_charge(&mut tx, &subscription, invoice, charge).await?;
+
+        let Some(failure) = failure else {
+            self.dunning.retry_succeeded(&mut tx, retry).await?;
+            Self::publish(&mut tx, "SUBSCRIPTION_RENEWED", &updated, Some(&invoice), None).await?;
+            tx.commit().await?;
+            return Ok(());
+        };
+
+        let decision = self
+            .dunning
+            .retry_failed(&mut tx, retry, &failure.decline_code, &failure.reason)
+            .await?;
+
+        if decision == DunningDecision::GaveUp {
+            Self::give_up(&mut tx, &updated, &invoice, failure.reason).await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Ends a subscription whose renewal could not be collected.
+    async fn give_up(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        subscription: &Subscription,
+        invoice: &SubscriptionInvoice,
+        reason: String,
+    ) -> Result<()> {
+        let canceled = Self::mark_canceled(tx, subscription.id).await?;
+        info!(
+            "Subscription {} canceled after invoice {} could not be collected",
+            subscription.id, invoice.id
+        );
+        Self::publish(tx, "SUBSCRIPTION_CANCELED", &canceled, Some(invoice), Some(reason)).await
+    }
+
+    /// Claims the invoice for a period. Returns None if it already exists, so a
+    /// period is never charged twice.
+    async fn insert_invoice(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        subscription: &Subscription,
+        plan: &SubscriptionPlan,
+        period_start: DateTime<Utc>,
+        period_end: DateTime<Utc>,
+    ) -> Result<Option<SubscriptionInvoice>> {
+        let now = Utc::now();
+        let amount = ((plan.amount + subscription.pending_proration) * 100.0).round() / 100.0;
+
+        let invoice = sqlx::query_as::<_, SubscriptionInvoice>(
+            r#"
+            INSERT INTO subscription_invoices
+                (id, subscription_id, period_start, period_end, amount, proration_amount, currency,
+                 status, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9)
+            ON CONFLICT (subscription_id, period_start) DO NOTHING
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(subscription.id)
+        .bind(period_start)
+        .bind(period_end)
+        .bind(amount)
+        .bind(subscription.pending_proration)
+        .bind(&plan.currency)
+        .bind(INVOICE_OPEN)
+        .bind(now)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        Ok(invoice)
+    }
+
+    /// Takes over the period's invoice if an earlier run left it open, so the
+    /// subscription is charged or failed instead of staying due forever.
+    async fn claim_stale_invoice(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        subscription_id: Uuid,
+        period_start: DateTime<Utc>,
+    ) -> Result<Option<SubscriptionInvoice>> {
+        let now = Utc::now();
+        let stale_before = now - chrono::Duration::minutes(STALE_INVOICE_AFTER_MINUTES);
+
+        let invoice = sqlx::query_as::<_, SubscriptionInvoice>(
+            r#"
+            UPDATE subscription_invoices SET updated_at = $1
+            WHERE subscription_id = $2 AND period_start = $3 AND status = $4 AND updated_at < $5
+            RETURNING *
+            "#,
+        )
+        .bind(now)
+        .bind(subscription_id)
+        .bind(period_start)
+        .bind(INVOICE_OPEN)
+        .bind(stale_before)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        if let Some(invoice) = &invoice {
+            warn!(
+                "Resuming invoice {} for subscription {} left open since {}",
+                invoice.id, subscription_id, invoice.created_at
+            );
+        }
+        Ok(invoice)
+    }
+
+    /// The completed payment for an invoice, if an earlier run was charged but
+    /// did not get to record it.
+    async fn completed_payment(&self, invoice_id: Uuid) -> Result<Option<String>> {
+        let payment_id = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            SELECT id FROM payments WHERE order_id = $1 AND status = $2
+            ORDER BY created_at DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(invoice_id.to_string())
+        .bind(events::STATUS_COMPLETED)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(payment_id.map(|id| id.to_string()))
+    }
+
+    /// Charges an open invoice through the payment service. Nothing is
+    /// recorded until `record_charge`.
+    async fn charge(&self, subscription: &Subscription, invoice: &SubscriptionInvoice) -> Result<Charge> {
+        // A credit larger than the price is carried over instead of charged
+        let (result, carried) = if invoice.amount <= 0.0 {
+            (Ok(None), invoice.amount)
+        } else if let Some(payment_id) = self.completed_payment(invoice.id).await? {
+            (Ok(Some(payment_id)), 0.0)
+        } else {
+            let request = PaymentRequest {
+                user_id: subscription.user_id.clone(),
+                total_amount: invoice.amount,
+                currency: Some(invoice.currency.clone()),
+                payment_method: "subscription".to_string(),
+                order_id: Some(invoice.id.to_string()),
+                payment_method_id: Some(subscription.payment_method_id.to_string()),
+                splits: None,
+            };
+            let result = self
+                .payment_service
+                .process_payment(request)
+                .await
+                .map(|response| Some(response.payment_id));
+            (result, 0.0)
+        };
+
+        Ok(Charge { result, carried })
+    }
+
+    /// Settles the invoice with the charge's outcome and moves the
+    /// subscription to the invoiced period on success. Returns the updated
+    /// subscription and why the charge failed, if it did.
+    async fn record_charge(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        subscription: &Subscription,
+        invoice: SubscriptionInvoice,
+        charge: Charge,
+    ) -> Result<(SubscriptionInvoice, Subscription, Option<ChargeFailure>)> {
+        let now = Utc::now();
+
+        let outcome = match charge.result {
+            Ok(payment_id) => {
+                let invoice = sqlx::query_as::<_, SubscriptionInvoice>(
+                    r#"
+                    UPDATE subscription_invoices SET status = $1, payment_id = $2, updated_at = $3
+                    WHERE id = $4
+                    RETURNING *
+                    "#,
+                )
+                .bind(INVOICE_PAID)
+                .bind(&payment_id)
+                .bind(now)
+                .bind(invoice.id)
+                .fetch_one(&mut **tx)
+                .await?;
+
+                // Only the proration this invoice billed is used up; plan
+                // changes made while it was being charged carry over
+                let updated = sqlx::query_as::<_, Subscription>(
+                    r#"
+                    UPDATE subscriptions
+                    SET status = $1, current_period_start = $2, current_period_end = $3,
+                        pending_proration = pending_proration - $4 + $5, updated_at = $6
+                    WHERE id = $7
+                    RETURNING *
+                    "#,
+                )
+                .bind(STATUS_ACTIVE)
+                .bind(invoice.period_start)
+                .bind(invoice.period_end)
+                .bind(invoice.proration_amount)
+                .bind(charge.carried)
+                .bind(now)
+                .bind(subscription.id)
+                .fetch_one(&mut **tx)
+                .await?;
+
+                info!("Subscription {} invoice {} paid", subscription.id, invoice.id);
+                (invoice, updated, None)
+            }
+            Err(e) => {
+                let reason = e.to_string();
+                let decline_code = payment::decline_code(&e);
+                let invoice = sqlx::query_as::<_, SubscriptionInvoice>(
+                    r#"
+                    UPDATE subscription_invoices SET status = $1, failure_reason = $2, updated_at = $3
+                    WHERE id = $4
+                    RETURNING *
+                    "#,
+                )
+                .bind(INVOICE_FAILED)
+                .bind(&reason)
+                .bind(now)
+                .bind(invoice.id)
+                .fetch_one(&mut **tx)
+                .await?;
+
+                // A subscription that was never paid stays incomplete
+                let updated = sqlx::query_as::<_, Subscription>(
+                    r#"
+                    UPDATE subscriptions
+                    SET status = CASE WHEN status = 'incomplete' THEN status ELSE $1 END, updated_at = $2
+                    WHERE id = $3
+                    RETURNING *
+                    "#,
+                )
+                .bind(STATUS_PAST_DUE)
+                .bind(now)
+                .bind(subscription.id)
+                .fetch_one(&mut **tx)
+                .await?;
+
+                warn!("Subscription {} invoice {} failed: {}", subscription.id, invoice.id, reason);
+                (
+                    invoice,
+                    updated,
+                    Some(ChargeFailure {
+                        reason,
+                        decline_code,
+                    }),
+                )
+            }
+        };
+
+        Ok(outcome)
+    }
+
+    async fn active_plan(&self, plan_id: Uuid) -> Result<SubscriptionPlan, SubscriptionError> {
+        sqlx::query_as::<_, SubscriptionPlan>(
+            r#"
+            SELECT * FROM subscription_plans WHERE id = $1 AND active
+            "#,
+        )
+        .bind(plan_id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| SubscriptionError::Invalid(format!("unknown plan {}", plan_id)))
+    }
+
+    async fn find(&self, id: Uuid) -> Result<Option<Subscription>> {
+        let subscription = sqlx::query_as::<_, Subscription>(
+            r#"
+            SELECT * FROM subscriptions WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(subscription)
+    }
+
+    async fn mark_canceled(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, id: Uuid) -> Result<Subscription> {
+        let now = Utc::now();
+        let subscription = sqlx::query_as::<_, Subscription>(
+            r#"
+            UPDATE subscriptions SET status = $1, canceled_at = $2, updated_at = $2
+            WHERE id = $3
+            RETURNING *
+            "#,
+        )
+        .bind(STATUS_CANCELED)
+        .bind(now)
+        .bind(id)
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(subscription)
+    }
+
+    /// Announces a subscription change in the transaction that made it.
+    async fn publish(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        event_type: &str,
+        subscription: &Subscription,
+        invoice: Option<&SubscriptionInvoice>,
+        reason: Option<String>,
+    ) -> Result<()> {
+        let event = SubscriptionEvent {
+            event_type: event_type.to_string(),
+            subscription_id: subscription.id.to_string(),
+            user_id: subscription.user_id.clone(),
+            plan_id: subscription.plan_id.to_string(),
+            invoice_id: invoice.map(|i| i.id.to_string()),
+            amount: invoice.map(|i| Money::new(i.amount, &i.currency)),
+            payment_id: invoice.and_then(|i| i.payment_id.clone()),
+            reason,
+            current_period_end: subscription.current_period_end,
+            timestamp: Utc::now(),
+        };
+
+        let message = OutboxMessage::json("subscription-events", &event.subscription_id, &event)?;
+        outbox::enqueue(tx, &message).await
+    }
+}
+
+/// Periodically bills subscriptions whose current period has ended.
+pub async fn run_billing_scheduler(service: Arc<SubscriptionService>, interval: Duration) {
+    info!("Subscription billing scheduler started, interval {:?}", interval);
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        match service.process_due().await {
+            Ok(0) => {}
+            Ok(count) => info!("Processed {} due subscriptions", count),
+            Err(e) => error!("Subscription billing run failed: {}", e),
+        }
+    }
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS subscription_plans (
+            id UUID PRIMARY KEY,
+            name VARCHAR(255) NOT NULL,
+            amount DOUBLE PRECISION NOT NULL,
+            currency VARCHAR(3) NOT NULL,
+            interval VARCHAR(10) NOT NULL,
+            interval_count INTEGER NOT NULL,
+            active BOOLEAN NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create subscription_plans table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS subscriptions (
+            id UUID PRIMARY KEY,
+            user_id VARCHAR(255) NOT NULL,
+            plan_id UUID NOT NULL REFERENCES subscription_plans(id),
+            payment_method_id UUID NOT NULL,
+            status VARCHAR(20) NOT NULL,
+            current_period_start TIMESTAMPTZ NOT NULL,
+            current_period_end TIMESTAMPTZ NOT NULL,
+            cancel_at_period_end BOOLEAN NOT NULL,
+            pending_proration DOUBLE PRECISION NOT NULL,
+            canceled_at TIMESTAMPTZ,
+            created_at TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create subscriptions table")?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_subscriptions_due ON subscriptions (status, current_period_end)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_subscriptions_user_id ON subscriptions (user_id)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS subscription_invoices (
+            id UUID PRIMARY KEY,
+            subscription_id UUID NOT NULL REFERENCES subscriptions(id),
+            period_start TIMESTAMPTZ NOT NULL,
+            period_end TIMESTAMPTZ NOT NULL,
+            amount DOUBLE PRECISION NOT NULL,
+            proration_amount DOUBLE PRECISION NOT NULL,
+            currency VARCHAR(3) NOT NULL,
+            status VARCHAR(20) NOT NULL,
+            payment_id VARCHAR(255),
+            failure_reason TEXT,
+            created_at TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL,
+            UNIQUE (subscription_id, period_start)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create subscription_invoices table")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn plan(interval: &str, interval_count: i32) -> SubscriptionPlan {
+        SubscriptionPlan {
+            id: Uuid::nil(),
+            name: "plan".to_string(),
+            amount: 10.0,
+            currency: "USD".to_string(),
+            interval: interval.to_string(),
+            interval_count,
+            active: true,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn period_end_follows_the_plan_interval() {
+        let start = Utc.with_ymd_and_hms(2024, 1, 31, 12, 0, 0).unwrap();
+
+        assert_eq!(plan("day", 3).period_end(start), Utc.with_ymd_and_hms(2024, 2, 3, 12, 0, 0).unwrap());
+        assert_eq!(plan("week", 2).period_end(start), Utc.with_ymd_and_hms(2024, 2, 14, 12, 0, 0).unwrap());
+        assert_eq!(plan("year", 1).period_end(start), Utc.with_ymd_and_hms(2025, 1, 31, 12, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn monthly_period_end_clamps_to_the_last_day_of_the_month() {
+        let start = Utc.with_ymd_and_hms(2024, 1, 31, 12, 0, 0).unwrap();
+
+        assert_eq!(plan("month", 1).period_end(start), Utc.with_ymd_and_hms(2024, 2, 29, 12, 0, 0).unwrap());
+        assert_eq!(plan("month", 3).period_end(start), Utc.with_ymd_and_hms(2024, 4, 30, 12, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn prorates_the_unused_share_of_the_period_to_cents() {
+        let period = chrono::Duration::days(30);
+
+        assert_eq!(prorate(20.0, period, chrono::Duration::days(10)), 6.67);
+        assert_eq!(prorate(-20.0, period, chrono::Duration::days(10)), -6.67);
+        assert_eq!(prorate(20.0, period, chrono::Duration::days(30)), 20.0);
+    }
+
+    #[test]
+    fn proration_is_clamped_to_the_period() {
+        let period = chrono::Duration::days(30);
+
+        assert_eq!(prorate(20.0, period, chrono::Duration::days(-1)), 0.0);
+        assert_eq!(prorate(20.0, period, chrono::Duration::days(45)), 20.0);
+        assert_eq!(prorate(20.0, chrono::Duration::zero(), chrono::Duration::days(1)), 0.0);
+    }
+}
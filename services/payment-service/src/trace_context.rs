@@ -0,0 +1,180 @@
+use opentelemetry::global::{self, BoxedSpan, BoxedTracer};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{SpanBuilder, SpanKind, TraceContextExt, TraceError};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use std::collections::BTreeMap;
+
+/// W3C vendor trace state; removed before injecting so that a stale one never
+/// outlives the `traceparent` it belonged to
+const HEADER_TRACESTATE: &str = "tracestate";
+
+/// Exports spans to the Jaeger agent and reads and writes W3C trace context.
+pub fn init_tracer(agent_host: &str, agent_port: u16) -> Result<(), TraceError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    opentelemetry_jaeger::new_agent_pipeline()
+        .with_endpoint(format!("{}:{}", agent_host, agent_port))
+        .with_service_name("payment-service")
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+    Ok(())
+}
+
+/// Flushes spans not yet exported.
+pub fn shutdown_tracer() {
+    global::shutdown_tracer_provider();
+}
+
+fn tracer() -> BoxedTracer {
+    global::tracer("payment-service")
+}
+
+struct HeaderInjector<'a>(&'a mut BTreeMap<String, String>);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key.to_string(), value);
+    }
+}
+
+struct HeaderExtractor<'a>(&'a BTreeMap<String, String>);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(String::as_str).collect()
+    }
+}
+
+/// The trace a message was sent in, or an empty context if it carries none.
+pub fn extract(headers: &BTreeMap<String, String>) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Sets the trace context headers to `cx`'s span, replacing any already
+/// there. Leaves the headers alone outside a trace.
+pub fn inject(cx: &Context, headers: &mut BTreeMap<String, String>) {
+    if !cx.span().span_context().is_valid() {
+        return;
+    }
+    headers.remove(HEADER_TRACESTATE);
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(cx, &mut HeaderInjector(headers))
+    });
+}
+
+/// Trace context headers for a message sent from the current context.
+pub fn current_headers() -> BTreeMap<String, String> {
+    let mut headers = BTreeMap::new();
+    inject(&Context::current(), &mut headers);
+    headers
+}
+
+/// Id of `cx`'s trace, for log fields; empty outside a trace.
+pub fn trace_id(cx: &Context) -> String {
+    let span_context = cx.span().span_context().clone();
+    if span_context.is_valid() {
+        span_context.trace_id().to_string()
+    } else {
+        String::new()
+    }
+}
+
+/// A span for receiving, processing or publishing a message on `topic`,
+/// following the OpenTelemetry messaging conventions.
+pub fn message_span(
+    topic: &str,
+    operation: &'static str,
+    kind: SpanKind,
+    attributes: Vec<KeyValue>,
+    parent: &Context,
+) -> BoxedSpan {
+    let mut attributes = attributes;
+    attributes.push(KeyValue::new("messaging.system", "kafka"));
+    attributes.push(KeyValue::new("messaging.destination.name", topic.to_string()));
+    attributes.push(KeyValue::new("messaging.operation", operation));
+
+    SpanBuilder::from_name(format!("{} {}", topic, operation))
+        .with_kind(kind)
+        .with_attributes(attributes)
+        .start_with_context(&tracer(), parent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceId, TraceState};
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn headers(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn remote(trace_id: &str, span_id: &str, trace_state: TraceState) -> Context {
+        Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from_hex(trace_id).unwrap(),
+            SpanId::from_hex(span_id).unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            trace_state,
+        ))
+    }
+
+    #[test]
+    fn reads_the_trace_a_message_was_sent_in() {
+        let cx = extract(&headers(&[("traceparent", TRACEPARENT)]));
+        let span_context = cx.span().span_context().clone();
+
+        assert!(span_context.is_remote());
+        assert_eq!(trace_id(&cx), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span_context.span_id().to_string(), "00f067aa0ba902b7");
+        assert!(span_context.is_sampled());
+    }
+
+    #[test]
+    fn malformed_trace_context_reads_as_no_trace() {
+        let cx = extract(&headers(&[("traceparent", "00-not-a-trace-01")]));
+
+        assert!(!cx.span().span_context().is_valid());
+        assert_eq!(trace_id(&cx), "");
+    }
+
+    #[test]
+    fn inject_round_trips_through_extract() {
+        let mut carried = headers(&[]);
+        inject(&extract(&headers(&[("traceparent", TRACEPARENT)])), &mut carried);
+
+        assert_eq!(carried.get("traceparent").map(String::as_str), Some(TRACEPARENT));
+    }
+
+    #[test]
+    fn inject_replaces_the_trace_context_already_there() {
+        let mut carried = headers(&[("traceparent", TRACEPARENT), ("tracestate", "old=1"), ("order", "1")]);
+        let cx = remote("0af7651916cd43dd8448eb211c80319c", "b7ad6b7169203331", TraceState::default());
+        inject(&cx, &mut carried);
+
+        assert_eq!(
+            carried.get("traceparent").map(String::as_str),
+            Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+        );
+        assert_eq!(carried.get("tracestate").map(String::as_str).unwrap_or_default(), "");
+        assert_eq!(carried.get("order").map(String::as_str), Some("1"));
+    }
+
+    #[test]
+    fn inject_leaves_headers_alone_outside_a_trace() {
+        let mut carried = headers(&[("traceparent", TRACEPARENT)]);
+        inject(&Context::new(), &mut carried);
+
+        assert_eq!(carried.get("traceparent").map(String::as_str), Some(TRACEPARENT));
+    }
+}
@@ -0,0 +1,383 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const PROVIDERS: &[&str] = &["stripe", "paypal"];
+
+/// A provider-issued token saved for a returning customer. The token itself is
+/// never returned over the API.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SavedPaymentMethod {
+    pub id: Uuid,
+    pub user_id: String,
+    pub provider: String,
+    #[serde(skip_serializing)]
+    pub provider_token: String,
+    pub brand: String,
+    pub last4: Option<String>,
+    pub exp_month: Option<i32>,
+    pub exp_year: Option<i32>,
+    pub is_default: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl SavedPaymentMethod {
+    pub fn is_expired(&self) -> bool {
+        match (self.exp_month, self.exp_year) {
+            (Some(month), Some(year)) => {
+                let now = Utc::now();
+                (year, month) < (now.year(), now.month() as i32)
+            }
+            _ => false,
+        }
+    }
+
+    /// Token with everything but the prefix and last four characters hidden, safe for logs.
+    pub fn masked_token(&self) -> String {
+        let token = &self.provider_token;
+        if token.len() <= 8 {
+            return "****".to_string();
+        }
+        format!("{}****{}", &token[..3], &token[token.len() - 4..])
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatePaymentMethodRequest {
+    pub user_id: String,
+    pub provider: String,
+    pub provider_token: String,
+    pub brand: String,
+    pub last4: Option<String>,
+    pub exp_month: Option<i32>,
+    pub exp_year: Option<i32>,
+    #[serde(default)]
+    pub is_default: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdatePaymentMethodRequest {
+    pub exp_month: Option<i32>,
+    pub exp_year: Option<i32>,
+    pub is_default: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListPaymentMethodsQuery {
+    pub user_id: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VaultError {
+    #[error("Payment method not found")]
+    NotFound,
+    #[error("Invalid payment method: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for VaultError {
+    fn from(e: sqlx::Error) -> Self {
+        VaultError::Failed(e.into())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VaultStore {
+    pool: PgPool,
+}
+
+impl VaultStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(&self, req: CreatePaymentMethodRequest) -> Result<SavedPaymentMethod, VaultError> {
+        validate_create(&req)?;
+
+        let mut tx = self.pool.begin().await?;
+
+        let existing: i64 = sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*) FROM saved_payment_methods WHERE user_id = $1
+            "#,
+        )
+        .bind(&req.user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // The first saved method always becomes the default
+        let is_default = req.is_default || existing == 0;
+        if is_default {
+            clear_default(&mut tx, &req.user_id).await?;
+        }
+
+        let now = Utc::now();
+        let result = sqlx::query_as::<_, SavedPaymentMethod>(
+            r#"
+            INSERT INTO saved_payment_methods
+                (id, user_id, provider, provider_token, brand, last4, exp_month, exp_year, is_default, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(&req.user_id)
+        .bind(&req.provider)
+        .bind(&req.provider_token)
+        .bind(&req.brand)
+        .bind(&req.last4)
+        .bind(req.exp_month)
+        .bind(req.exp_year)
+        .bind(is_default)
+        .bind(now)
+        .bind(now)
+        .fetch_one(&mut *tx)
+        .await;
+
+        let method = match result {
+            Ok(method) => method,
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                return Err(VaultError::Invalid("payment method already saved".to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        tx.commit().await?;
+
+        tracing::info!("Saved {} payment method {} for user {}", method.provider, method.id, method.user_id);
+        Ok(method)
+    }
+
+    pub async fn list_for_user(&self, user_id: &str) -> Result<Vec<SavedPaymentMethod>> {
+        let methods = sqlx::query_as::<_, SavedPaymentMethod>(
+            r#"
+            SELECT * FROM saved_payment_methods
+            WHERE user_id = $1
+            ORDER BY is_default DESC, created_at DESC
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(methods)
+    }
+
+    pub async fn get(&self, id: Uuid) -> Result<Option<SavedPaymentMethod>> {
+        let method = sqlx::query_as::<_, SavedPaymentMethod>(
+            r#"
+            SELECT * FROM saved_payment_methods WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(method)
+    }
+
+    pub async fn update(
+        &self,
+        id: Uuid,
+        req: UpdatePaymentMethodRequest,
+    ) -> Result<SavedPaymentMethod, VaultError> {
+        validate_expiry(req.exp_month, req.exp_year)?;
+
+        let mut tx = self.pool.begin().await?;
+
+        let current = sqlx::query_as::<_, SavedPaymentMethod>(
+            r#"
+            SELECT * FROM saved_payment_methods WHERE id = $1 FOR UPDATE
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(VaultError::NotFound)?;
+
+        if req.is_default == Some(true) {
+            clear_default(&mut tx, &current.user_id).await?;
+        }
+
+        let method = sqlx::query_as::<_, SavedPaymentMethod>(
+            r#"
+            UPDATE saved_payment_methods
+            SET exp_month = COALESCE($1, exp_month),
+                exp_year = COALESCE($2, exp_year),
+                is_default = COALESCE($3, is_default),
+                updated_at = $4
+            WHERE id = $5
+            RETURNING *
+            "#,
+        )
+        .bind(req.exp_month)
+        .bind(req.exp_year)
+        .bind(req.is_default)
+        .bind(Utc::now())
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(method)
+    }
+
+    /// Removes a saved method, promoting the most recent remaining one if the
+    /// default was deleted.
+    pub async fn delete(&self, id: Uuid) -> Result<(), VaultError> {
+        let mut tx = self.pool.begin().await?;
+
+        let deleted = sqlx::query_as::<_, SavedPaymentMethod>(
+            r#"
+            DELETE FROM saved_payment_methods WHERE id = $1 RETURNING *
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(VaultError::NotFound)?;
+
+        if deleted.is_default {
+            sqlx::query(
+                r#"
+                UPDATE saved_payment_methods
+                SET is_default = TRUE, updated_at = $1
+                WHERE id = (
+                    SELECT id FROM saved_payment_methods
+                    WHERE user_id = $2
+                    ORDER BY created_at DESC
+                    LIMIT 1
+                )
+                "#,
+            )
+            .bind(Utc::now())
+            .bind(&deleted.user_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        tracing::info!("Deleted payment method {} for user {}", id, deleted.user_id);
+        Ok(())
+    }
+
+    /// Looks up a method a user wants to pay with, rejecting methods that belong
+    /// to someone else or have expired.
+    pub async fn resolve(&self, user_id: &str, payment_method_id: &str) -> Result<SavedPaymentMethod, VaultError> {
+        let id = Uuid::parse_str(payment_method_id).map_err(|_| VaultError::NotFound)?;
+
+        let method = self
+            .get(id)
+            .await?
+            .filter(|m| m.user_id == user_id)
+            .ok_or(VaultError::NotFound)?;
+
+        if method.is_expired() {
+            return Err(VaultError::Invalid("payment method has expired".to_string()));
+        }
+
+        Ok(method)
+    }
+}
+
+async fn clear_default(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, user_id: &str) -> Result<()> {
+    sqlx::query(
+        r#"
+        UPDATE saved_payment_methods
+        SET is_default = FALSE, updated_at = $1
+        WHERE user_id = $2 AND is_default
+        "#,
+    )
+    .bind(Utc::now())
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+fn validate_create(req: &CreatePaymentMethodRequest) -> Result<(), VaultError> {
+    if req.user_id.trim().is_empty() {
+        return Err(VaultError::Invalid("user_id is required".to_string()));
+    }
+    if !PROVIDERS.contains(&req.provider.as_str()) {
+        return Err(VaultError::Invalid(format!("unsupported provider '{}'", req.provider)));
+    }
+    if req.provider_token.trim().is_empty() || !req.provider_token.is_ascii() {
+        return Err(VaultError::Invalid("provider_token is required".to_string()));
+    }
+    if let Some(last4) = &req.last4 {
+        if last4.len() != 4 || !last4.chars().all(|c| c.is_ascii_digit()) {
+            return Err(VaultError::Invalid("last4 must be four digits".to_string()));
+        }
+    }
+
+    validate_expiry(req.exp_month, req.exp_year)
+}
+
+fn validate_expiry(exp_month: Option<i32>, exp_year: Option<i32>) -> Result<(), VaultError> {
+    if let Some(month) = exp_month {
+        if !(1..=12).contains(&month) {
+            return Err(VaultError::Invalid("exp_month must be between 1 and 12".to_string()));
+        }
+    }
+    if let Some(year) = exp_year {
+        if !(2000..=2100).contains(&year) {
+            return Err(VaultError::Invalid("exp_year is out of range".to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+// Database initialization
+pub async fn init_db(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS saved_payment_methods (
+            id UUID PRIMARY KEY,
+            user_id VARCHAR(255) NOT NULL,
+            provider VARCHAR(50) NOT NULL,
+            provider_token VARCHAR(255) NOT NULL,
+            brand VARCHAR(50) NOT NULL,
+            last4 VARCHAR(4),
+            exp_month INTEGER,
+            exp_year INTEGER,
+            is_default BOOLEAN NOT NULL DEFAULT FALSE,
+            created_at TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create saved_payment_methods table")?;
+
+    sqlx::query(
+        r#"
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_saved_payment_methods_token
+        ON saved_payment_methods (user_id, provider, provider_token)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // At most one default method per user
+    sqlx::query(
+        r#"
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_saved_payment_methods_default
+        ON saved_payment_methods (user_id) WHERE is_default
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,778 @@
+use anyhow::{bail, Context, Result};
+use chrono::{TimeZone, Utc};
+use protobuf::wire_format::WireType;
+use protobuf::{CodedInputStream, CodedOutputStream};
+use std::collections::HashSet;
+
+use crate::lifecycle::{
+    PaymentLifecycleData, PaymentLifecycleEvent, RefundDetails, TOPIC_PAYMENT_EXPIRED,
+    TOPIC_PAYMENT_FAILED, TOPIC_PAYMENT_PROCESSED, TOPIC_PAYMENT_REFUNDED,
+};
+use crate::models::{OrderItem, PaymentProcessCommand, SagaEvent, SellerSplit, SplitInstructions};
+use crate::schema_registry::{FieldDef, MessageDef, SchemaDef};
+
+/// Says how a message payload is encoded. Messages without it are JSON,
+/// unless they start with the Confluent magic byte.
+pub const CONTENT_TYPE_HEADER: &str = "content-type";
+pub const CONTENT_TYPE_JSON: &str = "application/json";
+/// Protobuf in the Confluent wire format
+pub const CONTENT_TYPE_PROTOBUF: &str = "application/x-protobuf";
+
+const MAGIC_BYTE: u8 = 0;
+
+const SAGA_RESPONSE_TOPIC: &str = "saga-response";
+const PAYMENT_PROCESS_TOPIC: &str = "payment-process";
+const LIFECYCLE_TOPICS: [&str; 4] = [
+    TOPIC_PAYMENT_PROCESSED,
+    TOPIC_PAYMENT_FAILED,
+    TOPIC_PAYMENT_REFUNDED,
+    TOPIC_PAYMENT_EXPIRED,
+];
+
+pub fn saga_event_schema() -> SchemaDef {
+    SchemaDef {
+        subject: "payments.SagaEvent".to_string(),
+        package: "payments".to_string(),
+        messages: vec![
+            MessageDef {
+                name: "SagaEvent".to_string(),
+                fields: vec![
+                    FieldDef::new(1, "saga_id", "string"),
+                    FieldDef::new(2, "order_id", "string"),
+                    FieldDef::new(3, "step", "string"),
+                    FieldDef::new(4, "success", "bool"),
+                    FieldDef::new(5, "message", "string"),
+                    // Payload of steps without a message of their own, as JSON
+                    FieldDef::new(6, "data_json", "string"),
+                    FieldDef::new(7, "timestamp_millis", "int64"),
+                    // Payload of payment-process requests, instead of data_json
+                    FieldDef::optional(8, "payment_process", "PaymentProcessCommand"),
+                ],
+            },
+            MessageDef {
+                name: "PaymentProcessCommand".to_string(),
+                fields: vec![
+                    FieldDef::new(1, "user_id", "string"),
+                    FieldDef::new(2, "total_amount", "double"),
+                    FieldDef::new(3, "currency", "string"),
+                    FieldDef::new(4, "payment_method", "string"),
+                    FieldDef::optional(5, "payment_method_id", "string"),
+                    FieldDef::repeated(6, "items", "OrderItem"),
+                    FieldDef::optional(7, "splits", "SplitInstructions"),
+                ],
+            },
+            MessageDef {
+                name: "OrderItem".to_string(),
+                fields: vec![
+                    FieldDef::new(1, "product_id", "string"),
+                    FieldDef::new(2, "quantity", "int64"),
+                    FieldDef::new(3, "price", "double"),
+                ],
+            },
+            MessageDef {
+                name: "SplitInstructions".to_string(),
+                fields: vec![
+                    FieldDef::repeated(1, "sellers", "SellerSplit"),
+                    FieldDef::new(2, "platform_fee_percent", "double"),
+                ],
+            },
+            MessageDef {
+                name: "SellerSplit".to_string(),
+                fields: vec![
+                    FieldDef::new(1, "seller_id", "string"),
+                    FieldDef::optional(2, "amount", "double"),
+                    FieldDef::optional(3, "percent", "double"),
+                ],
+            },
+        ],
+    }
+}
+
+pub fn payment_lifecycle_schema() -> SchemaDef {
+    SchemaDef {
+        subject: "payments.PaymentLifecycleEvent".to_string(),
+        package: "payments".to_string(),
+        messages: vec![
+            MessageDef {
+                name: "PaymentLifecycleEvent".to_string(),
+                fields: vec![
+                    FieldDef::new(1, "event_id", "string"),
+                    FieldDef::new(2, "event_type", "string"),
+                    FieldDef::new(3, "schema_version", "uint32"),
+                    FieldDef::new(4, "payment_id", "string"),
+                    FieldDef::optional(5, "order_id", "string"),
+                    FieldDef::new(6, "occurred_at_millis", "int64"),
+                    FieldDef::new(7, "data", "PaymentLifecycleData"),
+                ],
+            },
+            MessageDef {
+                name: "PaymentLifecycleData".to_string(),
+                fields: vec![
+                    FieldDef::new(1, "payment_id", "string"),
+                    FieldDef::optional(2, "order_id", "string"),
+                    FieldDef::optional(3, "user_id", "string"),
+                    FieldDef::new(4, "provider", "string"),
+                    FieldDef::new(5, "status", "string"),
+                    FieldDef::new(6, "amount", "double"),
+                    FieldDef::new(7, "currency", "string"),
+                    FieldDef::optional(8, "settlement_amount", "double"),
+                    FieldDef::optional(9, "settlement_currency", "string"),
+                    FieldDef::optional(10, "reference", "string"),
+                    FieldDef::optional(11, "reason", "string"),
+                    FieldDef::optional(12, "refund", "RefundDetails"),
+                    FieldDef::new(13, "refunded_amount", "double"),
+                ],
+            },
+            MessageDef {
+                name: "RefundDetails".to_string(),
+                fields: vec![
+                    FieldDef::new(1, "reference", "string"),
+                    FieldDef::new(2, "amount", "double"),
+                    FieldDef::new(3, "currency", "string"),
+                ],
+            },
+        ],
+    }
+}
+
+/// A payload ready to publish, with the content type to send alongside it.
+pub struct Encoded {
+    pub payload: Vec<u8>,
+    pub content_type: &'static str,
+}
+
+impl Encoded {
+    fn json(payload: &str) -> Self {
+        Self {
+            payload: payload.as_bytes().to_vec(),
+            content_type: CONTENT_TYPE_JSON,
+        }
+    }
+}
+
+/// Turns outbox payloads, which are always JSON, into what goes on the wire.
+/// Topics are only written as protobuf once every consumer of them decodes
+/// it, and only saga and lifecycle topics have schemas; everything else stays
+/// JSON.
+#[derive(Debug, Clone)]
+pub struct PayloadEncoder {
+    protobuf_topics: HashSet<String>,
+    saga_event_schema_id: i32,
+    lifecycle_schema_id: i32,
+}
+
+impl PayloadEncoder {
+    /// Takes the topics to write as protobuf and the registry ids the schemas
+    /// were registered under at startup.
+    pub fn new(
+        protobuf_topics: impl IntoIterator<Item = String>,
+        saga_event_schema_id: i32,
+        lifecycle_schema_id: i32,
+    ) -> Self {
+        Self {
+            protobuf_topics: protobuf_topics.into_iter().collect(),
+            saga_event_schema_id,
+            lifecycle_schema_id,
+        }
+    }
+
+    pub fn encode(&self, topic: &str, payload: &str) -> Result<Encoded> {
+        if !self.protobuf_topics.contains(topic) {
+            return Ok(Encoded::json(payload));
+        }
+
+        let (schema_id, body) = if topic == SAGA_RESPONSE_TOPIC || topic == PAYMENT_PROCESS_TOPIC {
+            let event: SagaEvent = serde_json::from_str(payload).context("Invalid saga event")?;
+            let command = if topic == PAYMENT_PROCESS_TOPIC {
+                Some(serde_json::from_value(event.data.clone()).context("Invalid payment-process data")?)
+            } else {
+                None
+            };
+            (self.saga_event_schema_id, encode_saga_event(&event, command.as_ref())?)
+        } else if LIFECYCLE_TOPICS.contains(&topic) {
+            let event: PaymentLifecycleEvent =
+                serde_json::from_str(payload).context("Invalid payment lifecycle event")?;
+            (self.lifecycle_schema_id, encode_lifecycle_event(&event)?)
+        } else {
+            return Ok(Encoded::json(payload));
+        };
+
+        Ok(Encoded {
+            payload: frame(schema_id, &body),
+            content_type: CONTENT_TYPE_PROTOBUF,
+        })
+    }
+}
+
+/// Reads a saga event in either encoding. Protobuf payloads are read by
+/// field number, so any registered version of the schema decodes.
+pub fn decode_saga_event(content_type: Option<&str>, payload: &[u8]) -> Result<SagaEvent> {
+    let protobuf = match content_type {
+        Some(CONTENT_TYPE_PROTOBUF) => true,
+        Some(_) => false,
+        None => payload.first() == Some(&MAGIC_BYTE),
+    };
+
+    if protobuf {
+        let (_, body) = unframe(payload)?;
+        decode_saga_event_body(body)
+    } else {
+        serde_json::from_slice(payload).context("Invalid saga event JSON")
+    }
+}
+
+/// Confluent wire format: magic byte, big-endian schema id, message indexes
+/// (a single 0 for the first message in the schema), then the message.
+fn frame(schema_id: i32, body: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(body.len() + 6);
+    framed.push(MAGIC_BYTE);
+    framed.extend_from_slice(&schema_id.to_be_bytes());
+    framed.push(0);
+    framed.extend_from_slice(body);
+    framed
+}
+
+/// Splits a framed payload into its schema id and message.
+fn unframe(payload: &[u8]) -> Result<(i32, &[u8])> {
+    if payload.len() < 6 || payload[0] != MAGIC_BYTE {
+        bail!("Payload is not in the Confluent wire format");
+    }
+    let schema_id = i32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]);
+
+    // Zigzag varint count, then that many indexes; [0] is written as a lone 0
+    let mut rest = &payload[5..];
+    let count = read_zigzag(&mut rest)?;
+    for _ in 0..count {
+        if read_zigzag(&mut rest)? != 0 {
+            bail!("Schema {} message is not the first in its schema", schema_id);
+        }
+    }
+
+    Ok((schema_id, rest))
+}
+
+fn read_zigzag(bytes: &mut &[u8]) -> Result<i64> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().context("Truncated message indexes")?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+        }
+    }
+    bail!("Malformed message indexes")
+}
+
+/// Writes the payload as a typed `payment_process` when given its command,
+/// and as `data_json` otherwise.
+fn encode_saga_event(event: &SagaEvent, command: Option<&PaymentProcessCommand>) -> Result<Vec<u8>> {
+    let command = command.map(encode_payment_process).transpose()?;
+
+    let mut body = Vec::new();
+    let mut os = CodedOutputStream::vec(&mut body);
+    os.write_string(1, &event.saga_id)?;
+    os.write_string(2, &event.order_id)?;
+    os.write_string(3, &event.step)?;
+    os.write_bool(4, event.success)?;
+    os.write_string(5, &event.message)?;
+    if command.is_none() {
+        os.write_string(6, &event.data.to_string())?;
+    }
+    os.write_int64(7, event.timestamp.timestamp_millis())?;
+    if let Some(command) = &command {
+        os.write_bytes(8, command)?;
+    }
+    os.flush()?;
+    drop(os);
+    Ok(body)
+}
+
+fn encode_payment_process(command: &PaymentProcessCommand) -> Result<Vec<u8>> {
+    let items = command.items.iter().map(encode_order_item).collect::<Result<Vec<_>>>()?;
+    let splits = command.splits.as_ref().map(encode_split_instructions).transpose()?;
+
+    let mut body = Vec::new();
+    let mut os = CodedOutputStream::vec(&mut body);
+    os.write_string(1, &command.user_id)?;
+    os.write_double(2, command.total_amount)?;
+    os.write_string(3, &command.currency)?;
+    os.write_string(4, &command.payment_method)?;
+    if let Some(payment_method_id) = &command.payment_method_id {
+        os.write_string(5, payment_method_id)?;
+    }
+    for item in &items {
+        os.write_bytes(6, item)?;
+    }
+    if let Some(splits) = &splits {
+        os.write_bytes(7, splits)?;
+    }
+    os.flush()?;
+    drop(os);
+    Ok(body)
+}
+
+fn encode_order_item(item: &OrderItem) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut os = CodedOutputStream::vec(&mut body);
+    os.write_string(1, &item.product_id)?;
+    os.write_int64(2, item.quantity)?;
+    os.write_double(3, item.price)?;
+    os.flush()?;
+    drop(os);
+    Ok(body)
+}
+
+fn encode_split_instructions(splits: &SplitInstructions) -> Result<Vec<u8>> {
+    let sellers = splits.sellers.iter().map(encode_seller_split).collect::<Result<Vec<_>>>()?;
+
+    let mut body = Vec::new();
+    let mut os = CodedOutputStream::vec(&mut body);
+    for seller in &sellers {
+        os.write_bytes(1, seller)?;
+    }
+    os.write_double(2, splits.platform_fee_percent)?;
+    os.flush()?;
+    drop(os);
+    Ok(body)
+}
+
+fn encode_seller_split(split: &SellerSplit) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut os = CodedOutputStream::vec(&mut body);
+    os.write_string(1, &split.seller_id.to_string())?;
+    if let Some(amount) = split.amount {
+        os.write_double(2, amount)?;
+    }
+    if let Some(percent) = split.percent {
+        os.write_double(3, percent)?;
+    }
+    os.flush()?;
+    drop(os);
+    Ok(body)
+}
+
+fn decode_saga_event_body(body: &[u8]) -> Result<SagaEvent> {
+    let mut is = CodedInputStream::from_bytes(body);
+    let mut event = SagaEvent {
+        saga_id: String::new(),
+        order_id: String::new(),
+        step: String::new(),
+        success: false,
+        message: String::new(),
+        data: serde_json::Value::Null,
+        timestamp: Utc::now(),
+    };
+
+    while !is.eof()? {
+        let (field, wire_type) = is.read_tag_unpack()?;
+        match (field, wire_type) {
+            (1, WireType::WireTypeLengthDelimited) => event.saga_id = is.read_string()?,
+            (2, WireType::WireTypeLengthDelimited) => event.order_id = is.read_string()?,
+            (3, WireType::WireTypeLengthDelimited) => event.step = is.read_string()?,
+            (4, WireType::WireTypeVarint) => event.success = is.read_bool()?,
+            (5, WireType::WireTypeLengthDelimited) => event.message = is.read_string()?,
+            (6, WireType::WireTypeLengthDelimited) => {
+                let data = is.read_string()?;
+                if !data.is_empty() {
+                    event.data = serde_json::from_str(&data).context("Invalid saga event data")?;
+                }
+            }
+            (7, WireType::WireTypeVarint) => {
+                let millis = is.read_int64()?;
+                event.timestamp = Utc
+                    .timestamp_millis_opt(millis)
+                    .single()
+                    .context("Invalid saga event timestamp")?;
+            }
+            // Handlers read the command from `data`, whichever field carried it
+            (8, WireType::WireTypeLengthDelimited) => {
+                let command = decode_payment_process(&is.read_bytes()?)?;
+                event.data = serde_json::to_value(command)?;
+            }
+            // Fields added by newer writers
+            (_, wire_type) => is.skip_field(wire_type)?,
+        }
+    }
+
+    Ok(event)
+}
+
+fn decode_payment_process(body: &[u8]) -> Result<PaymentProcessCommand> {
+    let mut is = CodedInputStream::from_bytes(body);
+    let mut command = PaymentProcessCommand {
+        user_id: String::new(),
+        total_amount: 0.0,
+        currency: String::new(),
+        payment_method: String::new(),
+        payment_method_id: None,
+        items: Vec::new(),
+        splits: None,
+    };
+
+    while !is.eof()? {
+        let (field, wire_type) = is.read_tag_unpack()?;
+        match (field, wire_type) {
+            (1, WireType::WireTypeLengthDelimited) => command.user_id = is.read_string()?,
+            (2, WireType::WireTypeFixed64) => command.total_amount = is.read_double()?,
+            (3, WireType::WireTypeLengthDelimited) => command.currency = is.read_string()?,
+            (4, WireType::WireTypeLengthDelimited) => command.payment_method = is.read_string()?,
+            (5, WireType::WireTypeLengthDelimited) => {
+                command.payment_method_id = Some(is.read_string()?)
+            }
+            (6, WireType::WireTypeLengthDelimited) => {
+                command.items.push(decode_order_item(&is.read_bytes()?)?)
+            }
+            (7, WireType::WireTypeLengthDelimited) => {
+                command.splits = Some(decode_split_instructions(&is.read_bytes()?)?)
+            }
+            (_, wire_type) => is.skip_field(wire_type)?,
+        }
+    }
+
+    Ok(command)
+}
+
+fn decode_order_item(body: &[u8]) -> Result<OrderItem> {
+    let mut is = CodedInputStream::from_bytes(body);
+    let mut item = OrderItem {
+        product_id: String::new(),
+        quantity: 0,
+        price: 0.0,
+    };
+
+    while !is.eof()? {
+        let (field, wire_type) = is.read_tag_unpack()?;
+        match (field, wire_type) {
+            (1, WireType::WireTypeLengthDelimited) => item.product_id = is.read_string()?,
+            (2, WireType::WireTypeVarint) => item.quantity = is.read_int64()?,
+            (3, WireType::WireTypeFixed64) => item.price = is.read_double()?,
+            (_, wire_type) => is.skip_field(wire_type)?,
+        }
+    }
+
+    Ok(item)
+}
+
+fn decode_split_instructions(body: &[u8]) -> Result<SplitInstructions> {
+    let mut is = CodedInputStream::from_bytes(body);
+    let mut splits = SplitInstructions {
+        sellers: Vec::new(),
+        platform_fee_percent: 0.0,
+    };
+
+    while !is.eof()? {
+        let (field, wire_type) = is.read_tag_unpack()?;
+        match (field, wire_type) {
+            (1, WireType::WireTypeLengthDelimited) => {
+                splits.sellers.push(decode_seller_split(&is.read_bytes()?)?)
+            }
+            (2, WireType::WireTypeFixed64) => splits.platform_fee_percent = is.read_double()?,
+            (_, wire_type) => is.skip_field(wire_type)?,
+        }
+    }
+
+    Ok(splits)
+}
+
+fn decode_seller_split(body: &[u8]) -> Result<SellerSplit> {
+    let mut is = CodedInputStream::from_bytes(body);
+    let mut seller_id = None;
+    let mut split_amount = None;
+    let mut percent = None;
+
+    while !is.eof()? {
+        let (field, wire_type) = is.read_tag_unpack()?;
+        match (field, wire_type) {
+            (1, WireType::WireTypeLengthDelimited) => {
+                seller_id = Some(is.read_string()?.parse().context("Invalid seller id")?)
+            }
+            (2, WireType::WireTypeFixed64) => split_amount = Some(is.read_double()?),
+            (3, WireType::WireTypeFixed64) => percent = Some(is.read_double()?),
+            (_, wire_type) => is.skip_field(wire_type)?,
+        }
+    }
+
+    Ok(SellerSplit {
+        seller_id: seller_id.context("Seller split without a seller id")?,
+        amount: split_amount,
+        percent,
+    })
+}
+
+fn encode_lifecycle_event(event: &PaymentLifecycleEvent) -> Result<Vec<u8>> {
+    let data = encode_lifecycle_data(&event.data)?;
+
+    let mut body = Vec::new();
+    let mut os = CodedOutputStream::vec(&mut body);
+    os.write_string(1, &event.event_id)?;
+    os.write_string(2, &event.event_type)?;
+    os.write_uint32(3, event.schema_version)?;
+    os.write_string(4, &event.payment_id)?;
+    if let Some(order_id) = &event.order_id {
+        os.write_string(5, order_id)?;
+    }
+    os.write_int64(6, event.occurred_at.timestamp_millis())?;
+    os.write_bytes(7, &data)?;
+    os.flush()?;
+    drop(os);
+    Ok(body)
+}
+
+fn encode_lifecycle_data(data: &PaymentLifecycleData) -> Result<Vec<u8>> {
+    let refund = data.refund.as_ref().map(encode_refund).transpose()?;
+
+    let mut body = Vec::new();
+    let mut os = CodedOutputStream::vec(&mut body);
+    os.write_string(1, &data.payment_id)?;
+    if let Some(order_id) = &data.order_id {
+        os.write_string(2, order_id)?;
+    }
+    if let Some(user_id) = &data.user_id {
+        os.write_string(3, user_id)?;
+    }
+    os.write_string(4, &data.provider)?;
+    os.write_string(5, &data.status)?;
+    os.write_double(6, data.amount)?;
+    os.write_string(7, &data.currency)?;
+    if let Some(amount) = data.settlement_amount {
+        os.write_double(8, amount)?;
+    }
+    if let Some(currency) = &data.settlement_currency {
+        os.write_string(9, currency)?;
+    }
+    if let Some(reference) = &data.reference {
+        os.write_string(10, reference)?;
+    }
+    if let Some(reason) = &data.reason {
+        os.write_string(11, reason)?;
+    }
+    if let Some(refund) = &refund {
+        os.write_bytes(12, refund)?;
+    }
+    os.write_double(13, data.refunded_amount)?;
+    os.flush()?;
+    drop(os);
+    Ok(body)
+}
+
+fn encode_refund(refund: &RefundDetails) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut os = CodedOutputStream::vec(&mut body);
+    os.write_string(1, &refund.reference)?;
+    os.write_double(2, refund.amount)?;
+    os.write_string(3, &refund.currency)?;
+    os.flush()?;
+    drop(os);
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn saga_event(data: serde_json::Value) -> SagaEvent {
+        SagaEvent {
+            saga_id: "saga-1".to_string(),
+            order_id: "order-1".to_string(),
+            step: "PAYMENT_PROCESS".to_string(),
+            success: true,
+            message: "Process payment".to_string(),
+            data,
+            timestamp: Utc.timestamp_millis_opt(1_700_000_000_123).unwrap(),
+        }
+    }
+
+    fn command() -> PaymentProcessCommand {
+        PaymentProcessCommand {
+            user_id: "user-1".to_string(),
+            total_amount: 59.97,
+            currency: "EUR".to_string(),
+            payment_method: "credit_card".to_string(),
+            payment_method_id: Some("pm_1".to_string()),
+            items: vec![
+                OrderItem {
+                    product_id: "p-1".to_string(),
+                    quantity: 2,
+                    price: 19.99,
+                },
+                OrderItem {
+                    product_id: "p-2".to_string(),
+                    quantity: 1,
+                    price: 19.99,
+                },
+            ],
+            splits: Some(SplitInstructions {
+                sellers: vec![
+                    SellerSplit {
+                        seller_id: Uuid::from_u128(1),
+                        amount: Some(40.0),
+                        percent: None,
+                    },
+                    SellerSplit {
+                        seller_id: Uuid::from_u128(2),
+                        amount: None,
+                        percent: Some(25.0),
+                    },
+                ],
+                platform_fee_percent: 5.0,
+            }),
+        }
+    }
+
+    fn json(value: &impl serde::Serialize) -> serde_json::Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    #[test]
+    fn unframe_returns_the_framed_schema_and_body() {
+        let framed = frame(42, b"body");
+        let (schema_id, body) = unframe(&framed).unwrap();
+        assert_eq!(schema_id, 42);
+        assert_eq!(body, b"body");
+    }
+
+    #[test]
+    fn unframe_reads_an_explicit_first_message_index() {
+        // One index, zigzag encoded, pointing at message 0
+        let (schema_id, body) = unframe(&[0, 0, 0, 0, 7, 2, 0, 0xaa]).unwrap();
+        assert_eq!(schema_id, 7);
+        assert_eq!(body, [0xaa]);
+    }
+
+    #[test]
+    fn unframe_rejects_malformed_payloads() {
+        assert!(unframe(&[]).is_err());
+        assert!(unframe(&[0, 0, 0, 1]).is_err());
+        assert!(unframe(&[1, 0, 0, 0, 1, 0]).is_err());
+        // Index of the second message in the schema
+        assert!(unframe(&[0, 0, 0, 0, 1, 2, 2]).is_err());
+        // Two indexes announced, none present
+        assert!(unframe(&[0, 0, 0, 0, 1, 4]).is_err());
+    }
+
+    #[test]
+    fn read_zigzag_decodes_signed_varints() {
+        for (bytes, expected) in [
+            (&[0x00][..], 0),
+            (&[0x01][..], -1),
+            (&[0x02][..], 1),
+            (&[0x03][..], -2),
+            (&[0xd8, 0x04][..], 300),
+        ] {
+            let mut rest = bytes;
+            assert_eq!(read_zigzag(&mut rest).unwrap(), expected);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn read_zigzag_rejects_truncated_and_overlong_varints() {
+        let mut truncated: &[u8] = &[0x80];
+        assert!(read_zigzag(&mut truncated).is_err());
+
+        let mut overlong: &[u8] = &[0x80; 11];
+        assert!(read_zigzag(&mut overlong).is_err());
+    }
+
+    #[test]
+    fn saga_event_round_trips_with_json_data() {
+        let event = saga_event(serde_json::json!({ "payment_id": "p-1", "decline_code": null }));
+
+        let decoded = decode_saga_event_body(&encode_saga_event(&event, None).unwrap()).unwrap();
+
+        assert_eq!(json(&decoded), json(&event));
+    }
+
+    #[test]
+    fn payment_process_round_trips_as_a_typed_command() {
+        let command = command();
+        let event = saga_event(json(&command));
+
+        let decoded =
+            decode_saga_event_body(&encode_saga_event(&event, Some(&command)).unwrap()).unwrap();
+
+        assert_eq!(json(&decoded), json(&event));
+        let decoded: PaymentProcessCommand = serde_json::from_value(decoded.data).unwrap();
+        assert_eq!(json(&decoded), json(&command));
+    }
+
+    #[test]
+    fn payment_process_round_trips_without_optional_fields() {
+        let mut command = command();
+        command.payment_method_id = None;
+        command.splits = None;
+
+        let decoded =
+            decode_payment_process(&encode_payment_process(&command).unwrap()).unwrap();
+
+        assert_eq!(json(&decoded), json(&command));
+    }
+
+    #[test]
+    fn encoder_writes_opted_in_topics_as_protobuf() {
+        let encoder = PayloadEncoder::new([PAYMENT_PROCESS_TOPIC.to_string()], 3, 4);
+        let event = saga_event(json(&command()));
+        let payload = serde_json::to_string(&event).unwrap();
+
+        let encoded = encoder.encode(PAYMENT_PROCESS_TOPIC, &payload).unwrap();
+        assert_eq!(encoded.content_type, CONTENT_TYPE_PROTOBUF);
+        assert_eq!(unframe(&encoded.payload).unwrap().0, 3);
+
+        let decoded = decode_saga_event(Some(encoded.content_type), &encoded.payload).unwrap();
+        assert_eq!(json(&decoded), json(&event));
+        // Framed payloads are recognized without the header too
+        let decoded = decode_saga_event(None, &encoded.payload).unwrap();
+        assert_eq!(json(&decoded), json(&event));
+    }
+
+    #[test]
+    fn encoder_leaves_other_topics_json() {
+        let encoder = PayloadEncoder::new([PAYMENT_PROCESS_TOPIC.to_string()], 3, 4);
+        let payload = serde_json::to_string(&saga_event(serde_json::json!({}))).unwrap();
+
+        let encoded = encoder.encode(SAGA_RESPONSE_TOPIC, &payload).unwrap();
+        assert_eq!(encoded.content_type, CONTENT_TYPE_JSON);
+        assert_eq!(encoded.payload, payload.as_bytes());
+
+        let decoded = decode_saga_event(Some(CONTENT_TYPE_JSON), &encoded.payload).unwrap();
+        assert_eq!(decoded.saga_id, "saga-1");
+    }
+
+    #[test]
+    fn decoder_skips_fields_it_does_not_know() {
+        let mut body = encode_saga_event(&saga_event(serde_json::json!({})), None).unwrap();
+        let mut os = CodedOutputStream::vec(&mut body);
+        os.write_string(99, "from a newer writer").unwrap();
+        os.write_int64(100, 7).unwrap();
+        os.flush().unwrap();
+        drop(os);
+
+        let decoded = decode_saga_event_body(&body).unwrap();
+        assert_eq!(decoded.order_id, "order-1");
+    }
+
+    #[test]
+    fn decoder_rejects_malformed_bodies() {
+        let body = encode_saga_event(&saga_event(json(&command())), Some(&command())).unwrap();
+        assert!(decode_saga_event_body(&body[..body.len() - 1]).is_err());
+
+        let mut bad_data = Vec::new();
+        let mut os = CodedOutputStream::vec(&mut bad_data);
+        os.write_string(6, "{not json").unwrap();
+        os.flush().unwrap();
+        drop(os);
+        assert!(decode_saga_event_body(&bad_data).is_err());
+
+        let mut no_seller = Vec::new();
+        let mut os = CodedOutputStream::vec(&mut no_seller);
+        os.write_double(2, 10.0).unwrap();
+        os.flush().unwrap();
+        drop(os);
+        assert!(decode_seller_split(&no_seller).is_err());
+
+        assert!(decode_saga_event(Some(CONTENT_TYPE_PROTOBUF), b"{}").is_err());
+        assert!(decode_saga_event(Some(CONTENT_TYPE_JSON), b"{").is_err());
+    }
+}